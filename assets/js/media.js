@@ -0,0 +1,59 @@
+// 写文章页面: 选择文件后上传到媒体库, 把返回的 Markdown 插入到正文的光标位置
+(function () {
+    var picker = document.querySelector(".media_picker");
+    if (!picker) {
+        return;
+    }
+    var form = picker.closest("form");
+    var input = picker.querySelector("input[type=file]");
+    var status = picker.querySelector(".media_status");
+    var textarea = form.querySelector("textarea[name=raw_content]");
+    var token = form.querySelector("input[name=csrf_token]").value;
+
+    function insert(text) {
+        var start = textarea.selectionStart;
+        var end = textarea.selectionEnd;
+        var value = textarea.value;
+        textarea.value = value.slice(0, start) + text + value.slice(end);
+        textarea.selectionStart = textarea.selectionEnd = start + text.length;
+        textarea.focus();
+    }
+
+    function upload(file) {
+        var data = new FormData();
+        data.append("file", file);
+        return fetch("/media/upload", {
+            method: "POST",
+            headers: { "Accept": "application/json", "X-CSRF-Token": token },
+            credentials: "same-origin",
+            body: data
+        }).then(function (resp) {
+            return resp.json().then(function (body) {
+                if (!resp.ok) {
+                    throw new Error(body.message || resp.statusText);
+                }
+                return body;
+            });
+        });
+    }
+
+    input.addEventListener("change", function () {
+        var files = Array.prototype.slice.call(input.files);
+        var chain = Promise.resolve();
+        files.forEach(function (file) {
+            chain = chain.then(function () {
+                status.textContent = "正在上传 " + file.name;
+                return upload(file).then(function (body) {
+                    insert(body.markdown + "\n");
+                });
+            });
+        });
+        chain.then(function () {
+            status.textContent = "";
+        }, function (err) {
+            status.textContent = "上传失败: " + err.message;
+        }).then(function () {
+            input.value = "";
+        });
+    });
+})();
@@ -0,0 +1,327 @@
+//!
+//! 管理工具 `blog-admin` 的操作: 用户的角色、封禁和改名, 文章和评论的维护.
+//! 修改用户的操作和管理页面一样记录审计日志, 操作人为空, `detail.source` 为 [`SOURCE`]
+//!
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+    Database,
+};
+use serde_derive::Deserialize;
+use tracing::{info, warn};
+
+use crate::audit::{self, AuditEntry};
+use crate::db;
+use crate::error::{AppError, Result};
+use crate::handler::format_datetime;
+use crate::imaging;
+use crate::model::{Ban, BanKind, Comment, Role, User};
+use crate::notification;
+use crate::session_store;
+use crate::storage::Storage;
+
+/// 审计日志中记录的操作来源
+pub const SOURCE: &str = "blog-admin";
+
+fn record(action: &str, user: &User, mut detail: Document) -> AuditEntry {
+    detail.insert("source", SOURCE);
+    AuditEntry::new(action, "user", user.id).detail(detail)
+}
+
+/// 修改角色, 至少保留一个管理员
+pub async fn set_role(user: &User, role: Role, mongo: &Database) -> Result<()> {
+    if user.role == Role::Admin
+        && role != Role::Admin
+        && db::count_users_by_role(Role::Admin, mongo).await? <= 1
+    {
+        return Err(AppError::bad_request("不能移除最后一个管理员"));
+    }
+    db::update_user_role(&user.id.to_string(), role, mongo).await?;
+    record(
+        audit::USER_ROLE,
+        user,
+        doc! {
+            "username": &user.username,
+            "previous_role": user.role.as_str(),
+            "role": role.as_str(),
+        },
+    )
+    .record(mongo)
+    .await;
+    info!(
+        "user {} role {} -> {}",
+        user.username,
+        user.role.as_str(),
+        role.as_str()
+    );
+    Ok(())
+}
+
+/// 封禁用户, `days` 为 0 表示永久封禁
+pub async fn ban(
+    user: &User,
+    kind: BanKind,
+    reason: &str,
+    days: u32,
+    mongo: &Database,
+) -> Result<()> {
+    if user.role == Role::Admin {
+        return Err(AppError::bad_request("不能封禁管理员, 请先修改角色"));
+    }
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::bad_request("封禁原因不能为空"));
+    }
+
+    let now = Utc::now();
+    let expires_at = (days > 0).then(|| (now + chrono::Duration::days(days as i64)).into());
+    let ban = Ban {
+        kind,
+        reason: reason.to_owned(),
+        expires_at,
+        banned_by: None,
+        created_time: now,
+    };
+    let action = match kind {
+        BanKind::Ban => audit::USER_BAN,
+        BanKind::ShadowBan => audit::USER_SHADOW_BAN,
+    };
+    let detail = doc! {
+        "username": &user.username,
+        "reason": reason,
+        "expires_at": expires_at.map(|t: bson::DateTime| format_datetime(t.to_chrono())),
+    };
+    db::ban_user(user.id, ban, mongo).await?;
+    record(action, user, detail).record(mongo).await;
+    if kind == BanKind::Ban {
+        session_store::revoke_all_sessions(&user.id.to_string(), mongo).await?;
+    }
+    info!("user {} {}", user.username, kind.as_str());
+    Ok(())
+}
+
+pub async fn unban(user: &User, reason: &str, mongo: &Database) -> Result<()> {
+    db::unban_user(user.id, mongo).await?;
+    record(
+        audit::USER_UNBAN,
+        user,
+        doc! {"username": &user.username, "reason": reason.trim()},
+    )
+    .record(mongo)
+    .await;
+    info!("user {} unbanned", user.username);
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct RenameReport {
+    /// 修改了评论作者名或被回复人名字的文章
+    pub articles: u64,
+    pub notifications: u64,
+}
+
+///
+/// 修改用户名, 同步评论和通知中保存的名字. 审计日志是历史记录, 不修改;
+/// 已经登录的会话中的名字在重新登录后更新
+///
+pub async fn rename(user: &User, username: &str, mongo: &Database) -> Result<RenameReport> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err(AppError::bad_request("用户名不能为空"));
+    }
+    let report = RenameReport {
+        articles: db::rename_user(user.id, username, mongo).await?,
+        notifications: notification::rename_actor(user.id, username, mongo).await?,
+    };
+    record(
+        audit::USER_RENAME,
+        user,
+        doc! {"username": username, "previous_username": &user.username},
+    )
+    .record(mongo)
+    .await;
+    info!("user {} renamed to {}", user.username, username);
+    Ok(report)
+}
+
+#[derive(Debug, Deserialize)]
+struct ArticleContent {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    raw_content: String,
+}
+
+#[derive(Debug, Default)]
+pub struct RerenderReport {
+    pub articles: u64,
+    /// 重新生成了衍生文件的图片
+    pub images: u64,
+    /// 生成失败的图片: (哈希, 错误)
+    pub failed: Vec<(String, String)>,
+}
+
+///
+/// 重新渲染文章(`article` 为空时为全部文章), 并重新生成文章中引用的图片的衍生文件.
+/// 文章的 HTML 在请求时从 Markdown 渲染, 不保存, 需要重新生成的只有图片
+///
+pub async fn rerender(
+    article: Option<ObjectId>,
+    storage: &dyn Storage,
+    mongo: &Database,
+) -> Result<RerenderReport> {
+    let filter = match article {
+        Some(id) => doc! {"_id": id},
+        None => doc! {},
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {"raw_content": 1})
+        .build();
+    let mut cursor = mongo
+        .collection::<ArticleContent>("article")
+        .find(filter, options)
+        .await?;
+
+    let mut report = RerenderReport::default();
+    let mut hashes = BTreeSet::new();
+    while let Some(article) = cursor.next().await {
+        let article = article?;
+        let html = markdown::to_html(&article.raw_content);
+        let found = imaging::hashes_in(&html);
+        if !found.is_empty() {
+            info!("article {}: {} images", article.id, found.len());
+        }
+        hashes.extend(found);
+        report.articles += 1;
+    }
+    if article.is_some() && report.articles == 0 {
+        return Err(AppError::NotFound);
+    }
+
+    for hash in hashes {
+        match imaging::rederive(&hash, storage, mongo).await {
+            Ok(Some(_)) => report.images += 1,
+            // 外部图片, 或者 GIF 等不生成衍生文件的格式
+            Ok(None) => {}
+            Err(err) => {
+                warn!("derive image {}: {}", hash, err);
+                report.failed.push((hash, err.to_string()));
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentStatus {
+    status: i16,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArticleComments {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    title: String,
+    #[serde(default)]
+    comments: Option<Vec<CommentStatus>>,
+}
+
+/// 一篇文章各个状态的评论数
+#[derive(Debug, Default, PartialEq)]
+pub struct CommentCount {
+    pub article_id: String,
+    pub title: String,
+    pub visible: u32,
+    pub pending: u32,
+    pub rejected: u32,
+    pub spam: u32,
+}
+
+impl CommentCount {
+    fn add(&mut self, status: i16) {
+        match status {
+            Comment::VISIBLE => self.visible += 1,
+            Comment::PENDING => self.pending += 1,
+            Comment::REJECTED => self.rejected += 1,
+            _ => self.spam += 1,
+        }
+    }
+}
+
+///
+/// 按文章重新统计评论数. 页面上的评论数是查询时从评论计算的(还会排除被封禁用户的评论),
+/// 数据库中没有保存计数, 这里只用于核对
+///
+pub async fn count_comments(mongo: &Database) -> Result<Vec<CommentCount>> {
+    let options = FindOptions::builder()
+        .projection(doc! {"title": 1, "comments.status": 1})
+        .sort(doc! {"created_time": -1})
+        .build();
+    let mut cursor = mongo
+        .collection::<ArticleComments>("article")
+        .find(doc! {}, options)
+        .await?;
+
+    let mut result = Vec::new();
+    while let Some(article) = cursor.next().await {
+        let article = article?;
+        let mut count = CommentCount {
+            article_id: article.id.to_string(),
+            title: article.title,
+            ..Default::default()
+        };
+        for comment in article.comments.unwrap_or_default() {
+            count.add(comment.status);
+        }
+        result.push(count);
+    }
+    Ok(result)
+}
+
+///
+/// 未通过审核和垃圾评论只是不公开显示, 仍然保存在文章中; 这里删除审核时间早于 `before` 的.
+/// 清除后的垃圾评论不再用于重新训练分类器, 已经训练好的分类器不受影响.
+/// `dry_run` 时只统计, 返回清除(或将要清除)的评论数
+///
+pub async fn purge_comments(before: DateTime<Utc>, dry_run: bool, mongo: &Database) -> Result<u64> {
+    if dry_run {
+        return db::count_purgeable_comments(before, mongo).await;
+    }
+    let count = db::purge_comments(before, mongo).await?;
+    if count > 0 {
+        AuditEntry::new(audit::COMMENT_PURGE, "comment", "")
+            .detail(doc! {
+                "count": count as i64,
+                "before": format_datetime(before),
+                "source": SOURCE,
+            })
+            .record(mongo)
+            .await;
+    }
+    info!("purged {} comments", count);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment_count() {
+        let mut count = CommentCount::default();
+        for status in [
+            Comment::VISIBLE,
+            Comment::VISIBLE,
+            Comment::PENDING,
+            Comment::REJECTED,
+            Comment::SPAM,
+        ] {
+            count.add(status);
+        }
+        assert_eq!((count.visible, count.pending), (2, 1));
+        assert_eq!((count.rejected, count.spam), (1, 1));
+    }
+}
@@ -0,0 +1,326 @@
+//!
+//! 审计日志: 登录、内容修改和管理操作都追加一条记录到 `audit` 集合.
+//! 记录只追加, 不提供修改和删除的接口
+//!
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::{FindOptions, IndexOptions},
+    Database, IndexModel,
+};
+use poem::{Endpoint, IntoResponse, Request, Response};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config;
+use crate::error::{AppError, Result};
+use crate::middleware;
+use crate::model::User;
+
+pub const AUTH_SIGNIN: &str = "auth.signin";
+pub const AUTH_SIGNIN_FAILED: &str = "auth.signin_failed";
+pub const ARTICLE_CREATE: &str = "article.create";
+pub const ARTICLE_UPDATE: &str = "article.update";
+pub const COMMENT_CREATE: &str = "comment.create";
+pub const COMMENT_MODERATE: &str = "comment.moderate";
+pub const COMMENT_PURGE: &str = "comment.purge";
+pub const SPAM_FILTER_RETRAIN: &str = "spam_filter.retrain";
+pub const USER_ROLE: &str = "user.role";
+pub const USER_BAN: &str = "user.ban";
+pub const USER_SHADOW_BAN: &str = "user.shadow_ban";
+pub const USER_UNBAN: &str = "user.unban";
+pub const USER_RENAME: &str = "user.rename";
+pub const SUBSCRIBER_IMPORT: &str = "subscriber.import";
+pub const SUBSCRIBER_DELETE: &str = "subscriber.delete";
+pub const MEDIA_UPLOAD: &str = "media.upload";
+pub const MEDIA_DELETE: &str = "media.delete";
+
+/// 所有的操作类型, 用于筛选
+pub const ACTIONS: &[&str] = &[
+    AUTH_SIGNIN,
+    AUTH_SIGNIN_FAILED,
+    ARTICLE_CREATE,
+    ARTICLE_UPDATE,
+    COMMENT_CREATE,
+    COMMENT_MODERATE,
+    COMMENT_PURGE,
+    SPAM_FILTER_RETRAIN,
+    USER_ROLE,
+    USER_BAN,
+    USER_SHADOW_BAN,
+    USER_UNBAN,
+    USER_RENAME,
+    SUBSCRIBER_IMPORT,
+    SUBSCRIBER_DELETE,
+    MEDIA_UPLOAD,
+    MEDIA_DELETE,
+];
+
+/// 导出 CSV 的最大行数
+pub const EXPORT_LIMIT: i64 = 100_000;
+
+tokio::task_local! {
+    /// 当前请求的客户端 IP, 由 [`capture`] 中间件设置
+    static CLIENT_IP: Option<String>;
+}
+
+///
+/// Model: AuditEntry
+/// Db table: audit
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub action: String,
+    /// 操作人, 登录失败等匿名操作为空
+    pub actor_id: Option<ObjectId>,
+    pub actor_name: Option<String>,
+    /// 操作对象的类型(article/comment/user)和 id
+    pub target_type: String,
+    pub target_id: String,
+    /// 操作相关的数据, 例如修改前后的值
+    pub detail: Document,
+    pub ip: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+}
+
+impl AuditEntry {
+    pub fn new(action: &str, target_type: &str, target_id: impl ToString) -> Self {
+        Self {
+            id: ObjectId::new(),
+            action: action.to_owned(),
+            actor_id: None,
+            actor_name: None,
+            target_type: target_type.to_owned(),
+            target_id: target_id.to_string(),
+            detail: Document::new(),
+            ip: CLIENT_IP.try_with(Clone::clone).ok().flatten(),
+            created_time: Utc::now(),
+        }
+    }
+
+    pub fn actor(mut self, user: &User) -> Self {
+        self.actor_id = Some(user.id);
+        self.actor_name = Some(user.username.clone());
+        self
+    }
+
+    pub fn detail(mut self, detail: Document) -> Self {
+        self.detail = detail;
+        self
+    }
+
+    /// 写入审计日志; 写入失败只记录错误日志, 不影响已经完成的操作
+    pub async fn record(self, mongo: &Database) {
+        if let Err(err) = mongo
+            .collection::<AuditEntry>("audit")
+            .insert_one(&self, None)
+            .await
+        {
+            warn!("write audit log {} error: {}", self.action, err);
+        }
+    }
+}
+
+/// `audit` 集合的索引, 启动时由 [`crate::indexes`] 创建
+pub fn indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"created_time": -1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"action": 1, "created_time": -1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"actor_name": 1, "created_time": -1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"target_id": 1, "created_time": -1})
+            .options(IndexOptions::builder().sparse(true).build())
+            .build(),
+    ]
+}
+
+/// 查询条件, 为空的条件不生效
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    /// 操作类型, 以 `.` 结尾时按前缀匹配(例如 `user.`)
+    pub action: Option<String>,
+    pub actor_name: Option<String>,
+    pub target_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl AuditFilter {
+    pub fn to_query(&self) -> Document {
+        let mut query = Document::new();
+        match self.action.as_deref() {
+            Some(action) if action.ends_with('.') => {
+                let prefix = format!("^{}", regex_escape(action));
+                query.insert("action", doc! {"$regex": prefix});
+            }
+            Some(action) => {
+                query.insert("action", action);
+            }
+            None => {}
+        }
+        if let Some(actor_name) = &self.actor_name {
+            query.insert("actor_name", actor_name);
+        }
+        if let Some(target_id) = &self.target_id {
+            query.insert("target_id", target_id);
+        }
+        let mut time = Document::new();
+        if let Some(from) = self.from {
+            time.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            time.insert("$lt", to);
+        }
+        if !time.is_empty() {
+            query.insert("created_time", time);
+        }
+        query
+    }
+}
+
+fn regex_escape(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| {
+            let escape = "\\^$.|?*+()[]{}".contains(c);
+            escape.then_some('\\').into_iter().chain([c])
+        })
+        .collect()
+}
+
+/// 按时间倒序查询, `limit` 为 0 时不限制, 返回 (记录, 总数)
+pub async fn list(
+    filter: &AuditFilter,
+    skip: u64,
+    limit: i64,
+    mongo: &Database,
+) -> Result<(Vec<AuditEntry>, u64)> {
+    let collection = mongo.collection::<AuditEntry>("audit");
+    let query = filter.to_query();
+    let total = collection.count_documents(query.clone(), None).await?;
+
+    let options = FindOptions::builder()
+        .sort(doc! {"created_time": -1})
+        .skip(skip)
+        .limit(limit)
+        .build();
+    let mut cursor = collection.find(query, options).await?;
+    let mut result = Vec::new();
+    while let Some(entry) = cursor.next().await {
+        result.push(entry?);
+    }
+    Ok((result, total))
+}
+
+/// 导出为 CSV, 时间使用页面显示的时区
+pub fn to_csv(entries: &[AuditEntry], timezone: &FixedOffset) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer
+        .write_record([
+            "time",
+            "action",
+            "actor_id",
+            "actor_name",
+            "target_type",
+            "target_id",
+            "detail",
+            "ip",
+        ])
+        .map_err(AppError::internal)?;
+    for entry in entries {
+        let detail = serde_json::to_string(&entry.detail).map_err(AppError::internal)?;
+        writer
+            .write_record([
+                entry
+                    .created_time
+                    .with_timezone(timezone)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+                    .as_str(),
+                entry.action.as_str(),
+                entry
+                    .actor_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_default()
+                    .as_str(),
+                entry.actor_name.as_deref().unwrap_or_default(),
+                entry.target_type.as_str(),
+                entry.target_id.as_str(),
+                detail.as_str(),
+                entry.ip.as_deref().unwrap_or_default(),
+            ])
+            .map_err(AppError::internal)?;
+    }
+    writer.into_inner().map_err(AppError::internal)
+}
+
+/// 记录请求的客户端 IP, 供审计日志使用
+pub async fn capture<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+    let ip =
+        middleware::client_ip(&req, &config::get().server.trusted_proxies).map(|ip| ip.to_string());
+    CLIENT_IP
+        .scope(ip, async move {
+            next.call(req).await.map(IntoResponse::into_response)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_query() {
+        assert_eq!(AuditFilter::default().to_query(), doc! {});
+
+        let from = Utc::now();
+        let filter = AuditFilter {
+            action: Some("user.".to_owned()),
+            actor_name: Some("joey".to_owned()),
+            from: Some(from),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.to_query(),
+            doc! {
+                "action": {"$regex": "^user\\."},
+                "actor_name": "joey",
+                "created_time": {"$gte": from},
+            }
+        );
+
+        let filter = AuditFilter {
+            action: Some(USER_BAN.to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(filter.to_query(), doc! {"action": "user.ban"});
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let mut entry =
+            AuditEntry::new(ARTICLE_UPDATE, "article", "a1").detail(doc! {"title": "hello"});
+        entry.actor_name = Some("joey, cat".to_owned());
+        // 不在请求中时没有客户端 IP
+        assert_eq!(entry.ip, None);
+
+        let csv = to_csv(&[entry], &FixedOffset::east_opt(8 * 3600).unwrap()).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "time,action,actor_id,actor_name,target_type,target_id,detail,ip"
+        );
+        assert!(lines[1].contains(",article.update,"));
+        assert!(lines[1].contains(",\"joey, cat\",article,a1,\"{\"\"title\"\":\"\"hello\"\"}\","));
+    }
+}
@@ -0,0 +1,520 @@
+//!
+//! 备份和恢复: `blog backup` 把数据库和上传的文件打包成一个 `.tar.gz`, `blog restore` 校验后导入到空数据库.
+//!
+//! 归档的格式(见 README 的「备份与恢复」, 修改时增加 [`FORMAT_VERSION`]):
+//!
+//! - `collections/{集合}.jsonl`: 每行一个文档, MongoDB Extended JSON v2(canonical), 按 `_id` 排序
+//! - `media/{key}`: `media` 集合中引用的文件, key 和存储中的路径相同
+//! - `manifest.json`: 最后一个文件, 记录格式版本、每个文件的 SHA-256 和记录数
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bson::{Bson, Document};
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::StreamExt;
+use mongodb::{bson::doc, options::FindOptions, Database};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::error::{AppError, Result};
+use crate::media::Media;
+use crate::migrate;
+use crate::storage::Storage;
+
+pub const FORMAT: &str = "blog-backup";
+
+/// 归档格式的版本, 只在格式本身变化时增加; 集合中文档的结构变化由数据迁移处理
+pub const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+
+/// 不备份的集合: 会话和限流计数, 恢复后重新产生
+const EXCLUDED: [&str; 2] = ["session", "rate_limit"];
+
+/// 恢复时每批插入的文档数
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub format_version: u32,
+    /// 生成备份的程序版本, 只用于排查问题
+    pub app_version: String,
+    pub created_time: DateTime<Utc>,
+    pub collections: Vec<CollectionEntry>,
+    pub media: Vec<MediaEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionEntry {
+    pub name: String,
+    pub path: String,
+    pub count: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MediaEntry {
+    pub key: String,
+    pub path: String,
+    pub content_type: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 文档转换成一行 Extended JSON, 保留 ObjectId、日期和整数的类型
+pub fn encode_document(document: Document) -> Result<String> {
+    serde_json::to_string(&Bson::Document(document).into_canonical_extjson())
+        .map_err(AppError::internal)
+}
+
+pub fn decode_document(line: &str) -> Result<Document> {
+    let value: serde_json::Value = serde_json::from_str(line).map_err(AppError::internal)?;
+    match Bson::try_from(value).map_err(AppError::internal)? {
+        Bson::Document(document) => Ok(document),
+        other => Err(AppError::internal(format!(
+            "expect a document, got {:?}",
+            other.element_type()
+        ))),
+    }
+}
+
+/// 不需要恢复的文档: 数据迁移的锁
+fn is_transient(collection: &str, document: &Document) -> bool {
+    collection == migrate::COLLECTION && document.get_str("_id") == Ok(migrate::LOCK_ID)
+}
+
+///
+/// 一个集合的文档逐行写入临时文件, 同时计算 SHA-256 和记录数,
+/// 由 [`ArchiveWriter::add_collection`] 加入归档, 不需要把整个集合放在内存中
+///
+pub struct CollectionFile {
+    name: String,
+    path: PathBuf,
+    file: BufWriter<File>,
+    hasher: Sha256,
+    count: u64,
+}
+
+impl CollectionFile {
+    pub fn write(&mut self, document: Document) -> Result<()> {
+        let mut line = encode_document(document)?;
+        line.push('\n');
+        self.write_line(line.as_bytes())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        self.hasher.update(line);
+        self.file.write_all(line).map_err(AppError::internal)?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// 按顺序写入归档, 清单在 [`ArchiveWriter::finish`] 时最后写入
+pub struct ArchiveWriter {
+    path: PathBuf,
+    builder: tar::Builder<GzEncoder<File>>,
+    manifest: Manifest,
+}
+
+impl ArchiveWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).map_err(AppError::internal)?;
+        Ok(Self {
+            path: path.to_owned(),
+            builder: tar::Builder::new(GzEncoder::new(file, Compression::default())),
+            manifest: Manifest {
+                format: FORMAT.to_owned(),
+                format_version: FORMAT_VERSION,
+                app_version: env!("CARGO_PKG_VERSION").to_owned(),
+                created_time: Utc::now(),
+                collections: Vec::new(),
+                media: Vec::new(),
+            },
+        })
+    }
+
+    fn append_entry(&mut self, path: &str, size: u64, data: impl Read) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(self.manifest.created_time.timestamp() as u64);
+        header.set_cksum();
+        self.builder
+            .append_data(&mut header, path, data)
+            .map_err(AppError::internal)
+    }
+
+    fn append(&mut self, path: &str, data: &[u8]) -> Result<String> {
+        self.append_entry(path, data.len() as u64, data)?;
+        Ok(hex(&Sha256::digest(data)))
+    }
+
+    /// 集合的临时文件放在归档旁边, 加入归档后删除
+    pub fn collection_file(&self, name: &str) -> Result<CollectionFile> {
+        let path = PathBuf::from(format!("{}.{}.jsonl", self.path.display(), name));
+        let file = File::create(&path).map_err(AppError::internal)?;
+        Ok(CollectionFile {
+            name: name.to_owned(),
+            path,
+            file: BufWriter::new(file),
+            hasher: Sha256::new(),
+            count: 0,
+        })
+    }
+
+    pub fn add_collection(&mut self, collection: CollectionFile) -> Result<()> {
+        let CollectionFile {
+            name,
+            path: tmp,
+            file,
+            hasher,
+            count,
+        } = collection;
+        let file = file.into_inner().map_err(AppError::internal)?;
+        let size = file.metadata().map_err(AppError::internal)?.len();
+        drop(file);
+
+        let path = format!("collections/{}.jsonl", name);
+        let data = File::open(&tmp).map_err(AppError::internal)?;
+        self.append_entry(&path, size, data)?;
+        std::fs::remove_file(&tmp).map_err(AppError::internal)?;
+        self.manifest.collections.push(CollectionEntry {
+            name,
+            path,
+            count,
+            sha256: hex(&hasher.finalize()),
+        });
+        Ok(())
+    }
+
+    pub fn add_media(&mut self, key: &str, content_type: &str, data: &[u8]) -> Result<()> {
+        let path = format!("media/{}", key);
+        let sha256 = self.append(&path, data)?;
+        self.manifest.media.push(MediaEntry {
+            key: key.to_owned(),
+            path,
+            content_type: content_type.to_owned(),
+            size: data.len() as u64,
+            sha256,
+        });
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Manifest> {
+        let manifest = serde_json::to_vec_pretty(&self.manifest).map_err(AppError::internal)?;
+        self.append(MANIFEST, &manifest)?;
+        self.builder
+            .into_inner()
+            .and_then(|gz| gz.finish())
+            .and_then(|mut file| file.flush())
+            .map_err(AppError::internal)?;
+        Ok(self.manifest)
+    }
+}
+
+fn open_archive(path: &Path) -> Result<tar::Archive<GzDecoder<File>>> {
+    let file = File::open(path).map_err(AppError::internal)?;
+    Ok(tar::Archive::new(GzDecoder::new(file)))
+}
+
+fn invalid(msg: impl Into<String>) -> AppError {
+    AppError::bad_request(format!("备份文件无效: {}", msg.into()))
+}
+
+///
+/// 校验归档: 格式版本、每个文件的 SHA-256、集合的记录数, 以及每一行都是合法的文档.
+/// 读取整个归档但不写入任何数据
+///
+pub fn verify(path: &Path) -> Result<Manifest> {
+    let mut archive = open_archive(path)?;
+    // 路径 -> (SHA-256, 集合的记录数或文件大小)
+    let mut files: HashMap<String, (String, u64)> = HashMap::new();
+    let mut manifest = None;
+    for entry in archive.entries().map_err(AppError::internal)? {
+        let mut entry = entry.map_err(AppError::internal)?;
+        let path = entry
+            .path()
+            .map_err(AppError::internal)?
+            .to_string_lossy()
+            .into_owned();
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        if path == MANIFEST {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).map_err(AppError::internal)?;
+            let parsed: Manifest = serde_json::from_slice(&data)
+                .map_err(|err| invalid(format!("{}: {}", MANIFEST, err)))?;
+            manifest = Some(parsed);
+            continue;
+        } else if path.starts_with("collections/") {
+            let mut reader = BufReader::new(entry);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let n = reader.read_line(&mut line).map_err(AppError::internal)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(line.as_bytes());
+                decode_document(line.trim_end())
+                    .map_err(|err| invalid(format!("{} 第 {} 行: {}", path, size + 1, err)))?;
+                size += 1;
+            }
+        } else if path.starts_with("media/") {
+            size = std::io::copy(&mut entry, &mut hasher).map_err(AppError::internal)?;
+        } else {
+            return Err(invalid(format!("未知的文件 {}", path)));
+        }
+        files.insert(path, (hex(&hasher.finalize()), size));
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid(format!("缺少 {}", MANIFEST)))?;
+    if manifest.format != FORMAT {
+        return Err(invalid(format!("不是博客的备份: {}", manifest.format)));
+    }
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(invalid(format!(
+            "格式版本 {} 比程序支持的 {} 新, 请升级后再恢复",
+            manifest.format_version, FORMAT_VERSION
+        )));
+    }
+    let expected = manifest
+        .collections
+        .iter()
+        .map(|c| (&c.path, &c.sha256, c.count))
+        .chain(manifest.media.iter().map(|m| (&m.path, &m.sha256, m.size)));
+    let mut listed = 0;
+    for (path, sha256, size) in expected {
+        listed += 1;
+        match files.get(path) {
+            None => return Err(invalid(format!("缺少 {}", path))),
+            Some((actual, _)) if actual != sha256 => {
+                return Err(invalid(format!("{} 的校验和不一致", path)))
+            }
+            Some((_, actual)) if *actual != size => {
+                return Err(invalid(format!("{} 的记录数或大小不一致", path)))
+            }
+            Some(_) => {}
+        }
+    }
+    if listed != files.len() {
+        return Err(invalid("有清单中没有记录的文件"));
+    }
+    Ok(manifest)
+}
+
+/// 备份全部集合(会话和限流计数除外)和上传的文件, 先写临时文件, 完成后改名
+pub async fn backup(out: &Path, storage: &dyn Storage, mongo: &Database) -> Result<Manifest> {
+    let tmp = PathBuf::from(format!("{}.tmp", out.display()));
+    let mut writer = ArchiveWriter::create(&tmp)?;
+
+    let mut names: Vec<String> = mongo
+        .list_collection_names(None)
+        .await?
+        .into_iter()
+        .filter(|name| !name.starts_with("system.") && !EXCLUDED.contains(&name.as_str()))
+        .collect();
+    names.sort();
+    for name in &names {
+        let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+        let mut cursor = mongo
+            .collection::<Document>(name)
+            .find(doc! {}, options)
+            .await?;
+        let mut file = writer.collection_file(name)?;
+        while let Some(document) = cursor.next().await {
+            let document = document?;
+            if !is_transient(name, &document) {
+                file.write(document)?;
+            }
+        }
+        writer.add_collection(file)?;
+    }
+
+    // 多条记录可以引用同一个文件
+    let mut keys = BTreeMap::new();
+    let mut cursor = mongo
+        .collection::<Media>("media")
+        .find(doc! {}, None)
+        .await?;
+    while let Some(media) = cursor.next().await {
+        let media = media?;
+        keys.insert(media.key, media.content_type);
+    }
+    for (key, content_type) in &keys {
+        match storage.get(key).await? {
+            Some(data) => writer.add_media(key, content_type, &data)?,
+            None => warn!("backup: media {} is missing in storage", key),
+        }
+    }
+
+    let manifest = writer.finish()?;
+    std::fs::rename(&tmp, out).map_err(AppError::internal)?;
+    Ok(manifest)
+}
+
+///
+/// 恢复备份: 先完整校验归档, 确认备份中的集合在数据库中都是空的, 再导入文档和文件.
+/// `check_only` 时只校验
+///
+pub async fn restore(
+    archive: &Path,
+    check_only: bool,
+    storage: &dyn Storage,
+    mongo: &Database,
+) -> Result<Manifest> {
+    let manifest = verify(archive)?;
+    for collection in &manifest.collections {
+        let count = mongo
+            .collection::<Document>(&collection.name)
+            .estimated_document_count(None)
+            .await?;
+        if count > 0 {
+            return Err(AppError::bad_request(format!(
+                "数据库不是空的: 集合 {} 中已经有 {} 条记录",
+                collection.name, count
+            )));
+        }
+    }
+    if check_only {
+        return Ok(manifest);
+    }
+
+    let names: HashMap<&str, &str> = manifest
+        .collections
+        .iter()
+        .map(|c| (c.path.as_str(), c.name.as_str()))
+        .collect();
+    let media: HashMap<&str, &MediaEntry> = manifest
+        .media
+        .iter()
+        .map(|m| (m.path.as_str(), m))
+        .collect();
+    let existing = mongo.list_collection_names(None).await?;
+    let mut archive = open_archive(archive)?;
+    for entry in archive.entries().map_err(AppError::internal)? {
+        let entry = entry.map_err(AppError::internal)?;
+        let path = entry
+            .path()
+            .map_err(AppError::internal)?
+            .to_string_lossy()
+            .into_owned();
+        if let Some(name) = names.get(path.as_str()) {
+            let collection = mongo.collection::<Document>(name);
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            let mut count = 0;
+            for line in BufReader::new(entry).lines() {
+                let document = decode_document(&line.map_err(AppError::internal)?)?;
+                // 较早的备份中可能有数据迁移的锁
+                if is_transient(name, &document) {
+                    continue;
+                }
+                batch.push(document);
+                count += 1;
+                if batch.len() == BATCH_SIZE {
+                    collection
+                        .insert_many(std::mem::take(&mut batch), None)
+                        .await?;
+                }
+            }
+            if !batch.is_empty() {
+                collection.insert_many(batch, None).await?;
+            }
+            // 空集合也创建出来, 和备份时一致
+            if count == 0 && !existing.iter().any(|n| n == name) {
+                mongo.create_collection(name, None).await?;
+            }
+        } else if let Some(media) = media.get(path.as_str()) {
+            let mut data = Vec::with_capacity(media.size as usize);
+            let mut entry = entry;
+            entry.read_to_end(&mut data).map_err(AppError::internal)?;
+            storage.put(&media.key, data, &media.content_type).await?;
+        }
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+
+    #[test]
+    fn test_document_round_trip() {
+        let document = doc! {
+            "_id": ObjectId::new(),
+            "title": "标题\n\"引号\"",
+            "status": 1i32,
+            "size": 1i64 << 40,
+            "created_time": bson::DateTime::from_millis(1_600_000_000_123),
+            "tags": ["a", "b"],
+            "inner": {"score": 0.5, "none": Bson::Null},
+        };
+        let line = encode_document(document.clone()).unwrap();
+        assert!(!line.contains('\n'));
+        assert_eq!(decode_document(&line).unwrap(), document);
+        assert!(decode_document("[1]").is_err());
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(
+            "_migrations",
+            &doc! {"_id": "lock", "owner": "a"}
+        ));
+        assert!(!is_transient("_migrations", &doc! {"_id": 1, "name": "a"}));
+        assert!(!is_transient("user", &doc! {"_id": "lock"}));
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = std::env::temp_dir().join(format!("blog-backup-{}", ObjectId::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("backup.tar.gz");
+
+        let mut writer = ArchiveWriter::create(&path).unwrap();
+        let mut user = writer.collection_file("user").unwrap();
+        user.write(doc! {"_id": 1, "name": "a"}).unwrap();
+        writer.add_collection(user).unwrap();
+        let audit = writer.collection_file("audit").unwrap();
+        writer.add_collection(audit).unwrap();
+        writer
+            .add_media("ab/abc.png", "image/png", b"\x89PNG")
+            .unwrap();
+        let manifest = writer.finish().unwrap();
+        assert_eq!(verify(&path).unwrap(), manifest);
+        assert_eq!(manifest.collections[0].count, 1);
+        // 临时文件已经删除
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // 记录数和清单不一致
+        let mut writer = ArchiveWriter::create(&path).unwrap();
+        let mut user = writer.collection_file("user").unwrap();
+        user.write(doc! {"_id": 1, "name": "a"}).unwrap();
+        user.count = 2;
+        writer.add_collection(user).unwrap();
+        writer.finish().unwrap();
+        assert!(verify(&path).is_err());
+
+        // 不是合法的文档
+        let mut writer = ArchiveWriter::create(&path).unwrap();
+        let mut user = writer.collection_file("user").unwrap();
+        user.write_line(b"{\"a\":\n").unwrap();
+        writer.add_collection(user).unwrap();
+        writer.finish().unwrap();
+        assert!(verify(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//!
+//! 管理工具: 用户的角色、封禁和改名, 文章和评论的维护. 和服务器使用同一个配置文件
+//!
+use std::{path::PathBuf, str::FromStr};
+
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use mongodb::Database;
+
+use blog::{
+    admin, config, db,
+    error::{AppError, OptionalExt, Result},
+    handler::format_datetime,
+    model::{BanKind, Role, User},
+    storage,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Joeyscat 博客管理工具")]
+struct Cli {
+    /// 配置文件路径, 也可以通过环境变量 BLOG_CONFIG 指定
+    #[arg(long, env = "BLOG_CONFIG", default_value = "blog.toml")]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 用户管理
+    Users {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+    /// 文章维护
+    Articles {
+        #[command(subcommand)]
+        action: ArticleAction,
+    },
+    /// 评论维护
+    Comments {
+        #[command(subcommand)]
+        action: CommentAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserAction {
+    /// 列出用户
+    List {
+        /// 只列出这个角色的用户
+        #[arg(long)]
+        role: Option<Role>,
+    },
+    /// 修改用户的角色
+    Promote {
+        /// 登录名或用户 id
+        user: String,
+
+        /// admin/editor/author/commenter
+        #[arg(long)]
+        role: Role,
+    },
+    /// 封禁用户
+    Ban {
+        /// 登录名或用户 id
+        user: String,
+
+        #[arg(long)]
+        reason: String,
+
+        /// 封禁天数, 0 表示永久封禁
+        #[arg(long, default_value_t = 0)]
+        days: u32,
+
+        /// 影子封禁: 可以登录, 内容只有本人可见
+        #[arg(long)]
+        shadow: bool,
+    },
+    /// 解除封禁
+    Unban {
+        /// 登录名或用户 id
+        user: String,
+
+        #[arg(long, default_value = "")]
+        reason: String,
+    },
+    /// 修改用户名, 同时修改评论和通知中的名字
+    Rename {
+        /// 登录名或用户 id
+        user: String,
+
+        /// 新的用户名
+        username: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ArticleAction {
+    /// 重新渲染文章, 重新生成文章中图片的衍生文件
+    Rerender {
+        /// 只处理这篇文章, 默认全部
+        #[arg(long)]
+        id: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CommentAction {
+    /// 按文章重新统计各个状态的评论数
+    Recount,
+    /// 删除未通过审核和垃圾评论
+    Purge {
+        /// 只删除审核时间在这么多天之前的
+        #[arg(long, default_value_t = 30)]
+        older_than: u32,
+
+        /// 只统计, 不删除
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+impl Cli {
+    /// 显式指定的配置文件必须存在, 默认的 blog.toml 可以没有(全部使用环境变量)
+    fn config_required(&self) -> bool {
+        self.config != PathBuf::from("blog.toml")
+    }
+}
+
+/// 登录名(可以带上 provider, 例如 gitee:joey)或用户 id 对应的用户
+async fn find_user(key: &str, mongo: &Database) -> Result<User> {
+    db::find_user_by_key(key, mongo)
+        .await
+        .optional()?
+        .ok_or_else(|| AppError::bad_request(format!("用户不存在: {}", key)))
+}
+
+fn user_status(user: &User) -> String {
+    let Some(ban) = user.active_ban(Utc::now()) else {
+        return "正常".to_owned();
+    };
+    let kind = match ban.kind {
+        BanKind::Ban => "封禁",
+        BanKind::ShadowBan => "影子封禁",
+    };
+    match ban.expires_at {
+        Some(t) => format!("{}至 {}", kind, format_datetime(t.to_chrono())),
+        None => kind.to_owned(),
+    }
+}
+
+async fn users(action: UserAction, mongo: &Database) -> Result<()> {
+    match action {
+        UserAction::List { role } => {
+            let mut users = db::list_users(mongo).await?;
+            users.retain(|u| role.is_none_or(|r| u.role == r));
+            users.sort_by_key(|u| u.created_time);
+            for user in &users {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    user.id,
+                    user.username,
+                    user.role.as_str(),
+                    user_status(user),
+                    format_datetime(user.created_time)
+                );
+            }
+            println!("共 {} 个用户", users.len());
+        }
+        UserAction::Promote { user, role } => {
+            let user = find_user(&user, mongo).await?;
+            admin::set_role(&user, role, mongo).await?;
+            println!(
+                "{}: {} → {}",
+                user.username,
+                user.role.as_str(),
+                role.as_str()
+            );
+        }
+        UserAction::Ban {
+            user,
+            reason,
+            days,
+            shadow,
+        } => {
+            let user = find_user(&user, mongo).await?;
+            let kind = if shadow {
+                BanKind::ShadowBan
+            } else {
+                BanKind::Ban
+            };
+            admin::ban(&user, kind, &reason, days, mongo).await?;
+            println!(
+                "已{}: {}",
+                if shadow { "影子封禁" } else { "封禁" },
+                user.username
+            );
+        }
+        UserAction::Unban { user, reason } => {
+            let user = find_user(&user, mongo).await?;
+            admin::unban(&user, &reason, mongo).await?;
+            println!("已解封: {}", user.username);
+        }
+        UserAction::Rename { user, username } => {
+            let user = find_user(&user, mongo).await?;
+            let report = admin::rename(&user, &username, mongo).await?;
+            println!(
+                "{} → {}: 修改了 {} 篇文章中的评论, {} 条通知",
+                user.username,
+                username.trim(),
+                report.articles,
+                report.notifications
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn articles(action: ArticleAction, mongo: &Database) -> Result<()> {
+    match action {
+        ArticleAction::Rerender { id } => {
+            let id = id.as_deref().map(db::object_id).transpose()?;
+            let storage = storage::from_config(&config::get().upload)?;
+            let report = admin::rerender(id, storage.as_ref(), mongo).await?;
+            for (hash, err) in &report.failed {
+                println!("{}\t{}", hash, err);
+            }
+            println!(
+                "渲染了 {} 篇文章, 重新生成了 {} 张图片, {} 张失败",
+                report.articles,
+                report.images,
+                report.failed.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn comments(action: CommentAction, mongo: &Database) -> Result<()> {
+    match action {
+        CommentAction::Recount => {
+            let counts = admin::count_comments(mongo).await?;
+            println!("文章\t公开\t待审核\t未通过\t垃圾\t标题");
+            for c in &counts {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    c.article_id, c.visible, c.pending, c.rejected, c.spam, c.title
+                );
+            }
+            println!(
+                "{} 篇文章, {} 条公开评论, {} 条待审核",
+                counts.len(),
+                counts.iter().map(|c| c.visible).sum::<u32>(),
+                counts.iter().map(|c| c.pending).sum::<u32>()
+            );
+        }
+        CommentAction::Purge {
+            older_than,
+            dry_run,
+        } => {
+            let before = Utc::now() - chrono::Duration::days(older_than as i64);
+            let count = admin::purge_comments(before, dry_run, mongo).await?;
+            println!(
+                "{}删除了 {} 条 {} 之前审核的未通过和垃圾评论",
+                if dry_run { "(试运行) " } else { "" },
+                count,
+                format_datetime(before)
+            );
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), std::io::Error> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    let lvl = tracing::Level::from_str(std::env::var("RUST_LOG").unwrap().as_str())
+        .expect("请将环境变量RUST_LOG设置为可用的日志等级");
+    tracing_subscriber::fmt().with_max_level(lvl).init();
+
+    let cli = Cli::parse();
+    let config = config::Config::load(&cli.config, cli.config_required())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    config::init(config);
+    let config = config::get();
+
+    let mongodb = mongodb::Client::with_uri_str(&config.database.url)
+        .await
+        .map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                format!("初始化数据库连接错误: {}", err),
+            )
+        })?
+        .database(&config.database.name);
+
+    let result = match cli.command {
+        Command::Users { action } => users(action, &mongodb).await,
+        Command::Articles { action } => articles(action, &mongodb).await,
+        Command::Comments { action } => comments(action, &mongodb).await,
+    };
+    result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))
+}
@@ -0,0 +1,306 @@
+//!
+//! CSRF 防护: 每个会话一个随机 token, 模板中通过 `{{ csrf_token() }}` 放到表单的隐藏字段里,
+//! 所有 POST 请求都要带上(表单字段 `csrf_token` 或请求头 `X-CSRF-Token`), 不匹配时返回 403.
+//! 上传文件的 multipart 表单同样使用 `csrf_token` 字段, 需要放在文件字段之前.
+//! OAuth 授权请求的 `state` 也在这里生成和校验
+//!
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use poem::{
+    http::{header, Method},
+    session::Session,
+    web::Multipart,
+    Body, Endpoint, FromRequest, IntoResponse, Request, RequestBody, Response,
+};
+use rand::RngCore;
+use serde_derive::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::error::AppError;
+use crate::mail::UNSUBSCRIBE_PATH;
+use crate::middleware::CSP_REPORT_PATH;
+use crate::newsletter;
+
+const SESSION_KEY: &str = "csrf_token";
+const OAUTH_STATE_KEY: &str = "oauth_state";
+pub const HEADER: &str = "X-CSRF-Token";
+
+/// 不需要校验的路径: 浏览器/邮件客户端自动发出的请求无法携带 token, 退订链接本身带有签名
+const EXEMPT_PATHS: &[&str] = &[
+    CSP_REPORT_PATH,
+    UNSUBSCRIBE_PATH,
+    newsletter::UNSUBSCRIBE_PATH,
+];
+
+/// 校验时读入内存的普通表单请求体上限, 超过时返回 413
+const MAX_FORM_BYTES: usize = 2 * 1024 * 1024;
+
+/// multipart 表单只在请求体开头这么多字节中查找 token, 上传的文件不会读进内存
+const MULTIPART_PREFIX_BYTES: usize = 64 * 1024;
+
+tokio::task_local! {
+    /// 当前请求的会话, 供模板函数 `csrf_token()` 使用
+    static SESSION: Session;
+}
+
+/// 当前会话的 token, 没有则生成一个
+pub fn token(session: &Session) -> String {
+    if let Some(token) = session.get::<String>(SESSION_KEY) {
+        return token;
+    }
+    let token = random_token();
+    session.set(SESSION_KEY, &token);
+    token
+}
+
+fn random_token() -> String {
+    let mut buf = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 发起 OAuth 授权前生成本次的 `state`, 保存到会话中
+pub fn issue_oauth_state(session: &Session) -> String {
+    let state = random_token();
+    session.set(OAUTH_STATE_KEY, &state);
+    state
+}
+
+///
+/// 校验 OAuth 回调中的 `state` 是否是当前会话发起的授权, 校验后作废.
+/// 防止攻击者把自己的授权码带到受害者的会话中(登录成攻击者, 或者把攻击者的账号绑定到受害者)
+///
+pub fn take_oauth_state(session: &Session, submitted: Option<&str>) -> bool {
+    let expected = session.get::<String>(OAUTH_STATE_KEY);
+    session.remove(OAUTH_STATE_KEY);
+    matches(expected.as_deref(), submitted)
+}
+
+/// 注册到 Tera 的模板函数, 只有包含表单的页面才会调用, 避免给每个访客都创建会话
+pub fn tera_function(_args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    SESSION
+        .try_with(token)
+        .map(tera::Value::String)
+        .map_err(|_| tera::Error::msg("csrf_token() called outside of a request"))
+}
+
+fn matches(expected: Option<&str>, submitted: Option<&str>) -> bool {
+    match (expected, submitted) {
+        (Some(expected), Some(submitted)) => {
+            bool::from(expected.as_bytes().ct_eq(submitted.as_bytes()))
+        }
+        _ => false,
+    }
+}
+
+/// 校验提交的 token, 中间件不校验的 GET 请求(例如跳转到其他站点的链接)在 handler 中调用
+pub fn verify(session: &Session, submitted: Option<&str>) -> bool {
+    matches(session.get::<String>(SESSION_KEY).as_deref(), submitted)
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    csrf_token: Option<String>,
+}
+
+/// 从请求头或表单中取出提交的 token, 读取过的请求体会放回去, 后面的 handler 照常解析
+async fn submitted_token(req: &mut Request) -> poem::Result<Option<String>> {
+    if let Some(token) = req.headers().get(HEADER).and_then(|v| v.to_str().ok()) {
+        return Ok(Some(token.to_owned()));
+    }
+
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let is_form = content_type.starts_with("application/x-www-form-urlencoded");
+    let is_multipart = content_type.starts_with("multipart/form-data");
+    if !is_form && !is_multipart {
+        return Ok(None);
+    }
+
+    if is_form {
+        let body = req.take_body().into_bytes_limit(MAX_FORM_BYTES).await?;
+        let token = serde_urlencoded::from_bytes::<TokenForm>(&body)
+            .ok()
+            .and_then(|form| form.csrf_token);
+        req.set_body(body);
+        return Ok(token);
+    }
+
+    // 读到的部分和剩下的请求体拼起来放回去
+    let mut stream = req.take_body().into_bytes_stream();
+    let mut chunks = Vec::new();
+    let mut prefix = Vec::new();
+    while prefix.len() < MULTIPART_PREFIX_BYTES {
+        match stream.next().await {
+            Some(chunk) => {
+                let chunk = chunk.map_err(poem::error::BadRequest)?;
+                prefix.extend_from_slice(&chunk);
+                chunks.push(chunk);
+            }
+            None => break,
+        }
+    }
+    // 最后一块可能超出, 截掉之后结果不受分块方式影响
+    prefix.truncate(MULTIPART_PREFIX_BYTES);
+    let token = multipart_token(req, Body::from(prefix)).await;
+    req.set_body(Body::from_bytes_stream(
+        futures::stream::iter(chunks.into_iter().map(Ok)).chain(stream),
+    ));
+    Ok(token)
+}
+
+async fn multipart_token(req: &Request, body: Body) -> Option<String> {
+    let mut multipart = Multipart::from_request(req, &mut RequestBody::new(body))
+        .await
+        .ok()?;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("csrf_token") {
+            return field.text().await.ok();
+        }
+    }
+    None
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// CSRF 中间件, 需要放在会话中间件内层
+pub async fn protect<E: Endpoint>(next: E, mut req: Request) -> poem::Result<Response> {
+    let session = <&Session>::from_request_without_body(&req).await?.clone();
+
+    if !is_safe(req.method()) && !EXEMPT_PATHS.contains(&req.uri().path()) {
+        let submitted = submitted_token(&mut req).await?;
+        if !verify(&session, submitted.as_deref()) {
+            return Err(AppError::Csrf.into());
+        }
+    }
+
+    SESSION
+        .scope(session, async move {
+            next.call(req).await.map(IntoResponse::into_response)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{
+        handler,
+        http::StatusCode,
+        session::{CookieConfig, MemoryStorage, ServerSession},
+        EndpointExt, Route,
+    };
+
+    use super::*;
+
+    #[handler]
+    fn form_page(session: &Session) -> String {
+        token(session)
+    }
+
+    #[handler]
+    fn submit() -> &'static str {
+        "ok"
+    }
+
+    #[tokio::test]
+    async fn test_protect() {
+        let app = Route::new()
+            .at("/", poem::get(form_page).post(submit))
+            .around(protect)
+            .with(ServerSession::new(
+                CookieConfig::default(),
+                MemoryStorage::new(),
+            ));
+
+        let resp = app.call(Request::builder().finish()).await.unwrap();
+        let cookie = resp
+            .headers()
+            .get(header::SET_COOKIE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap()
+            .to_owned();
+        let token = resp.into_body().into_string().await.unwrap();
+
+        let post = |body: String| {
+            Request::builder()
+                .method(Method::POST)
+                .header(header::COOKIE, cookie.clone())
+                .content_type("application/x-www-form-urlencoded")
+                .body(body)
+        };
+
+        // 没有 token / token 错误
+        let err = app.call(post("a=1".to_owned())).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        let err = app
+            .call(post("csrf_token=bad".to_owned()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+
+        // 表单字段
+        let resp = app
+            .call(post(format!("a=1&csrf_token={}", token)))
+            .await
+            .unwrap();
+        assert_eq!(resp.into_body().into_string().await.unwrap(), "ok");
+
+        // multipart 表单
+        let multipart = |token: &str, file: &str| {
+            let body = format!(
+                "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.csv\"\r\n\r\n{}\r\n\
+                 --X\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\n{}\r\n--X--\r\n",
+                file, token
+            );
+            Request::builder()
+                .method(Method::POST)
+                .header(header::COOKIE, cookie.clone())
+                .content_type("multipart/form-data; boundary=X")
+                .body(body)
+        };
+        let resp = app.call(multipart(&token, "email")).await.unwrap();
+        assert_eq!(resp.into_body().into_string().await.unwrap(), "ok");
+        let err = app.call(multipart("bad", "email")).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        // 只在请求体开头查找, 放在大文件后面的 token 找不到
+        let file = "a".repeat(MULTIPART_PREFIX_BYTES);
+        let err = app.call(multipart(&token, &file)).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+
+        // 请求头
+        let req = Request::builder()
+            .method(Method::POST)
+            .header(header::COOKIE, cookie.clone())
+            .header(HEADER, token.clone())
+            .finish();
+        assert!(app.call(req).await.is_ok());
+    }
+
+    #[test]
+    fn test_oauth_state() {
+        let session = Session::default();
+        assert!(!take_oauth_state(&session, Some("")));
+
+        let state = issue_oauth_state(&session);
+        assert!(!take_oauth_state(&session, Some("bad")));
+        // 校验失败也会作废
+        assert!(!take_oauth_state(&session, Some(&state)));
+
+        let state = issue_oauth_state(&session);
+        assert_ne!(issue_oauth_state(&session), state);
+        let state = issue_oauth_state(&session);
+        assert!(take_oauth_state(&session, Some(&state)));
+        // 只能使用一次
+        assert!(!take_oauth_state(&session, Some(&state)));
+    }
+}
@@ -1,19 +1,59 @@
 use chrono::prelude::*;
 use futures::StreamExt;
-use poem::Result;
 use std::{ops::SubAssign, str::FromStr};
 
 use mongodb::{
-    bson::{bson, doc, oid::ObjectId},
+    bson::{bson, doc, oid::ObjectId, Bson, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions},
     Database,
 };
+use serde_derive::{Deserialize, Serialize};
 use tracing::{debug, info};
 
-use crate::gitee;
-use crate::model::{Article, Comment, User};
+use crate::config;
+use crate::error::{AppError, Result};
+use crate::model::{Article, Ban, Comment, Identity, Role, Totp, User};
+
+/// 解析请求中的 ObjectId, 格式不对时返回 BadRequest
+pub fn object_id(id: &str) -> Result<ObjectId> {
+    ObjectId::from_str(id).map_err(|_| AppError::bad_request(format!("无效的 id: {}", id)))
+}
+
+/// 聚合表达式: 公开显示的评论(过滤掉待审核、未通过和垃圾评论, 以及被隐藏的作者的评论)
+fn visible_comments(hidden: &[ObjectId]) -> Bson {
+    bson!({
+        "$filter":{
+            "input":{"$ifNull": ["$comments", []]},
+            "as":"c",
+            "cond":{"$and": [
+                {"$eq": ["$$c.status", Comment::VISIBLE as i32]},
+                {"$not": [{"$in": ["$$c.author_id", hidden]}]},
+            ]},
+        }
+    })
+}
+
+///
+/// 内容需要对 `viewer` 隐藏的用户: 被封禁的用户, 以及被影子封禁的用户(本人除外).
+/// 所有内容查询都要排除这些用户的文章和评论
+///
+pub async fn hidden_authors(viewer: Option<ObjectId>, mongo: &Database) -> Result<Vec<ObjectId>> {
+    let query = doc! {"status":{"$in":[User::BANNED as i32, User::SHADOW_BANNED as i32]}};
+    let mut cursor = mongo.collection::<User>("user").find(query, None).await?;
+
+    let now = Utc::now();
+    let mut result = Vec::new();
+    while let Some(user) = cursor.next().await {
+        let user = user?;
+        if user.is_hidden_from(viewer, now) {
+            result.push(user.id);
+        }
+    }
+    Ok(result)
+}
 
 pub async fn create_article(article: Article, mongo: &Database) -> Result<String> {
-    let now = Utc::now().with_timezone(&FixedOffset::east(8 * 3600));
+    let now = Utc::now().with_timezone(&config::timezone());
     let new_article = doc! {
         "_id": article.id,
         "title": article.title,
@@ -25,17 +65,12 @@ pub async fn create_article(article: Article, mongo: &Database) -> Result<String
         "status": 1,
     };
 
-    let id = mongo
+    mongo
         .collection("article")
         .insert_one(new_article, None)
-        .await
-        .map_err(poem::error::InternalServerError)?
-        .inserted_id
-        .as_object_id()
-        .unwrap()
-        .to_string();
+        .await?;
 
-    Ok(id)
+    Ok(article.id.to_string())
 }
 
 pub async fn update_article(article: Article, mongo: &Database) -> Result<bool> {
@@ -46,15 +81,14 @@ pub async fn update_article(article: Article, mongo: &Database) -> Result<bool>
             "raw_content":article.raw_content,
             "tags":article.tags,
             "status":article.status as i32,
-            "updated_time":Utc::now().with_timezone(&FixedOffset::east(8 * 3600)),
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
         }
     };
 
     let matched_count = mongo
         .collection::<Article>("article")
         .update_one(query, update, None)
-        .await
-        .map_err(poem::error::InternalServerError)?
+        .await?
         .matched_count;
 
     Ok(matched_count > 0)
@@ -65,19 +99,22 @@ pub async fn append_comment(
     comment: Comment,
     mongo: &Database,
 ) -> Result<bool> {
-    let query = doc! {"_id":ObjectId::from_str(article_id.as_str()).unwrap()};
+    let query = doc! {"_id":object_id(article_id.as_str())?};
     let update = doc! {
         "$push":{
             "comments":{
                 "$each": [{
+                    "id": comment.id,
                     "content": comment.content,
                     "author_id": comment.author_id,
                     "author_name": comment.author_name,
                     "reply_to": comment.reply_to,
                     "reply_to_name": comment.reply_to_name,
-                    "created_time": Utc::now().with_timezone(&FixedOffset::east(8 * 3600)),
-                    "updated_time": Utc::now().with_timezone(&FixedOffset::east(8 * 3600)),
+                    "created_time": Utc::now().with_timezone(&config::timezone()),
+                    "updated_time": Utc::now().with_timezone(&config::timezone()),
                     "status": comment.status as i32,
+                    "moderation_reason": comment.moderation_reason,
+                    "spam_score": comment.spam_score,
                 }],
                 "$position": 0
             },
@@ -89,8 +126,7 @@ pub async fn append_comment(
     let matched_count = mongo
         .collection::<Article>("article")
         .update_one(query, update, None)
-        .await
-        .map_err(poem::error::InternalServerError)?
+        .await?
         .matched_count;
 
     Ok(matched_count > 0)
@@ -100,8 +136,10 @@ pub async fn get_article(
     article_id: String,
     mut comment_page_size: Option<i32>,
     mut comment_page: Option<i32>,
+    viewer: Option<ObjectId>,
     mongo: &Database,
 ) -> Result<Article> {
+    let hidden = hidden_authors(viewer, mongo).await?;
     let comment_page_size = comment_page_size.get_or_insert(1); // Third argument to $slice must be positive: 0)
     let comment_page = comment_page.get_or_insert(0);
     if comment_page > &mut 0 {
@@ -111,7 +149,7 @@ pub async fn get_article(
     // db.article.aggregate([{$match:{_id: ObjectId("61d70cfa4a138b2ed4f4b088")}}, {$project: {comments:{$slice:["$comments",2,1]}}}]);
     let pipeline = vec![
         doc! {
-            "$match":{"_id":ObjectId::from_str(article_id.as_str()).unwrap()},
+            "$match":{"_id":object_id(article_id.as_str())?, "author_id":{"$nin":&hidden}},
         },
         doc! {
             "$lookup":{"from":"user","localField":"author_id","foreignField":"_id","as":"fromAuthors"},
@@ -129,8 +167,8 @@ pub async fn get_article(
                 "created_time":1,
                 "updated_time":1,
                 "status":1,
-                "comments":{"$slice":vec![bson!("$comments"), bson!(comment_page.clone() * comment_page_size.clone()), bson!(comment_page_size.clone())]},
-                "total_comments":{"$size": {"$ifNull": vec![bson!("$comments"), bson!(Vec::<i32>::new())]}},
+                "comments":{"$slice":vec![visible_comments(&hidden), bson!(comment_page.clone() * comment_page_size.clone()), bson!(comment_page_size.clone())]},
+                "total_comments":{"$size": visible_comments(&hidden)},
                 "author_name":"$fromAuthors.username",
             },
         },
@@ -138,23 +176,22 @@ pub async fn get_article(
     let mut cursor = mongo
         .collection::<Article>("article")
         .aggregate(pipeline, None)
-        .await
-        .map_err(poem::error::InternalServerError)?;
+        .await?;
 
     if let Some(c) = cursor.next().await {
-        let article: Article = bson::from_document(c.map_err(poem::error::InternalServerError)?)
-            .map_err(poem::error::InternalServerError)?;
+        let article: Article = bson::from_document(c?)?;
         debug!("get article result: {}", article.title);
         Ok(article)
     } else {
-        Err(poem::error::NotFoundError.into())
+        Err(AppError::NotFound)
     }
 }
 
-pub async fn list_article(mongo: &Database) -> Result<Vec<Article>> {
+pub async fn list_article(viewer: Option<ObjectId>, mongo: &Database) -> Result<Vec<Article>> {
+    let hidden = hidden_authors(viewer, mongo).await?;
     let pipeline = vec![
         doc! {
-            "$match":{},
+            "$match":{"author_id":{"$nin":&hidden}},
         },
         doc! {
             "$lookup":{"from":"user","localField":"author_id","foreignField":"_id","as":"fromAuthors"},
@@ -172,7 +209,7 @@ pub async fn list_article(mongo: &Database) -> Result<Vec<Article>> {
                 "created_time":1,
                 "updated_time":1,
                 "status":1,
-                "total_comments":{"$size": {"$ifNull": vec![bson!("$comments"), bson!(Vec::<i32>::new())]}},
+                "total_comments":{"$size": visible_comments(&hidden)},
                 "author_name":"$fromAuthors.username",
             },
         },
@@ -180,13 +217,11 @@ pub async fn list_article(mongo: &Database) -> Result<Vec<Article>> {
     let mut cursor = mongo
         .collection::<Article>("article")
         .aggregate(pipeline, None)
-        .await
-        .map_err(poem::error::InternalServerError)?;
+        .await?;
 
     let mut result = Vec::new();
     while let Some(c) = cursor.next().await {
-        let article: Article = bson::from_document(c.map_err(poem::error::InternalServerError)?)
-            .map_err(poem::error::InternalServerError)?;
+        let article: Article = bson::from_document(c?)?;
 
         result.push(article);
     }
@@ -199,59 +234,778 @@ pub async fn find_user_by_giteeid(mongo: &Database, id: i64) -> Result<User> {
     let user = mongo
         .collection::<User>("user")
         .find_one(doc! {"inner.id":id}, None)
-        .await
-        .map_err(poem::error::InternalServerError)?;
+        .await?;
 
     match user {
         Some(user) => Ok(user),
-        None => Err(poem::error::NotFoundError.into()),
+        None => Err(AppError::NotFound),
     }
 }
 
 pub async fn find_user_by_id(id: &str, mongo: &Database) -> Result<User> {
     info!("find_user_by_id : {}", id);
-    let oid = ObjectId::from_str(id).unwrap();
+    let oid = object_id(id)?;
     let user = mongo
         .collection::<User>("user")
         .find_one(doc! {"_id":oid}, None)
-        .await
-        .map_err(poem::error::InternalServerError)?;
+        .await?;
 
     match user {
         Some(user) => Ok(user),
-        None => Err(poem::error::NotFoundError.into()),
+        None => Err(AppError::NotFound),
     }
 }
 
-pub async fn create_giteeuser(mongo: &Database, gitee_user: gitee::UserInfo) -> Result<String> {
-    let now = Utc::now().with_timezone(&FixedOffset::east(8 * 3600));
+pub async fn create_user(mongo: &Database, username: &str, identity: Identity) -> Result<String> {
+    let now = Utc::now().with_timezone(&config::timezone());
+    let user_id = ObjectId::new();
     let new_user = doc! {
-        "_id": ObjectId::new(),
-        "username": &gitee_user.name,
-        "auth_type": "gitee".to_owned(),
-        "inner": {
-            "id": gitee_user.id,
-            "login": gitee_user.login,
-            "name": gitee_user.name,
-            "avatar_url": gitee_user.avatar_url,
-            "blog": gitee_user.blog,
-            "created_at": gitee_user.created_at,
-            "email": gitee_user.email,
-        },
+        "_id": user_id,
+        "username": username,
+        "auth_type": &identity.provider,
+        "inner": identity.inner.clone(),
         "created_time": now,
         "updated_time": now,
         "status": 1,
+        "role": Role::Commenter.as_str(),
+        // create_identity 中加 1
+        "identity_count": 0,
     };
 
-    let id = mongo
-        .collection("user")
-        .insert_one(new_user, None)
-        .await
-        .map_err(poem::error::InternalServerError)?
-        .inserted_id
-        .as_object_id()
-        .unwrap()
-        .to_string();
+    mongo.collection("user").insert_one(new_user, None).await?;
+
+    create_identity(mongo, user_id, identity).await?;
+
+    Ok(user_id.to_string())
+}
+
+pub async fn find_identity(
+    mongo: &Database,
+    provider: &str,
+    provider_uid: &str,
+) -> Result<Identity> {
+    info!("find_identity : {} {}", provider, provider_uid);
+
+    let identity = mongo
+        .collection::<Identity>("identity")
+        .find_one(
+            doc! {"provider":provider, "provider_uid":provider_uid},
+            None,
+        )
+        .await?;
+
+    match identity {
+        Some(identity) => Ok(identity),
+        None => Err(AppError::NotFound),
+    }
+}
+
+pub async fn list_identities(user_id: &str, mongo: &Database) -> Result<Vec<Identity>> {
+    let oid = object_id(user_id)?;
+    let mut cursor = mongo
+        .collection::<Identity>("identity")
+        .find(doc! {"user_id":oid}, None)
+        .await?;
+
+    let mut result = Vec::new();
+    while let Some(identity) = cursor.next().await {
+        result.push(identity?);
+    }
+    Ok(result)
+}
+
+pub async fn create_identity(
+    mongo: &Database,
+    user_id: ObjectId,
+    mut identity: Identity,
+) -> Result<String> {
+    identity.user_id = user_id;
+
+    let id = identity.id.to_string();
+    mongo
+        .collection::<Identity>("identity")
+        .insert_one(identity, None)
+        .await?;
+    mongo
+        .collection::<User>("user")
+        .update_one(
+            doc! {"_id":user_id},
+            doc! {"$inc":{"identity_count":1}},
+            None,
+        )
+        .await?;
 
     Ok(id)
 }
+
+///
+/// 解绑登录方式, 至少要保留一个, 否则用户将无法再登录.
+/// 先用 `user.identity_count > 1` 做条件减 1, 并发解绑时只有一个能成功, 再删除登录方式
+///
+pub async fn remove_identity(user_id: &str, identity_id: &str, mongo: &Database) -> Result<bool> {
+    let user_oid = object_id(user_id)?;
+    let identity_oid = object_id(identity_id)?;
+    let users = mongo.collection::<User>("user");
+
+    let matched_count = users
+        .update_one(
+            doc! {"_id":user_oid, "identity_count":{"$gt":1}},
+            doc! {"$inc":{"identity_count":-1}},
+            None,
+        )
+        .await?
+        .matched_count;
+    if matched_count == 0 {
+        return Err(AppError::bad_request("不能解绑最后一个登录方式"));
+    }
+
+    let deleted_count = mongo
+        .collection::<Identity>("identity")
+        .delete_one(doc! {"_id":identity_oid, "user_id":user_oid}, None)
+        .await?
+        .deleted_count;
+    if deleted_count == 0 {
+        // 登录方式不存在或不属于该用户, 把计数加回去
+        users
+            .update_one(
+                doc! {"_id":user_oid},
+                doc! {"$inc":{"identity_count":1}},
+                None,
+            )
+            .await?;
+    }
+
+    Ok(deleted_count > 0)
+}
+
+///
+/// 按登录名查找用户 id. 登录名只在同一个登录方式中唯一, 不同登录方式可以有同名的账号(例如密码登录注册了
+/// 别人的 gitee 登录名), 所以不带 provider 时同名的账号属于不同用户则报错, 不能随便取一个.
+/// `provider:login` 只在这个登录方式中查找
+///
+pub async fn find_user_id_by_login(key: &str, mongo: &Database) -> Result<ObjectId> {
+    let query = match key.split_once(':') {
+        Some((provider, login)) => doc! {"login":login, "provider":provider},
+        None => doc! {"login":key},
+    };
+    let mut cursor = mongo
+        .collection::<Identity>("identity")
+        .find(query, None)
+        .await?;
+
+    let mut user_id = None;
+    while let Some(identity) = cursor.next().await {
+        let identity = identity?;
+        match user_id {
+            Some(id) if id != identity.user_id => {
+                return Err(AppError::bad_request(format!(
+                    "登录名 {} 对应多个用户, 请使用 provider:登录名 或用户 id",
+                    key
+                )))
+            }
+            _ => user_id = Some(identity.user_id),
+        }
+    }
+    user_id.ok_or(AppError::NotFound)
+}
+
+/// 按 id 或登录名(见 [`find_user_id_by_login`])查找用户
+pub async fn find_user_by_key(key: &str, mongo: &Database) -> Result<User> {
+    match ObjectId::from_str(key) {
+        Ok(_) => find_user_by_id(key, mongo).await,
+        Err(_) => {
+            let user_id = find_user_id_by_login(key, mongo).await?;
+            find_user_by_id(&user_id.to_string(), mongo).await
+        }
+    }
+}
+
+pub async fn list_users(mongo: &Database) -> Result<Vec<User>> {
+    let mut cursor = mongo.collection::<User>("user").find(doc! {}, None).await?;
+
+    let mut result = Vec::new();
+    while let Some(user) = cursor.next().await {
+        result.push(user?);
+    }
+    Ok(result)
+}
+
+pub async fn count_users_by_role(role: Role, mongo: &Database) -> Result<u64> {
+    let count = mongo
+        .collection::<User>("user")
+        .count_documents(doc! {"role":role.as_str()}, None)
+        .await?;
+
+    Ok(count)
+}
+
+pub async fn update_user_role(user_id: &str, role: Role, mongo: &Database) -> Result<bool> {
+    let oid = object_id(user_id)?;
+    let update = doc! {
+        "$set":{
+            "role":role.as_str(),
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":oid}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+///
+/// 修改用户名, 同时修改评论中保存的作者名和被回复人的名字, 返回修改的文章数
+/// (两个名字都修改的文章算两次). 文章的作者名是查询时关联的, 不需要修改
+///
+pub async fn rename_user(user_id: ObjectId, username: &str, mongo: &Database) -> Result<u64> {
+    let update = doc! {
+        "$set":{
+            "username":username,
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":user_id}, update, None)
+        .await?
+        .matched_count;
+    if matched_count == 0 {
+        return Err(AppError::NotFound);
+    }
+
+    let mut modified_count = 0;
+    for (id_field, name_field) in [("author_id", "author_name"), ("reply_to", "reply_to_name")] {
+        let options = UpdateOptions::builder()
+            .array_filters(vec![doc! {format!("c.{}", id_field):user_id}])
+            .build();
+        modified_count += mongo
+            .collection::<Article>("article")
+            .update_many(
+                doc! {format!("comments.{}", id_field):user_id},
+                doc! {"$set":{format!("comments.$[c].{}", name_field):username}},
+                options,
+            )
+            .await?
+            .modified_count;
+    }
+    Ok(modified_count)
+}
+
+pub async fn enable_totp(user_id: &str, totp: Totp, mongo: &Database) -> Result<bool> {
+    let oid = object_id(user_id)?;
+    let update = doc! {
+        "$set":{
+            "totp":bson::to_bson(&totp)?,
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":oid}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+pub async fn disable_totp(user_id: &str, mongo: &Database) -> Result<bool> {
+    let oid = object_id(user_id)?;
+    let update = doc! {
+        "$unset":{"totp":""},
+        "$set":{"updated_time":Utc::now().with_timezone(&config::timezone())},
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":oid}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+/// 记录已使用的时间步, 只有比上次大才会更新成功, 用于防止重放
+pub async fn use_totp_counter(user_id: &str, counter: i64, mongo: &Database) -> Result<bool> {
+    let oid = object_id(user_id)?;
+    let query = doc! {
+        "_id":oid,
+        "$or":[
+            {"totp.last_counter":null},
+            {"totp.last_counter":{"$lt":counter}},
+        ],
+    };
+    let update = doc! {"$set":{"totp.last_counter":counter}};
+
+    let modified_count = mongo
+        .collection::<User>("user")
+        .update_one(query, update, None)
+        .await?
+        .modified_count;
+
+    Ok(modified_count > 0)
+}
+
+/// 使用一个恢复码, 使用后即失效
+pub async fn use_recovery_code(user_id: &str, code_hash: &str, mongo: &Database) -> Result<bool> {
+    let oid = object_id(user_id)?;
+    let query = doc! {"_id":oid, "totp.recovery_codes":code_hash};
+    let update = doc! {"$pull":{"totp.recovery_codes":code_hash}};
+
+    let modified_count = mongo
+        .collection::<User>("user")
+        .update_one(query, update, None)
+        .await?
+        .modified_count;
+
+    Ok(modified_count > 0)
+}
+
+/// 记录一次两步验证失败, 返回连续失败的次数
+pub async fn record_totp_failure(user_id: &str, mongo: &Database) -> Result<i32> {
+    let oid = object_id(user_id)?;
+    let query = doc! {"_id":oid, "totp":{"$ne":null}};
+    let update = doc! {"$inc":{"totp.failed_attempts":1}};
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+
+    let user = mongo
+        .collection::<User>("user")
+        .find_one_and_update(query, update, options)
+        .await?;
+
+    Ok(user
+        .and_then(|u| u.totp)
+        .map(|t| t.failed_attempts)
+        .unwrap_or_default())
+}
+
+/// 清零两步验证连续失败的次数
+pub async fn reset_totp_failures(user_id: &str, mongo: &Database) -> Result<()> {
+    let oid = object_id(user_id)?;
+    let query = doc! {"_id":oid, "totp":{"$ne":null}};
+    let update = doc! {"$set":{"totp.failed_attempts":0}};
+
+    mongo
+        .collection::<User>("user")
+        .update_one(query, update, None)
+        .await?;
+    Ok(())
+}
+
+/// 某个用户发表的文章, 按发表时间倒序分页, 返回 (当前页, 总数)
+pub async fn list_article_by_author(
+    author_id: ObjectId,
+    page: i32,
+    page_size: i32,
+    viewer: Option<ObjectId>,
+    mongo: &Database,
+) -> Result<(Vec<Article>, i32)> {
+    let hidden = hidden_authors(viewer, mongo).await?;
+    if hidden.contains(&author_id) {
+        return Ok((Vec::new(), 0));
+    }
+    let articles = mongo.collection::<Article>("article");
+    let total = articles
+        .count_documents(doc! {"author_id":author_id}, None)
+        .await? as i32;
+
+    let pipeline = vec![
+        doc! {
+            "$match":{"author_id":author_id},
+        },
+        doc! {
+            "$sort":{"created_time":-1},
+        },
+        doc! {
+            "$skip":((page.max(1) - 1) * page_size) as i64,
+        },
+        doc! {
+            "$limit":page_size as i64,
+        },
+        doc! {
+            "$lookup":{"from":"user","localField":"author_id","foreignField":"_id","as":"fromAuthors"},
+        },
+        doc! {
+            "$unwind":"$fromAuthors",
+        },
+        doc! {
+            "$project":{
+                "_id":1,
+                "title":1,
+                "raw_content":1,
+                "tags":1,
+                "author_id":1,
+                "created_time":1,
+                "updated_time":1,
+                "status":1,
+                "total_comments":{"$size": visible_comments(&hidden)},
+                "author_name":"$fromAuthors.username",
+            },
+        },
+    ];
+    let mut cursor = articles.aggregate(pipeline, None).await?;
+
+    let mut result = Vec::new();
+    while let Some(c) = cursor.next().await {
+        let article: Article = bson::from_document(c?)?;
+        result.push(article);
+    }
+    Ok((result, total))
+}
+
+/// 用户最近发表的评论
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserComment {
+    pub article_id: ObjectId,
+    pub article_title: String,
+    pub comment: Comment,
+}
+
+/// 某个用户公开显示的评论, 按时间倒序分页, 返回 (当前页, 总数)
+pub async fn list_comment_by_author(
+    author_id: ObjectId,
+    page: i32,
+    page_size: i32,
+    viewer: Option<ObjectId>,
+    mongo: &Database,
+) -> Result<(Vec<UserComment>, i32)> {
+    let hidden = hidden_authors(viewer, mongo).await?;
+    if hidden.contains(&author_id) {
+        return Ok((Vec::new(), 0));
+    }
+    let filter = doc! {
+        "author_id":{"$nin":hidden},
+        "comments.author_id":author_id,
+        "comments.status":Comment::VISIBLE as i32,
+    };
+    list_comments(filter, page, page_size, mongo).await
+}
+
+/// 某个状态的评论(审核队列), 按时间倒序分页, 返回 (当前页, 总数).
+/// 审核人需要看到所有评论, 这里不排除被封禁用户的评论
+pub async fn list_comment_by_status(
+    status: i16,
+    page: i32,
+    page_size: i32,
+    mongo: &Database,
+) -> Result<(Vec<UserComment>, i32)> {
+    let filter = doc! {"comments.status":status as i32};
+    list_comments(filter, page, page_size, mongo).await
+}
+
+/// 按条件(文章字段或 `comments.*` 字段)查询评论并分页, 返回 (当前页, 总数)
+async fn list_comments(
+    filter: Document,
+    page: i32,
+    page_size: i32,
+    mongo: &Database,
+) -> Result<(Vec<UserComment>, i32)> {
+    let articles = mongo.collection::<Article>("article");
+    let stages = vec![
+        doc! {
+            "$match":filter.clone(),
+        },
+        doc! {
+            "$unwind":"$comments",
+        },
+        doc! {
+            "$match":filter,
+        },
+    ];
+
+    let mut count_pipeline = stages.clone();
+    count_pipeline.push(doc! {"$count":"total"});
+    let total = match articles.aggregate(count_pipeline, None).await?.next().await {
+        Some(c) => c?.get_i32("total").unwrap_or_default(),
+        None => 0,
+    };
+
+    let mut pipeline = stages;
+    pipeline.extend(vec![
+        doc! {
+            "$sort":{"comments.created_time":-1},
+        },
+        doc! {
+            "$skip":((page.max(1) - 1) * page_size) as i64,
+        },
+        doc! {
+            "$limit":page_size as i64,
+        },
+        doc! {
+            "$project":{
+                "_id":0,
+                "article_id":"$_id",
+                "article_title":"$title",
+                "comment":"$comments",
+            },
+        },
+    ]);
+    let mut cursor = articles.aggregate(pipeline, None).await?;
+
+    let mut result = Vec::new();
+    while let Some(c) = cursor.next().await {
+        let comment: UserComment = bson::from_document(c?)?;
+        result.push(comment);
+    }
+    Ok((result, total))
+}
+
+/// 按 id 查找评论
+pub async fn find_comment(comment_id: &str, mongo: &Database) -> Result<UserComment> {
+    let filter = doc! {"comments.id":object_id(comment_id)?};
+    let (mut comments, _) = list_comments(filter, 1, 1, mongo).await?;
+    comments.pop().ok_or(AppError::NotFound)
+}
+
+/// 审核评论: 修改状态并记录审核人
+pub async fn moderate_comment(
+    comment_id: &str,
+    status: i16,
+    moderator: ObjectId,
+    mongo: &Database,
+) -> Result<bool> {
+    let query = doc! {"comments.id":object_id(comment_id)?};
+    let update = doc! {
+        "$set":{
+            "comments.$.status":status as i32,
+            "comments.$.moderated_by":moderator,
+            "comments.$.updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+
+    let matched_count = mongo
+        .collection::<Article>("article")
+        .update_one(query, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+/// 用户是否有通过审核(公开显示)的评论
+pub async fn has_visible_comment(author_id: ObjectId, mongo: &Database) -> Result<bool> {
+    let query = doc! {
+        "comments":{"$elemMatch":{"author_id":author_id, "status":Comment::VISIBLE as i32}},
+    };
+    let count = mongo
+        .collection::<Article>("article")
+        .count_documents(query, None)
+        .await?;
+    Ok(count > 0)
+}
+
+/// 审核人通过或标记为垃圾的评论, 用于重新训练分类器
+pub async fn list_moderated_comments(mongo: &Database) -> Result<Vec<Comment>> {
+    let filter = doc! {
+        "comments.moderated_by":{"$ne":null},
+        "comments.status":{"$in":[Comment::VISIBLE as i32, Comment::SPAM as i32]},
+    };
+    let pipeline = vec![
+        doc! {
+            "$match":filter.clone(),
+        },
+        doc! {
+            "$unwind":"$comments",
+        },
+        doc! {
+            "$match":filter,
+        },
+        doc! {
+            "$replaceRoot":{"newRoot":"$comments"},
+        },
+    ];
+    let mut cursor = mongo
+        .collection::<Article>("article")
+        .aggregate(pipeline, None)
+        .await?;
+
+    let mut result = Vec::new();
+    while let Some(c) = cursor.next().await {
+        let comment: Comment = bson::from_document(c?)?;
+        result.push(comment);
+    }
+    Ok(result)
+}
+
+/// 未通过审核和垃圾评论中, 最后修改(审核)时间早于 `before` 的
+fn purgeable_comment(before: DateTime<Utc>) -> Document {
+    doc! {
+        "status":{"$in":[Comment::REJECTED as i32, Comment::SPAM as i32]},
+        "updated_time":{"$lt":before},
+    }
+}
+
+/// 可以清除的评论数, 见 [`purge_comments`]
+pub async fn count_purgeable_comments(before: DateTime<Utc>, mongo: &Database) -> Result<u64> {
+    let filter = purgeable_comment(before);
+    let pipeline = vec![
+        doc! {
+            "$match":{"comments":{"$elemMatch":filter.clone()}},
+        },
+        doc! {
+            "$unwind":"$comments",
+        },
+        doc! {
+            "$replaceRoot":{"newRoot":"$comments"},
+        },
+        doc! {
+            "$match":filter,
+        },
+        doc! {
+            "$count":"total",
+        },
+    ];
+    let total = match mongo
+        .collection::<Article>("article")
+        .aggregate(pipeline, None)
+        .await?
+        .next()
+        .await
+    {
+        Some(c) => c?.get_i32("total").unwrap_or_default(),
+        None => 0,
+    };
+    Ok(total as u64)
+}
+
+/// 从文章中删除审核时间早于 `before` 的未通过和垃圾评论, 返回删除的评论数
+pub async fn purge_comments(before: DateTime<Utc>, mongo: &Database) -> Result<u64> {
+    let count = count_purgeable_comments(before, mongo).await?;
+    if count > 0 {
+        let filter = purgeable_comment(before);
+        mongo
+            .collection::<Article>("article")
+            .update_many(
+                doc! {"comments":{"$elemMatch":filter.clone()}},
+                doc! {"$pull":{"comments":filter}},
+                None,
+            )
+            .await?;
+    }
+    Ok(count)
+}
+
+pub async fn update_user_profile(
+    user_id: &str,
+    bio: &str,
+    blog: &str,
+    mongo: &Database,
+) -> Result<bool> {
+    let oid = object_id(user_id)?;
+    let update = doc! {
+        "$set":{
+            "bio":bio,
+            "blog":blog,
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":oid}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+/// 保存通知设置, `muted` 为关闭的通知类型
+pub async fn update_muted_notifications(
+    user_id: ObjectId,
+    muted: &[&str],
+    mongo: &Database,
+) -> Result<bool> {
+    let update = doc! {
+        "$set":{
+            "muted_notifications":muted,
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":user_id}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+/// 保存邮件设置, `muted` 为关闭的邮件通知类型
+pub async fn update_email_prefs(
+    user_id: ObjectId,
+    muted: &[&str],
+    weekly_digest: bool,
+    mongo: &Database,
+) -> Result<bool> {
+    let update = doc! {
+        "$set":{
+            "muted_emails":muted,
+            "weekly_digest":weekly_digest,
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":user_id}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+/// 订阅了每周摘要的正常用户
+pub async fn list_digest_subscribers(mongo: &Database) -> Result<Vec<User>> {
+    let query = doc! {"weekly_digest":true, "status":User::ACTIVE as i32};
+    let mut cursor = mongo.collection::<User>("user").find(query, None).await?;
+
+    let mut result = Vec::new();
+    while let Some(user) = cursor.next().await {
+        result.push(user?);
+    }
+    Ok(result)
+}
+
+/// 封禁用户, 同时修改用户状态
+pub async fn ban_user(user_id: ObjectId, ban: Ban, mongo: &Database) -> Result<bool> {
+    let update = doc! {
+        "$set":{
+            "status":ban.kind.status() as i32,
+            "ban":bson::to_bson(&ban)?,
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        }
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":user_id}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
+
+pub async fn unban_user(user_id: ObjectId, mongo: &Database) -> Result<bool> {
+    let update = doc! {
+        "$set":{
+            "status":User::ACTIVE as i32,
+            "updated_time":Utc::now().with_timezone(&config::timezone()),
+        },
+        "$unset":{"ban":""},
+    };
+
+    let matched_count = mongo
+        .collection::<User>("user")
+        .update_one(doc! {"_id":user_id}, update, None)
+        .await?
+        .matched_count;
+
+    Ok(matched_count > 0)
+}
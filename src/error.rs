@@ -0,0 +1,297 @@
+//!
+//! 统一的错误类型, 以及把错误渲染成错误页面/JSON 的中间件
+//!
+use std::fmt::{self, Display, Formatter};
+
+use poem::{
+    error::ResponseError,
+    http::{header, StatusCode},
+    web::{Html, Json},
+    Endpoint, IntoResponse, Request, Response,
+};
+use serde_json::json;
+use tera::Context;
+use tracing::error;
+
+use crate::handler::TEMPLATES;
+
+#[derive(Debug)]
+pub enum AppError {
+    /// 资源不存在
+    NotFound,
+    /// 需要登录
+    Unauthenticated,
+    /// 已登录但没有权限
+    Forbidden,
+    /// 账户已被封禁, 消息包含原因和到期时间
+    Banned(String),
+    /// CSRF token 校验失败
+    Csrf,
+    /// 请求参数错误, 消息会展示给用户
+    BadRequest(String),
+    /// 请求太频繁, `retry_after` 秒后可以重试
+    TooManyRequests { retry_after: u64 },
+    /// 调用第三方服务(gitee/github 等)出错
+    Upstream(String),
+    /// 数据库错误
+    Db(mongodb::error::Error),
+    /// 其他内部错误
+    Internal(String),
+}
+
+pub type Result<T, E = AppError> = std::result::Result<T, E>;
+
+/// 查询结果中 NotFound 转换成 None, 其他错误原样返回
+pub trait OptionalExt<T> {
+    fn optional(self) -> Result<Option<T>>;
+}
+
+impl<T> OptionalExt<T> for Result<T> {
+    fn optional(self) -> Result<Option<T>> {
+        match self {
+            Ok(v) => Ok(Some(v)),
+            Err(AppError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl AppError {
+    pub fn bad_request(msg: impl Into<String>) -> Self {
+        AppError::BadRequest(msg.into())
+    }
+
+    pub fn internal(err: impl Display) -> Self {
+        AppError::Internal(err.to_string())
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "not_found",
+            AppError::Unauthenticated => "unauthenticated",
+            AppError::Forbidden => "forbidden",
+            AppError::Banned(_) => "banned",
+            AppError::Csrf => "csrf",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::Upstream(_) => "upstream",
+            AppError::Db(_) => "db",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    /// 展示给用户的消息, 不包含内部错误的细节
+    fn public_message(&self) -> String {
+        match self {
+            AppError::NotFound => "页面不存在".to_owned(),
+            AppError::Unauthenticated => "请先登录".to_owned(),
+            AppError::Forbidden => "没有权限".to_owned(),
+            AppError::Banned(msg) => msg.clone(),
+            AppError::Csrf => "页面已过期, 请刷新后重试".to_owned(),
+            AppError::BadRequest(msg) => msg.clone(),
+            AppError::TooManyRequests { retry_after } => {
+                format!("操作太频繁, 请 {} 秒后再试", retry_after)
+            }
+            AppError::Upstream(_) => "第三方服务出错, 请稍后重试".to_owned(),
+            AppError::Db(_) | AppError::Internal(_) => "服务器内部错误".to_owned(),
+        }
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound => write!(f, "not found"),
+            AppError::Unauthenticated => write!(f, "unauthenticated"),
+            AppError::Forbidden => write!(f, "forbidden"),
+            AppError::Banned(msg) => write!(f, "banned: {}", msg),
+            AppError::Csrf => write!(f, "csrf token mismatch"),
+            AppError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            AppError::TooManyRequests { retry_after } => {
+                write!(f, "too many requests, retry after {}s", retry_after)
+            }
+            AppError::Upstream(msg) => write!(f, "upstream error: {}", msg),
+            AppError::Db(err) => write!(f, "db error: {}", err),
+            AppError::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl ResponseError for AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden | AppError::Banned(_) | AppError::Csrf => StatusCode::FORBIDDEN,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::Db(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for AppError {
+    fn from(err: mongodb::error::Error) -> Self {
+        AppError::Db(err)
+    }
+}
+
+impl From<bson::de::Error> for AppError {
+    fn from(err: bson::de::Error) -> Self {
+        AppError::Internal(format!("bson: {}", err))
+    }
+}
+
+impl From<bson::ser::Error> for AppError {
+    fn from(err: bson::ser::Error) -> Self {
+        AppError::Internal(format!("bson: {}", err))
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(err: reqwest::Error) -> Self {
+        AppError::Upstream(err.to_string())
+    }
+}
+
+impl From<tera::Error> for AppError {
+    fn from(err: tera::Error) -> Self {
+        AppError::Internal(format!("template: {:?}", err))
+    }
+}
+
+/// 客户端是否希望得到 JSON 格式的响应
+pub fn wants_json(req: &Request) -> bool {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+fn render_html(status: StatusCode, msg: &str, retry_after: Option<u64>) -> Response {
+    let mut context = Context::new();
+    let rendered = if status == StatusCode::NOT_FOUND {
+        context.insert("title", "404");
+        TEMPLATES.render("404.html", &context)
+    } else if let Some(retry_after) = retry_after {
+        context.insert("title", "请求太频繁");
+        context.insert("retry_after", &retry_after);
+        TEMPLATES.render("429.html", &context)
+    } else {
+        context.insert("title", "错误");
+        context.insert("msg", msg);
+        TEMPLATES.render("error.html", &context)
+    };
+    match rendered {
+        Ok(s) => Html(s).with_status(status).into_response(),
+        Err(err) => {
+            error!("render error page: {:?}", err);
+            status.with_body(msg.to_owned()).into_response()
+        }
+    }
+}
+
+///
+/// 所有错误在这里统一渲染: 浏览器得到 404.html/error.html, API 客户端得到 JSON
+///
+pub async fn render<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+    let json = wants_json(&req);
+    let err = match next.call(req).await {
+        Ok(resp) => return Ok(resp.into_response()),
+        Err(err) => err,
+    };
+
+    // 已经是完整响应的错误(例如中间件返回的)原样返回
+    if err.is_from_response() {
+        return Ok(err.into_response());
+    }
+
+    let status = err.status();
+    let retry_after = match err.downcast_ref::<AppError>() {
+        Some(AppError::TooManyRequests { retry_after }) => Some(*retry_after),
+        _ => None,
+    };
+    let (kind, msg) = match err.downcast_ref::<AppError>() {
+        Some(AppError::Unauthenticated) if !json => {
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, "/signin")
+                .finish());
+        }
+        Some(app_err) => (app_err.kind(), app_err.public_message()),
+        None if status.is_server_error() => ("internal", "服务器内部错误".to_owned()),
+        None => ("bad_request", err.to_string()),
+    };
+
+    let mut resp = if json {
+        let body = json!({
+            "code": status.as_u16(),
+            "error": kind,
+            "message": msg,
+        });
+        Json(body).with_status(status).into_response()
+    } else {
+        render_html(status, &msg, retry_after)
+    };
+    if let Some(retry_after) = retry_after {
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        assert_eq!(AppError::NotFound.status(), StatusCode::NOT_FOUND);
+        assert_eq!(AppError::Forbidden.status(), StatusCode::FORBIDDEN);
+        assert_eq!(AppError::bad_request("x").status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            AppError::Upstream("x".to_owned()).status(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(AppError::internal("x").public_message(), "服务器内部错误");
+        assert_eq!(
+            AppError::TooManyRequests { retry_after: 3 }.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_optional() {
+        assert_eq!(Ok::<_, AppError>(1).optional().unwrap(), Some(1));
+        assert_eq!(Err::<i32, _>(AppError::NotFound).optional().unwrap(), None);
+        assert!(Err::<i32, _>(AppError::Forbidden).optional().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_json() {
+        use poem::{handler, EndpointExt, Route};
+
+        #[handler]
+        fn missing() -> Result<&'static str> {
+            Err(AppError::NotFound)
+        }
+
+        let app = Route::new().at("/", missing).around(render);
+        let req = Request::builder()
+            .header(header::ACCEPT, "application/json")
+            .finish();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value =
+            serde_json::from_str(&resp.into_body().into_string().await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({"code": 404, "error": "not_found", "message": "页面不存在"})
+        );
+    }
+}
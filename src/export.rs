@@ -0,0 +1,642 @@
+//!
+//! 静态站点导出: `blog export --out dir` 用网站相同的模板把所有公开的文章渲染成静态页面,
+//! 生成分页的首页、标签页、Atom 订阅和 sitemap, 站内链接改写成相对路径, 可以直接放到静态托管上.
+//! 输出目录中的 `.export-manifest.json` 记录每篇文章的指纹, 再次导出时跳过没有变化的文章
+//!
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use mongodb::Database;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tera::Context;
+
+use crate::config;
+use crate::db;
+use crate::error::{AppError, Result};
+use crate::handler::{self, ArticleDetailView, TEMPLATES};
+use crate::imaging;
+use crate::model::Article;
+use crate::newsletter;
+use crate::storage;
+
+/// 输出目录中记录上次导出结果的文件
+pub const MANIFEST: &str = ".export-manifest.json";
+
+/// 修改导出逻辑后加一, 让所有文章重新生成
+const VERSION: u32 = 1;
+
+/// 首页和标签页每页的文章数
+const PAGE_SIZE: usize = 20;
+
+/// 订阅中的文章数
+const FEED_ENTRIES: usize = 20;
+
+pub struct Options {
+    pub out: PathBuf,
+    /// 订阅和 sitemap 中的绝对地址, 以及动态页面(登录、评论等)的链接指向的站点
+    pub base_url: String,
+    /// 忽略上次的记录, 重新生成全部文章
+    pub full: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// 内容有变化, 重新写入的文件数
+    pub written: usize,
+    /// 内容没有变化的文件数
+    pub unchanged: usize,
+    /// 没有变化, 跳过渲染的文章数
+    pub skipped: usize,
+    /// 删除的过期文件数
+    pub removed: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// 文章 id -> 指纹
+    articles: BTreeMap<String, String>,
+    /// 上次生成的全部页面, 本次没有生成的会被删除
+    files: BTreeSet<String>,
+}
+
+impl Manifest {
+    /// 上次导出时文章的指纹相同, 页面不需要重新生成
+    fn unchanged(&self, id: &str, fingerprint: &str) -> bool {
+        self.version == VERSION && self.articles.get(id).map(String::as_str) == Some(fingerprint)
+    }
+}
+
+/// 文章页面的指纹, 包括页面上显示的全部内容: 正文、模板和每条评论
+fn fingerprint(article: &Article, templates: &str, content: &str) -> String {
+    let mut hasher = Sha256::new();
+    let parts: [&str; 8] = [
+        &VERSION.to_string(),
+        templates,
+        &article.title,
+        &article.tags,
+        article.author_name.as_deref().unwrap_or_default(),
+        &article.total_comments.unwrap_or_default().to_string(),
+        &article.updated_time.timestamp_millis().to_string(),
+        content,
+    ];
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    // 编辑或审核评论不会修改文章的 updated_time
+    for comment in article.comments.iter().flatten() {
+        let id = comment.id.map(|id| id.to_hex()).unwrap_or_default();
+        for part in [&id, &comment.content, &comment.status.to_string()] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+    }
+    hex(&hasher.finalize())
+}
+
+#[derive(Serialize)]
+struct FeedEntry {
+    title: String,
+    url: String,
+    published: String,
+    updated: String,
+    author: String,
+    tags: Vec<String>,
+    content: String,
+}
+
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 文章页面的路径
+pub fn article_path(id: &str) -> String {
+    format!("/articles/{}.html", id)
+}
+
+/// 标签页的目录名: 字母、数字、`-`、`_` 之外的字符换成 `-`
+pub fn tag_slug(tag: &str) -> String {
+    let slug: String = tag
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    slug.trim_matches('-').to_owned()
+}
+
+/// 第 `page` 页的路径, `dir` 为空时是首页
+fn page_path(dir: &str, page: usize) -> String {
+    if page == 1 {
+        format!("{}/index.html", dir)
+    } else {
+        format!("{}/page/{}.html", dir, page)
+    }
+}
+
+/// 页面路径的深度, 改写链接时需要回到根目录的 `../` 个数
+fn depth(path: &str) -> usize {
+    path.matches('/').count() - 1
+}
+
+fn is_static_path(path: &str) -> bool {
+    ["/assets/", "/uploads/", "/articles/", "/tags/", "/page/"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+        || ["/index.html", "/feed.xml", "/sitemap.xml"].contains(&path)
+}
+
+///
+/// 改写站内链接: 导出的页面用相对路径(`depth` 为 Some)或绝对地址(None, 用于订阅),
+/// 没有导出的动态页面指向 `base_url`. 站外链接和页内锚点不变
+///
+pub fn map_link(url: &str, depth: Option<usize>, base_url: &str) -> String {
+    if !url.starts_with('/') || url.starts_with("//") {
+        return url.to_owned();
+    }
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, format!("#{}", fragment)),
+        None => (url, String::new()),
+    };
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+    let article_id = (path == "/article")
+        .then(|| {
+            serde_urlencoded::from_str::<HashMap<String, String>>(query)
+                .ok()?
+                .remove("id")
+        })
+        .flatten()
+        .filter(|id| db::object_id(id).is_ok());
+    let path = match article_id {
+        Some(id) => article_path(&id),
+        None if path == "/" => "/index.html".to_owned(),
+        None if is_static_path(path) => url.to_owned(),
+        None => return format!("{}{}{}", base_url, url, fragment),
+    };
+    match depth {
+        Some(depth) => format!("{}{}{}", "../".repeat(depth), &path[1..], fragment),
+        None => format!("{}{}{}", base_url, path, fragment),
+    }
+}
+
+/// 改写 HTML 中 `href`/`src`/`srcset` 属性的链接, 模板输出的 `/` 可能被转义成 `&#x2F;`
+pub fn rewrite_links(html: &str, depth: Option<usize>, base_url: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    loop {
+        let found = ["href=", "src=", "srcset="]
+            .iter()
+            .filter_map(|attr| {
+                let start = rest.find(&format!(" {}", attr))?;
+                let quote_at = start + 1 + attr.len();
+                let quote = rest[quote_at..].chars().next()?;
+                matches!(quote, '"' | '\'').then_some((start, attr, quote_at, quote))
+            })
+            .min_by_key(|(start, ..)| *start);
+        let Some((_, attr, quote_at, quote)) = found else {
+            break;
+        };
+        let value_start = quote_at + 1;
+        let Some(len) = rest[value_start..].find(quote) else {
+            break;
+        };
+        result.push_str(&rest[..value_start]);
+
+        let value = rest[value_start..value_start + len]
+            .replace("&#x2F;", "/")
+            .replace("&amp;", "&");
+        let mapped = if *attr == "srcset=" {
+            value
+                .split(',')
+                .map(|candidate| {
+                    let candidate = candidate.trim();
+                    match candidate.split_once(' ') {
+                        Some((url, descriptor)) => {
+                            format!("{} {}", map_link(url, depth, base_url), descriptor)
+                        }
+                        None => map_link(candidate, depth, base_url),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        } else {
+            map_link(&value, depth, base_url)
+        };
+        result.push_str(&mapped.replace('&', "&amp;"));
+        rest = &rest[value_start + len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// 模板文件的哈希, 模板修改后所有文章重新生成
+fn templates_hash(dir: &Path) -> Result<String> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.sort();
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update(std::fs::read(&file).map_err(AppError::internal)?);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+pub fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir).map_err(AppError::internal)? {
+        let path = entry.map_err(AppError::internal)?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// 复制目录, 目标已经存在且大小相同的文件跳过(衍生文件和上传的文件按内容命名)
+fn copy_dir(from: &Path, to: &Path) -> Result<usize> {
+    let mut files = Vec::new();
+    collect_files(from, &mut files)?;
+    let mut copied = 0;
+    for file in files {
+        let target = to.join(file.strip_prefix(from).map_err(AppError::internal)?);
+        let len = std::fs::metadata(&file).map_err(AppError::internal)?.len();
+        if std::fs::metadata(&target).is_ok_and(|m| m.len() == len) {
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(AppError::internal)?;
+        }
+        std::fs::copy(&file, &target).map_err(AppError::internal)?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+struct Writer<'a> {
+    out: &'a Path,
+    base_url: &'a str,
+    files: BTreeSet<String>,
+    report: Report,
+}
+
+impl Writer<'_> {
+    /// 写入页面, 内容没有变化时不改动文件
+    fn write(&mut self, path: &str, content: &[u8]) -> Result<()> {
+        let target = self.out.join(&path[1..]);
+        self.files.insert(path.to_owned());
+        if std::fs::read(&target).is_ok_and(|old| old == content) {
+            self.report.unchanged += 1;
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(AppError::internal)?;
+        }
+        std::fs::write(&target, content).map_err(AppError::internal)?;
+        self.report.written += 1;
+        Ok(())
+    }
+
+    fn render_html(&mut self, path: &str, template: &str, context: &Context) -> Result<()> {
+        let html = TEMPLATES.render(template, context)?;
+        let html = rewrite_links(&html, Some(depth(path)), self.base_url);
+        self.write(path, html.as_bytes())
+    }
+
+    /// 分页的文章列表, `dir` 为空时是首页, 否则是标签页
+    fn render_list(
+        &mut self,
+        dir: &str,
+        title: &str,
+        tag: Option<(&str, &str)>,
+        articles: &[&ArticleDetailView],
+    ) -> Result<()> {
+        let chunks: Vec<&[&ArticleDetailView]> = if articles.is_empty() {
+            vec![&[]]
+        } else {
+            articles.chunks(PAGE_SIZE).collect()
+        };
+        let pages: Vec<(usize, String)> = (1..=chunks.len())
+            .map(|page| (page, page_path(dir, page)))
+            .collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut context = Context::new();
+            context.insert("title", title);
+            context.insert("static_export", &true);
+            context.insert("article_list", chunk);
+            context.insert("page", &(i + 1));
+            if pages.len() > 1 {
+                context.insert("pages", &pages);
+            }
+            if let Some((tag, slug)) = tag {
+                context.insert("tag", tag);
+                context.insert("tag_slug", slug);
+            }
+            self.render_html(&pages[i].1, "index.html", &context)?;
+        }
+        Ok(())
+    }
+
+    fn render_feed(
+        &mut self,
+        path: &str,
+        title: &str,
+        page: &str,
+        entries: &[&FeedEntry],
+    ) -> Result<()> {
+        let mut context = Context::new();
+        context.insert("feed_title", title);
+        context.insert("feed_url", &format!("{}{}", self.base_url, path));
+        context.insert("page_url", &format!("{}{}", self.base_url, page));
+        let updated = entries
+            .iter()
+            .map(|e| e.updated.as_str())
+            .max()
+            .map_or_else(|| rfc3339(Utc::now()), str::to_owned);
+        context.insert("updated", &updated);
+        context.insert("entries", entries);
+        let xml = TEMPLATES.render("export/feed.xml", &context)?;
+        self.write(path, xml.as_bytes())
+    }
+}
+
+pub async fn run(options: &Options, mongo: &Database) -> Result<Report> {
+    let base_url = options.base_url.trim_end_matches('/');
+    let out = options.out.as_path();
+    std::fs::create_dir_all(out).map_err(AppError::internal)?;
+    let manifest_path = out.join(MANIFEST);
+    let old_manifest: Manifest = match std::fs::read(&manifest_path) {
+        Ok(data) if !options.full => serde_json::from_slice(&data).unwrap_or_default(),
+        _ => Manifest::default(),
+    };
+    let templates = templates_hash(Path::new("templates"))?;
+
+    // 和首页一样, 不包括被封禁用户的文章
+    let mut articles = db::list_article(None, mongo).await?;
+    articles.sort_by_key(|a| std::cmp::Reverse(a.created_time));
+
+    let mut writer = Writer {
+        out,
+        base_url,
+        files: BTreeSet::new(),
+        report: Report::default(),
+    };
+    let mut manifest = Manifest {
+        version: VERSION,
+        ..Default::default()
+    };
+    let mut entries = Vec::new();
+    for article in &articles {
+        let id = article.id.to_string();
+        let path = article_path(&id);
+        let content = handler::render_content(&article.raw_content, mongo).await?;
+        // 静态页面显示全部评论
+        let page_size = article.total_comments.unwrap_or_default().max(1);
+        let full = db::get_article(id.clone(), Some(page_size), None, None, mongo).await?;
+        let fingerprint = fingerprint(&full, &templates, &content);
+
+        if old_manifest.unchanged(&id, &fingerprint) && out.join(&path[1..]).exists() {
+            writer.files.insert(path.clone());
+            writer.report.skipped += 1;
+        } else {
+            let mut view: ArticleDetailView = full.into();
+            view.raw_content = content.clone();
+            let mut context = Context::new();
+            context.insert("title", &view.title);
+            context.insert("article", &view);
+            context.insert("static_export", &true);
+            context.insert("comment_current_page", &1);
+            context.insert("comment_held", &false);
+            writer.render_html(&path, "article.html", &context)?;
+        }
+        manifest.articles.insert(id, fingerprint);
+
+        entries.push(FeedEntry {
+            title: article.title.clone(),
+            url: format!("{}{}", base_url, path),
+            published: rfc3339(article.created_time),
+            updated: rfc3339(article.updated_time.max(article.created_time)),
+            author: article.author_name.clone().unwrap_or_default(),
+            tags: newsletter::parse_tags(&article.tags),
+            content: rewrite_links(&content, None, base_url),
+        });
+    }
+
+    let views = handler::article_list_views(articles.clone(), mongo).await?;
+    let all: Vec<&ArticleDetailView> = views.iter().collect();
+    writer.render_list("", "首页", None, &all)?;
+    let all_entries: Vec<&FeedEntry> = entries.iter().take(FEED_ENTRIES).collect();
+    writer.render_feed("/feed.xml", "Joeyscat", "/index.html", &all_entries)?;
+
+    // 标签 -> (显示的名称, 文章下标)
+    let mut tags: BTreeMap<String, (String, Vec<usize>)> = BTreeMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        for tag in &entry.tags {
+            let slug = tag_slug(tag);
+            if slug.is_empty() {
+                continue;
+            }
+            let (_, indexes) = tags
+                .entry(slug)
+                .or_insert_with(|| (tag.clone(), Vec::new()));
+            if !indexes.contains(&i) {
+                indexes.push(i);
+            }
+        }
+    }
+    for (slug, (tag, indexes)) in &tags {
+        let dir = format!("/tags/{}", slug);
+        let list: Vec<&ArticleDetailView> = indexes.iter().map(|&i| &views[i]).collect();
+        writer.render_list(&dir, &format!("标签: {}", tag), Some((tag, slug)), &list)?;
+        let tag_entries: Vec<&FeedEntry> = indexes
+            .iter()
+            .take(FEED_ENTRIES)
+            .map(|&i| &entries[i])
+            .collect();
+        writer.render_feed(
+            &format!("{}/feed.xml", dir),
+            &format!("Joeyscat: {}", tag),
+            &page_path(&dir, 1),
+            &tag_entries,
+        )?;
+    }
+
+    let mut urls: Vec<(String, Option<String>)> = vec![(format!("{}/index.html", base_url), None)];
+    for article in &articles {
+        urls.push((
+            format!("{}{}", base_url, article_path(&article.id.to_string())),
+            Some(
+                article
+                    .updated_time
+                    .max(article.created_time)
+                    .format("%Y-%m-%d")
+                    .to_string(),
+            ),
+        ));
+    }
+    for slug in tags.keys() {
+        urls.push((
+            format!("{}{}", base_url, page_path(&format!("/tags/{}", slug), 1)),
+            None,
+        ));
+    }
+    let mut context = Context::new();
+    context.insert("urls", &urls);
+    let sitemap = TEMPLATES.render("export/sitemap.xml", &context)?;
+    writer.write("/sitemap.xml", sitemap.as_bytes())?;
+
+    // 删除上次生成、这次没有生成的页面(例如删除了的文章)
+    let Writer {
+        files, mut report, ..
+    } = writer;
+    for stale in old_manifest.files.difference(&files) {
+        match std::fs::remove_file(out.join(stale.trim_start_matches('/'))) {
+            Ok(()) => report.removed += 1,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(AppError::internal(err)),
+        }
+    }
+
+    let upload = &config::get().upload;
+    report.written += copy_dir(Path::new("assets"), &out.join("assets"))?;
+    report.written += copy_dir(
+        Path::new(&upload.derived_dir),
+        &out.join(&imaging::URL_PREFIX[1..]),
+    )?;
+    if upload.storage == "local" {
+        report.written += copy_dir(
+            Path::new(&upload.dir),
+            &out.join(&storage::LOCAL_URL_PREFIX[1..]),
+        )?;
+    }
+
+    manifest.files = files;
+    let data = serde_json::to_vec_pretty(&manifest).map_err(AppError::internal)?;
+    std::fs::write(&manifest_path, data).map_err(AppError::internal)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "https://blog.example.com";
+
+    #[test]
+    fn test_map_link() {
+        let id = "61d70cfa4a138b2ed4f4b088";
+        let article = format!("/article?id={}", id);
+        assert_eq!(
+            map_link(&article, Some(0), BASE),
+            format!("articles/{}.html", id)
+        );
+        assert_eq!(
+            map_link(
+                &format!("{}&comment_page=2#comments", article),
+                Some(1),
+                BASE
+            ),
+            format!("../articles/{}.html#comments", id)
+        );
+        assert_eq!(map_link("/", Some(2), BASE), "../../index.html");
+        assert_eq!(
+            map_link("/assets/css/base.css", Some(1), BASE),
+            "../assets/css/base.css"
+        );
+        assert_eq!(
+            map_link("/tags/rust/index.html", None, BASE),
+            "https://blog.example.com/tags/rust/index.html"
+        );
+        // 没有导出的动态页面指向线上站点
+        assert_eq!(
+            map_link("/user/61d70cfa4a138b2ed4f4b088", Some(1), BASE),
+            "https://blog.example.com/user/61d70cfa4a138b2ed4f4b088"
+        );
+        assert_eq!(
+            map_link("/article?id=bad", Some(1), BASE),
+            "https://blog.example.com/article?id=bad"
+        );
+        assert_eq!(
+            map_link("https://x.com/a", Some(1), BASE),
+            "https://x.com/a"
+        );
+        assert_eq!(map_link("//cdn.x.com/a", Some(1), BASE), "//cdn.x.com/a");
+        assert_eq!(map_link("#comments", Some(1), BASE), "#comments");
+    }
+
+    #[test]
+    fn test_rewrite_links() {
+        let html = "<a href=\"&#x2F;article?id=61d70cfa4a138b2ed4f4b088&amp;x=1\">a</a>\
+                    <img src='/uploads/ab/c.png' alt='x' />\
+                    <source srcset='/assets/derived/a-320.webp 320w, /assets/derived/a-640.webp 640w'>\
+                    <a href=\"/signin?next=/&amp;a=b\">b</a>";
+        assert_eq!(
+            rewrite_links(html, Some(1), BASE),
+            "<a href=\"../articles/61d70cfa4a138b2ed4f4b088.html\">a</a>\
+             <img src='../uploads/ab/c.png' alt='x' />\
+             <source srcset='../assets/derived/a-320.webp 320w, ../assets/derived/a-640.webp 640w'>\
+             <a href=\"https://blog.example.com/signin?next=/&amp;a=b\">b</a>"
+        );
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(tag_slug("rust"), "rust");
+        assert_eq!(tag_slug("c++ / 编程"), "c-----编程");
+        assert_eq!(tag_slug("../"), "");
+        assert_eq!(page_path("", 1), "/index.html");
+        assert_eq!(page_path("/tags/rust", 3), "/tags/rust/page/3.html");
+        assert_eq!(depth("/index.html"), 0);
+        assert_eq!(depth("/tags/rust/page/3.html"), 3);
+    }
+
+    #[test]
+    fn test_fingerprint_comments() {
+        use crate::model::Comment;
+        use mongodb::bson::oid::ObjectId;
+
+        let user = ObjectId::new();
+        let mut article = Article {
+            title: "hello".to_owned(),
+            comments: Some(vec![
+                Comment::new("first".to_owned(), user, "joey".to_owned(), None, None),
+                Comment::new("second".to_owned(), user, "joey".to_owned(), None, None),
+            ]),
+            total_comments: Some(2),
+            ..Default::default()
+        };
+        let id = article.id.to_hex();
+        let old = fingerprint(&article, "templates", "<p>hello</p>");
+        let mut manifest = Manifest {
+            version: VERSION,
+            ..Default::default()
+        };
+        manifest.articles.insert(id.clone(), old.clone());
+        assert!(manifest.unchanged(&id, &old));
+
+        // 只修改一条评论的内容, 文章的 updated_time 和评论数都不变
+        article.comments.as_mut().unwrap()[1].content = "edited".to_owned();
+        let new = fingerprint(&article, "templates", "<p>hello</p>");
+        assert!(!manifest.unchanged(&id, &new));
+
+        // 评论被隐藏(审核状态改变)同样需要重新生成
+        article.comments.as_mut().unwrap()[0].status = Comment::REJECTED;
+        assert_ne!(fingerprint(&article, "templates", "<p>hello</p>"), new);
+    }
+}
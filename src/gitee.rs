@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
-use poem::{Error, Result};
 use serde_derive::{Deserialize, Serialize};
 
+use crate::error::{AppError, Result};
+
 #[derive(Deserialize)]
 struct TokenResp {
     access_token: String,
@@ -31,26 +32,16 @@ pub async fn get_access_token(
         .post("https://gitee.com/oauth/token")
         .json(&map)
         .send()
-        .await
-        .map_err(poem::error::InternalServerError)?;
+        .await?;
 
     match res.status() {
         reqwest::StatusCode::OK => {
-            let token_resp = res
-                .json::<TokenResp>()
-                .await
-                .map_err(poem::error::InternalServerError)?;
+            let token_resp = res.json::<TokenResp>().await?;
             Ok(token_resp.access_token)
         }
         _ => {
-            let t = res
-                .text_with_charset("utf-8")
-                .await
-                .map_err(poem::error::InternalServerError)?;
-            Err(Error::from_string(
-                t,
-                poem::http::StatusCode::INTERNAL_SERVER_ERROR,
-            ))
+            let t = res.text_with_charset("utf-8").await?;
+            Err(AppError::Upstream(t))
         }
     }
 }
@@ -71,11 +62,9 @@ pub async fn get_user_info(access_token: String) -> Result<UserInfo> {
         "https://gitee.com/api/v5/user?access_token={}",
         access_token
     ))
-    .await
-    .map_err(poem::error::InternalServerError)?
+    .await?
     .json::<UserInfo>()
-    .await
-    .map_err(poem::error::InternalServerError)?;
+    .await?;
 
     Ok(user)
 }
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::{AppError, Result};
+
+#[derive(Deserialize)]
+struct TokenResp {
+    access_token: Option<String>,
+    error_description: Option<String>,
+}
+
+pub async fn get_access_token(
+    code: String,
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+) -> Result<String> {
+    let mut map = HashMap::new();
+    map.insert("code", code.as_str());
+    map.insert("client_id", client_id.as_str());
+    map.insert("client_secret", client_secret.as_str());
+    map.insert("redirect_uri", redirect_uri.as_str());
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post("https://github.com/login/oauth/access_token")
+        .header(reqwest::header::ACCEPT, "application/json")
+        .json(&map)
+        .send()
+        .await?;
+
+    // github 出错时也返回 200, 错误信息在 body 里
+    let token_resp = res.json::<TokenResp>().await?;
+    match token_resp.access_token {
+        Some(access_token) => Ok(access_token),
+        None => Err(AppError::Upstream(
+            token_resp.error_description.unwrap_or_default(),
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub id: i64,
+    pub login: String,
+    pub name: Option<String>,
+    pub avatar_url: String,
+    pub blog: Option<String>,
+    pub created_at: String,
+    pub email: Option<String>,
+}
+
+pub async fn get_user_info(access_token: String) -> Result<UserInfo> {
+    let client = reqwest::Client::new();
+    let user = client
+        .get("https://api.github.com/user")
+        .bearer_auth(access_token)
+        .header(reqwest::header::USER_AGENT, "joeyscat-blog")
+        .send()
+        .await?
+        .json::<UserInfo>()
+        .await?;
+
+    Ok(user)
+}
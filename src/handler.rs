@@ -1,22 +1,42 @@
-use chrono::FixedOffset;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use lazy_static::lazy_static;
 use markdown;
-use mongodb::{bson::oid::ObjectId, Database};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    Database,
+};
 use poem::{
     handler,
     http::{header, StatusCode},
     session::Session,
-    web::{Data, Form, Html, Query},
-    IntoResponse, Response, Result,
+    web::{cookie::CookieJar, Data, Form, Html, Json, Multipart, Path, Query},
+    Body, IntoResponse, Request, Response,
 };
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use std::sync::Arc;
 use tera::{Context, Tera};
-use tracing::info;
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+use validator::Validate;
 
-use crate::gitee;
-use crate::model::Article;
+use crate::audit::{self, AuditEntry, AuditFilter};
+use crate::config;
+use crate::csrf;
+use crate::error::{self, AppError, OptionalExt, Result};
+use crate::imaging::{self, Thumbnail};
+use crate::mail;
+use crate::media::{self, Media};
+use crate::middleware;
+use crate::model::{Article, Ban, BanKind, Identity, Role, Totp, User};
+use crate::moderation::{self, Decision};
+use crate::newsletter::{self, Subscriber};
+use crate::notification::{self, Notification};
+use crate::policy::{self, Permission};
+use crate::storage::Storage;
+use crate::validate::{self, FieldErrors};
 use crate::{db, model::Comment};
+use crate::{gitee, github, password, session_store, spam, totp};
 
 lazy_static! {
     pub static ref TEMPLATES: Tera = {
@@ -28,543 +48,2774 @@ lazy_static! {
             }
         };
         tera.autoescape_on(vec!["html", ".sql"]);
+        tera.register_function("csrf_token", csrf::tera_function);
+        tera.register_function("csp_nonce", middleware::csp_nonce_function);
+        tera.register_function("unread_notifications", notification::tera_function);
         // tera.register_filter("do_nothing", do_nothing_filter);
         tera
     };
+}
 
-    pub static ref CLIENT_ID:String = {
-        std::env::var("GITEE_CLIENT_ID").expect("GITEE_CLIENT_ID")
-    };
-    pub static ref CLIENT_SECRET:String = {
-        std::env::var("GITEE_CLIENT_SECRET").expect("GITEE_CLIENT_SECRET")
-    };
-    pub static ref REDIRECT_URI:String = {
-        std::env::var("GITEE_REDIRECT_URI").expect("GITEE_REDIRECT_URI")
-    };
+/// 表单校验失败时重新渲染表单, 保留用户的输入并展示每个字段的错误
+fn render_form<T: Serialize>(
+    template: &str,
+    mut context: Context,
+    form: &T,
+    errors: &FieldErrors,
+) -> Result<Response> {
+    context.insert("form", form);
+    context.insert("errors", errors);
+    let s = TEMPLATES.render(template, &context)?;
+    Ok(Html(s).with_status(StatusCode::BAD_REQUEST).into_response())
 }
 
-#[handler]
-pub fn signin_ui() -> impl IntoResponse {
+fn signin_context() -> Context {
     let mut context = Context::new();
     context.insert("title", "登录");
-    let gitee_signin_uri = format!(
+    context.insert("gitee_signin_uri", "/oauth/authorize?provider=gitee");
+    if github_authorize_uri().is_some() {
+        context.insert("github_signin_uri", "/oauth/authorize?provider=github");
+    }
+    context
+}
+
+#[handler]
+pub fn signin_ui() -> Result<impl IntoResponse> {
+    let s = TEMPLATES.render("signin.html", &signin_context())?;
+    Ok(Html(s))
+}
+
+fn gitee_authorize_uri() -> String {
+    format!(
         "https://gitee.com/oauth/authorize?client_id={}&redirect_uri={}&response_type=code",
-        CLIENT_ID.to_string(),
-        REDIRECT_URI.to_string()
-    );
-    context.insert("gitee_signin_uri", &gitee_signin_uri);
-    let s = TEMPLATES.render("signin.html", &context).unwrap();
-    Html(s).into_response()
+        config::get().gitee.client_id,
+        config::get().gitee.redirect_uri
+    )
+}
+
+fn github_authorize_uri() -> Option<String> {
+    config::get().github.as_ref().map(|github| {
+        format!(
+            "https://github.com/login/oauth/authorize?client_id={}&redirect_uri={}",
+            github.client_id, github.redirect_uri
+        )
+    })
 }
 
-#[derive(Deserialize)]
-pub struct GiteeSignin {
+/// 带上本次授权的 `state` 的授权地址, provider 不存在或没有配置时返回 None
+fn oauth_authorize_uri(provider: &str, session: &Session) -> Option<String> {
+    let uri = match provider {
+        "gitee" => gitee_authorize_uri(),
+        "github" => github_authorize_uri()?,
+        _ => return None,
+    };
+    let state = csrf::issue_oauth_state(session);
+    Some(format!("{}&state={}", uri, state))
+}
+
+#[derive(Deserialize, Validate)]
+pub struct AuthorizeParams {
+    #[validate(length(max = 16))]
+    provider: String,
+}
+
+/// 第三方登录: 生成 `state` 后跳转到授权页面
+#[handler]
+pub fn oauth_authorize(
+    Query(params): Query<AuthorizeParams>,
+    session: &Session,
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let uri = oauth_authorize_uri(&params.provider, session).ok_or(AppError::NotFound)?;
+    session.remove("connect");
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, uri)
+        .finish())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct OAuthSignin {
+    #[validate(length(min = 1, max = 512, message = "无效的授权码"))]
     code: String,
+    #[validate(length(max = 128))]
+    state: Option<String>,
+}
+
+impl OAuthSignin {
+    /// 校验参数和 `state`, 返回授权码
+    fn check(self, session: &Session) -> Result<String> {
+        validate::check(&self)?;
+        if !csrf::take_oauth_state(session, self.state.as_deref()) {
+            return Err(AppError::Csrf);
+        }
+        Ok(self.code)
+    }
 }
 
 #[handler]
 pub async fn gitee_signin(
-    Query(GiteeSignin { code }): Query<GiteeSignin>,
+    Query(params): Query<OAuthSignin>,
     session: &Session,
     pool: Data<&Database>,
 ) -> Result<impl IntoResponse> {
+    let code = params.check(session)?;
     info!("code: {}", code);
 
     // get access_token
+    let gitee_config = &config::get().gitee;
     let access_token = gitee::get_access_token(
         code,
-        CLIENT_ID.to_string(),
-        CLIENT_SECRET.to_string(),
-        REDIRECT_URI.to_string(),
+        gitee_config.client_id.to_string(),
+        gitee_config.client_secret.to_string(),
+        gitee_config.redirect_uri.to_string(),
     )
     .await?;
-    info!("access_token: {}", access_token);
 
     // get user info
     let gitee_user = gitee::get_user_info(access_token).await?;
     info!("gitee_user: {:?}", gitee_user);
 
-    let user = db::find_user_by_giteeid(&pool, gitee_user.id).await;
-    info!("find user result: {:?}", user);
-    match user {
-        Ok(user) => {
-            // update session
-            session.set("uid", user.id.to_string());
-            session.set("username", user.username.to_string());
-            return Ok(Response::builder()
-                .status(StatusCode::FOUND)
-                .header(header::LOCATION, "/")
-                .finish());
-        }
-        Err(e) => {
-            info!("error: {}", e.to_string());
-            // save user if new
-            if e.to_string().contains("not found") {
-                let username = gitee_user.name.clone();
-                info!("creating new user =====> {}", username);
-                let nid = db::create_giteeuser(&pool, gitee_user).await?;
-
-                session.set("uid", nid);
-                session.set("username", username);
-                return Ok(Response::builder()
-                    .status(StatusCode::FOUND)
-                    .header(header::LOCATION, "/")
-                    .finish());
-            } else {
-                let mut context = Context::new();
-                context.insert("title", "错误");
-                context.insert("msg", &e.to_string());
-                let s = TEMPLATES.render("error.html", &context).unwrap();
-                Ok(Html(s).into_response())
-            }
-        }
-    }
-}
+    let username = gitee_user.name.clone();
+    let identity = Identity::new(
+        "gitee",
+        gitee_user.id.to_string(),
+        gitee_user.login.clone(),
+        bson::to_document(&gitee_user)?,
+    );
 
-#[handler]
-pub fn account(session: &Session) -> impl IntoResponse {
-    match session.get::<String>("username") {
-        Some(username) => {
-            let mut context = Context::new();
-            context.insert("title", &username);
-            context.insert("username", &username);
-            let s = TEMPLATES.render("account.html", &context).unwrap();
-            Html(s).into_response()
+    // 兼容绑定登录方式之前创建的用户
+    let found = db::find_identity(&pool, "gitee", &identity.provider_uid)
+        .await
+        .optional()?;
+    if found.is_none() {
+        if let Some(user) = db::find_user_by_giteeid(&pool, gitee_user.id)
+            .await
+            .optional()?
+        {
+            info!("migrating legacy gitee user =====> {}", user.username);
+            db::create_identity(&pool, user.id, identity.clone()).await?;
         }
-        None => Response::builder()
-            .status(StatusCode::FOUND)
-            .header(header::LOCATION, "/signin")
-            .finish(),
     }
+
+    signin_with_identity(session, &pool, identity, username).await
 }
 
 #[handler]
-pub fn signout(session: &Session) -> impl IntoResponse {
-    session.purge();
-    Response::builder()
-        .status(StatusCode::FOUND)
-        .header(header::LOCATION, "/signin")
-        .finish()
-}
+pub async fn github_signin(
+    Query(params): Query<OAuthSignin>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let code = params.check(session)?;
+    let github_config = match config::get().github.as_ref() {
+        Some(github) => github,
+        None => return Err(AppError::NotFound),
+    };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+    let access_token = github::get_access_token(
+        code,
+        github_config.client_id.to_string(),
+        github_config.client_secret.to_string(),
+        github_config.redirect_uri.to_string(),
+    )
+    .await?;
+    let github_user = github::get_user_info(access_token).await?;
+    info!("github_user: {:?}", github_user);
 
-pub struct ArticleDetailView {
-    pub id: String,
-    pub title: String,
-    pub raw_content: String,
-    pub tags: String,
-    pub author_id: String,
-    pub author_name: String,
-    pub created_time: String,
-    // pub updated_time: String,
-    pub status: i16,
-    pub comments: Vec<CommentView>,
-    pub total_comments: i32,
-    pub comment_page_nums: Vec<i32>,
-}
+    let username = github_user
+        .name
+        .clone()
+        .unwrap_or_else(|| github_user.login.clone());
+    let identity = Identity::new(
+        "github",
+        github_user.id.to_string(),
+        github_user.login.clone(),
+        bson::to_document(&github_user)?,
+    );
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+    signin_with_identity(session, &pool, identity, username).await
+}
 
-pub struct CommentView {
-    pub content: String,
-    pub author_id: String,
-    pub author_name: String,
-    pub reply_to: Option<String>,
-    pub reply_to_name: Option<String>,
-    pub created_time: String,
-    pub status: i16,
+#[derive(Serialize, Deserialize, Validate)]
+pub struct PasswordSigninParams {
+    #[validate(length(min = 1, max = 64, message = "请输入账户名"))]
+    login: String,
+    #[serde(skip_serializing)]
+    #[validate(length(min = 1, max = 128, message = "请输入密码"))]
+    password: String,
 }
 
 #[handler]
-pub async fn index(_session: &Session, pool: Data<&Database>) -> impl IntoResponse {
-    let articles = db::list_article(&pool).await;
+pub async fn password_signin(
+    Form(params): Form<PasswordSigninParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    if let Some(errors) = validate::form(&params) {
+        return render_form("signin.html", signin_context(), &params, &errors);
+    }
 
-    match articles {
-        Ok(articles) => {
-            let article_views: Vec<ArticleDetailView> =
-                articles.into_iter().map(|a| a.into()).collect();
-            let mut context = Context::new();
-            context.insert("title", "首页");
-            context.insert("article_list", &article_views);
-            let s = TEMPLATES.render("index.html", &context).unwrap();
-            Html(s).into_response()
+    let login = params.login.trim().to_lowercase();
+    let identity = db::find_identity(&pool, "password", login.as_str())
+        .await
+        .optional()?;
+    let identity = match identity {
+        Some(identity)
+            if password::verify(
+                &params.password,
+                identity.secret.as_deref().unwrap_or_default(),
+            ) =>
+        {
+            identity
         }
-        Err(err) => {
-            let mut context = Context::new();
-            context.insert("title", "错误");
-            context.insert("msg", &err.to_string());
-            let s = TEMPLATES.render("error.html", &context).unwrap();
-            Html(s).into_response()
+        _ => {
+            let user_id = identity.map(|i| i.user_id.to_string()).unwrap_or_default();
+            AuditEntry::new(audit::AUTH_SIGNIN_FAILED, "user", user_id)
+                .detail(doc! {"method": "password", "login": &login, "reason": "账户名或密码错误"})
+                .record(&pool)
+                .await;
+            let errors = FieldErrors::from([("password", "账户名或密码错误".to_owned())]);
+            return render_form("signin.html", signin_context(), &params, &errors);
         }
-    }
-}
+    };
 
-#[derive(Deserialize)]
-pub struct FindArticle {
-    id: Option<String>,
-    comment_page: Option<i32>,
+    let user = db::find_user_by_id(&identity.user_id.to_string(), &pool).await?;
+    complete_signin(session, &pool, user, "password").await
 }
 
-impl From<Article> for ArticleDetailView {
-    fn from(a: Article) -> Self {
-        let comments = match a.comments {
-            Some(cs) => cs.into_iter().map(|c| c.into()).collect(),
-            None => Vec::new(),
-        };
-        let mut comment_page_nums = vec![1];
-        if a.total_comments.is_some() {
-            let pages = (a.total_comments.unwrap() as f32 / comment_page_size() as f32).ceil() as i32;
-            for i in 2..pages + 1 {
-                comment_page_nums.push(i);
+/// OAuth 回调的公共部分:
+/// 如果当前处于"绑定登录方式"流程, 将该登录方式绑定到已登录用户;
+/// 否则使用该登录方式登录, 不存在则创建新用户
+async fn signin_with_identity(
+    session: &Session,
+    pool: &Database,
+    identity: Identity,
+    username: String,
+) -> Result<Response> {
+    let provider = identity.provider.clone();
+    let found = db::find_identity(pool, &identity.provider, &identity.provider_uid)
+        .await
+        .optional()?;
+
+    let connecting = session.get::<String>("connect");
+    let uid = session.get::<String>("uid");
+    if let (Some(provider), Some(uid)) = (connecting, uid) {
+        session.remove("connect");
+        if provider == identity.provider {
+            match found {
+                Some(found) if found.user_id.to_string() != uid => {
+                    return Err(AppError::bad_request("该账号已绑定到其他用户"));
+                }
+                Some(_) => {}
+                None => {
+                    db::create_identity(pool, db::object_id(&uid)?, identity).await?;
+                }
             }
-        }
-        ArticleDetailView {
-            id: a.id.to_string(),
-            title: a.title,
-            raw_content: a.raw_content,
-            tags: a.tags,
-            author_id: a.author_id.to_string(),
-            author_name: a.author_name.unwrap(),
-            created_time: a
-                .created_time
-                .with_timezone(&FixedOffset::east(8 * 3600))
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string(),
-            status: a.status,
-            comments,
-            total_comments: a.total_comments.unwrap(),
-            comment_page_nums,
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, "/account")
+                .finish());
         }
     }
-}
 
-impl From<Comment> for CommentView {
-    fn from(c: Comment) -> Self {
-        CommentView {
-            content: c.content,
-            author_id: c.author_id.to_string(),
-            author_name: c.author_name,
-            reply_to: c.reply_to.as_ref().map(ObjectId::to_string),
-            reply_to_name: c.reply_to_name,
-            created_time: c
-                .created_time
-                .with_timezone(&FixedOffset::east(8 * 3600))
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string(),
-            status: c.status,
+    let user = match found {
+        Some(found) => db::find_user_by_id(&found.user_id.to_string(), pool).await?,
+        None => {
+            info!("creating new user =====> {}", username);
+            let nid = db::create_user(pool, &username, identity).await?;
+            db::find_user_by_id(&nid, pool).await?
         }
-    }
+    };
+    complete_signin(session, pool, user, &provider).await
 }
 
-#[handler]
-pub async fn article_details(
-    Query(FindArticle { id, comment_page }): Query<FindArticle>,
+/// 第一步(OAuth/密码)登录成功后调用:
+/// 被封禁的用户不能登录, 开启了两步验证的用户先进入验证页面, 否则直接登录
+async fn complete_signin(
     session: &Session,
-    pool: Data<&Database>,
-) -> impl IntoResponse {
-    let article_r =
-        db::get_article(id.unwrap(), Some(comment_page_size()), comment_page, &pool).await;
+    pool: &Database,
+    user: User,
+    method: &str,
+) -> Result<Response> {
+    if let Err(err) = policy::check_ban(&user) {
+        audit_signin_failed(&user, method, "账户已被封禁", pool).await;
+        return Err(err);
+    }
+    if user.totp.is_some() {
+        session.set("pending_uid", user.id.to_string());
+        session.set("pending_method", method);
+        return Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, "/signin/2fa")
+            .finish());
+    }
 
-    match article_r {
-        Ok(mut article) => {
-            let author_id = (&article).author_id.to_string();
-            let mut articlev: ArticleDetailView = article.into();
-            articlev.raw_content = markdown::to_html(articlev.raw_content.as_str());
+    // update session, 登录后更换 session id, 防止会话固定攻击
+    session.renew();
+    session.set("uid", user.id.to_string());
+    session.set("username", user.username.to_string());
+    AuditEntry::new(audit::AUTH_SIGNIN, "user", user.id)
+        .actor(&user)
+        .detail(doc! {"method": method})
+        .record(pool)
+        .await;
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/")
+        .finish())
+}
 
-            let mut context = Context::new();
-            context.insert("title", &articlev.title);
-            context.insert("article", &articlev);
+async fn audit_signin_failed(user: &User, method: &str, reason: &str, pool: &Database) {
+    AuditEntry::new(audit::AUTH_SIGNIN_FAILED, "user", user.id)
+        .actor(user)
+        .detail(doc! {"method": method, "reason": reason})
+        .record(pool)
+        .await;
+}
 
-            // 标识是否是当前用户发表的文章，如果是则提供编辑按钮等
-            let uid = session.get::<String>("uid");
-            if uid.is_some() && uid.unwrap() == author_id {
-                context.insert("is_author", &true);
-            }
+/// 当前登录用户的 id, 用于内容查询时判断影子封禁的内容是否可见
+fn viewer(session: &Session) -> Option<ObjectId> {
+    session
+        .get::<String>("uid")
+        .and_then(|uid| ObjectId::from_str(&uid).ok())
+}
 
-            // 评论分页
-            let mut cp = 1;
-            if comment_page.is_some() {
-                cp = comment_page.unwrap();
-            }
-            context.insert("comment_current_page", &cp);
+#[handler]
+pub fn twofa_page(session: &Session) -> Result<impl IntoResponse> {
+    if session.get::<String>("pending_uid").is_none() {
+        return Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, "/signin")
+            .finish());
+    }
 
-            let s = TEMPLATES.render("article.html", &context).unwrap();
-            Html(s).into_response()
-        }
-        Err(err) => {
-            let mut context = Context::new();
+    let s = TEMPLATES.render("signin_2fa.html", &twofa_context())?;
+    Ok(Html(s).into_response())
+}
 
-            if err.to_string().contains("no rows returned") {
-                context.insert("title", "404");
-                let s = TEMPLATES.render("404.html", &context).unwrap();
-                return Html(s).into_response();
-            }
+fn twofa_context() -> Context {
+    let mut context = Context::new();
+    context.insert("title", "两步验证");
+    context
+}
 
-            context.insert("title", "错误");
-            context.insert("msg", &err.to_string());
-            let s = TEMPLATES.render("error.html", &context).unwrap();
-            Html(s).into_response()
-        }
+/// 两步验证连续失败这么多次后作废待验证的登录, 需要重新进行第一步登录
+const TWOFA_MAX_FAILURES: i32 = 5;
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct TwofaParams {
+    #[serde(skip_serializing)]
+    #[validate(length(min = 6, max = 16, message = "请输入6位验证码或恢复码"))]
+    code: String,
+}
+
+/// 校验 TOTP 验证码或一次性恢复码
+async fn verify_second_factor(user: &User, code: &str, pool: &Database) -> Result<bool> {
+    let totp = match &user.totp {
+        Some(totp) => totp,
+        None => return Ok(false),
+    };
+    let uid = user.id.to_string();
+
+    let last_counter = totp.last_counter.map(|c| c as u64);
+    if let Some(counter) = totp::verify(
+        &totp.secret,
+        code,
+        Utc::now().timestamp() as u64,
+        last_counter,
+    ) {
+        return db::use_totp_counter(&uid, counter as i64, pool).await;
     }
+
+    db::use_recovery_code(&uid, &totp::hash_recovery_code(code), pool).await
 }
 
 #[handler]
-pub async fn publish_article_page(session: &Session) -> impl IntoResponse {
-    match session.get::<String>("uid") {
-        Some(_uid) => {
-            let mut context = Context::new();
-            context.insert("title", "写文章");
-            let s = TEMPLATES.render("publish_article.html", &context).unwrap();
-            Html(s).into_response()
+pub async fn twofa_verify(
+    Form(params): Form<TwofaParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let pending_uid = match session.get::<String>("pending_uid") {
+        Some(uid) => uid,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, "/signin")
+                .finish())
         }
-        None => Response::builder()
-            .status(StatusCode::FOUND)
-            .header(header::LOCATION, "/signin")
-            .finish(),
+    };
+    if let Some(errors) = validate::form(&params) {
+        return render_form("signin_2fa.html", twofa_context(), &params, &errors);
+    }
+    let user = db::find_user_by_id(&pending_uid, &pool).await?;
+    let method = format!(
+        "{}+2fa",
+        session
+            .get::<String>("pending_method")
+            .unwrap_or_else(|| "unknown".to_owned())
+    );
+    if let Err(err) = policy::check_ban(&user) {
+        audit_signin_failed(&user, &method, "账户已被封禁", &pool).await;
+        return Err(err);
+    }
+
+    if !verify_second_factor(&user, &params.code, &pool).await? {
+        audit_signin_failed(&user, &method, "验证码错误", &pool).await;
+        if db::record_totp_failure(&pending_uid, &pool).await? >= TWOFA_MAX_FAILURES {
+            db::reset_totp_failures(&pending_uid, &pool).await?;
+            session.remove("pending_uid");
+            session.remove("pending_method");
+            return Err(AppError::bad_request("验证码错误次数过多, 请重新登录"));
+        }
+        let errors = FieldErrors::from([("code", "验证码错误".to_owned())]);
+        return render_form("signin_2fa.html", twofa_context(), &params, &errors);
+    }
+
+    if user.totp.as_ref().is_some_and(|t| t.failed_attempts > 0) {
+        db::reset_totp_failures(&pending_uid, &pool).await?;
     }
+    session.remove("pending_uid");
+    session.remove("pending_method");
+    session.renew();
+    session.set("uid", user.id.to_string());
+    session.set("username", user.username.to_string());
+    session.set("twofa_verified_at", Utc::now().timestamp());
+    AuditEntry::new(audit::AUTH_SIGNIN, "user", user.id)
+        .actor(&user)
+        .detail(doc! {"method": method})
+        .record(&pool)
+        .await;
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/")
+        .finish())
 }
 
-#[derive(Deserialize)]
-pub struct PublishArticleParams {
-    title: String,
-    raw_content: String,
-    tags: String,
+#[handler]
+pub async fn account_2fa(session: &Session, pool: Data<&Database>) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+
+    let mut context = Context::new();
+    context.insert("title", "两步验证");
+    match user.totp {
+        Some(totp) => {
+            context.insert("enabled", &true);
+            context.insert("recovery_codes_left", &totp.recovery_codes.len());
+        }
+        None => {
+            // 待确认的密钥先放在 session 中, 输入正确的验证码后才保存
+            let secret = match session.get::<String>("totp_pending_secret") {
+                Some(secret) => secret,
+                None => {
+                    let secret = totp::generate_secret();
+                    session.set("totp_pending_secret", &secret);
+                    secret
+                }
+            };
+            let uri = totp::provisioning_uri(&secret, &user.username);
+            context.insert("enabled", &false);
+            context.insert("secret", &secret);
+            context.insert("qrcode", &totp::qrcode_svg(&uri));
+        }
+    }
+    let s = TEMPLATES.render("account_2fa.html", &context)?;
+    Ok(Html(s).into_response())
 }
 
 #[handler]
-pub async fn publish_article(
-    Form(params): Form<PublishArticleParams>,
+pub async fn account_2fa_enable(
+    Form(params): Form<TwofaParams>,
     session: &Session,
     pool: Data<&Database>,
-) -> impl IntoResponse {
-    match session.get::<String>("uid") {
-        Some(uid) => {
-            let author_id = ObjectId::from_str(uid.as_str()).unwrap();
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let TwofaParams { code } = params;
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+    let secret = match session.get::<String>("totp_pending_secret") {
+        Some(secret) if user.totp.is_none() => secret,
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, "/account/2fa")
+                .finish())
+        }
+    };
 
-            let mut new_article = Article::default();
-            new_article.author_id = author_id;
-            new_article.title = params.title;
-            new_article.raw_content = params.raw_content;
-            new_article.tags = params.tags;
+    let counter = match totp::verify(&secret, &code, Utc::now().timestamp() as u64, None) {
+        Some(counter) => counter,
+        None => {
+            return Err(AppError::bad_request("验证码错误"));
+        }
+    };
 
-            let r = db::create_article(new_article, &pool).await.unwrap();
+    let (codes, hashes) = totp::generate_recovery_codes(10);
+    let totp = Totp {
+        secret,
+        last_counter: Some(counter as i64),
+        recovery_codes: hashes,
+        failed_attempts: 0,
+        enabled_time: Utc::now(),
+    };
+    db::enable_totp(&user.id.to_string(), totp, &pool).await?;
+    session.remove("totp_pending_secret");
+    session.set("twofa_verified_at", Utc::now().timestamp());
 
-            Response::builder()
-                .status(StatusCode::FOUND)
-                .header(header::LOCATION, format!("/article?id={}", r.to_string()))
-                .finish()
-        }
-        None => Response::builder()
-            .status(StatusCode::FOUND)
-            .header(header::LOCATION, "/signin")
-            .finish(),
-    }
+    // 恢复码只展示这一次
+    let mut context = Context::new();
+    context.insert("title", "两步验证");
+    context.insert("enabled", &true);
+    context.insert("recovery_codes", &codes);
+    let s = TEMPLATES.render("account_2fa.html", &context)?;
+    Ok(Html(s).into_response())
 }
 
 #[handler]
-pub async fn edit_article_page(
-    Query(FindArticle {
-        id,
-        comment_page: _,
-    }): Query<FindArticle>,
+pub async fn account_2fa_disable(
+    Form(params): Form<TwofaParams>,
     session: &Session,
     pool: Data<&Database>,
-) -> impl IntoResponse {
-    match session.get::<String>("uid") {
-        Some(_uid) => {
-            let article_r = db::get_article(id.unwrap(), None, None, &pool).await;
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let TwofaParams { code } = params;
+    let user = match policy::current_user(session, &pool).await {
+        Some(user) => user,
+        None => return Err(AppError::Unauthenticated),
+    };
 
-            match article_r {
-                Ok(article) => {
-                    let articlev: ArticleDetailView = article.into();
+    if !verify_second_factor(&user, &code, &pool).await? {
+        return Err(AppError::bad_request("验证码错误"));
+    }
+    db::disable_totp(&user.id.to_string(), &pool).await?;
 
-                    let mut context = Context::new();
-                    context.insert("title", &articlev.title);
-                    context.insert("article", &articlev);
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/account")
+        .finish())
+}
 
-                    let s = TEMPLATES.render("edit_article.html", &context).unwrap();
-                    Html(s).into_response()
-                }
-                Err(err) => {
-                    let mut context = Context::new();
-
-                    if err.to_string().contains("no rows returned") {
-                        context.insert("title", "404");
-                        let s = TEMPLATES.render("404.html", &context).unwrap();
-                        return Html(s).into_response();
-                    }
-
-                    context.insert("title", "错误");
-                    context.insert("msg", &err.to_string());
-                    let s = TEMPLATES.render("error.html", &context).unwrap();
-                    Html(s).into_response()
-                }
-            }
-        }
-        None => Response::builder()
-            .status(StatusCode::FOUND)
-            .header(header::LOCATION, "/signin")
-            .finish(),
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityView {
+    pub id: String,
+    pub provider: String,
+    pub login: String,
 }
 
-#[derive(Deserialize)]
-pub struct EditArticleParams {
-    id: String,
-    title: String,
-    raw_content: String,
-    tags: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionView {
+    pub id: String,
+    pub device: String,
+    pub ip: String,
+    pub last_seen: String,
+    pub current: bool,
 }
 
 #[handler]
-pub async fn edit_article(
-    Form(params): Form<EditArticleParams>,
+pub async fn account(
     session: &Session,
+    cookie_jar: &CookieJar,
     pool: Data<&Database>,
-) -> impl IntoResponse {
-    match session.get::<String>("uid") {
-        Some(_uid) => {
-            let article_r = db::get_article(params.id.clone(), None, None, &pool).await;
-
-            match article_r {
-                Ok(mut article) => {
-                    article.title = params.title;
-                    article.raw_content = params.raw_content;
-                    article.tags = params.tags;
-                    let _ok = db::update_article(article, &pool).await.unwrap();
-
-                    Response::builder()
-                        .status(StatusCode::FOUND)
-                        .header(header::LOCATION, format!("/article?id={}", params.id))
-                        .finish()
-                }
-                Err(err) => {
-                    let mut context = Context::new();
-
-                    if err.to_string().contains("no rows returned") {
-                        context.insert("title", "404");
-                        let s = TEMPLATES.render("404.html", &context).unwrap();
-                        return Html(s).into_response();
-                    }
-
-                    context.insert("title", "错误");
-                    context.insert("msg", &err.to_string());
-                    let s = TEMPLATES.render("error.html", &context).unwrap();
-                    Html(s).into_response()
-                }
-            }
-        }
-        None => Response::builder()
+) -> Result<impl IntoResponse> {
+    if session.get::<String>("uid").is_none() {
+        return Ok(Response::builder()
             .status(StatusCode::FOUND)
             .header(header::LOCATION, "/signin")
-            .finish(),
+            .finish());
     }
-}
 
-#[derive(Deserialize)]
-pub struct NewCommentPageReq {
-    article_id: String,
-    reply_to: Option<String>,
+    let context = account_context(session, cookie_jar, &pool).await?;
+    let s = TEMPLATES.render("account.html", &context)?;
+    Ok(Html(s).into_response())
 }
 
-#[handler]
-pub async fn new_comment_page(
-    Query(NewCommentPageReq {
-        article_id,
-        reply_to,
-    }): Query<NewCommentPageReq>,
+/// 账户页面的数据, 账户页面上的表单校验失败时也用它重新渲染页面
+async fn account_context(
     session: &Session,
+    cookie_jar: &CookieJar,
+    pool: &Database,
+) -> Result<Context> {
+    let user = policy::current_user(session, pool)
+        .await
+        .ok_or(AppError::Unauthenticated)?;
+    let uid = user.id.to_string();
+
+    let identities: Vec<IdentityView> = db::list_identities(&uid, pool)
+        .await?
+        .into_iter()
+        .map(|i| IdentityView {
+            id: i.id.to_string(),
+            provider: i.provider,
+            login: i.login,
+        })
+        .collect();
+    let connected = |p: &str| identities.iter().any(|i| i.provider == p);
+
+    let mut context = Context::new();
+    context.insert("title", &user.username);
+    context.insert("username", &user.username);
+    context.insert("can_disconnect", &(identities.len() > 1));
+    context.insert("can_connect_gitee", &!connected("gitee"));
+    context.insert(
+        "can_connect_github",
+        &(!connected("github") && github_authorize_uri().is_some()),
+    );
+    context.insert("can_connect_password", &!connected("password"));
+    context.insert("identities", &identities);
+
+    let current_session = cookie_jar
+        .get(session_store::COOKIE_NAME)
+        .map(|c| session_store::hash_session_id(c.value_str()));
+    let sessions: Vec<SessionView> = session_store::list_sessions(&uid, pool)
+        .await?
+        .into_iter()
+        .map(|r| SessionView {
+            current: Some(&r.id) == current_session.as_ref(),
+            id: r.id,
+            device: r.user_agent.unwrap_or_default(),
+            ip: r.ip.unwrap_or_default(),
+            last_seen: r
+                .last_seen
+                .with_timezone(&config::timezone())
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        })
+        .collect();
+    context.insert("sessions", &sessions);
+
+    context.insert("uid", &uid);
+    context.insert("bio", &user.bio.clone().unwrap_or_default());
+    context.insert("blog", &user.blog_url().unwrap_or_default());
+    context.insert("role", user.role.as_str());
+    context.insert(
+        "can_manage_users",
+        &policy::can(user.role, Permission::ManageUsers),
+    );
+    context.insert(
+        "can_moderate_comments",
+        &policy::can(user.role, Permission::ModerateComments),
+    );
+    let can_publish = policy::can(user.role, Permission::Publish);
+    context.insert("can_publish", &can_publish);
+    context.insert("can_use_2fa", &can_publish);
+    context.insert("twofa_enabled", &user.totp.is_some());
+    // (类型, 名称, 站内通知, 邮件通知)
+    let notification_prefs: Vec<(&str, &str, bool, bool)> = notification::Kind::ALL
+        .iter()
+        .map(|k| {
+            (
+                k.as_str(),
+                k.label(),
+                user.wants_notification(k.as_str()),
+                mail::wants(&user, k.as_str()),
+            )
+        })
+        .collect();
+    context.insert("notification_prefs", &notification_prefs);
+    context.insert("mail_enabled", &config::get().mail.enabled);
+    context.insert("email", &user.email());
+    context.insert("weekly_digest", &user.weekly_digest);
+    Ok(context)
+}
+
+/// 绑定其他登录方式: 记录下正在绑定的 provider, 然后走一遍 OAuth 流程
+#[derive(Deserialize, Validate)]
+pub struct ConnectParams {
+    #[validate(length(max = 16))]
+    provider: String,
+    #[validate(length(max = 128))]
+    csrf_token: Option<String>,
+}
+
+///
+/// 绑定第三方账号. 使用链接而不是表单: 表单提交后跳转到授权页面会被 CSP 的 `form-action 'self'` 拦截,
+/// 所以 CSRF token 放在查询参数中, 在这里校验
+///
+#[handler]
+pub fn connect_identity(
+    Query(params): Query<ConnectParams>,
+    session: &Session,
+) -> Result<impl IntoResponse> {
+    if session.get::<String>("uid").is_none() {
+        return Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, "/signin")
+            .finish());
+    }
+    validate::check(&params)?;
+    if !csrf::verify(session, params.csrf_token.as_deref()) {
+        return Err(AppError::Csrf);
+    }
+    let ConnectParams { provider, .. } = params;
+
+    match oauth_authorize_uri(&provider, session) {
+        Some(uri) => {
+            session.set("connect", provider);
+            Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, uri)
+                .finish())
+        }
+        None => Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, "/account")
+            .finish()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct ConnectPasswordParams {
+    #[validate(
+        length(min = 3, max = 32, message = "账户名需要3到32个字符"),
+        custom = "validate::login_name"
+    )]
+    login: String,
+    #[serde(skip_serializing)]
+    #[validate(length(min = 8, max = 128, message = "密码需要8到128个字符"))]
+    password: String,
+}
+
+#[handler]
+pub async fn connect_password(
+    Form(params): Form<ConnectPasswordParams>,
+    session: &Session,
+    cookie_jar: &CookieJar,
     pool: Data<&Database>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse> {
+    let uid = match session.get::<String>("uid") {
+        Some(uid) => uid,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, "/signin")
+                .finish())
+        }
+    };
+
+    let login = params.login.trim().to_lowercase();
+    let mut errors = validate::form(&params).unwrap_or_default();
+    if errors.is_empty()
+        && db::find_identity(&pool, "password", &login)
+            .await
+            .optional()?
+            .is_some()
+    {
+        errors.insert("login", "该账户名已被使用".to_owned());
+    }
+    if !errors.is_empty() {
+        let context = account_context(session, cookie_jar, &pool).await?;
+        return render_form("account.html", context, &params, &errors);
+    }
+
+    let mut identity = Identity::new("password", login.clone(), login, Document::new());
+    identity.secret = Some(password::hash(&params.password)?);
+    db::create_identity(&pool, db::object_id(&uid)?, identity).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/account")
+        .finish())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct DisconnectParams {
+    #[validate(custom = "validate::object_id")]
+    id: String,
+}
+
+#[handler]
+pub async fn disconnect_identity(
+    Form(params): Form<DisconnectParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let DisconnectParams { id } = params;
     match session.get::<String>("uid") {
-        Some(_uid) => {
-            let article_r = db::get_article(article_id, None, None, &pool).await;
-
-            match article_r {
-                Ok(article) => {
-                    let articlev: ArticleDetailView = article.into();
-                    let title = format!("评论: {}", &articlev.title.as_str());
-                    let mut context = Context::new();
-                    context.insert("title", &title);
-                    context.insert("reply_to", &reply_to);
-                    context.insert("article", &articlev);
-
-                    let s = TEMPLATES.render("new_comment.html", &context).unwrap();
-                    Html(s).into_response()
-                }
-                Err(err) => {
-                    let mut context = Context::new();
-
-                    if err.to_string().contains("no rows returned") {
-                        context.insert("title", "404");
-                        let s = TEMPLATES.render("404.html", &context).unwrap();
-                        return Html(s).into_response();
-                    }
-
-                    context.insert("title", "错误");
-                    context.insert("msg", &err.to_string());
-                    let s = TEMPLATES.render("error.html", &context).unwrap();
-                    Html(s).into_response()
-                }
-            }
+        Some(uid) => {
+            db::remove_identity(&uid, &id, &pool).await?;
+            Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, "/account")
+                .finish())
         }
-        None => Response::builder()
+        None => Ok(Response::builder()
             .status(StatusCode::FOUND)
             .header(header::LOCATION, "/signin")
-            .finish(),
+            .finish()),
     }
 }
 
-#[derive(Deserialize)]
-pub struct CommentArticleParams {
-    reply_to: Option<String>,
-    article_id: String,
-    content: String,
+#[handler]
+pub fn signout(session: &Session) -> impl IntoResponse {
+    session.purge();
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/signin")
+        .finish()
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RevokeSessionParams {
+    /// 会话 id 的 sha256
+    #[validate(length(equal = 64, message = "无效的会话"))]
+    id: String,
 }
 
 #[handler]
-pub async fn new_comment(
-    Form(CommentArticleParams {
-        reply_to,
-        article_id,
-        content,
-    }): Form<CommentArticleParams>,
+pub async fn revoke_session(
+    Form(params): Form<RevokeSessionParams>,
     session: &Session,
     pool: Data<&Database>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let RevokeSessionParams { id } = params;
     match session.get::<String>("uid") {
         Some(uid) => {
-            let reply_to_name = match reply_to.clone() {
-                Some(to) => {
-                    let u = db::find_user_by_id(to.as_str(), &pool).await.unwrap();
-                    Some(u.username)
-                }
-                None => None,
-            };
-            let comment = Comment::new(
-                content,
-                uid,
-                session.get::<String>("username").unwrap(),
-                reply_to,
-                reply_to_name,
-            );
-            let _r = db::append_comment(article_id.clone(), comment, &pool)
-                .await
-                .unwrap();
-
-            Response::builder()
+            session_store::revoke_session(&uid, &id, &pool).await?;
+            Ok(Response::builder()
                 .status(StatusCode::FOUND)
-                .header(header::LOCATION, format!("/article?id={}", article_id))
-                .finish()
+                .header(header::LOCATION, "/account")
+                .finish())
         }
-        None => Response::builder()
-            .status(StatusCode::FOUND)
-            .header(header::LOCATION, "/signin")
-            .finish(),
+        None => Err(AppError::Unauthenticated),
+    }
+}
+
+/// 退出所有设备上的登录
+#[handler]
+pub async fn signout_everywhere(
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    if let Some(uid) = session.get::<String>("uid") {
+        session_store::revoke_all_sessions(&uid, &pool).await?;
+    }
+    session.purge();
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/signin")
+        .finish())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+
+pub struct ArticleDetailView {
+    pub id: String,
+    pub title: String,
+    pub raw_content: String,
+    pub tags: String,
+    pub author_id: String,
+    pub author_name: String,
+    pub created_time: String,
+    // pub updated_time: String,
+    pub status: i16,
+    pub comments: Vec<CommentView>,
+    pub total_comments: i32,
+    pub comment_page_nums: Vec<i32>,
+    /// 首页列表显示的封面缩略图
+    pub cover: Option<Thumbnail>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+
+pub struct CommentView {
+    pub id: Option<String>,
+    pub content: String,
+    pub author_id: String,
+    pub author_name: String,
+    pub reply_to: Option<String>,
+    pub reply_to_name: Option<String>,
+    pub created_time: String,
+    pub status: i16,
+    pub moderation_reason: Option<String>,
+    /// 垃圾评论概率(百分比)
+    pub spam_score: Option<i32>,
+}
+
+#[handler]
+pub async fn index(session: &Session, pool: Data<&Database>) -> Result<impl IntoResponse> {
+    let articles = db::list_article(viewer(session), &pool).await?;
+
+    let article_views = article_list_views(articles, &pool).await?;
+    let mut context = Context::new();
+    context.insert("title", "首页");
+    context.insert("article_list", &article_views);
+    let s = TEMPLATES.render("index.html", &context)?;
+    Ok(Html(s))
+}
+
+/// 文章列表, 正文中第一张上传的图片作为封面
+pub async fn article_list_views(
+    articles: Vec<Article>,
+    pool: &Database,
+) -> Result<Vec<ArticleDetailView>> {
+    let covers: Vec<Option<String>> = articles
+        .iter()
+        .map(|a| imaging::cover_hash(&a.raw_content).map(str::to_owned))
+        .collect();
+    let hashes: Vec<String> = covers.iter().flatten().cloned().collect();
+    let infos = imaging::lookup(&hashes, pool).await?;
+    Ok(articles
+        .into_iter()
+        .zip(covers)
+        .map(|(a, cover)| {
+            let mut view: ArticleDetailView = a.into();
+            view.cover = cover.and_then(|hash| {
+                let info = infos.get(&hash)?;
+                Some(imaging::thumbnail(&hash, info))
+            });
+            view
+        })
+        .collect())
+}
+
+/// 文章正文渲染成 HTML, 上传的图片换成衍生版本
+pub async fn render_content(raw_content: &str, pool: &Database) -> Result<String> {
+    let html = markdown::to_html(raw_content);
+    let infos = imaging::lookup(&imaging::hashes_in(&html), pool).await?;
+    Ok(imaging::rewrite_images(&html, &infos))
+}
+
+#[derive(Deserialize, Validate)]
+pub struct FindArticle {
+    #[validate(custom = "validate::object_id")]
+    id: String,
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    comment_page: Option<i32>,
+    /// 刚发表的评论需要审核
+    #[serde(default)]
+    held: bool,
+}
+
+impl From<Article> for ArticleDetailView {
+    fn from(a: Article) -> Self {
+        let comments = match a.comments {
+            Some(cs) => cs.into_iter().map(|c| c.into()).collect(),
+            None => Vec::new(),
+        };
+        let comment_page_nums =
+            page_nums(a.total_comments.unwrap_or_default(), comment_page_size());
+        ArticleDetailView {
+            id: a.id.to_string(),
+            title: a.title,
+            raw_content: a.raw_content,
+            tags: a.tags,
+            author_id: a.author_id.to_string(),
+            author_name: a.author_name.unwrap(),
+            created_time: a
+                .created_time
+                .with_timezone(&config::timezone())
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            status: a.status,
+            comments,
+            total_comments: a.total_comments.unwrap(),
+            comment_page_nums,
+            cover: None,
+        }
+    }
+}
+
+impl From<Comment> for CommentView {
+    fn from(c: Comment) -> Self {
+        CommentView {
+            id: c.id.as_ref().map(ObjectId::to_string),
+            content: c.content,
+            author_id: c.author_id.to_string(),
+            author_name: c.author_name,
+            reply_to: c.reply_to.as_ref().map(ObjectId::to_string),
+            reply_to_name: c.reply_to_name,
+            created_time: c
+                .created_time
+                .with_timezone(&config::timezone())
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            status: c.status,
+            moderation_reason: c.moderation_reason,
+            spam_score: c.spam_score.map(|score| (score * 100.0).round() as i32),
+        }
+    }
+}
+
+#[handler]
+pub async fn article_details(
+    Query(params): Query<FindArticle>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let FindArticle {
+        id,
+        comment_page,
+        held,
+    } = params;
+    let article = db::get_article(
+        id,
+        Some(comment_page_size()),
+        comment_page,
+        viewer(session),
+        &pool,
+    )
+    .await?;
+
+    let can_edit = match policy::current_user(session, &pool).await {
+        Some(user) => policy::can_edit_article(&user, &article),
+        None => false,
+    };
+    let mut articlev: ArticleDetailView = article.into();
+    articlev.raw_content = render_content(&articlev.raw_content, &pool).await?;
+
+    let mut context = Context::new();
+    context.insert("title", &articlev.title);
+    context.insert("article", &articlev);
+
+    // 标识当前用户是否可以编辑该文章，如果是则提供编辑按钮等
+    if can_edit {
+        context.insert("is_author", &true);
+    }
+
+    // 评论分页
+    context.insert("comment_current_page", &comment_page.unwrap_or(1));
+    context.insert("comment_held", &held);
+
+    let s = TEMPLATES.render("article.html", &context)?;
+    Ok(Html(s))
+}
+
+#[handler]
+pub async fn publish_article_page(
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    policy::authorize(session, &pool, Permission::Publish).await?;
+
+    let mut context = Context::new();
+    context.insert("title", "写文章");
+    let s = TEMPLATES.render("publish_article.html", &context)?;
+    Ok(Html(s))
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct PublishArticleParams {
+    #[validate(
+        length(max = 200, message = "标题最多200个字"),
+        custom = "validate::not_blank"
+    )]
+    title: String,
+    #[validate(
+        length(max = 100000, message = "内容最多100000个字"),
+        custom = "validate::not_blank"
+    )]
+    raw_content: String,
+    #[validate(length(max = 200, message = "标签最多200个字"))]
+    tags: String,
+}
+
+#[handler]
+pub async fn publish_article(
+    Form(params): Form<PublishArticleParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+    if let Some(errors) = validate::form(&params) {
+        let mut context = Context::new();
+        context.insert("title", "写文章");
+        return render_form("publish_article.html", context, &params, &errors);
+    }
+
+    let new_article = Article {
+        author_id: user.id,
+        author_name: Some(user.username.clone()),
+        title: params.title.trim().to_owned(),
+        raw_content: params.raw_content,
+        tags: params.tags.trim().to_owned(),
+        ..Default::default()
+    };
+    let detail = doc! {"title": &new_article.title, "tags": &new_article.tags};
+
+    let id = db::create_article(new_article.clone(), &pool).await?;
+    AuditEntry::new(audit::ARTICLE_CREATE, "article", &id)
+        .actor(&user)
+        .detail(detail)
+        .record(&pool)
+        .await;
+    newsletter::on_publish(new_article, &user, &pool);
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, format!("/article?id={}", id))
+        .finish())
+}
+
+#[handler]
+pub async fn edit_article_page(
+    Query(params): Query<FindArticle>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+    validate::check(&params)?;
+    let id = params.id;
+    let article = db::get_article(id, None, None, Some(user.id), &pool).await?;
+    if !policy::can_edit_article(&user, &article) {
+        return Err(AppError::Forbidden);
     }
+    let form = EditArticleParams {
+        id: article.id.to_string(),
+        title: article.title,
+        raw_content: article.raw_content,
+        tags: article.tags,
+    };
+
+    let mut context = Context::new();
+    context.insert("title", &form.title);
+    context.insert("form", &form);
+
+    let s = TEMPLATES.render("edit_article.html", &context)?;
+    Ok(Html(s))
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct EditArticleParams {
+    #[validate(custom = "validate::object_id")]
+    id: String,
+    #[validate(
+        length(max = 200, message = "标题最多200个字"),
+        custom = "validate::not_blank"
+    )]
+    title: String,
+    #[validate(
+        length(max = 100000, message = "内容最多100000个字"),
+        custom = "validate::not_blank"
+    )]
+    raw_content: String,
+    #[validate(length(max = 200, message = "标签最多200个字"))]
+    tags: String,
 }
 
-fn comment_page_size() ->i32{
-    20
-}
\ No newline at end of file
+#[handler]
+pub async fn edit_article(
+    Form(params): Form<EditArticleParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+    if let Some(errors) = validate::form(&params) {
+        let mut context = Context::new();
+        context.insert("title", "编辑文章");
+        return render_form("edit_article.html", context, &params, &errors);
+    }
+
+    let mut article = db::get_article(params.id.clone(), None, None, Some(user.id), &pool).await?;
+    if !policy::can_edit_article(&user, &article) {
+        return Err(AppError::Forbidden);
+    }
+    // 只记录修改了哪些字段和修改前后的标题, 正文可能很长, 不放进审计日志
+    let title = params.title.trim().to_owned();
+    let tags = params.tags.trim().to_owned();
+    let changed: Vec<&str> = [
+        ("title", article.title != title),
+        ("raw_content", article.raw_content != params.raw_content),
+        ("tags", article.tags != tags),
+    ]
+    .into_iter()
+    .filter_map(|(field, changed)| changed.then_some(field))
+    .collect();
+    let detail = doc! {"previous_title": &article.title, "title": &title, "changed": changed};
+
+    article.title = title;
+    article.raw_content = params.raw_content;
+    article.tags = tags;
+    db::update_article(article, &pool).await?;
+    AuditEntry::new(audit::ARTICLE_UPDATE, "article", &params.id)
+        .actor(&user)
+        .detail(detail)
+        .record(&pool)
+        .await;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, format!("/article?id={}", params.id))
+        .finish())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct NewCommentPageReq {
+    #[validate(custom = "validate::object_id")]
+    article_id: String,
+    #[validate(custom = "validate::object_id")]
+    reply_to: Option<String>,
+}
+
+#[handler]
+pub async fn new_comment_page(
+    Query(params): Query<NewCommentPageReq>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Comment).await?;
+    validate::check(&params)?;
+
+    let context = new_comment_context(params.article_id, params.reply_to, user.id, &pool).await?;
+    let s = TEMPLATES.render("new_comment.html", &context)?;
+    Ok(Html(s))
+}
+
+async fn new_comment_context(
+    article_id: String,
+    reply_to: Option<String>,
+    viewer: ObjectId,
+    pool: &Database,
+) -> Result<Context> {
+    let article = db::get_article(article_id, None, None, Some(viewer), pool).await?;
+
+    let articlev: ArticleDetailView = article.into();
+    let title = format!("评论: {}", &articlev.title.as_str());
+    let mut context = Context::new();
+    context.insert("title", &title);
+    context.insert("reply_to", &reply_to);
+    context.insert("article", &articlev);
+    Ok(context)
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct CommentArticleParams {
+    #[validate(custom = "validate::object_id")]
+    reply_to: Option<String>,
+    #[validate(custom = "validate::object_id")]
+    article_id: String,
+    #[validate(
+        length(max = 5000, message = "评论最多5000个字"),
+        custom = "validate::not_blank"
+    )]
+    content: String,
+}
+
+#[handler]
+pub async fn new_comment(
+    Form(params): Form<CommentArticleParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Comment).await?;
+    if let Some(errors) = validate::form(&params) {
+        // article_id/reply_to 是隐藏字段, 格式不对说明请求是伪造的
+        if errors.contains_key("article_id") || errors.contains_key("reply_to") {
+            return Err(AppError::bad_request("无效的 id"));
+        }
+        let context = new_comment_context(
+            params.article_id.clone(),
+            params.reply_to.clone(),
+            user.id,
+            &pool,
+        )
+        .await?;
+        return render_form("new_comment.html", context, &params, &errors);
+    }
+    let CommentArticleParams {
+        reply_to,
+        article_id,
+        content,
+    } = params;
+
+    let reply_to = match reply_to {
+        Some(to) => Some(db::find_user_by_id(to.as_str(), &pool).await?),
+        None => None,
+    };
+    let verdict = moderation::check(&content, &user, &pool).await?;
+    let mut comment = Comment::new(
+        content,
+        user.id,
+        user.username.clone(),
+        reply_to.as_ref().map(|u| u.id),
+        reply_to.map(|u| u.username),
+    );
+    comment.status = verdict.status;
+    comment.moderation_reason = verdict.reason.clone();
+    comment.spam_score = verdict.spam_score;
+    let comment_id = comment.id.map(|id| id.to_string()).unwrap_or_default();
+    if !db::append_comment(article_id.clone(), comment.clone(), &pool).await? {
+        return Err(AppError::NotFound);
+    }
+    // 待审核的评论在审核通过时再通知, 通知失败不影响发表评论
+    if verdict.status == Comment::VISIBLE {
+        let article_oid = db::object_id(&article_id)?;
+        if let Err(err) = notification::notify_comment(article_oid, &comment, &user, &pool).await {
+            warn!("notify comment {} error: {}", comment_id, err);
+        }
+    }
+    AuditEntry::new(audit::COMMENT_CREATE, "comment", comment_id)
+        .actor(&user)
+        .detail(doc! {
+            "article_id": &article_id,
+            "status": verdict.status as i32,
+            "reason": verdict.reason,
+        })
+        .record(&pool)
+        .await;
+
+    // 需要审核的评论(包括判为垃圾的)都提示等待审核, 不透露判定结果
+    let location = if verdict.status == Comment::VISIBLE {
+        format!("/article?id={}", article_id)
+    } else {
+        format!("/article?id={}&held=true#comments", article_id)
+    };
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location)
+        .finish())
+}
+
+fn comment_page_size() -> i32 {
+    config::get().comment.page_size
+}
+
+/// 分页页码, 至少有一页
+fn page_nums(total: i32, page_size: i32) -> Vec<i32> {
+    let pages = (total as f32 / page_size as f32).ceil() as i32;
+    (1..pages.max(1) + 1).collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileView {
+    pub id: String,
+    pub username: String,
+    pub avatar_url: Option<String>,
+    pub bio: String,
+    pub blog: Option<String>,
+    pub created_time: String,
+}
+
+impl From<User> for ProfileView {
+    fn from(u: User) -> Self {
+        ProfileView {
+            id: u.id.to_string(),
+            avatar_url: u.avatar_url(),
+            blog: u.blog_url(),
+            username: u.username,
+            bio: u.bio.unwrap_or_default(),
+            created_time: u
+                .created_time
+                .with_timezone(&config::timezone())
+                .format("%Y-%m-%d")
+                .to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserCommentView {
+    pub article_id: String,
+    pub article_title: String,
+    pub comment: CommentView,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ProfileParams {
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    page: Option<i32>,
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    comment_page: Option<i32>,
+}
+
+/// 用户主页, 可以通过用户 id 或登录名访问
+#[handler]
+pub async fn user_profile(
+    Path(key): Path<String>,
+    Query(params): Query<ProfileParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let ProfileParams { page, comment_page } = params;
+    let user = db::find_user_by_key(&key, &pool).await?;
+    let viewer = viewer(session);
+    if user.is_hidden_from(viewer, Utc::now()) {
+        return Err(AppError::NotFound);
+    }
+
+    let page = page.unwrap_or(1).max(1);
+    let comment_page = comment_page.unwrap_or(1).max(1);
+    let (articles, total_articles) =
+        db::list_article_by_author(user.id, page, profile_page_size(), viewer, &pool).await?;
+    let (comments, total_comments) =
+        db::list_comment_by_author(user.id, comment_page, comment_page_size(), viewer, &pool)
+            .await?;
+
+    let article_views: Vec<ArticleDetailView> = articles.into_iter().map(|a| a.into()).collect();
+    let comment_views: Vec<UserCommentView> = comments
+        .into_iter()
+        .map(|c| UserCommentView {
+            article_id: c.article_id.to_string(),
+            article_title: c.article_title,
+            comment: c.comment.into(),
+        })
+        .collect();
+    let profile: ProfileView = user.into();
+
+    let mut context = Context::new();
+    context.insert("title", &profile.username);
+    context.insert("profile", &profile);
+    context.insert("article_list", &article_views);
+    context.insert("page", &page);
+    context.insert("page_nums", &page_nums(total_articles, profile_page_size()));
+    context.insert("comment_list", &comment_views);
+    context.insert("comment_page", &comment_page);
+    context.insert(
+        "comment_page_nums",
+        &page_nums(total_comments, comment_page_size()),
+    );
+    let s = TEMPLATES.render("user.html", &context)?;
+    Ok(Html(s).into_response())
+}
+
+fn profile_page_size() -> i32 {
+    config::get().profile.page_size
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct UpdateProfileParams {
+    #[validate(length(max = 500, message = "简介最多500个字"))]
+    bio: String,
+    #[validate(
+        length(max = 200, message = "博客地址最多200个字符"),
+        custom = "validate::http_url"
+    )]
+    blog: String,
+}
+
+#[handler]
+pub async fn update_profile(
+    Form(params): Form<UpdateProfileParams>,
+    session: &Session,
+    cookie_jar: &CookieJar,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let uid = match session.get::<String>("uid") {
+        Some(uid) => uid,
+        None => return Err(AppError::Unauthenticated),
+    };
+
+    if let Some(errors) = validate::form(&params) {
+        let context = account_context(session, cookie_jar, &pool).await?;
+        return render_form("account.html", context, &params, &errors);
+    }
+    db::update_user_profile(&uid, params.bio.trim(), params.blog.trim(), &pool).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/account")
+        .finish())
+}
+
+/// 通知设置, 表单中勾选的通知类型为开启: 站内通知的字段名为类型名, 邮件为 `email_` 加类型名
+#[handler]
+pub async fn update_notification_prefs(
+    Form(params): Form<Vec<(String, String)>>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::current_user(session, &pool)
+        .await
+        .ok_or(AppError::Unauthenticated)?;
+
+    let checked = |name: &str| params.iter().any(|(n, _)| n == name);
+    let kinds = notification::Kind::ALL
+        .iter()
+        .map(notification::Kind::as_str);
+    let muted: Vec<&str> = kinds.clone().filter(|kind| !checked(kind)).collect();
+    db::update_muted_notifications(user.id, &muted, &pool).await?;
+    let muted_emails: Vec<&str> = kinds
+        .filter(|kind| !checked(&format!("email_{}", kind)))
+        .collect();
+    let weekly_digest = checked(&format!("email_{}", mail::DIGEST));
+    db::update_email_prefs(user.id, &muted_emails, weekly_digest, &pool).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/account")
+        .finish())
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct UnsubscribeParams {
+    #[validate(custom = "validate::object_id")]
+    uid: String,
+    #[validate(length(max = 16))]
+    kind: String,
+    #[validate(length(max = 128))]
+    token: String,
+}
+
+/// 校验退订链接, 返回用户
+async fn unsubscribe_user(params: &UnsubscribeParams, pool: &Database) -> Result<User> {
+    validate::check(params)?;
+    let uid = db::object_id(&params.uid)?;
+    let secret = &config::get().mail.secret;
+    let known_kind =
+        params.kind == mail::DIGEST || notification::Kind::from_str(&params.kind).is_ok();
+    if !known_kind || !mail::verify_token(secret, uid, &params.kind, &params.token) {
+        return Err(AppError::bad_request("退订链接无效"));
+    }
+    db::find_user_by_id(&params.uid, pool).await
+}
+
+fn unsubscribe_context(kind: &str) -> Context {
+    let label = notification::Kind::from_str(kind)
+        .map(|k| k.label())
+        .unwrap_or("每周摘要");
+    let mut context = Context::new();
+    context.insert("title", "退订邮件");
+    context.insert("label", label);
+    context
+}
+
+/// 邮件中的退订链接, 打开后需要点击确认, 避免被邮件客户端预取链接时误退订
+#[handler]
+pub async fn unsubscribe_page(
+    Query(params): Query<UnsubscribeParams>,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    unsubscribe_user(&params, &pool).await?;
+
+    let mut context = unsubscribe_context(&params.kind);
+    context.insert("form", &params);
+    let s = TEMPLATES.render("unsubscribe.html", &context)?;
+    Ok(Html(s))
+}
+
+/// 确认退订, 也用于邮件客户端的一键退订(RFC 8058), 链接带有签名, 不需要登录和 CSRF token
+#[handler]
+pub async fn unsubscribe(
+    Query(params): Query<UnsubscribeParams>,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = unsubscribe_user(&params, &pool).await?;
+    mail::unsubscribe(&user, &params.kind, &pool).await?;
+
+    let mut context = unsubscribe_context(&params.kind);
+    context.insert("done", &true);
+    let s = TEMPLATES.render("unsubscribe.html", &context)?;
+    Ok(Html(s))
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct SubscribeParams {
+    #[validate(
+        length(max = 200, message = "邮箱最多200个字符"),
+        email(message = "邮箱格式不正确")
+    )]
+    email: String,
+    #[serde(default)]
+    #[validate(length(max = 200, message = "标签最多200个字"))]
+    tags: String,
+}
+
+fn subscribe_context() -> Context {
+    let mut context = Context::new();
+    context.insert("title", "订阅");
+    context.insert("enabled", &config::get().mail.enabled);
+    context
+}
+
+#[handler]
+pub fn subscribe_page() -> Result<impl IntoResponse> {
+    let s = TEMPLATES.render("subscribe.html", &subscribe_context())?;
+    Ok(Html(s))
+}
+
+/// 不需要登录, 发送确认邮件, 点击邮件中的链接后才订阅
+#[handler]
+pub async fn subscribe(
+    Form(params): Form<SubscribeParams>,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    if !config::get().mail.enabled {
+        return Err(AppError::NotFound);
+    }
+    let mut context = subscribe_context();
+    if let Some(errors) = validate::form(&params) {
+        return render_form("subscribe.html", context, &params, &errors);
+    }
+    let tags = newsletter::parse_tags(&params.tags);
+    newsletter::subscribe(&params.email, &tags, &pool).await?;
+
+    context.insert("form", &params);
+    context.insert("done", &true);
+    let s = TEMPLATES.render("subscribe.html", &context)?;
+    Ok(Html(s).into_response())
+}
+
+/// 确认订阅和退订链接中的参数
+#[derive(Serialize, Deserialize, Validate)]
+pub struct NewsletterLinkParams {
+    #[validate(custom = "validate::object_id")]
+    sid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    #[validate(length(max = 200))]
+    tags: String,
+    #[validate(length(max = 128))]
+    token: String,
+}
+
+impl NewsletterLinkParams {
+    /// 表单提交到当前链接
+    fn action(&self, path: &str) -> Result<String> {
+        let query = serde_urlencoded::to_string(self).map_err(AppError::internal)?;
+        Ok(format!("{}?{}", path, query))
+    }
+}
+
+/// 校验确认链接, 返回订阅者和要订阅的标签
+async fn confirm_link(
+    params: &NewsletterLinkParams,
+    pool: &Database,
+) -> Result<(Subscriber, Vec<String>)> {
+    validate::check(params)?;
+    let sid = db::object_id(&params.sid)?;
+    let tags = newsletter::parse_tags(&params.tags);
+    if !newsletter::verify_confirm_token(sid, &tags, &params.token) {
+        return Err(AppError::bad_request("确认链接无效"));
+    }
+    let subscriber = newsletter::find(sid, pool)
+        .await
+        .optional()?
+        .ok_or_else(|| AppError::bad_request("确认链接已过期, 请重新订阅"))?;
+    Ok((subscriber, tags))
+}
+
+fn confirm_context(email: &str, tags: &[String]) -> Context {
+    let mut context = Context::new();
+    context.insert("title", "确认订阅");
+    context.insert("email", email);
+    context.insert("tags", tags);
+    context
+}
+
+/// 确认邮件中的链接, 打开后需要点击确认, 避免被邮件客户端预取链接时误订阅
+#[handler]
+pub async fn confirm_subscription_page(
+    Query(params): Query<NewsletterLinkParams>,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let (subscriber, tags) = confirm_link(&params, &pool).await?;
+
+    let mut context = confirm_context(&subscriber.email, &tags);
+    context.insert("action", &params.action(newsletter::CONFIRM_PATH)?);
+    let s = TEMPLATES.render("subscribe_confirm.html", &context)?;
+    Ok(Html(s))
+}
+
+#[handler]
+pub async fn confirm_subscription(
+    Query(params): Query<NewsletterLinkParams>,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let (subscriber, tags) = confirm_link(&params, &pool).await?;
+    newsletter::confirm(subscriber.id, &tags, &pool).await?;
+
+    let mut context = confirm_context(&subscriber.email, &tags);
+    context.insert("done", &true);
+    let s = TEMPLATES.render("subscribe_confirm.html", &context)?;
+    Ok(Html(s))
+}
+
+/// 校验退订链接, 返回订阅者
+async fn newsletter_unsubscribe_link(
+    params: &NewsletterLinkParams,
+    pool: &Database,
+) -> Result<Subscriber> {
+    validate::check(params)?;
+    let sid = db::object_id(&params.sid)?;
+    if !newsletter::verify_unsubscribe_token(sid, &params.token) {
+        return Err(AppError::bad_request("退订链接无效"));
+    }
+    newsletter::find(sid, pool).await
+}
+
+fn newsletter_unsubscribe_context(email: &str) -> Context {
+    let mut context = Context::new();
+    context.insert("title", "退订");
+    context.insert("email", email);
+    context
+}
+
+/// 新文章邮件中的退订链接, 打开后需要点击确认
+#[handler]
+pub async fn newsletter_unsubscribe_page(
+    Query(params): Query<NewsletterLinkParams>,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let subscriber = newsletter_unsubscribe_link(&params, &pool).await?;
+
+    let mut context = newsletter_unsubscribe_context(&subscriber.email);
+    context.insert("action", &params.action(newsletter::UNSUBSCRIBE_PATH)?);
+    let s = TEMPLATES.render("newsletter_unsubscribe.html", &context)?;
+    Ok(Html(s))
+}
+
+/// 确认退订, 也用于邮件客户端的一键退订(RFC 8058), 不需要 CSRF token
+#[handler]
+pub async fn newsletter_unsubscribe(
+    Query(params): Query<NewsletterLinkParams>,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let subscriber = newsletter_unsubscribe_link(&params, &pool).await?;
+    newsletter::unsubscribe(subscriber.id, &pool).await?;
+
+    let mut context = newsletter_unsubscribe_context(&subscriber.email);
+    context.insert("done", &true);
+    let s = TEMPLATES.render("newsletter_unsubscribe.html", &context)?;
+    Ok(Html(s))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationView {
+    pub id: String,
+    pub kind: String,
+    pub actor_id: String,
+    pub actor_name: String,
+    pub article_id: String,
+    pub article_title: String,
+    pub excerpt: String,
+    pub read: bool,
+    pub created_time: String,
+}
+
+impl From<Notification> for NotificationView {
+    fn from(n: Notification) -> Self {
+        NotificationView {
+            id: n.id.to_string(),
+            kind: n.kind,
+            actor_id: n.actor_id.to_string(),
+            actor_name: n.actor_name,
+            article_id: n.article_id.to_string(),
+            article_title: n.article_title,
+            excerpt: n.excerpt,
+            read: n.read,
+            created_time: format_datetime(n.created_time),
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct NotificationsParams {
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    page: Option<u64>,
+}
+
+/// 通知每页条数
+const NOTIFICATION_PAGE_SIZE: i64 = 20;
+
+#[handler]
+pub async fn notifications(
+    Query(params): Query<NotificationsParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::current_user(session, &pool)
+        .await
+        .ok_or(AppError::Unauthenticated)?;
+    validate::check(&params)?;
+    let page = params.page.unwrap_or(1);
+
+    let (list, total) = notification::list(user.id, page, NOTIFICATION_PAGE_SIZE, &pool).await?;
+    let list: Vec<NotificationView> = list.into_iter().map(Into::into).collect();
+
+    let mut context = Context::new();
+    context.insert("title", "通知");
+    context.insert("notifications", &list);
+    context.insert("total", &total);
+    context.insert("page", &page);
+    context.insert(
+        "page_nums",
+        &page_nums(total as i32, NOTIFICATION_PAGE_SIZE as i32),
+    );
+    let s = TEMPLATES.render("notifications.html", &context)?;
+    Ok(Html(s).into_response())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct MarkReadParams {
+    #[validate(custom = "validate::object_id")]
+    id: String,
+    /// 标记后跳转的地址, 只允许站内路径
+    redirect: Option<String>,
+}
+
+#[handler]
+pub async fn mark_notification_read(
+    Form(params): Form<MarkReadParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::current_user(session, &pool)
+        .await
+        .ok_or(AppError::Unauthenticated)?;
+    validate::check(&params)?;
+    notification::mark_read(user.id, db::object_id(&params.id)?, &pool).await?;
+
+    let location = params
+        .redirect
+        .filter(|r| r.starts_with('/') && !r.starts_with("//"))
+        .unwrap_or_else(|| "/notifications".to_owned());
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location)
+        .finish())
+}
+
+#[handler]
+pub async fn mark_all_notifications_read(
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let user = policy::current_user(session, &pool)
+        .await
+        .ok_or(AppError::Unauthenticated)?;
+    notification::mark_all_read(user.id, &pool).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/notifications")
+        .finish())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserView {
+    pub id: String,
+    pub username: String,
+    pub role: String,
+    pub created_time: String,
+    pub ban: Option<BanView>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanView {
+    pub kind: String,
+    pub reason: String,
+    pub expires_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntryView {
+    pub action: String,
+    pub actor_id: Option<String>,
+    pub actor_name: Option<String>,
+    pub target_type: String,
+    pub target_id: String,
+    pub detail: serde_json::Value,
+    pub ip: Option<String>,
+    pub created_time: String,
+}
+
+impl From<AuditEntry> for AuditEntryView {
+    fn from(entry: AuditEntry) -> Self {
+        AuditEntryView {
+            action: entry.action,
+            actor_id: entry.actor_id.map(|id| id.to_string()),
+            actor_name: entry.actor_name,
+            target_type: entry.target_type,
+            target_id: entry.target_id,
+            detail: serde_json::to_value(&entry.detail).unwrap_or_default(),
+            ip: entry.ip,
+            created_time: format_datetime(entry.created_time),
+        }
+    }
+}
+
+pub fn format_datetime(t: DateTime<Utc>) -> String {
+    t.with_timezone(&config::timezone())
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+#[handler]
+pub async fn admin_users(session: &Session, pool: Data<&Database>) -> Result<impl IntoResponse> {
+    policy::authorize(session, &pool, Permission::ManageUsers).await?;
+
+    let now = Utc::now();
+    let users: Vec<UserView> = db::list_users(&pool)
+        .await?
+        .into_iter()
+        .map(|u| UserView {
+            id: u.id.to_string(),
+            role: u.role.as_str().to_owned(),
+            created_time: format_datetime(u.created_time),
+            ban: u.active_ban(now).map(|ban| BanView {
+                kind: ban.kind.as_str().to_owned(),
+                reason: ban.reason.clone(),
+                expires_at: ban.expires_at.map(|t| format_datetime(t.to_chrono())),
+            }),
+            username: u.username,
+        })
+        .collect();
+    let roles: Vec<&str> = Role::ALL.iter().map(Role::as_str).collect();
+    // 最近的用户管理操作(角色修改和封禁)
+    let filter = AuditFilter {
+        action: Some("user.".to_owned()),
+        ..Default::default()
+    };
+    let (entries, _) = audit::list(&filter, 0, 50, &pool).await?;
+    let user_logs: Vec<AuditEntryView> = entries.into_iter().map(Into::into).collect();
+
+    let mut context = Context::new();
+    context.insert("title", "用户管理");
+    context.insert("users", &users);
+    context.insert("roles", &roles);
+    context.insert("user_logs", &user_logs);
+    let s = TEMPLATES.render("admin_users.html", &context)?;
+    Ok(Html(s).into_response())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct SetRoleParams {
+    #[validate(custom = "validate::object_id")]
+    user_id: String,
+    #[validate(custom = "validate::role")]
+    role: String,
+}
+
+#[handler]
+pub async fn admin_set_role(
+    Form(params): Form<SetRoleParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    validate::check(&params)?;
+    let SetRoleParams { user_id, role } = params;
+    let admin = policy::authorize(session, &pool, Permission::ManageUsers).await?;
+
+    let role = Role::from_str(role.as_str()).map_err(AppError::BadRequest)?;
+    // 至少保留一个管理员
+    if admin.id.to_string() == user_id
+        && role != Role::Admin
+        && db::count_users_by_role(Role::Admin, &pool).await? <= 1
+    {
+        return Err(AppError::bad_request("不能移除最后一个管理员"));
+    }
+    let user = db::find_user_by_id(&user_id, &pool).await?;
+    db::update_user_role(&user_id, role, &pool).await?;
+    AuditEntry::new(audit::USER_ROLE, "user", &user_id)
+        .actor(&admin)
+        .detail(doc! {
+            "username": &user.username,
+            "previous_role": user.role.as_str(),
+            "role": role.as_str(),
+        })
+        .record(&pool)
+        .await;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/admin/users")
+        .finish())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct BanParams {
+    #[validate(custom = "validate::object_id")]
+    user_id: String,
+    #[validate(custom = "validate::ban_kind")]
+    kind: String,
+    #[validate(
+        length(max = 200, message = "原因最多200个字"),
+        custom = "validate::not_blank"
+    )]
+    reason: String,
+    /// 封禁天数, 0 表示永久
+    #[validate(range(max = 3650, message = "最多封禁3650天"))]
+    days: u32,
+}
+
+/// 封禁或影子封禁用户
+#[handler]
+pub async fn admin_ban_user(
+    Form(params): Form<BanParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let admin = policy::authorize(session, &pool, Permission::ManageUsers).await?;
+    validate::check(&params)?;
+    let user = db::find_user_by_id(&params.user_id, &pool).await?;
+    if user.id == admin.id {
+        return Err(AppError::bad_request("不能封禁自己"));
+    }
+    if user.role == Role::Admin {
+        return Err(AppError::bad_request("不能封禁管理员, 请先修改角色"));
+    }
+
+    let kind = BanKind::from_str(&params.kind).map_err(AppError::BadRequest)?;
+    let now = Utc::now();
+    let expires_at =
+        (params.days > 0).then(|| (now + chrono::Duration::days(params.days as i64)).into());
+    let ban = Ban {
+        kind,
+        reason: params.reason.trim().to_owned(),
+        expires_at,
+        banned_by: Some(admin.id),
+        created_time: now,
+    };
+    let action = match kind {
+        BanKind::Ban => audit::USER_BAN,
+        BanKind::ShadowBan => audit::USER_SHADOW_BAN,
+    };
+    let detail = doc! {
+        "username": &user.username,
+        "reason": &ban.reason,
+        "expires_at": expires_at.map(|t: bson::DateTime| format_datetime(t.to_chrono())),
+    };
+    db::ban_user(user.id, ban, &pool).await?;
+    AuditEntry::new(action, "user", user.id)
+        .actor(&admin)
+        .detail(detail)
+        .record(&pool)
+        .await;
+    // 封禁后立即退出所有设备, 影子封禁不让用户察觉
+    if kind == BanKind::Ban {
+        session_store::revoke_all_sessions(&user.id.to_string(), &pool).await?;
+    }
+    info!(
+        "user {} {} by {}",
+        user.username,
+        kind.as_str(),
+        admin.username
+    );
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/admin/users")
+        .finish())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct UnbanParams {
+    #[validate(custom = "validate::object_id")]
+    user_id: String,
+    #[validate(length(max = 200, message = "原因最多200个字"))]
+    reason: String,
+}
+
+#[handler]
+pub async fn admin_unban_user(
+    Form(params): Form<UnbanParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let admin = policy::authorize(session, &pool, Permission::ManageUsers).await?;
+    validate::check(&params)?;
+    let user = db::find_user_by_id(&params.user_id, &pool).await?;
+
+    db::unban_user(user.id, &pool).await?;
+    AuditEntry::new(audit::USER_UNBAN, "user", user.id)
+        .actor(&admin)
+        .detail(doc! {"username": &user.username, "reason": params.reason.trim()})
+        .record(&pool)
+        .await;
+    info!("user {} unbanned by {}", user.username, admin.username);
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/admin/users")
+        .finish())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct AdminCommentsParams {
+    /// 0: 待审核, -1: 未通过, -2: 垃圾评论, 1: 已通过
+    #[validate(range(min = -2, max = 1, message = "未知的评论状态"))]
+    status: Option<i16>,
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    page: Option<i32>,
+}
+
+/// 评论审核队列
+#[handler]
+pub async fn admin_comments(
+    Query(params): Query<AdminCommentsParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    policy::authorize(session, &pool, Permission::ModerateComments).await?;
+    validate::check(&params)?;
+    let status = params.status.unwrap_or(Comment::PENDING);
+    let page = params.page.unwrap_or(1);
+
+    let (comments, total) =
+        db::list_comment_by_status(status, page, comment_page_size(), &pool).await?;
+    let comment_views: Vec<UserCommentView> = comments
+        .into_iter()
+        .map(|c| UserCommentView {
+            article_id: c.article_id.to_string(),
+            article_title: c.article_title,
+            comment: c.comment.into(),
+        })
+        .collect();
+    let (spam_docs, ham_docs) = spam::training_size(&pool).await?;
+
+    let mut context = Context::new();
+    context.insert("title", "评论审核");
+    let tabs = [
+        (Comment::PENDING, "待审核"),
+        (Comment::SPAM, "垃圾评论"),
+        (Comment::REJECTED, "未通过"),
+        (Comment::VISIBLE, "已通过"),
+    ];
+    context.insert("tabs", &tabs);
+    context.insert("status", &status);
+    context.insert("comment_list", &comment_views);
+    context.insert("total", &total);
+    context.insert("page", &page);
+    context.insert("page_nums", &page_nums(total, comment_page_size()));
+    context.insert("spam_docs", &spam_docs);
+    context.insert("ham_docs", &ham_docs);
+    let s = TEMPLATES.render("admin_comments.html", &context)?;
+    Ok(Html(s).into_response())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ModerateCommentParams {
+    #[validate(custom = "validate::object_id")]
+    comment_id: String,
+    decision: String,
+    /// 审核后回到的队列
+    #[validate(range(min = -2, max = 1, message = "未知的评论状态"))]
+    status: i16,
+}
+
+#[handler]
+pub async fn admin_moderate_comment(
+    Form(params): Form<ModerateCommentParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let moderator = policy::authorize(session, &pool, Permission::ModerateComments).await?;
+    validate::check(&params)?;
+    let decision = Decision::from_str(&params.decision).map_err(AppError::BadRequest)?;
+    moderation::decide(&params.comment_id, decision, &moderator, &pool).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(
+            header::LOCATION,
+            format!("/admin/comments?status={}", params.status),
+        )
+        .finish())
+}
+
+/// 用所有审核过的评论重新训练垃圾评论分类器
+#[handler]
+pub async fn admin_retrain_spam_filter(
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let moderator = policy::authorize(session, &pool, Permission::ModerateComments).await?;
+    let (spam_docs, ham_docs) = moderation::retrain(&pool).await?;
+    AuditEntry::new(audit::SPAM_FILTER_RETRAIN, "spam_filter", "")
+        .actor(&moderator)
+        .detail(doc! {"spam_docs": spam_docs, "ham_docs": ham_docs})
+        .record(&pool)
+        .await;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/admin/comments")
+        .finish())
+}
+
+#[derive(Serialize, Deserialize, Validate, Default)]
+pub struct AuditParams {
+    #[serde(default)]
+    #[validate(length(max = 64, message = "操作类型最多64个字"))]
+    action: String,
+    #[serde(default)]
+    #[validate(length(max = 64, message = "操作人最多64个字"))]
+    actor: String,
+    #[serde(default)]
+    #[validate(length(max = 64, message = "对象 id 最多64个字"))]
+    target_id: String,
+    #[serde(default)]
+    #[validate(custom = "validate::date")]
+    from: String,
+    #[serde(default)]
+    #[validate(custom = "validate::date")]
+    to: String,
+    #[serde(skip_serializing)]
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    page: Option<u64>,
+}
+
+impl AuditParams {
+    /// 转换为查询条件, 日期按配置的时区计算, 包含结束日期当天
+    fn filter(&self) -> AuditFilter {
+        let non_empty = |s: &str| {
+            let s = s.trim();
+            (!s.is_empty()).then(|| s.to_owned())
+        };
+        let day_start = |date: &str, days: i64| {
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()? + Duration::days(days);
+            let time = date
+                .and_hms_opt(0, 0, 0)?
+                .and_local_timezone(config::timezone());
+            time.single().map(|t| t.with_timezone(&Utc))
+        };
+        AuditFilter {
+            action: non_empty(&self.action),
+            actor_name: non_empty(&self.actor),
+            target_id: non_empty(&self.target_id),
+            from: day_start(&self.from, 0),
+            to: day_start(&self.to, 1),
+        }
+    }
+}
+
+/// 审计日志每页条数
+const AUDIT_PAGE_SIZE: i64 = 50;
+
+/// 审计日志, 可按操作类型、操作人、对象和日期筛选
+#[handler]
+pub async fn admin_audit(
+    Query(params): Query<AuditParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    policy::authorize(session, &pool, Permission::ManageUsers).await?;
+    let mut context = Context::new();
+    context.insert("title", "审计日志");
+    context.insert("actions", audit::ACTIONS);
+    if let Some(errors) = validate::form(&params) {
+        context.insert("entries", &Vec::<AuditEntryView>::new());
+        return render_form("admin_audit.html", context, &params, &errors);
+    }
+
+    let page = params.page.unwrap_or(1);
+    let skip = (page - 1) * AUDIT_PAGE_SIZE as u64;
+    let (entries, total) = audit::list(&params.filter(), skip, AUDIT_PAGE_SIZE, &pool).await?;
+    let entries: Vec<AuditEntryView> = entries.into_iter().map(Into::into).collect();
+
+    // 翻页链接保留筛选条件
+    let query = serde_urlencoded::to_string(&params).map_err(AppError::internal)?;
+    context.insert("entries", &entries);
+    context.insert("total", &total);
+    context.insert("page", &page);
+    context.insert("has_next", &(skip + (entries.len() as u64) < total));
+    context.insert("query", &query);
+    context.insert("form", &params);
+    context.insert("errors", &FieldErrors::new());
+    let s = TEMPLATES.render("admin_audit.html", &context)?;
+    Ok(Html(s).into_response())
+}
+
+/// 按筛选条件导出 CSV
+#[handler]
+pub async fn admin_audit_export(
+    Query(params): Query<AuditParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    policy::authorize(session, &pool, Permission::ManageUsers).await?;
+    validate::check(&params)?;
+
+    let (entries, _) = audit::list(&params.filter(), 0, audit::EXPORT_LIMIT, &pool).await?;
+    let csv = audit::to_csv(&entries, &config::timezone())?;
+    let filename = format!("audit-{}.csv", Utc::now().format("%Y%m%d%H%M%S"));
+
+    Ok(Response::builder()
+        .content_type("text/csv; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .body(csv))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriberView {
+    pub id: String,
+    pub email: String,
+    pub tags: Vec<String>,
+    pub status: String,
+    pub created_time: String,
+    pub confirmed_time: Option<String>,
+}
+
+impl From<Subscriber> for SubscriberView {
+    fn from(s: Subscriber) -> Self {
+        SubscriberView {
+            id: s.id.to_string(),
+            email: s.email,
+            tags: s.tags,
+            status: s.status,
+            created_time: format_datetime(s.created_time),
+            confirmed_time: s.confirmed_time.map(|t| format_datetime(t.to_chrono())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct SubscribersParams {
+    #[serde(default)]
+    #[validate(custom = "validate::subscriber_status")]
+    status: String,
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    page: Option<u64>,
+}
+
+impl SubscribersParams {
+    fn status(&self) -> Option<&str> {
+        (!self.status.is_empty()).then_some(self.status.as_str())
+    }
+}
+
+/// 订阅管理每页条数
+const SUBSCRIBER_PAGE_SIZE: i64 = 50;
+
+/// 导入的 CSV 文件最大字节数
+const SUBSCRIBER_IMPORT_MAX_BYTES: usize = 5 * 1024 * 1024;
+
+async fn admin_subscribers_context(params: &SubscribersParams, pool: &Database) -> Result<Context> {
+    let page = params.page.unwrap_or(1);
+    let skip = (page - 1) * SUBSCRIBER_PAGE_SIZE as u64;
+    let (subscribers, total) =
+        newsletter::list(params.status(), skip, SUBSCRIBER_PAGE_SIZE, pool).await?;
+    let subscribers: Vec<SubscriberView> = subscribers.into_iter().map(Into::into).collect();
+
+    let mut context = Context::new();
+    context.insert("title", "订阅管理");
+    context.insert("statuses", &Subscriber::STATUSES);
+    context.insert("status", &params.status);
+    context.insert("has_next", &(skip + (subscribers.len() as u64) < total));
+    context.insert("subscribers", &subscribers);
+    context.insert("total", &total);
+    context.insert("page", &page);
+    Ok(context)
+}
+
+/// 订阅者列表, 可按状态筛选
+#[handler]
+pub async fn admin_subscribers(
+    Query(params): Query<SubscribersParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    policy::authorize(session, &pool, Permission::ManageUsers).await?;
+    validate::check(&params)?;
+
+    let context = admin_subscribers_context(&params, &pool).await?;
+    let s = TEMPLATES.render("admin_subscribers.html", &context)?;
+    Ok(Html(s))
+}
+
+/// 按状态导出全部订阅者
+#[handler]
+pub async fn admin_subscribers_export(
+    Query(params): Query<SubscribersParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    policy::authorize(session, &pool, Permission::ManageUsers).await?;
+    validate::check(&params)?;
+
+    let (subscribers, _) = newsletter::list(params.status(), 0, 0, &pool).await?;
+    let csv = newsletter::to_csv(&subscribers, &config::timezone())?;
+    let filename = format!("subscribers-{}.csv", Utc::now().format("%Y%m%d%H%M%S"));
+
+    Ok(Response::builder()
+        .content_type("text/csv; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .body(csv))
+}
+
+/// 从 CSV 导入订阅者, 格式见 [`newsletter::parse_csv`]
+#[handler]
+pub async fn admin_subscribers_import(
+    mut multipart: Multipart,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let admin = policy::authorize(session, &pool, Permission::ManageUsers).await?;
+
+    let mut data = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| AppError::bad_request(err.to_string()))?
+    {
+        if field.name() == Some("file") {
+            // 只多读一个字节, 超过上限的文件不会整个读进内存
+            let reader = field.into_async_read();
+            tokio::pin!(reader);
+            data.clear();
+            reader
+                .take(SUBSCRIBER_IMPORT_MAX_BYTES as u64 + 1)
+                .read_to_end(&mut data)
+                .await
+                .map_err(|err| AppError::bad_request(err.to_string()))?;
+        }
+    }
+    if data.is_empty() {
+        return Err(AppError::bad_request("请选择要导入的 CSV 文件"));
+    }
+    if data.len() > SUBSCRIBER_IMPORT_MAX_BYTES {
+        return Err(AppError::bad_request("文件最大 5MB"));
+    }
+
+    let (rows, errors) = newsletter::parse_csv(&data)?;
+    let mut report = newsletter::import(rows, &pool).await?;
+    report.errors = errors;
+    AuditEntry::new(audit::SUBSCRIBER_IMPORT, "subscriber", "")
+        .actor(&admin)
+        .detail(doc! {
+            "inserted": report.inserted as i64,
+            "updated": report.updated as i64,
+            "skipped": report.skipped as i64,
+            "errors": report.errors.len() as i64,
+        })
+        .record(&pool)
+        .await;
+
+    let params = SubscribersParams {
+        status: String::new(),
+        page: None,
+    };
+    let mut context = admin_subscribers_context(&params, &pool).await?;
+    context.insert("report", &report);
+    let s = TEMPLATES.render("admin_subscribers.html", &context)?;
+    Ok(Html(s))
+}
+
+#[derive(Deserialize, Validate)]
+pub struct DeleteSubscriberParams {
+    #[validate(custom = "validate::object_id")]
+    id: String,
+}
+
+#[handler]
+pub async fn admin_delete_subscriber(
+    Form(params): Form<DeleteSubscriberParams>,
+    session: &Session,
+    pool: Data<&Database>,
+) -> Result<impl IntoResponse> {
+    let admin = policy::authorize(session, &pool, Permission::ManageUsers).await?;
+    validate::check(&params)?;
+
+    let subscriber = newsletter::delete(db::object_id(&params.id)?, &pool)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    AuditEntry::new(audit::SUBSCRIBER_DELETE, "subscriber", subscriber.id)
+        .actor(&admin)
+        .detail(doc! {"email": &subscriber.email, "status": &subscriber.status})
+        .record(&pool)
+        .await;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/admin/subscribers")
+        .finish())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaView {
+    pub id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub is_image: bool,
+    pub size: i64,
+    pub url: String,
+    pub markdown: String,
+    pub created_time: String,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+impl MediaView {
+    fn new(media: Media, storage: &dyn Storage) -> Self {
+        let url = storage.url(&media.key);
+        MediaView {
+            id: media.id.to_string(),
+            thumbnail: media
+                .image
+                .as_ref()
+                .map(|info| imaging::thumbnail(&media.hash, info)),
+            markdown: media::markdown(&media, &url),
+            is_image: media.is_image(),
+            filename: media.filename,
+            content_type: media.content_type,
+            size: media.size,
+            url,
+            created_time: format_datetime(media.created_time),
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct MediaLibraryParams {
+    #[validate(range(min = 1, message = "页码从1开始"))]
+    page: Option<u64>,
+}
+
+/// 媒体库每页条数
+const MEDIA_PAGE_SIZE: i64 = 24;
+
+/// 当前用户上传的文件, 可以复制 Markdown 插入文章
+#[handler]
+pub async fn media_library(
+    Query(params): Query<MediaLibraryParams>,
+    session: &Session,
+    pool: Data<&Database>,
+    storage: Data<&Arc<dyn Storage>>,
+) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+    validate::check(&params)?;
+    let page = params.page.unwrap_or(1);
+
+    let (list, total) = media::list(user.id, page, MEDIA_PAGE_SIZE, &pool).await?;
+    let list: Vec<MediaView> = list
+        .into_iter()
+        .map(|m| MediaView::new(m, storage.0.as_ref()))
+        .collect();
+
+    let upload = &config::get().upload;
+    let mut context = Context::new();
+    context.insert("title", "媒体库");
+    context.insert("media", &list);
+    context.insert("total", &total);
+    context.insert("page", &page);
+    context.insert(
+        "page_nums",
+        &page_nums(total as i32, MEDIA_PAGE_SIZE as i32),
+    );
+    context.insert("max_kb", &(upload.max_bytes / 1024));
+    context.insert("allowed_types", &upload.allowed_types.join(","));
+    let s = TEMPLATES.render("media.html", &context)?;
+    Ok(Html(s).into_response())
+}
+
+///
+/// 上传文件(multipart 的 `file` 字段). 请求头 Accept 为 application/json 时返回
+/// `{id, url, markdown}`, 供写文章页面直接插入, 否则跳转回媒体库
+///
+#[handler]
+pub async fn media_upload(
+    req: &Request,
+    mut multipart: Multipart,
+    session: &Session,
+    pool: Data<&Database>,
+    storage: Data<&Arc<dyn Storage>>,
+) -> Result<Response> {
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+    let max_bytes = config::get().upload.max_bytes;
+
+    let mut file = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| AppError::bad_request(err.to_string()))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = field.file_name().unwrap_or_default().to_owned();
+        // 只多读一个字节, 超过上限的文件不会整个读进内存
+        let reader = field.into_async_read();
+        tokio::pin!(reader);
+        let mut data = Vec::new();
+        reader
+            .take(max_bytes as u64 + 1)
+            .read_to_end(&mut data)
+            .await
+            .map_err(|err| AppError::bad_request(err.to_string()))?;
+        file = Some((filename, data));
+        break;
+    }
+    let (filename, data) = file.ok_or_else(|| AppError::bad_request("请选择要上传的文件"))?;
+
+    let media = media::save(user.id, &filename, data, storage.0.as_ref(), &pool).await?;
+    AuditEntry::new(audit::MEDIA_UPLOAD, "media", media.id)
+        .actor(&user)
+        .detail(doc! {"filename": &media.filename, "hash": &media.hash, "size": media.size})
+        .record(&pool)
+        .await;
+
+    if error::wants_json(req) {
+        let view = MediaView::new(media, storage.0.as_ref());
+        return Ok(Json(serde_json::json!({
+            "id": view.id,
+            "url": view.url,
+            "markdown": view.markdown,
+        }))
+        .into_response());
+    }
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/media")
+        .finish())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct DeleteMediaParams {
+    #[validate(custom = "validate::object_id")]
+    id: String,
+}
+
+#[handler]
+pub async fn media_delete(
+    Form(params): Form<DeleteMediaParams>,
+    session: &Session,
+    pool: Data<&Database>,
+    storage: Data<&Arc<dyn Storage>>,
+) -> Result<impl IntoResponse> {
+    let user = policy::authorize(session, &pool, Permission::Publish).await?;
+    validate::check(&params)?;
+    let id = db::object_id(&params.id)?;
+
+    media::delete(user.id, id, storage.0.as_ref(), &pool).await?;
+    AuditEntry::new(audit::MEDIA_DELETE, "media", id)
+        .actor(&user)
+        .record(&pool)
+        .await;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, "/media")
+        .finish())
+}
+
+/// CSP 违规报告的大小上限, 浏览器的报告一般只有几百字节
+const CSP_REPORT_MAX_BYTES: usize = 8 * 1024;
+
+///
+/// 浏览器上报的 CSP 违规, 只记录日志.
+/// 不需要登录也不校验 CSRF, 超过上限的请求体返回 413, 不会读进内存
+///
+#[handler]
+pub async fn csp_report(req: &Request, body: Body) -> StatusCode {
+    let content_length = req
+        .header(header::CONTENT_LENGTH)
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > CSP_REPORT_MAX_BYTES) {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+
+    // 没有 Content-Length(分块传输)时只多读一个字节
+    let mut data = Vec::new();
+    if body
+        .into_async_read()
+        .take(CSP_REPORT_MAX_BYTES as u64 + 1)
+        .read_to_end(&mut data)
+        .await
+        .is_err()
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    if data.len() > CSP_REPORT_MAX_BYTES {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+    warn!("csp violation: {}", String::from_utf8_lossy(&data));
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{
+        get,
+        session::{CookieConfig, MemoryStorage, ServerSession},
+        Endpoint, EndpointExt, Request, Route,
+    };
+
+    use super::*;
+
+    #[handler]
+    fn fake_signin(session: &Session) -> String {
+        session.set("uid", "u1");
+        csrf::token(session)
+    }
+
+    #[tokio::test]
+    async fn test_csp_report() {
+        let app = Route::new().at(middleware::CSP_REPORT_PATH, poem::post(csp_report));
+        let report = |body: String, content_length: Option<usize>| {
+            let mut req = Request::builder()
+                .method(poem::http::Method::POST)
+                .uri_str(middleware::CSP_REPORT_PATH);
+            if let Some(len) = content_length {
+                req = req.header(header::CONTENT_LENGTH, len);
+            }
+            req.body(body)
+        };
+
+        let body = r#"{"csp-report":{}}"#.to_owned();
+        let len = body.len();
+        let resp = app.call(report(body, Some(len))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = app
+            .call(report(String::new(), Some(1 << 30)))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = "a".repeat(CSP_REPORT_MAX_BYTES + 1);
+        let resp = app.call(report(body, None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_connect_identity_with_default_csp() {
+        config::init_for_test();
+        let app = Route::new()
+            .at("/signin", get(fake_signin))
+            .at("/account/connect", get(connect_identity))
+            .around(csrf::protect)
+            .with(ServerSession::new(
+                CookieConfig::default(),
+                MemoryStorage::new(),
+            ))
+            .around(middleware::security_headers);
+
+        // 默认的 CSP 不允许表单提交到授权页面所在的站点, 所以绑定入口只能是链接, 不能是表单
+        let template = include_str!("../templates/account.html");
+        assert!(template
+            .contains("href=\"/account/connect?provider=gitee&csrf_token={{ csrf_token() }}\""));
+        assert!(!template.contains("action=\"/account/connect\""));
+
+        let resp = app
+            .call(Request::builder().uri_str("/signin").finish())
+            .await
+            .unwrap();
+        let csp = resp.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap();
+        assert!(csp.contains("form-action 'self';"));
+        assert!(!csp.contains("gitee.com"));
+        let cookie = resp
+            .headers()
+            .get(header::SET_COOKIE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap()
+            .to_owned();
+        let token = resp.into_body().into_string().await.unwrap();
+        let connect = |token: &str| {
+            Request::builder()
+                .uri_str(format!(
+                    "/account/connect?provider=gitee&csrf_token={}",
+                    token
+                ))
+                .header(header::COOKIE, cookie.clone())
+                .finish()
+        };
+
+        let resp = app.call(connect(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let location = resp.headers()[header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("https://gitee.com/oauth/authorize?"));
+        assert!(location.contains("&state="));
+
+        let resp = app.call(connect("bad")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
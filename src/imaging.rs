@@ -0,0 +1,545 @@
+//!
+//! 图片的衍生版本: 上传后在后台把图片缩放成几种宽度的 WebP 和 JPEG(有透明通道时为 PNG),
+//! 另外生成首页列表用的缩略图. 重新编码会去掉 EXIF 等元数据(先按 EXIF 方向旋转).
+//! 衍生文件按原图的哈希命名, 保存在 `upload.derived_dir` 目录, 通过 `/assets/derived` 访问,
+//! 内容不会变化, 可以长期缓存; 目录被清空时启动后会重新生成
+//!
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::StreamExt;
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat, ImageReader, Limits};
+use mongodb::{bson::doc, options::FindOptions, Database};
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config;
+use crate::error::{AppError, Result};
+use crate::media::{self, Media};
+use crate::storage::Storage;
+
+/// 衍生文件的访问路径
+pub const URL_PREFIX: &str = "/assets/derived";
+
+/// 生成的宽度, 不会超过原图的宽度
+pub const WIDTHS: [u32; 4] = [320, 640, 960, 1280];
+
+/// 缩略图的尺寸, 按比例缩放后居中裁剪
+pub const THUMBNAIL_SIZE: (u32, u32) = (240, 160);
+
+/// 文章中图片的显示宽度, 用于 `sizes` 属性
+const SIZES: &str = "(max-width: 960px) 100vw, 960px";
+
+/// 能生成衍生版本的类型, GIF 可能是动图, 保留原图
+const SOURCE_TYPES: [&str; 3] = ["image/png", "image/jpeg", "image/webp"];
+
+/// 解码时允许的最大宽高
+const MAX_DIMENSION: u32 = 12000;
+
+const WEBP_QUALITY: f32 = 80.0;
+const JPEG_QUALITY: u8 = 82;
+
+/// 生成衍生版本后记录在 [`Media::image`] 中
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// 原图按 EXIF 方向旋转后的尺寸
+    pub width: u32,
+    pub height: u32,
+    /// 生成了哪些宽度, 从小到大
+    pub widths: Vec<u32>,
+    /// WebP 之外的格式: jpg/png
+    pub fallback: String,
+}
+
+impl ImageInfo {
+    fn height_at(&self, width: u32) -> u32 {
+        ((self.height as u64 * width as u64) / self.width.max(1) as u64).max(1) as u32
+    }
+}
+
+/// 生成的文件: (文件名, 内容)
+pub type DerivedFile = (String, Vec<u8>);
+
+/// 衍生文件名, `variant` 是宽度或 `thumb`
+pub fn file_name(hash: &str, variant: &str, ext: &str) -> String {
+    format!("{}-{}.{}", hash, variant, ext)
+}
+
+pub fn url(hash: &str, variant: &str, ext: &str) -> String {
+    format!("{}/{}", URL_PREFIX, file_name(hash, variant, ext))
+}
+
+pub fn can_derive(content_type: &str) -> bool {
+    SOURCE_TYPES.contains(&content_type)
+}
+
+/// 要生成的宽度: 小于原图的标准宽度, 再加上原图宽度(不超过最大的标准宽度)
+fn target_widths(width: u32) -> Vec<u32> {
+    let max = WIDTHS[WIDTHS.len() - 1];
+    let mut widths: Vec<u32> = WIDTHS.iter().copied().filter(|w| *w < width).collect();
+    if width <= max {
+        widths.push(width);
+    }
+    widths
+}
+
+fn decode(data: &[u8]) -> Result<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(AppError::internal)?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(AppError::internal)?;
+    let orientation = image::ImageDecoder::orientation(&mut decoder).map_err(AppError::internal)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(AppError::internal)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn encode_webp(image: &DynamicImage) -> Vec<u8> {
+    if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+            .encode(WEBP_QUALITY)
+            .to_vec()
+    } else {
+        let rgb = image.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+            .encode(WEBP_QUALITY)
+            .to_vec()
+    }
+}
+
+fn encode_fallback(image: &DynamicImage, ext: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if ext == "png" {
+        image
+            .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .map_err(AppError::internal)?;
+    } else {
+        JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY)
+            .encode_image(&image.to_rgb8())
+            .map_err(AppError::internal)?;
+    }
+    Ok(buf)
+}
+
+///
+/// 生成全部衍生文件, 返回图片信息和文件列表.
+/// CPU 密集, 需要在 `spawn_blocking` 中调用
+///
+pub fn derive(hash: &str, data: &[u8]) -> Result<(ImageInfo, Vec<DerivedFile>)> {
+    let image = decode(data)?;
+    let fallback = if image.color().has_alpha() {
+        "png"
+    } else {
+        "jpg"
+    };
+    let info = ImageInfo {
+        width: image.width(),
+        height: image.height(),
+        widths: target_widths(image.width()),
+        fallback: fallback.to_owned(),
+    };
+
+    let mut files = Vec::new();
+    for &width in &info.widths {
+        let resized = if width == image.width() {
+            image.clone()
+        } else {
+            image.resize_exact(
+                width,
+                info.height_at(width),
+                image::imageops::FilterType::Lanczos3,
+            )
+        };
+        let variant = width.to_string();
+        files.push((file_name(hash, &variant, "webp"), encode_webp(&resized)));
+        files.push((
+            file_name(hash, &variant, fallback),
+            encode_fallback(&resized, fallback)?,
+        ));
+    }
+
+    let (thumb_width, thumb_height) = THUMBNAIL_SIZE;
+    let thumbnail = image.resize_to_fill(
+        thumb_width,
+        thumb_height,
+        image::imageops::FilterType::Lanczos3,
+    );
+    files.push((file_name(hash, "thumb", "webp"), encode_webp(&thumbnail)));
+    files.push((
+        file_name(hash, "thumb", fallback),
+        encode_fallback(&thumbnail, fallback)?,
+    ));
+    Ok((info, files))
+}
+
+async fn write_files(dir: &Path, files: Vec<DerivedFile>) -> Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(AppError::internal)?;
+    for (name, data) in files {
+        let path = dir.join(&name);
+        let tmp = dir.join(format!(".{}.tmp-{}", name, rand::random::<u32>()));
+        tokio::fs::write(&tmp, data)
+            .await
+            .map_err(AppError::internal)?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .map_err(AppError::internal)?;
+    }
+    Ok(())
+}
+
+/// 生成衍生文件并记录到所有引用这个文件的 media 中
+pub async fn process(hash: String, data: Vec<u8>, mongo: &Database) -> Result<ImageInfo> {
+    let derive_hash = hash.clone();
+    let (info, files) = tokio::task::spawn_blocking(move || derive(&derive_hash, &data))
+        .await
+        .map_err(AppError::internal)??;
+    write_files(Path::new(&config::get().upload.derived_dir), files).await?;
+    mongo
+        .collection::<Media>("media")
+        .update_many(
+            doc! {"hash": &hash},
+            doc! {"$set": {"image": bson::to_bson(&info)?}},
+            None,
+        )
+        .await?;
+    Ok(info)
+}
+
+/// 删除原图时一起删除衍生文件
+pub async fn remove(hash: &str, info: &ImageInfo) {
+    let dir = Path::new(&config::get().upload.derived_dir);
+    let variants = info
+        .widths
+        .iter()
+        .map(u32::to_string)
+        .chain(std::iter::once("thumb".to_owned()));
+    for variant in variants {
+        for ext in ["webp", info.fallback.as_str()] {
+            let path = dir.join(file_name(hash, &variant, ext));
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("remove {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+}
+
+/// 上传后在后台生成, 失败只记录日志, 下次启动时会重试
+pub fn spawn(hash: String, data: Vec<u8>, mongo: Database) {
+    tokio::spawn(async move {
+        if let Err(err) = process(hash.clone(), data, &mongo).await {
+            warn!("derive image {}: {}", hash, err);
+        }
+    });
+}
+
+///
+/// 启动时检查: 没有生成过衍生文件的图片(例如之前生成失败), 或者衍生文件已经不在了
+/// (例如目录被清空), 从存储中读取原图重新生成
+///
+pub async fn reconcile(storage: Arc<dyn Storage>, mongo: Database) {
+    let dir = Path::new(&config::get().upload.derived_dir);
+    let options = FindOptions::builder().sort(doc! {"hash": 1}).build();
+    let mut cursor = match mongo
+        .collection::<Media>("media")
+        .find(
+            doc! {"content_type": {"$in": SOURCE_TYPES.to_vec()}},
+            options,
+        )
+        .await
+    {
+        Ok(cursor) => cursor,
+        Err(err) => {
+            warn!("reconcile derived images: {}", err);
+            return;
+        }
+    };
+
+    let mut last_hash = String::new();
+    let mut count = 0;
+    while let Some(media) = cursor.next().await {
+        let media = match media {
+            Ok(media) => media,
+            Err(err) => {
+                warn!("reconcile derived images: {}", err);
+                return;
+            }
+        };
+        if media.hash == last_hash {
+            continue;
+        }
+        last_hash = media.hash.clone();
+        let exists = media.image.as_ref().is_some_and(|info| {
+            dir.join(file_name(&media.hash, "thumb", &info.fallback))
+                .exists()
+        });
+        if exists {
+            continue;
+        }
+
+        let result = match storage.get(&media.key).await {
+            Ok(Some(data)) => process(media.hash.clone(), data, &mongo).await.map(|_| ()),
+            Ok(None) => Err(AppError::internal(format!("missing {}", media.key))),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => count += 1,
+            Err(err) => warn!("derive image {}: {}", media.hash, err),
+        }
+    }
+    if count > 0 {
+        info!("derived images for {} files", count);
+    }
+}
+
+/// 从存储中读取原图重新生成衍生文件(例如修改了生成的尺寸之后), 没有上传过或不能生成时返回 None
+pub async fn rederive(
+    hash: &str,
+    storage: &dyn Storage,
+    mongo: &Database,
+) -> Result<Option<ImageInfo>> {
+    let media = mongo
+        .collection::<Media>("media")
+        .find_one(
+            doc! {"hash": hash, "content_type": {"$in": SOURCE_TYPES.to_vec()}},
+            None,
+        )
+        .await?;
+    let Some(media) = media else {
+        return Ok(None);
+    };
+    let data = storage
+        .get(&media.key)
+        .await?
+        .ok_or_else(|| AppError::internal(format!("missing {}", media.key)))?;
+    process(media.hash, data, mongo).await.map(Some)
+}
+
+/// 从原图地址中取出哈希(存储路径为 `{hash前两位}/{hash}.{扩展名}`)
+pub fn hash_from_url(url: &str) -> Option<&str> {
+    let name = url.rsplit('/').next()?;
+    let (hash, _) = name.split_once('.')?;
+    media::is_hash(hash).then_some(hash)
+}
+
+/// 查询这些原图的衍生信息, 只返回已经生成好的
+pub async fn lookup(hashes: &[String], mongo: &Database) -> Result<HashMap<String, ImageInfo>> {
+    let mut result = HashMap::new();
+    if hashes.is_empty() {
+        return Ok(result);
+    }
+    let mut cursor = mongo
+        .collection::<Media>("media")
+        .find(doc! {"hash": {"$in": hashes}, "image": {"$ne": null}}, None)
+        .await?;
+    while let Some(media) = cursor.next().await {
+        let media = media?;
+        if let Some(info) = media.image {
+            result.insert(media.hash, info);
+        }
+    }
+    Ok(result)
+}
+
+fn srcset(hash: &str, info: &ImageInfo, ext: &str) -> String {
+    info.widths
+        .iter()
+        .map(|w| format!("{} {}w", url(hash, &w.to_string(), ext), w))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// 渲染后的文章中 `<img src='...'>` 的地址
+fn img_sources(html: &str) -> impl Iterator<Item = (usize, &str)> {
+    const PREFIX: &str = "<img src='";
+    html.match_indices(PREFIX).filter_map(|(start, _)| {
+        let src_start = start + PREFIX.len();
+        let len = html[src_start..].find('\'')?;
+        Some((start, &html[src_start..src_start + len]))
+    })
+}
+
+/// 渲染后的文章中引用的原图哈希
+pub fn hashes_in(html: &str) -> Vec<String> {
+    let mut hashes: Vec<String> = Vec::new();
+    for (_, src) in img_sources(html) {
+        if let Some(hash) = hash_from_url(src) {
+            if !hashes.iter().any(|h| h == hash) {
+                hashes.push(hash.to_owned());
+            }
+        }
+    }
+    hashes
+}
+
+///
+/// 改写 Markdown 渲染出的图片: 生成过衍生文件的换成 `<picture>`(WebP 和 JPEG/PNG 的 srcset),
+/// 所有图片都延迟加载
+///
+pub fn rewrite_images(html: &str, infos: &HashMap<String, ImageInfo>) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut last = 0;
+    for (start, src) in img_sources(html) {
+        let Some(end) = html[start..].find("/>").map(|i| start + i + 2) else {
+            continue;
+        };
+        // `<img src='...'` 之后的属性(alt/title), 不含结尾的 `/>`
+        let rest = html[start + "<img src=''".len() + src.len()..end - 2].trim();
+        result.push_str(&html[last..start]);
+        last = end;
+
+        let info = hash_from_url(src).and_then(|hash| infos.get(hash).map(|info| (hash, info)));
+        match info {
+            Some((hash, info)) => {
+                let largest = info.widths[info.widths.len() - 1];
+                result.push_str(&format!(
+                    "<picture><source type='image/webp' srcset='{}' sizes='{}'>\
+                     <img src='{}' srcset='{}' sizes='{}' width='{}' height='{}' {} loading='lazy' decoding='async' /></picture>",
+                    srcset(hash, info, "webp"),
+                    SIZES,
+                    url(hash, &largest.to_string(), &info.fallback),
+                    srcset(hash, info, &info.fallback),
+                    SIZES,
+                    largest,
+                    info.height_at(largest),
+                    rest,
+                ));
+            }
+            None => {
+                result.push_str(&format!("<img src='{}' {} loading='lazy' />", src, rest));
+            }
+        }
+    }
+    result.push_str(&html[last..]);
+    result
+}
+
+/// Markdown 中第一张上传的图片的哈希, 作为文章的封面
+pub fn cover_hash(markdown: &str) -> Option<&str> {
+    markdown.match_indices("![").find_map(|(start, _)| {
+        let rest = &markdown[start..];
+        let url_start = rest.find("](")? + 2;
+        let url = rest[url_start..].split([')', ' ', '\n']).next()?;
+        hash_from_url(url)
+    })
+}
+
+/// 首页列表的缩略图
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub webp: String,
+    pub fallback: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub fn thumbnail(hash: &str, info: &ImageInfo) -> Thumbnail {
+    Thumbnail {
+        webp: url(hash, "thumb", "webp"),
+        fallback: url(hash, "thumb", &info.fallback),
+        width: THUMBNAIL_SIZE.0,
+        height: THUMBNAIL_SIZE.1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    const HASH: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_target_widths() {
+        assert_eq!(target_widths(100), vec![100]);
+        assert_eq!(target_widths(640), vec![320, 640]);
+        assert_eq!(target_widths(800), vec![320, 640, 800]);
+        assert_eq!(target_widths(4000), vec![320, 640, 960, 1280]);
+    }
+
+    #[test]
+    fn test_derive() {
+        let mut buf = Vec::new();
+        RgbImage::from_pixel(700, 350, Rgb([200, 30, 30]))
+            .write_to(&mut Cursor::new(&mut buf), ImageFormat::Jpeg)
+            .unwrap();
+
+        let (info, files) = derive(HASH, &buf).unwrap();
+        assert_eq!(
+            info,
+            ImageInfo {
+                width: 700,
+                height: 350,
+                widths: vec![320, 640, 700],
+                fallback: "jpg".to_owned(),
+            }
+        );
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names.len(), 8);
+        assert!(names.contains(&format!("{}-320.webp", HASH).as_str()));
+        assert!(names.contains(&format!("{}-thumb.jpg", HASH).as_str()));
+
+        let (_, data) = &files[1];
+        let image = image::load_from_memory(data).unwrap();
+        assert_eq!(image.dimensions(), (320, 160));
+        let (_, data) = files.last().unwrap();
+        let image = image::load_from_memory(data).unwrap();
+        assert_eq!(image.dimensions(), THUMBNAIL_SIZE);
+    }
+
+    #[test]
+    fn test_rewrite_images() {
+        let src = format!("/uploads/2c/{}.jpg", HASH);
+        let html = format!(
+            "<p><img src='{}' alt='cat' /> <img src='https://example.com/a.png' alt='' /></p>",
+            src
+        );
+        assert_eq!(hashes_in(&html), vec![HASH.to_owned()]);
+
+        let info = ImageInfo {
+            width: 700,
+            height: 350,
+            widths: vec![320, 700],
+            fallback: "jpg".to_owned(),
+        };
+        let infos = HashMap::from([(HASH.to_owned(), info)]);
+        let result = rewrite_images(&html, &infos);
+        let d = URL_PREFIX;
+        assert_eq!(
+            result,
+            format!(
+                "<p><picture><source type='image/webp' srcset='{d}/{h}-320.webp 320w, {d}/{h}-700.webp 700w' sizes='{s}'>\
+                 <img src='{d}/{h}-700.jpg' srcset='{d}/{h}-320.jpg 320w, {d}/{h}-700.jpg 700w' sizes='{s}' width='700' height='350' alt='cat' loading='lazy' decoding='async' /></picture> \
+                 <img src='https://example.com/a.png' alt='' loading='lazy' /></p>",
+                d = d,
+                h = HASH,
+                s = SIZES
+            )
+        );
+
+        // 还没有生成衍生文件时保留原图
+        let result = rewrite_images(&html, &HashMap::new());
+        assert!(result.contains(&format!("<img src='{}' alt='cat' loading='lazy' />", src)));
+    }
+
+    #[test]
+    fn test_cover_hash() {
+        let markdown = format!(
+            "text ![a](https://example.com/x.png) and ![b](/uploads/2c/{}.png \"title\")",
+            HASH
+        );
+        assert_eq!(cover_hash(&markdown), Some(HASH));
+        assert_eq!(cover_hash("![a](/uploads/xx/notahash.png)"), None);
+        assert_eq!(cover_hash("no images"), None);
+    }
+}
@@ -0,0 +1,585 @@
+//!
+//! Markdown 导入: `blog import dir --author login` 把 Hexo/Jekyll/Hugo 的文章目录导入成文章.
+//! 文章开头的 YAML(`---`) 或 TOML(`+++`) front matter 提供标题、日期、标签、slug 和草稿标记,
+//! 导入过的 slug 记录在 `article_import` 集合中, 再次导入时只更新有变化的文章
+//!
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    options::ReplaceOptions,
+    Database,
+};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::db;
+use crate::error::{AppError, OptionalExt, Result};
+use crate::export;
+
+pub struct Options {
+    pub dir: PathBuf,
+    /// front matter 中没有作者或作者没有对应关系时使用的用户(登录名或 id)
+    pub author: String,
+    /// front matter 中的作者 -> 用户(登录名或 id)
+    pub author_map: Vec<(String, String)>,
+    /// 只检查和报告, 不写入数据库
+    pub dry_run: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Create,
+    Update,
+    Unchanged,
+    /// 草稿不导入
+    Draft,
+    Failed(String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Create => write!(f, "新建"),
+            Action::Update => write!(f, "更新"),
+            Action::Unchanged => write!(f, "无变化"),
+            Action::Draft => write!(f, "草稿"),
+            Action::Failed(reason) => write!(f, "失败: {}", reason),
+        }
+    }
+}
+
+pub struct Entry {
+    pub path: PathBuf,
+    pub slug: String,
+    pub title: String,
+    pub action: Action,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub entries: Vec<Entry>,
+}
+
+impl Report {
+    pub fn count(&self, f: impl Fn(&Action) -> bool) -> usize {
+        self.entries.iter().filter(|e| f(&e.action)).count()
+    }
+}
+
+///
+/// Model: ImportRecord
+/// Db table: article_import
+///
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportRecord {
+    #[serde(rename = "_id")]
+    slug: String,
+    article_id: ObjectId,
+    /// 导入的内容的哈希, 相同时跳过
+    hash: String,
+    source: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    imported_time: DateTime<Utc>,
+}
+
+/// 标签可以是逗号分隔的字符串, 也可以是列表
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Tags {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FrontMatter {
+    title: Option<String>,
+    date: Option<String>,
+    tags: Option<Tags>,
+    slug: Option<String>,
+    #[serde(default)]
+    draft: bool,
+    /// Jekyll 用 `published: false` 表示草稿
+    published: Option<bool>,
+    author: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Post {
+    slug: String,
+    title: String,
+    tags: String,
+    content: String,
+    created_time: DateTime<Utc>,
+    author: Option<String>,
+    draft: bool,
+}
+
+/// 解析 `--map-author` 参数: `作者=登录名`
+pub fn parse_author_map(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, login)) if !name.trim().is_empty() && !login.trim().is_empty() => {
+            Ok((name.trim().to_owned(), login.trim().to_owned()))
+        }
+        _ => Err(format!("格式应为 作者=登录名: {}", s)),
+    }
+}
+
+/// 拆分 front matter 和正文
+fn split_front_matter(text: &str) -> std::result::Result<(FrontMatter, &str), String> {
+    let text = text.trim_start_matches('\u{feff}');
+    let first = text.lines().next().unwrap_or_default().trim_end();
+    let (delimiter, yaml) = match first {
+        "---" => ("---", true),
+        "+++" => ("+++", false),
+        _ => return Ok((FrontMatter::default(), text)),
+    };
+    let start = text.find('\n').map_or(text.len(), |i| i + 1);
+    let mut offset = start;
+    for line in text[start..].split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if trimmed == delimiter || (yaml && trimmed == "...") {
+            let matter = &text[start..offset];
+            let body = &text[offset + line.len()..];
+            let value = if yaml {
+                let value: serde_yaml::Value =
+                    serde_yaml::from_str(matter).map_err(|err| err.to_string())?;
+                if value.is_null() {
+                    return Ok((FrontMatter::default(), body));
+                }
+                serde_json::to_value(value).map_err(|err| err.to_string())?
+            } else {
+                let table: toml::Table = toml::from_str(matter).map_err(|err| err.to_string())?;
+                toml_to_json(toml::Value::Table(table))
+            };
+            let front_matter = serde_json::from_value(value).map_err(|err| err.to_string())?;
+            return Ok((front_matter, body));
+        }
+        offset += line.len();
+    }
+    Err(format!("front matter 没有结束的 {}", delimiter))
+}
+
+/// TOML 的日期时间转换成字符串, 和 YAML 一样交给 [`parse_date`] 处理
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(s) => s.into(),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => f.into(),
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(d) => d.to_string().into(),
+        toml::Value::Array(a) => a.into_iter().map(toml_to_json).collect(),
+        toml::Value::Table(t) => t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect(),
+    }
+}
+
+/// 支持 RFC 3339 和常见的 `2019-03-01 12:00:00` 等格式, 没有时区的按配置的时区
+fn parse_date<Tz: TimeZone>(s: &str, tz: &Tz) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Some(time.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S %z", "%Y-%m-%d %H:%M:%S%.f %z"] {
+        if let Ok(time) = DateTime::parse_from_str(s, format) {
+            return Some(time.with_timezone(&Utc));
+        }
+    }
+    let naive = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+        "%Y/%m/%d %H:%M:%S",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+    .or_else(|| {
+        ["%Y-%m-%d", "%Y/%m/%d"]
+            .iter()
+            .find_map(|format| NaiveDate::parse_from_str(s, format).ok())
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+    })?;
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// Jekyll 的文件名带日期前缀: `2019-03-01-hello.md`
+fn split_date_prefix(stem: &str) -> (Option<&str>, &str) {
+    let bytes = stem.as_bytes();
+    let is_date = bytes.len() > 11
+        && bytes[..10].iter().enumerate().all(|(i, b)| {
+            if i == 4 || i == 7 {
+                *b == b'-'
+            } else {
+                b.is_ascii_digit()
+            }
+        })
+        && bytes[10] == b'-';
+    if is_date {
+        (Some(&stem[..10]), &stem[11..])
+    } else {
+        (None, stem)
+    }
+}
+
+/// 没有指定 slug 时使用文件名, Hugo 的 `post/hello/index.md` 使用目录名
+fn path_slug(path: &Path) -> (Option<&str>, &str) {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    if stem == "index" || stem == "_index" {
+        let dir = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+        return (None, dir);
+    }
+    split_date_prefix(stem)
+}
+
+fn parse_post<Tz: TimeZone>(path: &Path, text: &str, tz: &Tz) -> std::result::Result<Post, String> {
+    let (front_matter, body) = split_front_matter(text)?;
+    let (file_date, file_slug) = path_slug(path);
+
+    let slug = front_matter
+        .slug
+        .as_deref()
+        .unwrap_or(file_slug)
+        .trim()
+        .to_owned();
+    if slug.is_empty() {
+        return Err("缺少 slug".to_owned());
+    }
+    let title = front_matter.title.unwrap_or_default().trim().to_owned();
+    if title.is_empty() {
+        return Err("缺少标题".to_owned());
+    }
+    if title.chars().count() > 200 {
+        return Err("标题最多200个字".to_owned());
+    }
+    let content = body.trim().to_owned();
+    if content.is_empty() {
+        return Err("内容是空的".to_owned());
+    }
+    if content.chars().count() > 100000 {
+        return Err("内容最多100000个字".to_owned());
+    }
+    let tags = match front_matter.tags {
+        Some(Tags::One(tags)) => tags.split(',').map(|t| t.trim().to_owned()).collect(),
+        Some(Tags::Many(tags)) => tags,
+        None => Vec::new(),
+    };
+    let tags = tags
+        .iter()
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>()
+        .join(",");
+    if tags.chars().count() > 200 {
+        return Err("标签最多200个字".to_owned());
+    }
+    let date = front_matter
+        .date
+        .as_deref()
+        .or(file_date)
+        .ok_or_else(|| "缺少日期".to_owned())?;
+    let created_time = parse_date(date, tz).ok_or_else(|| format!("无法识别的日期: {}", date))?;
+    // Hexo 的草稿放在 _drafts 目录下
+    let in_drafts = path.components().any(|c| c.as_os_str() == "_drafts");
+
+    Ok(Post {
+        slug,
+        title,
+        tags,
+        content,
+        created_time,
+        author: front_matter.author,
+        draft: front_matter.draft || front_matter.published == Some(false) || in_drafts,
+    })
+}
+
+/// 用户的登录名(可以带上 provider, 见 [`db::find_user_id_by_login`])或 id
+async fn resolve_user(user: &str, mongo: &Database) -> Result<ObjectId> {
+    if let Ok(id) = db::object_id(user) {
+        return Ok(db::find_user_by_id(&id.to_string(), mongo).await?.id);
+    }
+    match db::find_user_id_by_login(user, mongo).await.optional()? {
+        Some(user_id) => Ok(user_id),
+        None => Err(AppError::bad_request(format!("用户不存在: {}", user))),
+    }
+}
+
+fn content_hash(post: &Post, author_id: ObjectId) -> String {
+    let mut hasher = Sha256::new();
+    for part in [
+        post.title.as_str(),
+        &post.tags,
+        &post.content,
+        &post.created_time.to_rfc3339(),
+        &author_id.to_hex(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub async fn run(options: &Options, mongo: &Database) -> Result<Report> {
+    let default_author = resolve_user(&options.author, mongo).await?;
+    let mut authors = HashMap::new();
+    for (name, user) in &options.author_map {
+        authors.insert(name.as_str(), resolve_user(user, mongo).await?);
+    }
+
+    let mut files = Vec::new();
+    export::collect_files(&options.dir, &mut files)?;
+    files.retain(|f| {
+        f.extension()
+            .is_some_and(|ext| ext == "md" || ext == "markdown")
+    });
+    files.sort();
+
+    let tz = config::timezone();
+    let records = mongo.collection::<ImportRecord>("article_import");
+    let articles = mongo.collection::<mongodb::bson::Document>("article");
+    let mut slugs = HashSet::new();
+    let mut report = Report::default();
+    for path in files {
+        let relative = path
+            .strip_prefix(&options.dir)
+            .unwrap_or(&path)
+            .to_path_buf();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => {
+                report.entries.push(Entry {
+                    path: relative,
+                    slug: String::new(),
+                    title: String::new(),
+                    action: Action::Failed(err.to_string()),
+                });
+                continue;
+            }
+        };
+        let post = match parse_post(&relative, &text, &tz) {
+            Ok(post) => post,
+            Err(reason) => {
+                report.entries.push(Entry {
+                    path: relative,
+                    slug: String::new(),
+                    title: String::new(),
+                    action: Action::Failed(reason),
+                });
+                continue;
+            }
+        };
+        let mut entry = Entry {
+            path: relative,
+            slug: post.slug.clone(),
+            title: post.title.clone(),
+            action: Action::Unchanged,
+        };
+        if !slugs.insert(post.slug.clone()) {
+            entry.action = Action::Failed("slug 重复".to_owned());
+            report.entries.push(entry);
+            continue;
+        }
+        if post.draft {
+            entry.action = Action::Draft;
+            report.entries.push(entry);
+            continue;
+        }
+
+        let author_id = post
+            .author
+            .as_deref()
+            .and_then(|name| authors.get(name).copied())
+            .unwrap_or(default_author);
+        let hash = content_hash(&post, author_id);
+        let record = records.find_one(doc! {"_id": &post.slug}, None).await?;
+        // 文章被删除后重新创建
+        let existing = match &record {
+            Some(record) => articles
+                .find_one(doc! {"_id": record.article_id}, None)
+                .await?
+                .map(|_| record.article_id),
+            None => None,
+        };
+        entry.action = match (existing, &record) {
+            (Some(_), Some(record)) if record.hash == hash => Action::Unchanged,
+            (Some(_), _) => Action::Update,
+            (None, _) => Action::Create,
+        };
+        if options.dry_run || entry.action == Action::Unchanged {
+            report.entries.push(entry);
+            continue;
+        }
+
+        let article_id = match existing {
+            Some(article_id) => {
+                let update = doc! {
+                    "$set":{
+                        "title": &post.title,
+                        "raw_content": &post.content,
+                        "tags": &post.tags,
+                        "author_id": author_id,
+                        "created_time": post.created_time,
+                        "updated_time": Utc::now(),
+                    }
+                };
+                articles
+                    .update_one(doc! {"_id": article_id}, update, None)
+                    .await?;
+                article_id
+            }
+            None => {
+                let article_id = ObjectId::new();
+                let article = doc! {
+                    "_id": article_id,
+                    "title": &post.title,
+                    "raw_content": &post.content,
+                    "tags": &post.tags,
+                    "author_id": author_id,
+                    "created_time": post.created_time,
+                    "updated_time": post.created_time,
+                    "status": 1,
+                };
+                articles.insert_one(article, None).await?;
+                article_id
+            }
+        };
+        let record = ImportRecord {
+            slug: post.slug,
+            article_id,
+            hash,
+            source: entry.path.to_string_lossy().into_owned(),
+            imported_time: Utc::now(),
+        };
+        records
+            .replace_one(
+                doc! {"_id": &record.slug},
+                &record,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        report.entries.push(entry);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn tz() -> FixedOffset {
+        FixedOffset::east_opt(8 * 3600).unwrap()
+    }
+
+    #[test]
+    fn test_yaml_front_matter() {
+        let text = "\u{feff}---\ntitle: \"Hello: World\"\ndate: 2019-03-01 12:30:00\ntags:\n  - rust\n  - web\nauthor: joey\n---\n\n正文\n";
+        let post = parse_post(Path::new("posts/hello.md"), text, &tz()).unwrap();
+        assert_eq!(
+            post,
+            Post {
+                slug: "hello".to_owned(),
+                title: "Hello: World".to_owned(),
+                tags: "rust,web".to_owned(),
+                content: "正文".to_owned(),
+                created_time: Utc.with_ymd_and_hms(2019, 3, 1, 4, 30, 0).unwrap(),
+                author: Some("joey".to_owned()),
+                draft: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_toml_front_matter() {
+        let text = "+++\ntitle = \"Hugo\"\ndate = 2020-01-02T03:04:05Z\ntags = \"a, b\"\nslug = \"custom\"\ndraft = true\n+++\nbody";
+        let post = parse_post(Path::new("post/x/index.md"), text, &tz()).unwrap();
+        assert_eq!(post.slug, "custom");
+        assert_eq!(post.tags, "a,b");
+        assert_eq!(
+            post.created_time,
+            Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap()
+        );
+        assert!(post.draft);
+
+        let text = "+++\ntitle = \"Hugo\"\ndate = 2020-01-02\n+++\nbody";
+        let post = parse_post(Path::new("post/bundle/index.md"), text, &tz()).unwrap();
+        assert_eq!(post.slug, "bundle");
+        assert_eq!(
+            post.created_time,
+            Utc.with_ymd_and_hms(2020, 1, 1, 16, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_jekyll_file_name() {
+        let text = "---\ntitle: Jekyll\npublished: false\n---\nbody";
+        let post = parse_post(Path::new("_posts/2018-05-06-my-post.md"), text, &tz()).unwrap();
+        assert_eq!(post.slug, "my-post");
+        assert_eq!(
+            post.created_time,
+            Utc.with_ymd_and_hms(2018, 5, 5, 16, 0, 0).unwrap()
+        );
+        assert!(post.draft);
+
+        let post = parse_post(
+            Path::new("_drafts/idea.md"),
+            "---\ntitle: t\ndate: 2018-05-06\n---\nx",
+            &tz(),
+        )
+        .unwrap();
+        assert!(post.draft);
+
+        assert_eq!(
+            parse_post(Path::new("a.md"), "no front matter", &tz()).unwrap_err(),
+            "缺少标题"
+        );
+        assert_eq!(
+            parse_post(Path::new("a.md"), "---\ntitle: t\n", &tz()).unwrap_err(),
+            "front matter 没有结束的 ---"
+        );
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let expected = Utc.with_ymd_and_hms(2019, 3, 1, 4, 30, 0).unwrap();
+        for s in [
+            "2019-03-01 12:30:00",
+            "2019-03-01T12:30:00",
+            "2019-03-01 12:30",
+            "2019/03/01 12:30:00",
+            "2019-03-01T12:30:00+08:00",
+            "2019-03-01 12:30:00 +0800",
+            "2019-03-01T04:30:00Z",
+        ] {
+            assert_eq!(parse_date(s, &tz()), Some(expected), "{}", s);
+        }
+        assert_eq!(parse_date("yesterday", &tz()), None);
+    }
+
+    #[test]
+    fn test_parse_author_map() {
+        assert_eq!(
+            parse_author_map("Joey = joeyscat"),
+            Ok(("Joey".to_owned(), "joeyscat".to_owned()))
+        );
+        assert!(parse_author_map("joeyscat").is_err());
+        assert!(parse_author_map("=joeyscat").is_err());
+    }
+}
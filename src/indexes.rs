@@ -0,0 +1,351 @@
+//!
+//! 索引: 每个集合需要的索引在 [`declared`] 中汇总(各模块的 `indexes()` 声明自己的集合),
+//! 启动时和数据库中已有的索引对比, 创建缺少的, 报告多余的和定义不一致的(不会自动删除)
+//!
+use std::fmt;
+
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::ErrorKind,
+    options::IndexOptions,
+    Database, IndexModel,
+};
+use tracing::{info, warn};
+
+use crate::audit;
+use crate::error::{AppError, Result};
+use crate::mail;
+use crate::media;
+use crate::newsletter;
+use crate::notification;
+use crate::rate_limit;
+use crate::session_store;
+
+/// `user` 集合的索引
+fn user_indexes() -> Vec<IndexModel> {
+    vec![
+        // 旧版 gitee 登录按 inner.id 查找用户
+        IndexModel::builder().keys(doc! {"inner.id": 1}).build(),
+        IndexModel::builder().keys(doc! {"role": 1}).build(),
+        // 查找被封禁的用户, 以及每周摘要的收件人
+        IndexModel::builder()
+            .keys(doc! {"status": 1, "weekly_digest": 1})
+            .build(),
+    ]
+}
+
+/// `identity` 集合的索引
+fn identity_indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"provider": 1, "provider_uid": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        // 按登录名查找用户, 可以带上 provider
+        IndexModel::builder()
+            .keys(doc! {"login": 1, "provider": 1})
+            .build(),
+        IndexModel::builder().keys(doc! {"user_id": 1}).build(),
+    ]
+}
+
+/// `article` 集合的索引
+fn article_indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"status": 1, "created_time": -1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"author_id": 1, "created_time": -1})
+            .build(),
+        IndexModel::builder().keys(doc! {"tags": 1}).build(),
+        // 评论审核队列
+        IndexModel::builder()
+            .keys(doc! {"comments.status": 1})
+            .build(),
+    ]
+}
+
+/// `article_import` 集合的索引, 导入的 slug 是主键, 这里只需要按文章查找
+fn article_import_indexes() -> Vec<IndexModel> {
+    vec![IndexModel::builder().keys(doc! {"article_id": 1}).build()]
+}
+
+/// 全部集合的索引
+pub fn declared() -> Vec<(&'static str, Vec<IndexModel>)> {
+    vec![
+        ("user", user_indexes()),
+        ("identity", identity_indexes()),
+        ("article", article_indexes()),
+        ("article_import", article_import_indexes()),
+        ("session", session_store::indexes()),
+        ("audit", audit::indexes()),
+        ("notification", notification::indexes()),
+        ("subscriber", newsletter::indexes()),
+        ("media", media::indexes()),
+        ("mail_queue", mail::indexes()),
+        ("rate_limit", rate_limit::indexes()),
+    ]
+}
+
+/// 索引键的值统一成字符串比较, 服务器返回的 `1` 可能是 Int32、Int64 或 Double
+fn key_spec(keys: &Document) -> Vec<(String, String)> {
+    keys.iter()
+        .map(|(field, value)| {
+            let value = match value {
+                Bson::Int32(i) => i.to_string(),
+                Bson::Int64(i) => i.to_string(),
+                Bson::Double(f) => (*f as i64).to_string(),
+                Bson::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (field.clone(), value)
+        })
+        .collect()
+}
+
+/// 索引的名字, 没有指定时和 MongoDB 默认的一样: `字段_方向` 用 `_` 连接
+pub fn index_name(index: &IndexModel) -> String {
+    if let Some(name) = index.options.as_ref().and_then(|o| o.name.clone()) {
+        return name;
+    }
+    key_spec(&index.keys)
+        .iter()
+        .map(|(field, value)| format!("{}_{}", field, value))
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// 会影响查询结果或数据的选项
+fn same_options(a: &IndexModel, b: &IndexModel) -> bool {
+    let options = |index: &IndexModel| {
+        let o = index.options.clone().unwrap_or_default();
+        (
+            o.unique.unwrap_or(false),
+            o.sparse.unwrap_or(false),
+            o.expire_after,
+            o.partial_filter_expression.map(|f| key_spec(&f)),
+        )
+    };
+    options(a) == options(b)
+}
+
+fn is_unique(index: &IndexModel) -> bool {
+    index.options.as_ref().and_then(|o| o.unique) == Some(true)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Kind {
+    /// 数据库中没有, 检查时只报告, 执行时创建
+    Missing,
+    Created,
+    /// 创建失败, 例如唯一索引的字段有重复的数据
+    Failed(String),
+    /// 数据库中有同样的键, 但选项和声明的不一致, 需要手动删除后重新创建
+    Conflict,
+    /// 数据库中有, 但没有声明
+    Extra,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub collection: String,
+    pub name: String,
+    pub kind: Kind,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match &self.kind {
+            Kind::Missing => "缺少".to_owned(),
+            Kind::Created => "已创建".to_owned(),
+            Kind::Failed(err) => format!("创建失败: {}", err),
+            Kind::Conflict => "选项和声明的不一致".to_owned(),
+            Kind::Extra => "没有声明".to_owned(),
+        };
+        write!(f, "{}.{}\t{}", self.collection, self.name, kind)
+    }
+}
+
+/// 对比声明的和已有的索引, 返回缺少的索引(按声明的顺序)和其他发现
+fn plan<'a>(
+    collection: &str,
+    declared: &'a [IndexModel],
+    existing: &[IndexModel],
+) -> (Vec<&'a IndexModel>, Vec<Finding>) {
+    let mut missing = Vec::new();
+    let mut findings = Vec::new();
+    for index in declared {
+        match existing
+            .iter()
+            .find(|e| key_spec(&e.keys) == key_spec(&index.keys))
+        {
+            None => missing.push(index),
+            Some(e) if !same_options(index, e) => findings.push(Finding {
+                collection: collection.to_owned(),
+                name: index_name(e),
+                kind: Kind::Conflict,
+            }),
+            Some(_) => {}
+        }
+    }
+    for e in existing {
+        let name = index_name(e);
+        let is_declared = declared
+            .iter()
+            .any(|index| key_spec(&index.keys) == key_spec(&e.keys));
+        if name != "_id_" && !is_declared {
+            findings.push(Finding {
+                collection: collection.to_owned(),
+                name,
+                kind: Kind::Extra,
+            });
+        }
+    }
+    (missing, findings)
+}
+
+async fn existing_indexes(mongo: &Database, collection: &str) -> Result<Vec<IndexModel>> {
+    let mut cursor = match mongo
+        .collection::<Document>(collection)
+        .list_indexes(None)
+        .await
+    {
+        Ok(cursor) => cursor,
+        // 集合还不存在(NamespaceNotFound)
+        Err(err) if matches!(err.kind.as_ref(), ErrorKind::Command(e) if e.code == 26) => {
+            return Ok(Vec::new())
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut result = Vec::new();
+    while let Some(index) = cursor.next().await {
+        result.push(index?);
+    }
+    Ok(result)
+}
+
+///
+/// 对比并创建缺少的索引. `apply` 为 false 时只检查;
+/// `strict` 时唯一索引创建失败返回错误(其他索引创建失败只记录)
+///
+pub async fn reconcile(mongo: &Database, apply: bool, strict: bool) -> Result<Vec<Finding>> {
+    let mut report = Vec::new();
+    for (collection, declared) in declared() {
+        let existing = existing_indexes(mongo, collection).await?;
+        let (missing, findings) = plan(collection, &declared, &existing);
+        for index in missing {
+            let name = index_name(index);
+            let kind = if !apply {
+                Kind::Missing
+            } else {
+                match mongo
+                    .collection::<Document>(collection)
+                    .create_index(index.clone(), None)
+                    .await
+                {
+                    Ok(_) => {
+                        info!("index created: {}.{}", collection, name);
+                        Kind::Created
+                    }
+                    Err(err) if strict && is_unique(index) => {
+                        return Err(AppError::internal(format!(
+                            "无法创建唯一索引 {}.{}: {}",
+                            collection, name, err
+                        )));
+                    }
+                    Err(err) => {
+                        warn!("create index {}.{} failed: {}", collection, name, err);
+                        Kind::Failed(err.to_string())
+                    }
+                }
+            };
+            report.push(Finding {
+                collection: collection.to_owned(),
+                name,
+                kind,
+            });
+        }
+        for finding in findings {
+            warn!("index {}", finding);
+            report.push(finding);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn index(keys: Document, options: Option<IndexOptions>) -> IndexModel {
+        IndexModel::builder().keys(keys).options(options).build()
+    }
+
+    #[test]
+    fn test_index_name() {
+        assert_eq!(
+            index_name(&index(doc! {"owner_id": 1, "created_time": -1}, None)),
+            "owner_id_1_created_time_-1"
+        );
+        let named = IndexOptions::builder()
+            .name("pending_ttl".to_owned())
+            .build();
+        assert_eq!(
+            index_name(&index(doc! {"created_time": 1}, Some(named))),
+            "pending_ttl"
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let unique = || Some(IndexOptions::builder().unique(true).build());
+        let declared = vec![
+            index(doc! {"email": 1}, unique()),
+            index(doc! {"status": 1, "created_time": -1}, None),
+            index(doc! {"created_time": 1}, None),
+        ];
+        let existing = vec![
+            index(
+                doc! {"_id": 1},
+                Some(IndexOptions::builder().name("_id_".to_owned()).build()),
+            ),
+            // 服务器返回的方向可能是 Int64/Double
+            index(doc! {"email": 1i64}, unique()),
+            index(
+                doc! {"created_time": 1.0},
+                Some(
+                    IndexOptions::builder()
+                        .expire_after(Duration::from_secs(60))
+                        .build(),
+                ),
+            ),
+            index(doc! {"legacy": 1}, None),
+        ];
+        let (missing, findings) = plan("subscriber", &declared, &existing);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(index_name(missing[0]), "status_1_created_time_-1");
+        assert_eq!(
+            findings,
+            vec![
+                Finding {
+                    collection: "subscriber".to_owned(),
+                    name: "created_time_1".to_owned(),
+                    kind: Kind::Conflict,
+                },
+                Finding {
+                    collection: "subscriber".to_owned(),
+                    name: "legacy_1".to_owned(),
+                    kind: Kind::Extra,
+                },
+            ]
+        );
+
+        // 字段顺序不同是不同的索引
+        let reversed = [index(doc! {"created_time": -1, "status": 1}, None)];
+        let (missing, _) = plan("subscriber", &reversed, &declared);
+        assert_eq!(missing.len(), 1);
+    }
+}
@@ -0,0 +1,33 @@
+//!
+//! 博客的全部模块, 服务器(`blog`)和管理工具(`blog-admin`)两个程序共用
+//!
+pub mod admin;
+pub mod audit;
+pub mod backup;
+pub mod config;
+pub mod csrf;
+pub mod db;
+pub mod error;
+pub mod export;
+pub mod gitee;
+pub mod github;
+pub mod handler;
+pub mod imaging;
+pub mod import;
+pub mod indexes;
+pub mod mail;
+pub mod media;
+pub mod middleware;
+pub mod migrate;
+pub mod model;
+pub mod moderation;
+pub mod newsletter;
+pub mod notification;
+pub mod password;
+pub mod policy;
+pub mod rate_limit;
+pub mod session_store;
+pub mod spam;
+pub mod storage;
+pub mod totp;
+pub mod validate;
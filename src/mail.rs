@@ -0,0 +1,575 @@
+//!
+//! 邮件: 评论/回复通知和每周摘要. 邮件先渲染好放进 `mail_queue` 集合,
+//! 由后台任务通过 [`Transport`] 发送, 失败后按指数退避重试
+//!
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
+use hmac::{Hmac, Mac};
+use lettre::{
+    message::{
+        header::{HeaderName, HeaderValue},
+        Mailbox, MultiPart,
+    },
+    transport::smtp::{authentication::Credentials, client::Tls},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument, UpdateOptions},
+    Database, IndexModel,
+};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tera::Context;
+use tracing::{info, warn};
+
+use crate::config::{self, MailConfig};
+use crate::db;
+use crate::error::{AppError, Result};
+use crate::handler::TEMPLATES;
+use crate::model::User;
+use crate::notification::{Kind, Notification};
+
+/// 每周摘要的邮件类型, 评论和回复通知使用 [`Kind`] 的名称
+pub const DIGEST: &str = "digest";
+
+/// 退订链接, 带有签名, 不需要登录
+pub const UNSUBSCRIBE_PATH: &str = "/unsubscribe";
+
+/// 取出一封邮件后锁定的分钟数, 超时未完成(例如进程退出)会被重新发送
+const LEASE_MINUTES: i64 = 10;
+
+/// 两次重试之间的最长间隔
+const MAX_RETRY_DELAY_MINUTES: i64 = 6 * 60;
+
+const LIST_UNSUBSCRIBE: HeaderName = HeaderName::new_from_ascii_str("List-Unsubscribe");
+const LIST_UNSUBSCRIBE_POST: HeaderName = HeaderName::new_from_ascii_str("List-Unsubscribe-Post");
+
+///
+/// Model: QueuedMail
+/// Db table: mail_queue
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMail {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    /// 一键退订地址, 放在 List-Unsubscribe 头中
+    pub unsubscribe_url: Option<String>,
+    /// pending/sent/failed
+    pub status: String,
+    pub attempts: u32,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+    pub sent_time: Option<bson::DateTime>,
+}
+
+impl QueuedMail {
+    pub const PENDING: &'static str = "pending";
+    pub const SENT: &'static str = "sent";
+    pub const FAILED: &'static str = "failed";
+}
+
+/// 发送邮件的方式, 默认是 SMTP
+#[poem::async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, mail: &QueuedMail) -> Result<()>;
+}
+
+pub struct SmtpTransport {
+    from: Mailbox,
+    inner: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn from_config(config: &MailConfig) -> Result<Self> {
+        let builder = match config.smtp_tls.as_str() {
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)
+                .map_err(AppError::internal)?,
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+                .map_err(AppError::internal)?,
+            _ => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+                .tls(Tls::None),
+        };
+        let mut builder = builder.port(config.smtp_port);
+        if !config.smtp_username.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                config.smtp_username.clone(),
+                config.smtp_password.clone(),
+            ));
+        }
+        Ok(Self {
+            from: config.from.parse().map_err(AppError::internal)?,
+            inner: builder.build(),
+        })
+    }
+}
+
+#[poem::async_trait]
+impl Transport for SmtpTransport {
+    async fn send(&self, mail: &QueuedMail) -> Result<()> {
+        let message = build_message(&self.from, mail)?;
+        self.inner.send(message).await.map_err(AppError::internal)?;
+        Ok(())
+    }
+}
+
+/// 组装邮件: 纯文本和 HTML 两个版本, 有退订地址时加上一键退订(RFC 8058)的头
+pub fn build_message(from: &Mailbox, mail: &QueuedMail) -> Result<Message> {
+    let mut builder = Message::builder()
+        .from(from.clone())
+        .to(mail.to.parse().map_err(AppError::internal)?)
+        .subject(&mail.subject);
+    if let Some(url) = &mail.unsubscribe_url {
+        builder = builder
+            .raw_header(HeaderValue::new(LIST_UNSUBSCRIBE, format!("<{}>", url)))
+            .raw_header(HeaderValue::new(
+                LIST_UNSUBSCRIBE_POST,
+                "List-Unsubscribe=One-Click".to_owned(),
+            ));
+    }
+    builder
+        .multipart(MultiPart::alternative_plain_html(
+            mail.text.clone(),
+            mail.html.clone(),
+        ))
+        .map_err(AppError::internal)
+}
+
+/// 第 `attempts` 次发送失败后的重试间隔, 达到最大次数后返回 None
+pub fn retry_delay(attempts: u32, max_attempts: u32) -> Option<chrono::Duration> {
+    if attempts >= max_attempts {
+        return None;
+    }
+    let minutes = 1i64 << attempts.saturating_sub(1).min(16);
+    Some(chrono::Duration::minutes(
+        minutes.min(MAX_RETRY_DELAY_MINUTES),
+    ))
+}
+
+/// 邮件链接(退订、确认订阅)中的签名, 不需要登录就能操作. `purpose` 区分不同的链接
+pub fn sign_token(secret: &str, id: ObjectId, purpose: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("{}:{}", id, purpose).as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn verify_token(secret: &str, id: ObjectId, purpose: &str, token: &str) -> bool {
+    let expected = sign_token(secret, id, purpose);
+    bool::from(expected.as_bytes().ct_eq(token.as_bytes()))
+}
+
+fn unsubscribe_url(config: &MailConfig, user_id: ObjectId, kind: &str) -> String {
+    format!(
+        "{}{}?uid={}&kind={}&token={}",
+        config.site_url.trim_end_matches('/'),
+        UNSUBSCRIBE_PATH,
+        user_id,
+        kind,
+        sign_token(&config.secret, user_id, kind)
+    )
+}
+
+/// 用户是否接收某类邮件: 评论和回复通知默认接收, 每周摘要需要订阅
+pub fn wants(user: &User, kind: &str) -> bool {
+    if kind == DIGEST {
+        user.weekly_digest
+    } else {
+        !user.muted_emails.iter().any(|k| k == kind)
+    }
+}
+
+/// 退订某类邮件
+pub async fn unsubscribe(user: &User, kind: &str, mongo: &Database) -> Result<()> {
+    let mut muted: Vec<&str> = user.muted_emails.iter().map(String::as_str).collect();
+    let weekly_digest = user.weekly_digest && kind != DIGEST;
+    if kind != DIGEST && !muted.contains(&kind) {
+        muted.push(kind);
+    }
+    db::update_email_prefs(user.id, &muted, weekly_digest, mongo).await?;
+    info!("user {} unsubscribed from {} emails", user.username, kind);
+    Ok(())
+}
+
+/// `mail_queue` 集合的索引, 启动时由 [`crate::indexes`] 创建
+pub fn indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"status": 1, "next_attempt_at": 1})
+            .build(),
+        // 发送成功的邮件保留 30 天
+        IndexModel::builder()
+            .keys(doc! {"sent_time": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(30 * 24 * 3600))
+                    .build(),
+            )
+            .build(),
+    ]
+}
+
+/// 渲染 `email/{template}.html` 和 `email/{template}.txt` 并放进发送队列
+pub async fn enqueue(
+    to: String,
+    subject: String,
+    template: &str,
+    mut context: Context,
+    unsubscribe_url: Option<String>,
+    mongo: &Database,
+) -> Result<()> {
+    let config = &config::get().mail;
+    context.insert("subject", &subject);
+    context.insert("site_url", config.site_url.trim_end_matches('/'));
+    context.insert("unsubscribe_url", &unsubscribe_url);
+    let now = Utc::now();
+    let mail = QueuedMail {
+        id: ObjectId::new(),
+        to,
+        subject,
+        html: TEMPLATES.render(&format!("email/{}.html", template), &context)?,
+        text: TEMPLATES.render(&format!("email/{}.txt", template), &context)?,
+        unsubscribe_url,
+        status: QueuedMail::PENDING.to_owned(),
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        created_time: now,
+        sent_time: None,
+    };
+    mongo
+        .collection::<QueuedMail>("mail_queue")
+        .insert_one(mail, None)
+        .await?;
+    Ok(())
+}
+
+/// 站内通知的同时发送邮件, 需要开启邮件、用户有邮箱并且没有关闭该类邮件
+pub async fn notify(recipient: &User, notification: &Notification, mongo: &Database) -> Result<()> {
+    let config = &config::get().mail;
+    let to = match recipient.email() {
+        Some(email) if config.enabled && wants(recipient, &notification.kind) => email,
+        _ => return Ok(()),
+    };
+    let subject = if notification.kind == Kind::Reply.as_str() {
+        format!("{} 回复了你", notification.actor_name)
+    } else {
+        format!(
+            "{} 评论了《{}》",
+            notification.actor_name, notification.article_title
+        )
+    };
+
+    let mut context = Context::new();
+    context.insert("username", &recipient.username);
+    context.insert("actor_name", &notification.actor_name);
+    context.insert("article_id", &notification.article_id.to_string());
+    context.insert("article_title", &notification.article_title);
+    context.insert("excerpt", &notification.excerpt);
+    let unsubscribe_url = unsubscribe_url(config, recipient.id, &notification.kind);
+    enqueue(
+        to,
+        subject,
+        &notification.kind,
+        context,
+        Some(unsubscribe_url),
+        mongo,
+    )
+    .await
+}
+
+/// 取出一封到期的邮件并锁定一段时间, 多个实例同时运行时不会重复发送
+async fn claim(mongo: &Database) -> Result<Option<QueuedMail>> {
+    let now = Utc::now();
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! {"next_attempt_at": 1})
+        .return_document(ReturnDocument::After)
+        .build();
+    let mail = mongo
+        .collection::<QueuedMail>("mail_queue")
+        .find_one_and_update(
+            doc! {"status": QueuedMail::PENDING, "next_attempt_at": {"$lte": now}},
+            doc! {"$set": {"next_attempt_at": now + chrono::Duration::minutes(LEASE_MINUTES)}},
+            options,
+        )
+        .await?;
+    Ok(mail)
+}
+
+async fn deliver(transport: &dyn Transport, mail: QueuedMail, mongo: &Database) -> Result<()> {
+    let collection = mongo.collection::<QueuedMail>("mail_queue");
+    let update = match transport.send(&mail).await {
+        Ok(()) => {
+            info!("mail {} sent to {}", mail.id, mail.to);
+            doc! {"$set": {"status": QueuedMail::SENT, "sent_time": Utc::now()}}
+        }
+        Err(err) => {
+            let attempts = mail.attempts + 1;
+            let max_attempts = config::get().mail.max_attempts;
+            warn!(
+                "send mail {} to {} error (attempt {}/{}): {}",
+                mail.id, mail.to, attempts, max_attempts, err
+            );
+            match retry_delay(attempts, max_attempts) {
+                Some(delay) => doc! {"$set": {
+                    "attempts": attempts,
+                    "last_error": err.to_string(),
+                    "next_attempt_at": Utc::now() + delay,
+                }},
+                None => doc! {"$set": {
+                    "status": QueuedMail::FAILED,
+                    "attempts": attempts,
+                    "last_error": err.to_string(),
+                }},
+            }
+        }
+    };
+    collection
+        .update_one(doc! {"_id": mail.id}, update, None)
+        .await?;
+    Ok(())
+}
+
+/// 后台发送队列中的邮件
+pub async fn run_queue(transport: Arc<dyn Transport>, mongo: Database) {
+    let interval = Duration::from_secs(config::get().mail.poll_interval_secs);
+    loop {
+        loop {
+            match claim(&mongo).await {
+                Ok(Some(mail)) => {
+                    if let Err(err) = deliver(transport.as_ref(), mail, &mongo).await {
+                        warn!("update mail queue error: {}", err);
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("read mail queue error: {}", err);
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// 当前时间(页面时区)是否已经到了本周发送摘要的时间, 并且上次发送在 6 天之前
+pub fn digest_due(
+    now: DateTime<FixedOffset>,
+    weekday: u32,
+    hour: u32,
+    last_run: Option<DateTime<Utc>>,
+) -> bool {
+    let scheduled = now.weekday().number_from_monday() == weekday && now.hour() >= hour;
+    let recent = last_run.is_some_and(|t| now.with_timezone(&Utc) - t < chrono::Duration::days(6));
+    scheduled && !recent
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DigestState {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    last_run: DateTime<Utc>,
+}
+
+/// 记录本次摘要的发送时间, 返回 false 表示其他实例已经发送过了
+async fn claim_digest(now: DateTime<Utc>, mongo: &Database) -> Result<bool> {
+    let options = UpdateOptions::builder().upsert(true).build();
+    let result = mongo
+        .collection::<DigestState>("mail_state")
+        .update_one(
+            doc! {"_id": DIGEST, "last_run": {"$lt": now - chrono::Duration::days(6)}},
+            doc! {"$set": {"last_run": now}},
+            options,
+        )
+        .await;
+    match result {
+        Ok(_) => Ok(true),
+        // 最近发送过, 条件不匹配, upsert 插入时 _id 冲突
+        Err(err) => match *err.kind {
+            ErrorKind::Write(WriteFailure::WriteError(ref e)) if e.code == 11000 => Ok(false),
+            _ => Err(err.into()),
+        },
+    }
+}
+
+/// 给订阅的用户发送最近一周的新文章, 没有新文章时不发送
+async fn send_digest(mongo: &Database) -> Result<usize> {
+    let config = &config::get().mail;
+    let since = Utc::now() - chrono::Duration::days(7);
+    let mut articles: Vec<_> = db::list_article(None, mongo)
+        .await?
+        .into_iter()
+        .filter(|a| a.created_time >= since)
+        .collect();
+    if articles.is_empty() {
+        return Ok(0);
+    }
+    articles.sort_by_key(|a| std::cmp::Reverse(a.created_time));
+    let articles: Vec<(String, String, String)> = articles
+        .into_iter()
+        .map(|a| (a.id.to_string(), a.title, a.author_name.unwrap_or_default()))
+        .collect();
+
+    let mut sent = 0;
+    for user in db::list_digest_subscribers(mongo).await? {
+        let to = match user.email() {
+            Some(email) => email,
+            None => continue,
+        };
+        let mut context = Context::new();
+        context.insert("username", &user.username);
+        context.insert("articles", &articles);
+        let unsubscribe_url = unsubscribe_url(config, user.id, DIGEST);
+        let subject = format!("本周新文章({}篇)", articles.len());
+        enqueue(to, subject, DIGEST, context, Some(unsubscribe_url), mongo).await?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// 后台检查是否到了发送每周摘要的时间
+pub async fn run_digest(mongo: Database) {
+    let config = &config::get().mail;
+    loop {
+        let now = Utc::now();
+        let state = mongo
+            .collection::<DigestState>("mail_state")
+            .find_one(doc! {"_id": DIGEST}, None)
+            .await;
+        let due = match state {
+            Ok(state) => digest_due(
+                now.with_timezone(&config::timezone()),
+                config.digest_weekday,
+                config.digest_hour,
+                state.map(|s| s.last_run),
+            ),
+            Err(err) => {
+                warn!("read digest state error: {}", err);
+                false
+            }
+        };
+        if due {
+            match claim_digest(now, &mongo).await {
+                Ok(true) => match send_digest(&mongo).await {
+                    Ok(count) => info!("weekly digest queued for {} subscribers", count),
+                    Err(err) => warn!("send weekly digest error: {}", err),
+                },
+                Ok(false) => {}
+                Err(err) => warn!("claim weekly digest error: {}", err),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn mail(unsubscribe_url: Option<&str>) -> QueuedMail {
+        let now = Utc::now();
+        QueuedMail {
+            id: ObjectId::new(),
+            to: "joey@example.com".to_owned(),
+            subject: "bob 回复了你".to_owned(),
+            html: "<p>hello</p>".to_owned(),
+            text: "hello".to_owned(),
+            unsubscribe_url: unsubscribe_url.map(str::to_owned),
+            status: QueuedMail::PENDING.to_owned(),
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_time: now,
+            sent_time: None,
+        }
+    }
+
+    #[test]
+    fn test_build_message() {
+        let from: Mailbox = "Blog <noreply@example.com>".parse().unwrap();
+        let message = build_message(&from, &mail(Some("https://b.example/unsubscribe?x=1")))
+            .unwrap()
+            .formatted();
+        let message = String::from_utf8_lossy(&message);
+        assert!(message.contains("To: joey@example.com"));
+        assert!(message.contains("List-Unsubscribe: <https://b.example/unsubscribe?x=1>"));
+        assert!(message.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+        assert!(message.contains("multipart/alternative"));
+
+        let message = build_message(&from, &mail(None)).unwrap().formatted();
+        assert!(!String::from_utf8_lossy(&message).contains("List-Unsubscribe"));
+
+        let mut bad = mail(None);
+        bad.to = "not an address".to_owned();
+        assert!(build_message(&from, &bad).is_err());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1, 5), Some(chrono::Duration::minutes(1)));
+        assert_eq!(retry_delay(2, 5), Some(chrono::Duration::minutes(2)));
+        assert_eq!(retry_delay(4, 5), Some(chrono::Duration::minutes(8)));
+        assert_eq!(retry_delay(5, 5), None);
+        assert_eq!(
+            retry_delay(30, 100),
+            Some(chrono::Duration::minutes(MAX_RETRY_DELAY_MINUTES))
+        );
+    }
+
+    #[test]
+    fn test_sign_token() {
+        let uid = ObjectId::new();
+        let token = sign_token("secret", uid, DIGEST);
+        assert!(verify_token("secret", uid, DIGEST, &token));
+        assert!(!verify_token("secret", uid, "reply", &token));
+        assert!(!verify_token("other", uid, DIGEST, &token));
+        assert!(!verify_token("secret", ObjectId::new(), DIGEST, &token));
+        assert!(!verify_token("secret", uid, DIGEST, "zz"));
+    }
+
+    #[test]
+    fn test_wants() {
+        let mut user = User::default();
+        assert!(wants(&user, "reply"));
+        assert!(!wants(&user, DIGEST));
+        user.muted_emails = vec!["reply".to_owned()];
+        user.weekly_digest = true;
+        assert!(!wants(&user, "reply"));
+        assert!(wants(&user, "comment"));
+        assert!(wants(&user, DIGEST));
+    }
+
+    #[test]
+    fn test_digest_due() {
+        let tz = FixedOffset::east_opt(8 * 3600).unwrap();
+        // 2024-01-01 是周一
+        let monday_10 = tz.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+        let monday_8 = tz.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap();
+        let tuesday = tz.with_ymd_and_hms(2024, 1, 2, 10, 0, 0).unwrap();
+        assert!(digest_due(monday_10, 1, 9, None));
+        assert!(!digest_due(monday_8, 1, 9, None));
+        assert!(!digest_due(tuesday, 1, 9, None));
+
+        let last_week = (monday_10 - chrono::Duration::days(7)).with_timezone(&Utc);
+        let an_hour_ago = (monday_10 - chrono::Duration::hours(1)).with_timezone(&Utc);
+        assert!(digest_due(monday_10, 1, 9, Some(last_week)));
+        assert!(!digest_due(monday_10, 1, 9, Some(an_hour_ago)));
+    }
+}
@@ -1,18 +1,116 @@
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
+use clap::{Parser, Subcommand};
 use poem::{
-    endpoint::StaticFiles,
-    get,
-    listener::TcpListener,
-    session::{CookieConfig, CookieSession},
-    EndpointExt, Result, Route, Server,
+    endpoint::StaticFiles, get, listener::TcpListener, post, session::ServerSession, EndpointExt,
+    Result, Route, Server,
 };
 
-mod db;
-mod gitee;
-mod handler;
-mod middleware;
-mod model;
+use blog::{
+    audit, backup, config, csrf, error, export, handler, imaging, import, indexes, mail,
+    middleware, migrate, newsletter, notification, policy, rate_limit, session_store, storage,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Joeyscat 博客")]
+struct Cli {
+    /// 配置文件路径, 也可以通过环境变量 BLOG_CONFIG 指定
+    #[arg(long, env = "BLOG_CONFIG", default_value = "blog.toml")]
+    config: PathBuf,
+
+    /// 打印生效的配置(隐藏密钥)后退出
+    #[arg(long)]
+    print_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 把公开的文章导出成静态站点
+    Export {
+        /// 输出目录
+        #[arg(long)]
+        out: PathBuf,
+
+        /// 订阅和 sitemap 中使用的站点地址, 默认是 mail.site_url
+        #[arg(long)]
+        base_url: Option<String>,
+
+        /// 重新生成全部文章, 不跳过没有变化的
+        #[arg(long)]
+        full: bool,
+    },
+    /// 从 Markdown 文件(Hexo/Jekyll/Hugo 的 front matter)导入文章
+    Import {
+        /// 文章目录, 包括子目录中的 .md 文件
+        dir: PathBuf,
+
+        /// 默认的作者(登录名或用户 id)
+        #[arg(long)]
+        author: String,
+
+        /// front matter 中的作者对应的用户, 格式为 作者=登录名, 可以指定多次
+        #[arg(long = "map-author", value_parser = import::parse_author_map)]
+        author_map: Vec<(String, String)>,
+
+        /// 只检查和报告, 不写入数据库
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// 备份数据库和上传的文件
+    Backup {
+        /// 备份文件, 默认是当前目录下的 blog-backup-{时间}.tar.gz
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// 把备份恢复到空数据库
+    Restore {
+        /// 备份文件
+        archive: PathBuf,
+
+        /// 只校验备份文件和数据库是否为空, 不写入
+        #[arg(long)]
+        check: bool,
+    },
+    /// 对比声明的索引和数据库中的索引
+    Indexes {
+        /// 创建缺少的索引, 默认只报告
+        #[arg(long)]
+        apply: bool,
+    },
+    /// 数据迁移
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum MigrateAction {
+    /// 执行未执行的迁移
+    Up {
+        /// 只执行到这个版本(包括)
+        #[arg(long)]
+        to: Option<i32>,
+    },
+    /// 撤销迁移, 默认只撤销最后一个
+    Down {
+        /// 撤销版本号大于这个版本的迁移, 0 表示全部撤销
+        #[arg(long)]
+        to: Option<i32>,
+    },
+    /// 列出迁移和执行时间
+    Status,
+}
+
+impl Cli {
+    /// 显式指定的配置文件必须存在, 默认的 blog.toml 可以没有(全部使用环境变量)
+    fn config_required(&self) -> bool {
+        self.config != PathBuf::from("blog.toml")
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
@@ -23,14 +121,17 @@ async fn main() -> Result<(), std::io::Error> {
         .expect("请将环境变量RUST_LOG设置为可用的日志等级");
     tracing_subscriber::fmt().with_max_level(lvl).init();
 
-    let database_uri = std::env::var("DATABASE_URL").map_err(|err| {
-        std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("获取环境变量 DATABASE_URI 错误: {}", err),
-        )
-    })?;
+    let cli = Cli::parse();
+    let config = config::Config::load(&cli.config, cli.config_required())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    if cli.print_config {
+        print!("{}", config.to_masked_toml());
+        return Ok(());
+    }
+    config::init(config);
+    let config = config::get();
 
-    let mongodb = mongodb::Client::with_uri_str(database_uri)
+    let mongodb = mongodb::Client::with_uri_str(&config.database.url)
         .await
         .map_err(|err| {
             std::io::Error::new(
@@ -38,15 +139,300 @@ async fn main() -> Result<(), std::io::Error> {
                 format!("初始化数据库连接错误: {}", err),
             )
         })?
-        .database("joeyscat");
+        .database(&config.database.name);
+
+    match cli.command {
+        Some(Command::Export {
+            out,
+            base_url,
+            full,
+        }) => {
+            let options = export::Options {
+                out,
+                base_url: base_url.unwrap_or_else(|| config.mail.site_url.clone()),
+                full,
+            };
+            let report = export::run(&options, &mongodb).await.map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::Other, format!("导出错误: {}", err))
+            })?;
+            println!(
+                "导出到 {}: 写入 {} 个文件, {} 个没有变化, 跳过 {} 篇文章, 删除 {} 个文件",
+                options.out.display(),
+                report.written,
+                report.unchanged,
+                report.skipped,
+                report.removed
+            );
+            return Ok(());
+        }
+        Some(Command::Import {
+            dir,
+            author,
+            author_map,
+            dry_run,
+        }) => {
+            let options = import::Options {
+                dir,
+                author,
+                author_map,
+                dry_run,
+            };
+            let report = import::run(&options, &mongodb).await.map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::Other, format!("导入错误: {}", err))
+            })?;
+            for entry in &report.entries {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    entry.action,
+                    entry.path.display(),
+                    entry.slug,
+                    entry.title
+                );
+            }
+            println!(
+                "{}新建 {} 篇, 更新 {} 篇, {} 篇没有变化, {} 篇草稿, {} 个文件失败",
+                if dry_run { "(试运行) " } else { "" },
+                report.count(|a| *a == import::Action::Create),
+                report.count(|a| *a == import::Action::Update),
+                report.count(|a| *a == import::Action::Unchanged),
+                report.count(|a| *a == import::Action::Draft),
+                report.count(|a| matches!(a, import::Action::Failed(_)))
+            );
+            return Ok(());
+        }
+        Some(Command::Backup { out }) => {
+            let out = out.unwrap_or_else(|| {
+                PathBuf::from(format!(
+                    "blog-backup-{}.tar.gz",
+                    chrono::Utc::now().format("%Y%m%d-%H%M%S")
+                ))
+            });
+            let storage = storage::from_config(&config.upload).map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("初始化文件存储错误: {}", err),
+                )
+            })?;
+            let manifest = backup::backup(&out, storage.as_ref(), &mongodb)
+                .await
+                .map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::Other, format!("备份错误: {}", err))
+                })?;
+            for collection in &manifest.collections {
+                println!("{}\t{}", collection.name, collection.count);
+            }
+            println!(
+                "备份到 {}: {} 个集合, {} 个文件",
+                out.display(),
+                manifest.collections.len(),
+                manifest.media.len()
+            );
+            return Ok(());
+        }
+        Some(Command::Restore { archive, check }) => {
+            let storage = storage::from_config(&config.upload).map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("初始化文件存储错误: {}", err),
+                )
+            })?;
+            let manifest = backup::restore(&archive, check, storage.as_ref(), &mongodb)
+                .await
+                .map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::Other, format!("恢复错误: {}", err))
+                })?;
+            println!(
+                "{} {} 创建的备份(格式版本 {}): {} 个集合 {} 条记录, {} 个文件",
+                if check { "校验通过:" } else { "已恢复:" },
+                manifest.created_time.to_rfc3339(),
+                manifest.format_version,
+                manifest.collections.len(),
+                manifest.collections.iter().map(|c| c.count).sum::<u64>(),
+                manifest.media.len()
+            );
+            return Ok(());
+        }
+        Some(Command::Indexes { apply }) => {
+            let report = indexes::reconcile(&mongodb, apply, config.database.strict_indexes)
+                .await
+                .map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::Other, format!("索引错误: {}", err))
+                })?;
+            for finding in &report {
+                println!("{}", finding);
+            }
+            if report.is_empty() {
+                println!("索引和声明的一致");
+            }
+            return Ok(());
+        }
+        Some(Command::Migrate { action }) => {
+            let migrator = migrate::Migrator::new(migrate::migrations());
+            let store = migrate::MongoStore::new(mongodb.clone());
+            let result = match action {
+                MigrateAction::Up { to } => migrator.up(&store, to).await.map(|done| {
+                    println!("执行了 {} 个迁移: {:?}", done.len(), done);
+                }),
+                MigrateAction::Down { to } => migrator.down(&store, to).await.map(|done| {
+                    println!("撤销了 {} 个迁移: {:?}", done.len(), done);
+                }),
+                MigrateAction::Status => migrator.status(&store).await.map(|status| {
+                    for s in status {
+                        let applied = match s.applied_time {
+                            Some(time) => time.to_rfc3339(),
+                            None => "未执行".to_owned(),
+                        };
+                        let unknown = if s.unknown {
+                            "\t(程序中没有这个迁移)"
+                        } else {
+                            ""
+                        };
+                        println!("{}\t{}\t{}{}", s.version, s.name, applied, unknown);
+                    }
+                }),
+            };
+            result.map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::Other, format!("数据迁移错误: {}", err))
+            })?;
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if config.database.migrate_on_start {
+        migrate::Migrator::new(migrate::migrations())
+            .up(&migrate::MongoStore::new(mongodb.clone()), None)
+            .await
+            .map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::Other, format!("数据迁移错误: {}", err))
+            })?;
+    }
+
+    indexes::reconcile(&mongodb, true, config.database.strict_indexes)
+        .await
+        .map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::Other, format!("创建索引错误: {}", err))
+        })?;
+
+    policy::bootstrap_admin(&mongodb, config.admin.bootstrap_identity())
+        .await
+        .map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("初始化管理员错误: {}", err),
+            )
+        })?;
+
+    let session_storage = session_store::MongoStorage::new(
+        mongodb.clone(),
+        Duration::from_secs(config.session.ttl_days * 24 * 3600),
+    );
+    let storage = storage::from_config(&config.upload).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("初始化文件存储错误: {}", err),
+        )
+    })?;
 
-    let app = Route::new()
+    tokio::spawn(imaging::reconcile(storage.clone(), mongodb.clone()));
+
+    if config.mail.enabled {
+        let transport = mail::SmtpTransport::from_config(&config.mail).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("初始化 SMTP 错误: {}", err),
+            )
+        })?;
+        tokio::spawn(mail::run_queue(Arc::new(transport), mongodb.clone()));
+        tokio::spawn(mail::run_digest(mongodb.clone()));
+    }
+
+    let rate_limiter = rate_limit::RateLimiter::from_config(&config.rate_limit, &mongodb)
+        .trusted_proxies(config.server.trusted_proxies.clone());
+
+    let mut routes = Route::new()
         .at("/", get(handler::index))
         .at("/article", get(handler::article_details))
-        .at("/signin", get(handler::signin_ui))
+        .at(
+            "/signin",
+            get(handler::signin_ui).post(handler::password_signin),
+        )
+        .at(
+            "/signin/2fa",
+            get(handler::twofa_page).post(handler::twofa_verify),
+        )
+        .at("/oauth/authorize", get(handler::oauth_authorize))
         .at("/gitee/signin", get(handler::gitee_signin))
+        .at("/github/signin", get(handler::github_signin))
         .at("/signout", get(handler::signout))
         .at("/account", get(handler::account))
+        .at("/account/profile", post(handler::update_profile))
+        .at(
+            "/account/notifications",
+            post(handler::update_notification_prefs),
+        )
+        .at(
+            mail::UNSUBSCRIBE_PATH,
+            get(handler::unsubscribe_page).post(handler::unsubscribe),
+        )
+        .at(
+            "/subscribe",
+            get(handler::subscribe_page).post(handler::subscribe),
+        )
+        .at(
+            newsletter::CONFIRM_PATH,
+            get(handler::confirm_subscription_page).post(handler::confirm_subscription),
+        )
+        .at(
+            newsletter::UNSUBSCRIBE_PATH,
+            get(handler::newsletter_unsubscribe_page).post(handler::newsletter_unsubscribe),
+        )
+        .at("/notifications", get(handler::notifications))
+        .at("/notifications/read", post(handler::mark_notification_read))
+        .at(
+            "/notifications/read_all",
+            post(handler::mark_all_notifications_read),
+        )
+        .at("/account/connect", get(handler::connect_identity))
+        .at("/account/connect/password", post(handler::connect_password))
+        .at("/account/disconnect", post(handler::disconnect_identity))
+        .at("/account/sessions/revoke", post(handler::revoke_session))
+        .at(
+            "/account/sessions/revoke_all",
+            post(handler::signout_everywhere),
+        )
+        .at("/account/2fa", get(handler::account_2fa))
+        .at("/account/2fa/enable", post(handler::account_2fa_enable))
+        .at("/account/2fa/disable", post(handler::account_2fa_disable))
+        .at("/user/:key", get(handler::user_profile))
+        .at("/admin/users", get(handler::admin_users))
+        .at("/admin/users/role", post(handler::admin_set_role))
+        .at("/admin/users/ban", post(handler::admin_ban_user))
+        .at("/admin/users/unban", post(handler::admin_unban_user))
+        .at("/admin/audit", get(handler::admin_audit))
+        .at("/admin/audit/export", get(handler::admin_audit_export))
+        .at("/admin/subscribers", get(handler::admin_subscribers))
+        .at(
+            "/admin/subscribers/export",
+            get(handler::admin_subscribers_export),
+        )
+        .at(
+            "/admin/subscribers/import",
+            post(handler::admin_subscribers_import),
+        )
+        .at(
+            "/admin/subscribers/delete",
+            post(handler::admin_delete_subscriber),
+        )
+        .at("/admin/comments", get(handler::admin_comments))
+        .at(
+            "/admin/comments/moderate",
+            post(handler::admin_moderate_comment),
+        )
+        .at(
+            "/admin/comments/retrain",
+            post(handler::admin_retrain_spam_filter),
+        )
         .at(
             "/article/publish",
             get(handler::publish_article_page).post(handler::publish_article),
@@ -59,11 +445,39 @@ async fn main() -> Result<(), std::io::Error> {
             "/comment/new",
             get(handler::new_comment_page).post(handler::new_comment),
         )
-        .nest("/assets", StaticFiles::new("./assets").show_files_listing())
-        .with(CookieSession::new(CookieConfig::default().secure(false)))
+        .at("/media", get(handler::media_library))
+        .at("/media/upload", post(handler::media_upload))
+        .at("/media/delete", post(handler::media_delete))
+        .at(middleware::CSP_REPORT_PATH, post(handler::csp_report))
+        .nest(
+            imaging::URL_PREFIX,
+            StaticFiles::new(&config.upload.derived_dir).around(middleware::immutable),
+        )
+        .nest("/assets", StaticFiles::new("./assets").show_files_listing());
+    // S3 的文件由对象存储(或 CDN)直接提供
+    if config.upload.storage == "local" {
+        routes = routes.nest(
+            storage::LOCAL_URL_PREFIX,
+            StaticFiles::new(&config.upload.dir),
+        );
+    }
+
+    let app = routes
+        .around(notification::unread)
+        .around(audit::capture)
+        .around(session_store::meta)
+        .around(csrf::protect)
+        .with(rate_limiter)
+        .with(ServerSession::new(
+            session_store::cookie_config(&config.session),
+            session_storage,
+        ))
         .data(mongodb)
-        .around(middleware::log);
-    Server::new(TcpListener::bind("0.0.0.0:9527"))
+        .data(storage)
+        .around(middleware::log)
+        .around(error::render)
+        .around(middleware::security_headers);
+    Server::new(TcpListener::bind(config.server.bind.as_str()))
         .run(app)
         .await
 }
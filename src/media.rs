@@ -0,0 +1,326 @@
+//!
+//! 媒体库: 作者上传的图片和文件, 元数据保存在 `media` 集合中, 文件内容交给 [`Storage`].
+//! 文件按内容的 SHA-256 命名, 相同的内容只保存一份, 同一个用户重复上传时返回已有的记录
+//!
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOptions, IndexOptions},
+    Database, IndexModel,
+};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::error::{AppError, Result};
+use crate::imaging::{self, ImageInfo};
+use crate::storage::Storage;
+
+/// 支持上传的文件类型, 可以在配置的 `upload.allowed_types` 中选择
+pub const SUPPORTED_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+];
+
+///
+/// Model: Media
+/// Db table: media
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Media {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub owner_id: ObjectId,
+    /// 文件内容的 SHA-256, 十六进制
+    pub hash: String,
+    /// 在存储中的路径
+    pub key: String,
+    /// 上传时的文件名, 只用于展示
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+    /// 生成衍生版本后才有, 见 [`imaging`]
+    #[serde(default)]
+    pub image: Option<ImageInfo>,
+}
+
+impl Media {
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
+}
+
+/// `media` 集合的索引, 启动时由 [`crate::indexes`] 创建
+pub fn indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"owner_id": 1, "hash": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"owner_id": 1, "created_time": -1})
+            .build(),
+        // 删除时检查文件是否还被其他记录引用
+        IndexModel::builder().keys(doc! {"hash": 1}).build(),
+    ]
+}
+
+/// 根据文件开头的字节判断类型, 返回 (MIME 类型, 扩展名)
+pub fn detect_type(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("image/png", "png"))
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some(("image/jpeg", "jpg"))
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(("image/gif", "gif"))
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some(("image/webp", "webp"))
+    } else if data.starts_with(b"%PDF-") {
+        Some(("application/pdf", "pdf"))
+    } else {
+        None
+    }
+}
+
+pub fn content_hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// 存储中的路径, 用哈希的前两位分目录, 避免单个目录下文件过多
+pub fn object_key(hash: &str, ext: &str) -> String {
+    format!("{}/{}.{}", &hash[..2], hash, ext)
+}
+
+/// 去掉客户端传来的目录部分和控制字符, 限制长度
+fn clean_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).take(200).collect();
+    match name.trim() {
+        "" => "file".to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+/// 插入文章的 Markdown, 图片用文件名(去掉扩展名)作为替代文本
+pub fn markdown(media: &Media, url: &str) -> String {
+    let text: String = media
+        .filename
+        .rsplit_once('.')
+        .map_or(media.filename.as_str(), |(stem, _)| stem)
+        .chars()
+        .filter(|c| !matches!(c, '[' | ']' | '\\'))
+        .collect();
+    if media.is_image() {
+        format!("![{}]({})", text, url)
+    } else {
+        format!("[{}]({})", text, url)
+    }
+}
+
+///
+/// 保存上传的文件: 检查大小和类型, 同一个用户上传过相同的内容时直接返回已有的记录,
+/// 其他用户上传过相同的内容时复用存储中的文件
+///
+pub async fn save(
+    owner_id: ObjectId,
+    filename: &str,
+    data: Vec<u8>,
+    storage: &dyn Storage,
+    mongo: &Database,
+) -> Result<Media> {
+    let upload = &config::get().upload;
+    if data.is_empty() {
+        return Err(AppError::bad_request("文件是空的"));
+    }
+    if data.len() > upload.max_bytes {
+        return Err(AppError::bad_request(format!(
+            "文件不能超过 {} KB",
+            upload.max_bytes / 1024
+        )));
+    }
+    let (content_type, ext) = detect_type(&data)
+        .filter(|(content_type, _)| upload.allowed_types.iter().any(|t| t == content_type))
+        .ok_or_else(|| {
+            AppError::bad_request(format!(
+                "只能上传这些类型的文件: {}",
+                upload.allowed_types.join(", ")
+            ))
+        })?;
+
+    let collection = mongo.collection::<Media>("media");
+    let hash = content_hash(&data);
+    if let Some(media) = collection
+        .find_one(doc! {"owner_id": owner_id, "hash": &hash}, None)
+        .await?
+    {
+        return Ok(media);
+    }
+
+    let key = object_key(&hash, ext);
+    let size = data.len() as i64;
+    let existing = collection.find_one(doc! {"hash": &hash}, None).await?;
+    let image = existing.as_ref().and_then(|media| media.image.clone());
+    if existing.is_none() {
+        storage.put(&key, data.clone(), content_type).await?;
+    }
+
+    let media = Media {
+        id: ObjectId::new(),
+        owner_id,
+        hash,
+        key,
+        filename: clean_filename(filename),
+        content_type: content_type.to_owned(),
+        size,
+        created_time: Utc::now(),
+        image,
+    };
+    match collection.insert_one(&media, None).await {
+        Ok(_) => {
+            if media.image.is_none() && imaging::can_derive(content_type) {
+                imaging::spawn(media.hash.clone(), data, mongo.clone());
+            }
+            Ok(media)
+        }
+        // 同一个用户并发上传了相同的文件
+        Err(err) => match *err.kind {
+            ErrorKind::Write(WriteFailure::WriteError(ref e)) if e.code == 11000 => collection
+                .find_one(doc! {"owner_id": owner_id, "hash": &media.hash}, None)
+                .await?
+                .ok_or(AppError::NotFound),
+            _ => Err(err.into()),
+        },
+    }
+}
+
+/// 某个用户上传的文件, 按时间倒序分页, 返回 (当前页, 总数)
+pub async fn list(
+    owner_id: ObjectId,
+    page: u64,
+    page_size: i64,
+    mongo: &Database,
+) -> Result<(Vec<Media>, u64)> {
+    let collection = mongo.collection::<Media>("media");
+    let query = doc! {"owner_id": owner_id};
+    let total = collection.count_documents(query.clone(), None).await?;
+
+    let options = FindOptions::builder()
+        .sort(doc! {"created_time": -1})
+        .skip((page.max(1) - 1) * page_size as u64)
+        .limit(page_size)
+        .build();
+    let mut cursor = collection.find(query, options).await?;
+    let mut result = Vec::new();
+    while let Some(media) = cursor.next().await {
+        result.push(media?);
+    }
+    Ok((result, total))
+}
+
+/// 删除自己的文件, 没有其他记录引用时才从存储中删除(连同衍生文件)
+pub async fn delete(
+    owner_id: ObjectId,
+    id: ObjectId,
+    storage: &dyn Storage,
+    mongo: &Database,
+) -> Result<()> {
+    let collection = mongo.collection::<Media>("media");
+    let media = collection
+        .find_one_and_delete(doc! {"_id": id, "owner_id": owner_id}, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let referenced = collection
+        .count_documents(doc! {"hash": &media.hash}, None)
+        .await?;
+    if referenced == 0 {
+        storage.delete(&media.key).await?;
+        if let Some(info) = &media.image {
+            imaging::remove(&media.hash, info).await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_type() {
+        assert_eq!(
+            detect_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some(("image/png", "png"))
+        );
+        assert_eq!(
+            detect_type(b"\xff\xd8\xff\xe0\0\x10JFIF"),
+            Some(("image/jpeg", "jpg"))
+        );
+        assert_eq!(detect_type(b"GIF89a\x01\0"), Some(("image/gif", "gif")));
+        assert_eq!(
+            detect_type(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(("image/webp", "webp"))
+        );
+        assert_eq!(detect_type(b"%PDF-1.7\n"), Some(("application/pdf", "pdf")));
+        assert_eq!(
+            detect_type(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+            None
+        );
+        assert_eq!(detect_type(b"RIFF"), None);
+        assert_eq!(detect_type(b""), None);
+    }
+
+    #[test]
+    fn test_object_key() {
+        let hash = content_hash(b"hello");
+        assert_eq!(
+            hash,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(object_key(&hash, "png"), format!("2c/{}.png", hash));
+        assert!(is_hash(&hash));
+        assert!(!is_hash(&hash.to_uppercase()));
+        assert!(!is_hash(&hash[1..]));
+    }
+
+    #[test]
+    fn test_markdown() {
+        let mut media = Media {
+            id: ObjectId::new(),
+            owner_id: ObjectId::new(),
+            hash: "".to_owned(),
+            key: "".to_owned(),
+            filename: clean_filename("C:\\photos\\[cat].v2.png"),
+            content_type: "image/png".to_owned(),
+            size: 0,
+            created_time: Utc::now(),
+            image: None,
+        };
+        assert_eq!(media.filename, "[cat].v2.png");
+        assert_eq!(
+            markdown(&media, "/uploads/a.png"),
+            "![cat.v2](/uploads/a.png)"
+        );
+
+        media.filename = clean_filename("../paper.pdf");
+        media.content_type = "application/pdf".to_owned();
+        assert_eq!(
+            markdown(&media, "/uploads/b.pdf"),
+            "[paper](/uploads/b.pdf)"
+        );
+        assert_eq!(clean_filename("/"), "file");
+    }
+}
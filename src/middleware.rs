@@ -1,6 +1,17 @@
-use poem::{Endpoint, IntoResponse, Request, Response, Result};
+use std::{collections::HashMap, net::IpAddr};
+
+use poem::{
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    Endpoint, IntoResponse, Request, Response, Result,
+};
+use rand::RngCore;
 use tracing::{info, warn};
 
+use crate::config::{self, SecurityConfig};
+
+/// 浏览器发送 CSP 违规报告的地址
+pub const CSP_REPORT_PATH: &str = "/csp-report";
+
 pub async fn log<E: Endpoint>(next: E, req: Request) -> Result<Response> {
     // Dec 24 23:37:47.729  INFO blog::middleware: 200 OK - socket://127.0.0.1:53372 0ms GET /signin?a=1
     let remote = (&req).remote_addr().to_string();
@@ -27,7 +38,169 @@ pub async fn log<E: Endpoint>(next: E, req: Request) -> Result<Response> {
     }
 }
 
+///
+/// 客户端 IP. 默认使用 TCP 连接的对端地址; 对端是 `trusted_proxies` 中的反向代理时,
+/// 依次使用 X-Real-IP 和 X-Forwarded-For 中从右往左第一个不是代理的地址.
+/// 这两个请求头客户端可以随意设置, 不能无条件信任
+///
+pub fn client_ip(req: &Request, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.remote_addr().as_socket_addr()?.ip();
+    Some(forwarded_ip(peer, req.headers(), trusted_proxies))
+}
+
+fn forwarded_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    let header_str = |name: HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(ip) =
+        header_str(HeaderName::from_static("x-real-ip")).and_then(|v| v.trim().parse().ok())
+    {
+        return ip;
+    }
+    header_str(HeaderName::from_static("x-forwarded-for"))
+        .and_then(|v| {
+            v.rsplit(',')
+                .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+                .find(|ip| !trusted_proxies.contains(ip))
+        })
+        .unwrap_or(peer)
+}
+
+tokio::task_local! {
+    /// 当前请求的 CSP nonce, 供模板函数 `csp_nonce()` 使用
+    static CSP_NONCE: String;
+}
+
+/// 注册到 Tera 的模板函数, 内联的 `<script>`/`<style>` 需要带上 `nonce="{{ csp_nonce() }}"`
+pub fn csp_nonce_function(_args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    CSP_NONCE
+        .try_with(|nonce| tera::Value::String(nonce.clone()))
+        .map_err(|_| tera::Error::msg("csp_nonce() called outside of a request"))
+}
+
+fn generate_nonce() -> String {
+    let mut buf = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn set_header(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+fn apply_security_headers(headers: &mut HeaderMap, config: &SecurityConfig, nonce: &str) {
+    if !config.csp.is_empty() {
+        let csp = format!(
+            "{}; report-uri {}",
+            config.csp.replace("{nonce}", nonce),
+            CSP_REPORT_PATH
+        );
+        let name = if config.csp_report_only {
+            header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+        } else {
+            header::CONTENT_SECURITY_POLICY
+        };
+        set_header(headers, name, &csp);
+    }
+    if config.hsts_max_age > 0 {
+        set_header(
+            headers,
+            header::STRICT_TRANSPORT_SECURITY,
+            &format!("max-age={}; includeSubDomains", config.hsts_max_age),
+        );
+    }
+    set_header(headers, header::X_FRAME_OPTIONS, &config.frame_options);
+    set_header(headers, header::REFERRER_POLICY, &config.referrer_policy);
+    set_header(headers, header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+}
+
+/// 安全相关的响应头, 放在最外层, 错误页面也会带上
+pub async fn security_headers<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let nonce = generate_nonce();
+    let res = CSP_NONCE.scope(nonce.clone(), next.call(req)).await;
+
+    let mut resp = match res {
+        Ok(resp) => resp.into_response(),
+        Err(err) => err.into_response(),
+    };
+    apply_security_headers(resp.headers_mut(), &config::get().security, &nonce);
+    Ok(resp)
+}
+
+/// 文件名带有内容哈希的静态文件, 内容不会变化, 允许浏览器和 CDN 长期缓存
+pub async fn immutable<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let mut resp = next.call(req).await?.into_response();
+    if resp.status().is_success() {
+        resp.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=31536000, immutable"),
+        );
+    }
+    Ok(resp)
+}
 
 pub async fn _auth<E: Endpoint>(_next: E, _req: Request) -> Result<Response> {
     unimplemented!()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_ip() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxy = ip("127.0.0.1");
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", HeaderValue::from_static("10.0.0.1"));
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("10.0.0.2, 203.0.113.7, 127.0.0.1"),
+        );
+
+        // 不是来自反向代理的请求, 忽略请求头
+        assert_eq!(
+            forwarded_ip(ip("198.51.100.1"), &headers, &[proxy]),
+            ip("198.51.100.1")
+        );
+        assert_eq!(forwarded_ip(proxy, &headers, &[]), proxy);
+        assert_eq!(forwarded_ip(proxy, &headers, &[proxy]), ip("10.0.0.1"));
+
+        headers.remove("x-real-ip");
+        assert_eq!(forwarded_ip(proxy, &headers, &[proxy]), ip("203.0.113.7"));
+        headers.remove("x-forwarded-for");
+        assert_eq!(forwarded_ip(proxy, &headers, &[proxy]), proxy);
+    }
+
+    #[test]
+    fn test_security_headers() {
+        let mut config = SecurityConfig::default();
+        let mut headers = HeaderMap::new();
+        apply_security_headers(&mut headers, &config, "abc");
+        let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(csp.contains("script-src 'self' 'nonce-abc'"));
+        assert!(csp.ends_with("report-uri /csp-report"));
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        config.csp_report_only = true;
+        config.hsts_max_age = 3600;
+        config.frame_options = "".to_owned();
+        let mut headers = HeaderMap::new();
+        apply_security_headers(&mut headers, &config, "abc");
+        assert!(!headers.contains_key(header::CONTENT_SECURITY_POLICY));
+        assert!(headers.contains_key(header::CONTENT_SECURITY_POLICY_REPORT_ONLY));
+        assert_eq!(
+            headers[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=3600; includeSubDomains"
+        );
+        assert!(!headers.contains_key(header::X_FRAME_OPTIONS));
+    }
+}
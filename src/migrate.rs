@@ -0,0 +1,951 @@
+//!
+//! 数据迁移: 按版本号顺序执行的 [`Migration`], 执行过的版本记录在 `_migrations` 集合中.
+//! 启动时自动执行(`database.migrate_on_start`), 也可以用 `blog migrate up/down/status` 手动执行.
+//! 执行前在 `_migrations` 中加锁, 多个实例同时启动时只有一个执行, 其他的等待
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{ReplaceOptions, UpdateOptions},
+    Database,
+};
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::{AppError, Result};
+
+/// 锁的有效期, 持有锁的进程异常退出后, 超过这个时间其他进程可以重新加锁
+const LOCK_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// 执行迁移期间续期锁的间隔, 耗时超过 LOCK_TTL 的迁移不会被其他进程抢锁
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 等待其他进程释放锁的时间
+const LOCK_WAIT: Duration = Duration::from_secs(60);
+
+/// 执行记录和锁所在的集合
+pub const COLLECTION: &str = "_migrations";
+
+/// 锁文档的 `_id`, 锁是运行时状态, 不备份
+pub const LOCK_ID: &str = "lock";
+
+///
+/// Model: AppliedMigration
+/// Db table: _migrations
+///
+/// 锁也保存在这个集合中(`_id` 为 "lock"), 执行记录的 `_id` 是版本号
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    #[serde(rename = "_id")]
+    pub version: i32,
+    pub name: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub applied_time: DateTime<Utc>,
+}
+
+///
+/// 迁移记录和迁移要修改的数据的存储
+///
+#[poem::async_trait]
+pub trait MigrationStore: Send + Sync {
+    /// 加锁, 已经被其他 `owner` 持有并且没有过期时返回 false
+    async fn lock(&self, owner: &str) -> Result<bool>;
+    async fn unlock(&self, owner: &str) -> Result<()>;
+    /// 执行过的迁移, 按版本号排序
+    async fn applied(&self) -> Result<Vec<AppliedMigration>>;
+    async fn record(&self, migration: AppliedMigration) -> Result<()>;
+    async fn remove(&self, version: i32) -> Result<()>;
+    /// 集合中的全部文档
+    async fn find_all(&self, collection: &str) -> Result<Vec<Document>>;
+    /// 按 `_id` 替换文档
+    async fn replace(&self, collection: &str, document: Document) -> Result<()>;
+}
+
+pub struct MongoStore {
+    mongo: Database,
+}
+
+impl MongoStore {
+    pub fn new(mongo: Database) -> Self {
+        Self { mongo }
+    }
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
+
+#[poem::async_trait]
+impl MigrationStore for MongoStore {
+    async fn lock(&self, owner: &str) -> Result<bool> {
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::from_std(LOCK_TTL).unwrap();
+        // 没有锁或锁已经过期时 upsert 成功, 否则插入 "lock" 时主键冲突
+        let result = self
+            .mongo
+            .collection::<Document>(COLLECTION)
+            .update_one(
+                doc! {"_id": LOCK_ID, "$or": [{"owner": owner}, {"expires_at": {"$lt": now}}]},
+                doc! {"$set": {"owner": owner, "expires_at": expires_at}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(err) if is_duplicate_key(&err) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn unlock(&self, owner: &str) -> Result<()> {
+        self.mongo
+            .collection::<Document>(COLLECTION)
+            .delete_one(doc! {"_id": LOCK_ID, "owner": owner}, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn applied(&self) -> Result<Vec<AppliedMigration>> {
+        let mut cursor = self
+            .mongo
+            .collection::<AppliedMigration>(COLLECTION)
+            .find(doc! {"_id": {"$type": "int"}}, None)
+            .await?;
+        let mut result = Vec::new();
+        while let Some(migration) = cursor.next().await {
+            result.push(migration?);
+        }
+        result.sort_by_key(|m| m.version);
+        Ok(result)
+    }
+
+    async fn record(&self, migration: AppliedMigration) -> Result<()> {
+        self.mongo
+            .collection::<AppliedMigration>(COLLECTION)
+            .replace_one(
+                doc! {"_id": migration.version},
+                &migration,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, version: i32) -> Result<()> {
+        self.mongo
+            .collection::<Document>(COLLECTION)
+            .delete_one(doc! {"_id": version}, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_all(&self, collection: &str) -> Result<Vec<Document>> {
+        let mut cursor = self
+            .mongo
+            .collection::<Document>(collection)
+            .find(doc! {}, None)
+            .await?;
+        let mut result = Vec::new();
+        while let Some(document) = cursor.next().await {
+            result.push(document?);
+        }
+        Ok(result)
+    }
+
+    async fn replace(&self, collection: &str, document: Document) -> Result<()> {
+        let id = document
+            .get("_id")
+            .cloned()
+            .ok_or_else(|| AppError::internal("document without _id"))?;
+        self.mongo
+            .collection::<Document>(collection)
+            .replace_one(doc! {"_id": id}, document, None)
+            .await?;
+        Ok(())
+    }
+}
+
+///
+/// 一次迁移, `up` 和 `down` 都需要可以重复执行(执行到一半失败后会重新执行)
+///
+#[poem::async_trait]
+pub trait Migration: Send + Sync {
+    /// 版本号, 新的迁移使用比已有的都大的版本号, 发布后不能修改
+    fn version(&self) -> i32;
+    fn name(&self) -> &'static str;
+    async fn up(&self, store: &dyn MigrationStore) -> Result<()>;
+    /// 撤销 `up` 的修改, 无法撤销时什么都不做
+    async fn down(&self, store: &dyn MigrationStore) -> Result<()>;
+}
+
+/// 全部迁移, 按版本号排序
+pub fn migrations() -> Vec<Box<dyn Migration>> {
+    vec![
+        Box::new(CommentAuthorNames),
+        Box::new(CommentsNewestFirst),
+        Box::new(UserIdentityCounts),
+        Box::new(LegacyUserRoles),
+    ]
+}
+
+fn comments_mut(article: &mut Document) -> Option<&mut Vec<Bson>> {
+    article.get_array_mut("comments").ok()
+}
+
+///
+/// 评论中的作者名和被回复人的名字改成用户当前的用户名(以前用 mongo shell 脚本按用户手动修改).
+/// 作者名只是冗余的显示字段, down 不恢复
+///
+struct CommentAuthorNames;
+
+#[poem::async_trait]
+impl Migration for CommentAuthorNames {
+    fn version(&self) -> i32 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "comment_author_names"
+    }
+
+    async fn up(&self, store: &dyn MigrationStore) -> Result<()> {
+        let names: HashMap<ObjectId, String> = store
+            .find_all("user")
+            .await?
+            .into_iter()
+            .filter_map(|user| {
+                Some((
+                    user.get_object_id("_id").ok()?,
+                    user.get_str("username").ok()?.to_owned(),
+                ))
+            })
+            .collect();
+        for mut article in store.find_all("article").await? {
+            let Some(comments) = comments_mut(&mut article) else {
+                continue;
+            };
+            let mut changed = false;
+            for comment in comments.iter_mut() {
+                let Some(comment) = comment.as_document_mut() else {
+                    continue;
+                };
+                for (id_field, name_field) in
+                    [("author_id", "author_name"), ("reply_to", "reply_to_name")]
+                {
+                    let Some(name) = comment
+                        .get_object_id(id_field)
+                        .ok()
+                        .and_then(|id| names.get(&id))
+                    else {
+                        continue;
+                    };
+                    if comment.get_str(name_field).ok() != Some(name.as_str()) {
+                        comment.insert(name_field, name.as_str());
+                        changed = true;
+                    }
+                }
+            }
+            if changed {
+                store.replace("article", article).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, _store: &dyn MigrationStore) -> Result<()> {
+        Ok(())
+    }
+}
+
+///
+/// 评论按发表时间倒序保存(新评论插入到最前面), 早期的文章是正序的(以前用 mongo shell 脚本手动反转).
+/// 按时间排序而不是直接反转, 重复执行结果不变
+///
+struct CommentsNewestFirst;
+
+fn sort_comments(article: &mut Document, newest_first: bool) -> bool {
+    let Some(comments) = comments_mut(article) else {
+        return false;
+    };
+    let time = |c: &Bson| {
+        c.as_document()
+            .and_then(|c| c.get_datetime("created_time").ok())
+            .copied()
+    };
+    let mut sorted = comments.clone();
+    // 稳定排序, 时间相同的评论保持原来的顺序
+    sorted.sort_by_key(time);
+    if newest_first {
+        sorted.reverse();
+    }
+    if sorted == *comments {
+        return false;
+    }
+    *comments = sorted;
+    true
+}
+
+async fn sort_all_comments(store: &dyn MigrationStore, newest_first: bool) -> Result<()> {
+    for mut article in store.find_all("article").await? {
+        if sort_comments(&mut article, newest_first) {
+            store.replace("article", article).await?;
+        }
+    }
+    Ok(())
+}
+
+#[poem::async_trait]
+impl Migration for CommentsNewestFirst {
+    fn version(&self) -> i32 {
+        2
+    }
+
+    fn name(&self) -> &'static str {
+        "comments_newest_first"
+    }
+
+    async fn up(&self, store: &dyn MigrationStore) -> Result<()> {
+        sort_all_comments(store, true).await
+    }
+
+    async fn down(&self, store: &dyn MigrationStore) -> Result<()> {
+        sort_all_comments(store, false).await
+    }
+}
+
+///
+/// 用户的登录方式数量保存在 `user.identity_count` 中, 解绑时用它做条件更新,
+/// 并发解绑不会删掉最后一个登录方式. 按 `identity` 集合统计已有用户的数量
+///
+struct UserIdentityCounts;
+
+#[poem::async_trait]
+impl Migration for UserIdentityCounts {
+    fn version(&self) -> i32 {
+        3
+    }
+
+    fn name(&self) -> &'static str {
+        "user_identity_counts"
+    }
+
+    async fn up(&self, store: &dyn MigrationStore) -> Result<()> {
+        let mut counts: HashMap<ObjectId, i32> = HashMap::new();
+        for identity in store.find_all("identity").await? {
+            if let Ok(user_id) = identity.get_object_id("user_id") {
+                *counts.entry(user_id).or_default() += 1;
+            }
+        }
+        for mut user in store.find_all("user").await? {
+            let Ok(id) = user.get_object_id("_id") else {
+                continue;
+            };
+            let count = counts.get(&id).copied().unwrap_or_default();
+            if user.get_i32("identity_count").ok() != Some(count) {
+                user.insert("identity_count", count);
+                store.replace("user", user).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, store: &dyn MigrationStore) -> Result<()> {
+        for mut user in store.find_all("user").await? {
+            if user.remove("identity_count").is_some() {
+                store.replace("user", user).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// 角色引入之前的用户没有 `role`, 当时所有用户都可以发文章, 设置为 author.
+/// 新用户创建时写入 commenter. 无法区分哪些是迁移设置的, down 不恢复
+///
+struct LegacyUserRoles;
+
+#[poem::async_trait]
+impl Migration for LegacyUserRoles {
+    fn version(&self) -> i32 {
+        4
+    }
+
+    fn name(&self) -> &'static str {
+        "legacy_user_roles"
+    }
+
+    async fn up(&self, store: &dyn MigrationStore) -> Result<()> {
+        for mut user in store.find_all("user").await? {
+            if !user.contains_key("role") {
+                user.insert("role", "author");
+                store.replace("user", user).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn down(&self, _store: &dyn MigrationStore) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Status {
+    pub version: i32,
+    pub name: String,
+    /// 没有执行时为 None
+    pub applied_time: Option<DateTime<Utc>>,
+    /// 数据库中有记录但程序中没有这个迁移(由更新的版本执行)
+    pub unknown: bool,
+}
+
+/// 执行期间锁是否已经丢失, 由 [`Migrator::locked`] 在续期失败时设置
+#[derive(Default)]
+struct Lease {
+    lost: AtomicBool,
+}
+
+impl Lease {
+    fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    fn lose(&self) {
+        self.lost.store(true, Ordering::SeqCst);
+    }
+
+    /// 每个迁移执行完并记录之后检查, 锁已经丢失则不再执行后面的迁移
+    fn check(&self) -> Result<()> {
+        if self.is_lost() {
+            return Err(AppError::internal(
+                "数据迁移的锁已经过期或被其他进程获取, 当前的迁移已经完成, 停止执行后面的迁移",
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct Migrator {
+    migrations: Vec<Box<dyn Migration>>,
+    owner: String,
+    renew_interval: Duration,
+}
+
+impl Migrator {
+    pub fn new(migrations: Vec<Box<dyn Migration>>) -> Self {
+        let mut migrations = migrations;
+        migrations.sort_by_key(|m| m.version());
+        Self {
+            migrations,
+            owner: format!("{}-{}", std::process::id(), ObjectId::new()),
+            renew_interval: LOCK_RENEW_INTERVAL,
+        }
+    }
+
+    /// 加锁后执行 `f`, 执行期间定期续期, 无论成功与否都释放锁.
+    /// 不会中途丢弃 `f`(可能停在一个迁移的中间): 续期出错时重试, 直到锁确实过期;
+    /// 锁过期或被其他进程获取时通过 `lease` 通知 `f` 在当前的迁移完成后停止
+    async fn locked<T>(
+        &self,
+        store: &dyn MigrationStore,
+        lease: &Lease,
+        f: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let started = Instant::now();
+        while !store.lock(&self.owner).await? {
+            if started.elapsed() > LOCK_WAIT {
+                return Err(AppError::internal(
+                    "另一个进程正在执行数据迁移, 如果它已经退出, 请在锁过期后重试",
+                ));
+            }
+            info!("migrate: waiting for lock");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        let mut expires = Instant::now() + LOCK_TTL;
+        let mut renew = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.renew_interval,
+            self.renew_interval,
+        );
+        tokio::pin!(f);
+        let result = loop {
+            tokio::select! {
+                result = &mut f => break result,
+                _ = renew.tick(), if !lease.is_lost() => {
+                    let now = Instant::now();
+                    match store.lock(&self.owner).await {
+                        Ok(true) => expires = now + LOCK_TTL,
+                        Ok(false) => {
+                            warn!("migrate: lock was taken by another process");
+                            lease.lose();
+                        }
+                        Err(err) if now >= expires => {
+                            warn!("migrate: lock expired, renew failed: {}", err);
+                            lease.lose();
+                        }
+                        Err(err) => warn!("migrate: renew lock failed, will retry: {}", err),
+                    }
+                }
+            }
+        };
+        store.unlock(&self.owner).await?;
+        result
+    }
+
+    /// 执行版本号不超过 `target`(默认全部)的未执行的迁移, 返回执行了的版本
+    pub async fn up(&self, store: &dyn MigrationStore, target: Option<i32>) -> Result<Vec<i32>> {
+        let lease = Lease::default();
+        self.locked(store, &lease, async {
+            let applied: Vec<i32> = store.applied().await?.iter().map(|m| m.version).collect();
+            let mut done = Vec::new();
+            for migration in &self.migrations {
+                let version = migration.version();
+                if applied.contains(&version) || target.is_some_and(|t| version > t) {
+                    continue;
+                }
+                info!("migrate up: {} {}", version, migration.name());
+                migration.up(store).await?;
+                store
+                    .record(AppliedMigration {
+                        version,
+                        name: migration.name().to_owned(),
+                        applied_time: Utc::now(),
+                    })
+                    .await?;
+                done.push(version);
+                lease.check()?;
+            }
+            Ok(done)
+        })
+        .await
+    }
+
+    /// 按版本号从大到小撤销版本号大于 `target` 的迁移; 没有 `target` 时只撤销最后一个
+    pub async fn down(&self, store: &dyn MigrationStore, target: Option<i32>) -> Result<Vec<i32>> {
+        let lease = Lease::default();
+        self.locked(store, &lease, async {
+            let applied: Vec<i32> = store.applied().await?.iter().map(|m| m.version).collect();
+            let target = match target {
+                Some(target) => target,
+                None => match applied.iter().rev().nth(1) {
+                    Some(previous) => *previous,
+                    None => 0,
+                },
+            };
+            let mut done = Vec::new();
+            for version in applied.iter().rev().filter(|v| **v > target) {
+                let migration = self
+                    .migrations
+                    .iter()
+                    .find(|m| m.version() == *version)
+                    .ok_or_else(|| {
+                        AppError::internal(format!(
+                            "迁移 {} 不在当前版本的程序中, 无法撤销",
+                            version
+                        ))
+                    })?;
+                info!("migrate down: {} {}", version, migration.name());
+                migration.down(store).await?;
+                store.remove(*version).await?;
+                done.push(*version);
+                lease.check()?;
+            }
+            Ok(done)
+        })
+        .await
+    }
+
+    pub async fn status(&self, store: &dyn MigrationStore) -> Result<Vec<Status>> {
+        let mut applied: BTreeMap<i32, AppliedMigration> = store
+            .applied()
+            .await?
+            .into_iter()
+            .map(|m| (m.version, m))
+            .collect();
+        let mut result: Vec<Status> = self
+            .migrations
+            .iter()
+            .map(|m| Status {
+                version: m.version(),
+                name: m.name().to_owned(),
+                applied_time: applied.remove(&m.version()).map(|a| a.applied_time),
+                unknown: false,
+            })
+            .collect();
+        result.extend(applied.into_values().map(|a| Status {
+            version: a.version,
+            name: a.name,
+            applied_time: Some(a.applied_time),
+            unknown: true,
+        }));
+        result.sort_by_key(|s| s.version);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    ///
+    /// 进程内存储, 测试用
+    ///
+    #[derive(Default)]
+    struct MemoryStore {
+        lock: Mutex<Option<String>>,
+        applied: Mutex<BTreeMap<i32, AppliedMigration>>,
+        collections: Mutex<HashMap<String, Vec<Document>>>,
+    }
+
+    impl MemoryStore {
+        fn insert(&self, collection: &str, document: Document) {
+            self.collections
+                .lock()
+                .unwrap()
+                .entry(collection.to_owned())
+                .or_default()
+                .push(document);
+        }
+    }
+
+    #[poem::async_trait]
+    impl MigrationStore for MemoryStore {
+        async fn lock(&self, owner: &str) -> Result<bool> {
+            let mut lock = self.lock.lock().unwrap();
+            match lock.as_deref() {
+                Some(current) if current != owner => Ok(false),
+                _ => {
+                    *lock = Some(owner.to_owned());
+                    Ok(true)
+                }
+            }
+        }
+
+        async fn unlock(&self, owner: &str) -> Result<()> {
+            let mut lock = self.lock.lock().unwrap();
+            if lock.as_deref() == Some(owner) {
+                *lock = None;
+            }
+            Ok(())
+        }
+
+        async fn applied(&self) -> Result<Vec<AppliedMigration>> {
+            Ok(self.applied.lock().unwrap().values().cloned().collect())
+        }
+
+        async fn record(&self, migration: AppliedMigration) -> Result<()> {
+            self.applied
+                .lock()
+                .unwrap()
+                .insert(migration.version, migration);
+            Ok(())
+        }
+
+        async fn remove(&self, version: i32) -> Result<()> {
+            self.applied.lock().unwrap().remove(&version);
+            Ok(())
+        }
+
+        async fn find_all(&self, collection: &str) -> Result<Vec<Document>> {
+            Ok(self
+                .collections
+                .lock()
+                .unwrap()
+                .get(collection)
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        async fn replace(&self, collection: &str, document: Document) -> Result<()> {
+            let mut collections = self.collections.lock().unwrap();
+            let documents = collections.entry(collection.to_owned()).or_default();
+            if let Some(old) = documents
+                .iter_mut()
+                .find(|d| d.get("_id") == document.get("_id"))
+            {
+                *old = document;
+            }
+            Ok(())
+        }
+    }
+
+    fn comment(author: ObjectId, name: &str, millis: i64) -> Document {
+        doc! {
+            "author_id": author,
+            "author_name": name,
+            "reply_to": Bson::Null,
+            "created_time": bson::DateTime::from_millis(millis),
+        }
+    }
+
+    fn article_comments(store: &MemoryStore) -> Vec<Document> {
+        let articles = store.collections.lock().unwrap()["article"].clone();
+        articles[0]
+            .get_array("comments")
+            .unwrap()
+            .iter()
+            .map(|c| c.as_document().unwrap().clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_builtin_migrations() {
+        let store = MemoryStore::default();
+        let joey = ObjectId::new();
+        let other = ObjectId::new();
+        store.insert(
+            "user",
+            doc! {"_id": joey, "username": "Joeyscat", "role": "admin"},
+        );
+        let mut reply = comment(other, "other", 3000);
+        reply.insert("reply_to", joey);
+        reply.insert("reply_to_name", "joey");
+        store.insert(
+            "article",
+            doc! {
+                "_id": ObjectId::new(),
+                "comments": [comment(joey, "joey", 1000), comment(other, "other", 2000), reply],
+            },
+        );
+        store.insert(
+            "article",
+            doc! {"_id": ObjectId::new(), "title": "no comments"},
+        );
+
+        // 没有登录方式的用户
+        store.insert("user", doc! {"_id": ObjectId::new(), "username": "legacy"});
+        for user_id in [joey, joey, other] {
+            store.insert(
+                "identity",
+                doc! {"_id": ObjectId::new(), "user_id": user_id},
+            );
+        }
+
+        let migrator = Migrator::new(migrations());
+        assert_eq!(migrator.up(&store, None).await.unwrap(), vec![1, 2, 3, 4]);
+        let users = store.collections.lock().unwrap()["user"].clone();
+        let identity_counts: Vec<i32> = users
+            .iter()
+            .map(|u| u.get_i32("identity_count").unwrap())
+            .collect();
+        assert_eq!(identity_counts, vec![2, 0]);
+        let roles: Vec<&str> = users.iter().map(|u| u.get_str("role").unwrap()).collect();
+        assert_eq!(roles, vec!["admin", "author"]);
+        let comments = article_comments(&store);
+        let times: Vec<i64> = comments
+            .iter()
+            .map(|c| c.get_datetime("created_time").unwrap().timestamp_millis())
+            .collect();
+        assert_eq!(times, vec![3000, 2000, 1000]);
+        assert_eq!(comments[0].get_str("reply_to_name").unwrap(), "Joeyscat");
+        // 没有对应用户的评论不修改
+        assert_eq!(comments[1].get_str("author_name").unwrap(), "other");
+        assert_eq!(comments[2].get_str("author_name").unwrap(), "Joeyscat");
+
+        // 已经执行过的不再执行
+        assert!(migrator.up(&store, None).await.unwrap().is_empty());
+
+        assert_eq!(migrator.down(&store, Some(1)).await.unwrap(), vec![4, 3, 2]);
+        assert!(store.collections.lock().unwrap()["user"]
+            .iter()
+            .all(|u| !u.contains_key("identity_count")));
+        let times: Vec<i64> = article_comments(&store)
+            .iter()
+            .map(|c| c.get_datetime("created_time").unwrap().timestamp_millis())
+            .collect();
+        assert_eq!(times, vec![1000, 2000, 3000]);
+        let status = migrator.status(&store).await.unwrap();
+        assert!(status[0].applied_time.is_some());
+        assert!(status[1].applied_time.is_none());
+    }
+
+    struct Recording(i32, &'static Mutex<Vec<String>>);
+
+    #[poem::async_trait]
+    impl Migration for Recording {
+        fn version(&self) -> i32 {
+            self.0
+        }
+
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        async fn up(&self, _store: &dyn MigrationStore) -> Result<()> {
+            if self.0 == 30 {
+                return Err(AppError::internal("failed"));
+            }
+            self.1.lock().unwrap().push(format!("up {}", self.0));
+            Ok(())
+        }
+
+        async fn down(&self, _store: &dyn MigrationStore) -> Result<()> {
+            self.1.lock().unwrap().push(format!("down {}", self.0));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_order_and_targets() {
+        let log: &'static Mutex<Vec<String>> = Box::leak(Box::default());
+        let store = MemoryStore::default();
+        let migrator = Migrator::new(vec![
+            Box::new(Recording(20, log)),
+            Box::new(Recording(10, log)),
+            Box::new(Recording(30, log)),
+        ]);
+
+        assert_eq!(migrator.up(&store, Some(20)).await.unwrap(), vec![10, 20]);
+        // 失败的迁移不记录, 也不影响之前的
+        assert!(migrator.up(&store, None).await.is_err());
+        assert_eq!(store.lock.lock().unwrap().as_deref(), None);
+        assert_eq!(migrator.down(&store, Some(0)).await.unwrap(), vec![20, 10]);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["up 10", "up 20", "down 20", "down 10"]
+        );
+
+        // 由更新的程序执行的迁移
+        store
+            .record(AppliedMigration {
+                version: 40,
+                name: "future".to_owned(),
+                applied_time: Utc::now(),
+            })
+            .await
+            .unwrap();
+        let status = migrator.status(&store).await.unwrap();
+        assert_eq!(status.len(), 4);
+        assert!(status[3].unknown);
+        assert!(migrator.down(&store, None).await.is_err());
+    }
+
+    /// 第一次加锁成功, 之后的续期返回错误或者锁已经被其他进程获取
+    struct RenewStore {
+        inner: MemoryStore,
+        calls: Mutex<usize>,
+        taken: bool,
+    }
+
+    #[poem::async_trait]
+    impl MigrationStore for RenewStore {
+        async fn lock(&self, owner: &str) -> Result<bool> {
+            let calls = {
+                let mut calls = self.calls.lock().unwrap();
+                *calls += 1;
+                *calls
+            };
+            match calls {
+                1 => self.inner.lock(owner).await,
+                _ if self.taken => Ok(false),
+                _ => Err(AppError::internal("connection reset")),
+            }
+        }
+
+        async fn unlock(&self, owner: &str) -> Result<()> {
+            self.inner.unlock(owner).await
+        }
+
+        async fn applied(&self) -> Result<Vec<AppliedMigration>> {
+            self.inner.applied().await
+        }
+
+        async fn record(&self, migration: AppliedMigration) -> Result<()> {
+            self.inner.record(migration).await
+        }
+
+        async fn remove(&self, version: i32) -> Result<()> {
+            self.inner.remove(version).await
+        }
+
+        async fn find_all(&self, collection: &str) -> Result<Vec<Document>> {
+            self.inner.find_all(collection).await
+        }
+
+        async fn replace(&self, collection: &str, document: Document) -> Result<()> {
+            self.inner.replace(collection, document).await
+        }
+    }
+
+    struct Slow(i32);
+
+    #[poem::async_trait]
+    impl Migration for Slow {
+        fn version(&self) -> i32 {
+            self.0
+        }
+
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        async fn up(&self, _store: &dyn MigrationStore) -> Result<()> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(())
+        }
+
+        async fn down(&self, _store: &dyn MigrationStore) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_renewal() {
+        let mut migrator = Migrator::new(vec![Box::new(Slow(1)), Box::new(Slow(2))]);
+        migrator.renew_interval = Duration::from_millis(10);
+        let applied = |store: &RenewStore| -> Vec<i32> {
+            store
+                .inner
+                .applied
+                .lock()
+                .unwrap()
+                .keys()
+                .copied()
+                .collect()
+        };
+
+        // 续期出错时重试, 锁还没有过期, 继续执行
+        let store = RenewStore {
+            inner: MemoryStore::default(),
+            calls: Mutex::new(0),
+            taken: false,
+        };
+        assert_eq!(migrator.up(&store, None).await.unwrap(), vec![1, 2]);
+        assert!(*store.calls.lock().unwrap() > 2);
+
+        // 锁被其他进程获取: 当前的迁移执行完并记录, 后面的不再执行
+        let store = RenewStore {
+            inner: MemoryStore::default(),
+            calls: Mutex::new(0),
+            taken: true,
+        };
+        assert!(migrator.up(&store, None).await.is_err());
+        assert_eq!(applied(&store), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let store = MemoryStore::default();
+        assert!(store.lock("other").await.unwrap());
+        let migrator = Migrator::new(Vec::new());
+        let task = tokio::spawn(async move {
+            let result = migrator.up(&store, None).await;
+            (result, store)
+        });
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!task.is_finished());
+        task.abort();
+    }
+}
@@ -46,6 +46,9 @@ impl Default for Article {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Comment {
+    /// 引入审核之前的评论没有 id
+    #[serde(default)]
+    pub id: Option<ObjectId>,
     pub content: String,
     pub author_id: ObjectId,
     pub author_name: String,
@@ -56,22 +59,36 @@ pub struct Comment {
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub updated_time: DateTime<Utc>,
     pub status: i16,
+    /// 进入待审核队列或被判为垃圾评论的原因
+    #[serde(default)]
+    pub moderation_reason: Option<String>,
+    /// 垃圾评论分类器给出的概率
+    #[serde(default)]
+    pub spam_score: Option<f64>,
+    /// 审核人, 只有审核过的评论会用于训练分类器
+    #[serde(default)]
+    pub moderated_by: Option<ObjectId>,
 }
 
 impl Comment {
+    /// 公开显示
+    pub const VISIBLE: i16 = 1;
+    /// 等待审核
+    pub const PENDING: i16 = 0;
+    /// 审核未通过
+    pub const REJECTED: i16 = -1;
+    /// 垃圾评论
+    pub const SPAM: i16 = -2;
+
     pub fn new(
         content: String,
-        author_id: String,
+        author_id: ObjectId,
         author_name: String,
-        reply_to: Option<String>,
+        reply_to: Option<ObjectId>,
         reply_to_name: Option<String>,
     ) -> Self {
-        let author_id = ObjectId::from_str(author_id.as_str()).unwrap();
-        let reply_to = reply_to
-            .as_ref()
-            .map(|s| ObjectId::from_str(s.as_str()).unwrap());
-
         Self {
+            id: Some(ObjectId::new()),
             content,
             author_name,
             author_id,
@@ -79,7 +96,10 @@ impl Comment {
             reply_to_name,
             created_time: Utc::now(),
             updated_time: Utc::now(),
-            status: 1,
+            status: Comment::VISIBLE,
+            moderation_reason: None,
+            spam_score: None,
+            moderated_by: None,
         }
     }
 }
@@ -96,6 +116,186 @@ pub struct User {
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub updated_time: DateTime<Utc>,
     pub status: i16,
+    /// 角色引入之前的用户由数据迁移设置为 author
+    pub role: Role,
+    #[serde(default)]
+    pub totp: Option<Totp>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    #[serde(default)]
+    pub blog: Option<String>,
+    #[serde(default)]
+    pub ban: Option<Ban>,
+    /// 关闭的通知类型
+    #[serde(default)]
+    pub muted_notifications: Vec<String>,
+    /// 关闭的邮件通知类型
+    #[serde(default)]
+    pub muted_emails: Vec<String>,
+    /// 订阅每周摘要邮件
+    #[serde(default)]
+    pub weekly_digest: bool,
+}
+
+impl User {
+    /// 正常
+    pub const ACTIVE: i16 = 1;
+    /// 已封禁: 不能登录, 内容对所有人隐藏
+    pub const BANNED: i16 = -1;
+    /// 影子封禁: 可以登录, 内容只有本人可见
+    pub const SHADOW_BANNED: i16 = -2;
+
+    /// 当前生效的封禁, 过期的封禁不算
+    pub fn active_ban(&self, now: DateTime<Utc>) -> Option<&Ban> {
+        if self.status == User::ACTIVE {
+            return None;
+        }
+        self.ban
+            .as_ref()
+            .filter(|ban| ban.expires_at.is_none_or(|t| t.to_chrono() > now))
+    }
+
+    /// 该用户的内容是否对 `viewer` 隐藏
+    pub fn is_hidden_from(&self, viewer: Option<ObjectId>, now: DateTime<Utc>) -> bool {
+        match self.active_ban(now) {
+            Some(ban) => ban.kind == BanKind::Ban || viewer != Some(self.id),
+            None => false,
+        }
+    }
+
+    /// 是否接收某类通知, 默认全部接收
+    pub fn wants_notification(&self, kind: &str) -> bool {
+        !self.muted_notifications.iter().any(|k| k == kind)
+    }
+
+    /// 邮箱使用第三方登录时获取的 email
+    pub fn email(&self) -> Option<String> {
+        self.inner
+            .get_str("email")
+            .ok()
+            .map(str::trim)
+            .filter(|email| !email.is_empty())
+            .map(str::to_owned)
+    }
+
+    /// 头像使用第三方登录时获取的 avatar_url
+    pub fn avatar_url(&self) -> Option<String> {
+        self.inner.get_str("avatar_url").ok().map(str::to_owned)
+    }
+
+    /// 没有设置过博客链接时, 使用第三方账户中的
+    pub fn blog_url(&self) -> Option<String> {
+        self.blog
+            .clone()
+            .or_else(|| self.inner.get_str("blog").ok().map(str::to_owned))
+            .filter(|b| b.starts_with("http://") || b.starts_with("https://"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanKind {
+    Ban,
+    ShadowBan,
+}
+
+impl BanKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BanKind::Ban => "ban",
+            BanKind::ShadowBan => "shadow_ban",
+        }
+    }
+
+    /// 对应的用户状态
+    pub fn status(&self) -> i16 {
+        match self {
+            BanKind::Ban => User::BANNED,
+            BanKind::ShadowBan => User::SHADOW_BANNED,
+        }
+    }
+}
+
+impl FromStr for BanKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ban" => Ok(BanKind::Ban),
+            "shadow_ban" => Ok(BanKind::ShadowBan),
+            _ => Err(format!("unknown ban kind: {}", s)),
+        }
+    }
+}
+
+///
+/// 封禁信息, 内嵌在 user 中, 解封时删除
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub kind: BanKind,
+    pub reason: String,
+    /// 到期时间, 为空表示永久封禁
+    pub expires_at: Option<bson::DateTime>,
+    /// 封禁人, 用管理工具(blog-admin)封禁时为空
+    pub banned_by: Option<ObjectId>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+}
+
+///
+/// 两步验证(TOTP)设置, 内嵌在 user 中
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Totp {
+    pub secret: String,
+    /// 最近一次验证通过的时间步, 防止验证码被重复使用
+    pub last_counter: Option<i64>,
+    /// 一次性恢复码的哈希
+    pub recovery_codes: Vec<String>,
+    /// 连续验证失败的次数, 验证通过后清零
+    #[serde(default)]
+    pub failed_attempts: i32,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub enabled_time: DateTime<Utc>,
+}
+
+///
+/// 用户角色, 权限见 policy 模块
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Editor,
+    Author,
+    // 新用户只能评论, 发文章需要管理员修改角色
+    #[default]
+    Commenter,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Admin, Role::Editor, Role::Author, Role::Commenter];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Editor => "editor",
+            Role::Author => "author",
+            Role::Commenter => "commenter",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|r| r.as_str() == s)
+            .ok_or_else(|| format!("unknown role: {}", s))
+    }
 }
 
 impl Default for User {
@@ -108,6 +308,52 @@ impl Default for User {
             created_time: Utc::now(),
             updated_time: MIN_DATETIME,
             status: 1,
+            role: Role::default(),
+            totp: None,
+            bio: None,
+            blog: None,
+            ban: None,
+            muted_notifications: Vec::new(),
+            muted_emails: Vec::new(),
+            weekly_digest: false,
+        }
+    }
+}
+
+///
+/// Model: Identity
+/// Db table: identity
+///
+/// 一个用户可以绑定多个登录方式(gitee/github/password)
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub provider: String,
+    pub provider_uid: String,
+    pub login: String,
+    pub inner: Document,
+    pub secret: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub updated_time: DateTime<Utc>,
+}
+
+impl Identity {
+    pub fn new(provider: &str, provider_uid: String, login: String, inner: Document) -> Self {
+        Self {
+            id: ObjectId::new(),
+            user_id: Default::default(),
+            provider: provider.to_owned(),
+            provider_uid,
+            login,
+            inner,
+            secret: None,
+            created_time: Utc::now(),
+            updated_time: Utc::now(),
         }
     }
 }
@@ -142,10 +388,39 @@ mod tests {
         println!("dd:\t{}", dd);
     }
 
+    #[test]
+    fn test_ban() {
+        let now = Utc::now();
+        let viewer = ObjectId::new();
+        let mut user = User::default();
+        assert!(user.active_ban(now).is_none());
+
+        user.status = User::SHADOW_BANNED;
+        user.ban = Some(Ban {
+            kind: BanKind::ShadowBan,
+            reason: "spam".to_owned(),
+            expires_at: None,
+            banned_by: Some(viewer),
+            created_time: now,
+        });
+        assert!(user.is_hidden_from(Some(viewer), now));
+        assert!(user.is_hidden_from(None, now));
+        assert!(!user.is_hidden_from(Some(user.id), now));
+
+        user.status = User::BANNED;
+        user.ban.as_mut().unwrap().kind = BanKind::Ban;
+        assert!(user.is_hidden_from(Some(user.id), now));
+
+        // 过期之后自动失效
+        user.ban.as_mut().unwrap().expires_at = Some((now - chrono::Duration::days(1)).into());
+        assert!(user.active_ban(now).is_none());
+        assert!(!user.is_hidden_from(None, now));
+    }
+
     #[test]
     fn test_div() {
         let d = (8 as f32 / 3 as f32).ceil() as i32;
-        println!("{}",d);
+        println!("{}", d);
 
         let mut v = vec![1];
         for i in 2..d + 1 {
@@ -155,7 +430,7 @@ mod tests {
         println!("{:?}", v);
 
         let d = (8 as f32 / 2 as f32).ceil() as i32;
-        println!("{}",d);
+        println!("{}", d);
 
         let mut v = vec![1];
         for i in 2..d + 1 {
@@ -0,0 +1,274 @@
+//!
+//! 评论审核: 新评论按配置的策略直接显示、进入待审核队列或判为垃圾评论,
+//! 审核人的决定用于训练垃圾评论分类器
+//!
+use std::str::FromStr;
+
+use mongodb::{bson::doc, Database};
+use tracing::{info, warn};
+
+use crate::audit::{self, AuditEntry};
+use crate::config::{self, ModerationConfig};
+use crate::db::{self, UserComment};
+use crate::error::Result;
+use crate::model::{Comment, User};
+use crate::notification;
+use crate::policy::{self, Permission};
+use crate::spam;
+
+/// 对一条新评论的判定结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub status: i16,
+    pub reason: Option<String>,
+    pub spam_score: Option<f64>,
+}
+
+/// 评论中的链接数
+pub fn count_links(content: &str) -> usize {
+    let content = content.to_lowercase();
+    content.matches("http://").count() + content.matches("https://").count()
+}
+
+/// 评论包含的第一个屏蔽词
+fn blocked_keyword<'a>(content: &str, blocklist: &'a [String]) -> Option<&'a str> {
+    let content = content.to_lowercase();
+    blocklist
+        .iter()
+        .map(|k| k.trim())
+        .find(|k| !k.is_empty() && content.contains(&k.to_lowercase()))
+}
+
+///
+/// 按策略判定新评论, 依次检查: 屏蔽词和分类器(判为垃圾评论), 链接数、分类器和首次评论(进入待审核队列)
+///
+pub fn evaluate(
+    content: &str,
+    config: &ModerationConfig,
+    first_time: bool,
+    spam_score: Option<f64>,
+) -> Verdict {
+    let verdict = |status, reason: Option<String>| Verdict {
+        status,
+        reason,
+        spam_score,
+    };
+
+    if let Some(keyword) = blocked_keyword(content, &config.blocklist) {
+        return verdict(Comment::SPAM, Some(format!("包含屏蔽词: {}", keyword)));
+    }
+    match spam_score {
+        Some(score) if score >= config.spam_threshold => {
+            return verdict(
+                Comment::SPAM,
+                Some(format!("垃圾评论概率 {:.0}%", score * 100.0)),
+            );
+        }
+        _ => {}
+    }
+    let links = count_links(content);
+    if links > config.max_links {
+        return verdict(Comment::PENDING, Some(format!("包含 {} 个链接", links)));
+    }
+    match spam_score {
+        Some(score) if score >= config.hold_threshold => {
+            return verdict(
+                Comment::PENDING,
+                Some(format!("垃圾评论概率 {:.0}%", score * 100.0)),
+            );
+        }
+        _ => {}
+    }
+    if first_time {
+        return verdict(Comment::PENDING, Some("首次评论".to_owned()));
+    }
+    verdict(Comment::VISIBLE, None)
+}
+
+/// 判定用户发表的新评论, 有审核权限的用户不受限制
+pub async fn check(content: &str, author: &User, mongo: &Database) -> Result<Verdict> {
+    let config = &config::get().moderation;
+    if policy::can(author.role, Permission::ModerateComments) {
+        return Ok(Verdict {
+            status: Comment::VISIBLE,
+            reason: None,
+            spam_score: None,
+        });
+    }
+
+    let first_time = config.hold_first_time && !db::has_visible_comment(author.id, mongo).await?;
+    let spam_score = if config.spam_filter {
+        // 分类器出错时不影响发表评论, 只是少一项检查
+        spam::score(content, config.min_training, mongo)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("spam filter error: {}", err);
+                None
+            })
+    } else {
+        None
+    };
+    Ok(evaluate(content, config, first_time, spam_score))
+}
+
+/// 审核人的决定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Approve,
+    Reject,
+    Spam,
+}
+
+impl Decision {
+    pub fn status(&self) -> i16 {
+        match self {
+            Decision::Approve => Comment::VISIBLE,
+            Decision::Reject => Comment::REJECTED,
+            Decision::Spam => Comment::SPAM,
+        }
+    }
+}
+
+impl FromStr for Decision {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "approve" => Ok(Decision::Approve),
+            "reject" => Ok(Decision::Reject),
+            "spam" => Ok(Decision::Spam),
+            _ => Err(format!("unknown decision: {}", s)),
+        }
+    }
+}
+
+/// 用于训练分类器的类别: 通过的是正常评论, 标记为垃圾的是垃圾评论, 拒绝的不参与训练
+fn training_class(status: i16) -> Option<bool> {
+    match status {
+        Comment::VISIBLE => Some(false),
+        Comment::SPAM => Some(true),
+        _ => None,
+    }
+}
+
+/// 审核一条评论, 并用这次决定训练分类器(撤销之前审核的训练)
+pub async fn decide(
+    comment_id: &str,
+    decision: Decision,
+    moderator: &User,
+    mongo: &Database,
+) -> Result<()> {
+    let UserComment {
+        article_id,
+        comment,
+        ..
+    } = db::find_comment(comment_id, mongo).await?;
+    db::moderate_comment(comment_id, decision.status(), moderator.id, mongo).await?;
+    info!(
+        "comment {} moderated by {}: {:?}",
+        comment_id, moderator.username, decision
+    );
+    AuditEntry::new(audit::COMMENT_MODERATE, "comment", comment_id)
+        .actor(moderator)
+        .detail(doc! {
+            "article_id": article_id.to_string(),
+            "author_name": &comment.author_name,
+            "previous_status": comment.status as i32,
+            "status": decision.status() as i32,
+        })
+        .record(mongo)
+        .await;
+
+    // 评论第一次显示出来时通知相关的人
+    if decision == Decision::Approve && comment.status != Comment::VISIBLE {
+        let author = db::find_user_by_id(&comment.author_id.to_string(), mongo).await?;
+        if let Err(err) = notification::notify_comment(article_id, &comment, &author, mongo).await {
+            warn!("notify comment {} error: {}", comment_id, err);
+        }
+    }
+
+    let previous = comment
+        .moderated_by
+        .and_then(|_| training_class(comment.status));
+    let current = training_class(decision.status());
+    if previous != current {
+        if let Some(is_spam) = previous {
+            spam::train(&comment.content, is_spam, -1, mongo).await?;
+        }
+        if let Some(is_spam) = current {
+            spam::train(&comment.content, is_spam, 1, mongo).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 用所有审核过的评论重新训练分类器, 返回训练样本数 (垃圾评论, 正常评论)
+pub async fn retrain(mongo: &Database) -> Result<(i64, i64)> {
+    let mut model = spam::Model::default();
+    for comment in db::list_moderated_comments(mongo).await? {
+        if let Some(is_spam) = training_class(comment.status) {
+            model.train(&spam::tokenize(&comment.content), is_spam);
+        }
+    }
+    let size = (model.spam_docs, model.ham_docs);
+    spam::replace(model, mongo).await?;
+    info!("spam filter retrained: spam={}, ham={}", size.0, size.1);
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_links() {
+        assert_eq!(count_links("no links"), 0);
+        assert_eq!(
+            count_links("see HTTPS://a.com and http://b.com, [c](https://c.com)"),
+            3
+        );
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let config = ModerationConfig {
+            blocklist: vec!["Casino".to_owned(), " ".to_owned()],
+            ..ModerationConfig::default()
+        };
+
+        let ok = evaluate("写得很好", &config, false, None);
+        assert_eq!(ok.status, Comment::VISIBLE);
+        assert_eq!(ok.reason, None);
+
+        let first = evaluate("写得很好", &config, true, None);
+        assert_eq!(first.status, Comment::PENDING);
+        assert_eq!(first.reason.as_deref(), Some("首次评论"));
+
+        let links = evaluate("http://a http://b http://c", &config, false, None);
+        assert_eq!(links.status, Comment::PENDING);
+        assert_eq!(links.reason.as_deref(), Some("包含 3 个链接"));
+
+        let blocked = evaluate("online CASINO", &config, false, Some(0.1));
+        assert_eq!(blocked.status, Comment::SPAM);
+        assert_eq!(blocked.reason.as_deref(), Some("包含屏蔽词: Casino"));
+        assert_eq!(blocked.spam_score, Some(0.1));
+
+        assert_eq!(
+            evaluate("x", &config, false, Some(0.8)).status,
+            Comment::PENDING
+        );
+        assert_eq!(
+            evaluate("x", &config, false, Some(0.99)).status,
+            Comment::SPAM
+        );
+    }
+
+    #[test]
+    fn test_training_class() {
+        assert_eq!(training_class(Decision::Approve.status()), Some(false));
+        assert_eq!(training_class(Decision::Spam.status()), Some(true));
+        assert_eq!(training_class(Decision::Reject.status()), None);
+        assert_eq!(Decision::from_str("spam"), Ok(Decision::Spam));
+        assert!(Decision::from_str("delete").is_err());
+    }
+}
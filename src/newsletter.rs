@@ -0,0 +1,569 @@
+//!
+//! 邮件订阅: 没有账户的读者也可以用邮箱订阅新文章, 需要点击确认邮件中的链接才生效(double opt-in).
+//! 文章发表时给订阅了相应标签的读者发送邮件, 订阅者保存在 `subscriber` 集合中
+//!
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument},
+    Database, IndexModel,
+};
+use serde_derive::{Deserialize, Serialize};
+use tera::Context;
+use tracing::{info, warn};
+
+use crate::config;
+use crate::error::{AppError, Result};
+use crate::mail;
+use crate::model::{Article, User};
+use crate::notification;
+
+/// 确认订阅的链接
+pub const CONFIRM_PATH: &str = "/subscribe/confirm";
+
+/// 退订链接, 带有签名, 支持一键退订
+pub const UNSUBSCRIBE_PATH: &str = "/newsletter/unsubscribe";
+
+/// 退订链接签名的用途, 确认链接的用途还包含订阅的标签
+const UNSUBSCRIBE_PURPOSE: &str = "newsletter";
+
+/// 同一个邮箱两封确认邮件之间的最短间隔
+const CONFIRM_INTERVAL_MINUTES: i64 = 10;
+
+/// 没有确认的订阅保留的天数
+const PENDING_DAYS: u64 = 7;
+
+/// 导出 CSV 的表头, 导入时 `email` 列必须有, 其他列可选
+const CSV_HEADERS: [&str; 5] = ["email", "tags", "status", "created_time", "confirmed_time"];
+
+///
+/// Model: Subscriber
+/// Db table: subscriber
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscriber {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// 小写
+    pub email: String,
+    /// 订阅的标签, 为空时接收所有文章
+    pub tags: Vec<String>,
+    /// pending/active/unsubscribed
+    pub status: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+    pub confirmed_time: Option<bson::DateTime>,
+    /// 上次发送确认邮件的时间
+    pub confirm_sent_time: Option<bson::DateTime>,
+}
+
+impl Subscriber {
+    pub const PENDING: &'static str = "pending";
+    pub const ACTIVE: &'static str = "active";
+    pub const UNSUBSCRIBED: &'static str = "unsubscribed";
+    pub const STATUSES: [&'static str; 3] = [Self::PENDING, Self::ACTIVE, Self::UNSUBSCRIBED];
+}
+
+/// `subscriber` 集合的索引, 启动时由 [`crate::indexes`] 创建
+pub fn indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"email": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"status": 1, "created_time": -1})
+            .build(),
+        // 没有确认的订阅过期后删除
+        IndexModel::builder()
+            .keys(doc! {"created_time": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(PENDING_DAYS * 24 * 3600))
+                    .partial_filter_expression(doc! {"status": Subscriber::PENDING})
+                    .name("pending_ttl".to_owned())
+                    .build(),
+            )
+            .build(),
+    ]
+}
+
+/// 解析逗号分隔的标签(中英文逗号都可以), 去掉空白和重复, 统一为小写
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for tag in tags.split([',', '，']) {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !result.contains(&tag) {
+            result.push(tag);
+        }
+    }
+    result
+}
+
+/// 订阅了 `subscribed` 标签的读者是否接收带有 `tags` 标签的文章
+pub fn matches(subscribed: &[String], tags: &[String]) -> bool {
+    subscribed.is_empty() || subscribed.iter().any(|t| tags.contains(t))
+}
+
+fn confirm_purpose(tags: &[String]) -> String {
+    format!("confirm:{}", tags.join(","))
+}
+
+fn link(path: &str, subscriber_id: ObjectId, tags: Option<&[String]>, token: &str) -> String {
+    let mut params = vec![("sid", subscriber_id.to_string())];
+    if let Some(tags) = tags {
+        params.push(("tags", tags.join(",")));
+    }
+    params.push(("token", token.to_owned()));
+    format!(
+        "{}{}?{}",
+        config::get().mail.site_url.trim_end_matches('/'),
+        path,
+        serde_urlencoded::to_string(params).unwrap_or_default()
+    )
+}
+
+pub fn verify_confirm_token(subscriber_id: ObjectId, tags: &[String], token: &str) -> bool {
+    let secret = &config::get().mail.secret;
+    mail::verify_token(secret, subscriber_id, &confirm_purpose(tags), token)
+}
+
+pub fn verify_unsubscribe_token(subscriber_id: ObjectId, token: &str) -> bool {
+    let secret = &config::get().mail.secret;
+    mail::verify_token(secret, subscriber_id, UNSUBSCRIBE_PURPOSE, token)
+}
+
+fn unsubscribe_url(subscriber_id: ObjectId) -> String {
+    let secret = &config::get().mail.secret;
+    let token = mail::sign_token(secret, subscriber_id, UNSUBSCRIBE_PURPOSE);
+    link(UNSUBSCRIBE_PATH, subscriber_id, None, &token)
+}
+
+///
+/// 订阅: 第一次订阅时创建 pending 状态的记录, 然后发送确认邮件, 确认后才生效.
+/// 已经订阅过的邮箱也发送确认邮件, 确认后更新订阅的标签. 为了不泄露邮箱是否订阅过, 两种情况的返回相同;
+/// 同一个邮箱 10 分钟内只发送一封确认邮件
+///
+pub async fn subscribe(email: &str, tags: &[String], mongo: &Database) -> Result<()> {
+    let collection = mongo.collection::<Subscriber>("subscriber");
+    let email = email.trim().to_lowercase();
+    let now = Utc::now();
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let subscriber = collection
+        .find_one_and_update(
+            doc! {"email": &email},
+            doc! {"$setOnInsert": {
+                "tags": [],
+                "status": Subscriber::PENDING,
+                "created_time": now,
+                "confirmed_time": null,
+            }},
+            options,
+        )
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let resend_before = now - chrono::Duration::minutes(CONFIRM_INTERVAL_MINUTES);
+    let claimed = collection
+        .update_one(
+            doc! {
+                "_id": subscriber.id,
+                "$or": [
+                    {"confirm_sent_time": null},
+                    {"confirm_sent_time": {"$lt": resend_before}},
+                ],
+            },
+            doc! {"$set": {"confirm_sent_time": now}},
+            None,
+        )
+        .await?
+        .modified_count
+        > 0;
+    if !claimed {
+        info!("skip newsletter confirmation for {}: sent recently", email);
+        return Ok(());
+    }
+
+    let secret = &config::get().mail.secret;
+    let token = mail::sign_token(secret, subscriber.id, &confirm_purpose(tags));
+    let mut context = Context::new();
+    context.insert("tags", tags);
+    context.insert(
+        "confirm_url",
+        &link(CONFIRM_PATH, subscriber.id, Some(tags), &token),
+    );
+    let subject = "确认订阅 Joeyscat".to_owned();
+    mail::enqueue(email, subject, "newsletter_confirm", context, None, mongo).await
+}
+
+/// 确认订阅, 设置订阅的标签. 没有确认的订阅过期删除后链接失效
+pub async fn confirm(
+    subscriber_id: ObjectId,
+    tags: &[String],
+    mongo: &Database,
+) -> Result<Subscriber> {
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let subscriber = mongo
+        .collection::<Subscriber>("subscriber")
+        .find_one_and_update(
+            doc! {"_id": subscriber_id},
+            doc! {"$set": {
+                "tags": tags.to_vec(),
+                "status": Subscriber::ACTIVE,
+                "confirmed_time": Utc::now(),
+            }},
+            options,
+        )
+        .await?
+        .ok_or(AppError::NotFound)?;
+    info!("newsletter subscription confirmed: {}", subscriber.email);
+    Ok(subscriber)
+}
+
+pub async fn find(subscriber_id: ObjectId, mongo: &Database) -> Result<Subscriber> {
+    mongo
+        .collection::<Subscriber>("subscriber")
+        .find_one(doc! {"_id": subscriber_id}, None)
+        .await?
+        .ok_or(AppError::NotFound)
+}
+
+/// 退订, 保留记录, 导入时不会重新订阅
+pub async fn unsubscribe(subscriber_id: ObjectId, mongo: &Database) -> Result<bool> {
+    let matched_count = mongo
+        .collection::<Subscriber>("subscriber")
+        .update_one(
+            doc! {"_id": subscriber_id},
+            doc! {"$set": {"status": Subscriber::UNSUBSCRIBED}},
+            None,
+        )
+        .await?
+        .matched_count;
+    Ok(matched_count > 0)
+}
+
+pub async fn delete(subscriber_id: ObjectId, mongo: &Database) -> Result<Option<Subscriber>> {
+    let subscriber = mongo
+        .collection::<Subscriber>("subscriber")
+        .find_one_and_delete(doc! {"_id": subscriber_id}, None)
+        .await?;
+    Ok(subscriber)
+}
+
+///
+/// 文章发表时给订阅者发送邮件, 返回发送的数量.
+/// `newsletter` 集合中按文章 id 记录发送过的文章, 同一篇文章只发送一次
+///
+pub async fn send_article(article: &Article, mongo: &Database) -> Result<usize> {
+    let sent = mongo
+        .collection::<Document>("newsletter")
+        .insert_one(doc! {"_id": article.id, "created_time": Utc::now()}, None)
+        .await;
+    if let Err(err) = sent {
+        return match *err.kind {
+            ErrorKind::Write(WriteFailure::WriteError(ref e)) if e.code == 11000 => Ok(0),
+            _ => Err(err.into()),
+        };
+    }
+
+    let tags = parse_tags(&article.tags);
+    let mut context = Context::new();
+    context.insert("article_id", &article.id.to_string());
+    context.insert("article_title", &article.title);
+    context.insert("author_name", &article.author_name);
+    context.insert("excerpt", &notification::excerpt(&article.raw_content));
+    context.insert("tags", &tags);
+
+    let mut cursor = mongo
+        .collection::<Subscriber>("subscriber")
+        .find(doc! {"status": Subscriber::ACTIVE}, None)
+        .await?;
+    let mut count = 0;
+    while let Some(subscriber) = cursor.next().await {
+        let subscriber = subscriber?;
+        if !matches(&subscriber.tags, &tags) {
+            continue;
+        }
+        let unsubscribe_url = unsubscribe_url(subscriber.id);
+        mail::enqueue(
+            subscriber.email,
+            article.title.clone(),
+            "newsletter",
+            context.clone(),
+            Some(unsubscribe_url),
+            mongo,
+        )
+        .await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// 文章发表后在后台发送邮件, 需要开启邮件, 作者被封禁(包括影子封禁)时不发送
+pub fn on_publish(article: Article, author: &User, mongo: &Database) {
+    if !config::get().mail.enabled || author.is_hidden_from(None, Utc::now()) {
+        return;
+    }
+    let mongo = mongo.clone();
+    tokio::spawn(async move {
+        match send_article(&article, &mongo).await {
+            Ok(count) => info!(
+                "newsletter for article {} queued for {} subscribers",
+                article.id, count
+            ),
+            Err(err) => warn!("send newsletter for article {} error: {}", article.id, err),
+        }
+    });
+}
+
+/// 按状态筛选, 按订阅时间倒序分页, `limit` 为 0 时不限制, 返回 (当前页, 总数)
+pub async fn list(
+    status: Option<&str>,
+    skip: u64,
+    limit: i64,
+    mongo: &Database,
+) -> Result<(Vec<Subscriber>, u64)> {
+    let collection = mongo.collection::<Subscriber>("subscriber");
+    let query = match status {
+        Some(status) => doc! {"status": status},
+        None => doc! {},
+    };
+    let total = collection.count_documents(query.clone(), None).await?;
+
+    let options = FindOptions::builder()
+        .sort(doc! {"created_time": -1})
+        .skip(skip)
+        .limit(limit)
+        .build();
+    let mut cursor = collection.find(query, options).await?;
+    let mut result = Vec::new();
+    while let Some(subscriber) = cursor.next().await {
+        result.push(subscriber?);
+    }
+    Ok((result, total))
+}
+
+/// 导出为 CSV, 时间使用页面显示的时区
+pub fn to_csv(subscribers: &[Subscriber], timezone: &FixedOffset) -> Result<Vec<u8>> {
+    let format = |time: DateTime<Utc>| {
+        time.with_timezone(timezone)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer
+        .write_record(CSV_HEADERS)
+        .map_err(AppError::internal)?;
+    for subscriber in subscribers {
+        writer
+            .write_record([
+                subscriber.email.clone(),
+                subscriber.tags.join(","),
+                subscriber.status.clone(),
+                format(subscriber.created_time),
+                subscriber
+                    .confirmed_time
+                    .map(|t| format(t.to_chrono()))
+                    .unwrap_or_default(),
+            ])
+            .map_err(AppError::internal)?;
+    }
+    writer.into_inner().map_err(AppError::internal)
+}
+
+/// CSV 中的一行
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImportRow {
+    pub email: String,
+    pub tags: Vec<String>,
+    pub status: String,
+}
+
+///
+/// 解析导入的 CSV, 第一行是表头, 需要有 `email` 列, 可以有 `tags` 和 `status` 列(导出的文件可以直接导入).
+/// 没有 status 时为 active(导入的订阅者视为已经确认过). 返回 (有效的行, 错误信息)
+///
+pub fn parse_csv(data: &[u8]) -> Result<(Vec<ImportRow>, Vec<String>)> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(data);
+    let headers = reader
+        .headers()
+        .map_err(|err| AppError::bad_request(err.to_string()))?
+        .clone();
+    let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let email_column = column("email").ok_or_else(|| AppError::bad_request("CSV 缺少 email 列"))?;
+    let (tags_column, status_column) = (column("tags"), column("status"));
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        // 表头是第 1 行
+        let line = i + 2;
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                errors.push(format!("第 {} 行: {}", line, err));
+                continue;
+            }
+        };
+        let field = |column: Option<usize>| column.and_then(|c| record.get(c)).unwrap_or_default();
+        let email = field(Some(email_column)).to_lowercase();
+        if !validator::validate_email(&email) {
+            errors.push(format!("第 {} 行: 邮箱格式不正确: {}", line, email));
+            continue;
+        }
+        let status = match field(status_column) {
+            "" => Subscriber::ACTIVE,
+            status => match Subscriber::STATUSES.iter().find(|s| **s == status) {
+                Some(status) => status,
+                None => {
+                    errors.push(format!("第 {} 行: 未知的状态: {}", line, status));
+                    continue;
+                }
+            },
+        };
+        rows.push(ImportRow {
+            email,
+            tags: parse_tags(field(tags_column)),
+            status: status.to_owned(),
+        });
+    }
+    Ok((rows, errors))
+}
+
+/// 导入结果
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub inserted: u64,
+    pub updated: u64,
+    /// 已经退订的邮箱不会被导入重新订阅
+    pub skipped: u64,
+    pub errors: Vec<String>,
+}
+
+/// 按邮箱导入: 新邮箱插入, 已有的更新标签和状态, 重复导入同一个文件结果不变
+pub async fn import(rows: Vec<ImportRow>, mongo: &Database) -> Result<ImportReport> {
+    let collection = mongo.collection::<Subscriber>("subscriber");
+    let mut report = ImportReport::default();
+    for row in rows {
+        let now = Utc::now();
+        let existing = collection
+            .find_one(doc! {"email": &row.email}, None)
+            .await?;
+        match existing {
+            Some(s) if s.status == Subscriber::UNSUBSCRIBED && row.status != s.status => {
+                report.skipped += 1;
+            }
+            Some(s) => {
+                let mut update = doc! {"tags": &row.tags, "status": &row.status};
+                if row.status == Subscriber::ACTIVE && s.confirmed_time.is_none() {
+                    update.insert("confirmed_time", now);
+                }
+                collection
+                    .update_one(doc! {"_id": s.id}, doc! {"$set": update}, None)
+                    .await?;
+                report.updated += 1;
+            }
+            None => {
+                let confirmed_time =
+                    (row.status == Subscriber::ACTIVE).then(|| bson::DateTime::from_chrono(now));
+                let subscriber = Subscriber {
+                    id: ObjectId::new(),
+                    email: row.email,
+                    tags: row.tags,
+                    status: row.status,
+                    created_time: now,
+                    confirmed_time,
+                    confirm_sent_time: None,
+                };
+                collection.insert_one(subscriber, None).await?;
+                report.inserted += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(parse_tags(""), Vec::<String>::new());
+        assert_eq!(parse_tags(" Rust, web，rust ,, "), tags(&["rust", "web"]));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(&[], &tags(&["rust"])));
+        assert!(matches(&[], &[]));
+        assert!(matches(&tags(&["go", "rust"]), &tags(&["rust", "web"])));
+        assert!(!matches(&tags(&["go"]), &tags(&["rust"])));
+        assert!(!matches(&tags(&["go"]), &[]));
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let data = "Email,tags,status\n\
+                    Joey@Example.com,\"rust,web\",\n\
+                    cat@example.com,,unsubscribed\n\
+                    not-an-email,,\n\
+                    dog@example.com,,deleted\n";
+        let (rows, errors) = parse_csv(data.as_bytes()).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ImportRow {
+                    email: "joey@example.com".to_owned(),
+                    tags: tags(&["rust", "web"]),
+                    status: Subscriber::ACTIVE.to_owned(),
+                },
+                ImportRow {
+                    email: "cat@example.com".to_owned(),
+                    tags: vec![],
+                    status: Subscriber::UNSUBSCRIBED.to_owned(),
+                },
+            ]
+        );
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("第 4 行"));
+        assert!(errors[1].contains("deleted"));
+
+        assert!(parse_csv(b"name\njoey\n").is_err());
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let subscriber = Subscriber {
+            id: ObjectId::new(),
+            email: "joey@example.com".to_owned(),
+            tags: tags(&["rust", "web"]),
+            status: Subscriber::ACTIVE.to_owned(),
+            created_time: Utc::now(),
+            confirmed_time: Some(bson::DateTime::now()),
+            confirm_sent_time: None,
+        };
+        let csv = to_csv(&[subscriber], &FixedOffset::east_opt(8 * 3600).unwrap()).unwrap();
+        let (rows, errors) = parse_csv(&csv).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(rows[0].email, "joey@example.com");
+        assert_eq!(rows[0].tags, tags(&["rust", "web"]));
+        assert_eq!(rows[0].status, Subscriber::ACTIVE);
+    }
+}
@@ -0,0 +1,341 @@
+//!
+//! 站内通知: 文章收到评论、评论被回复时通知对方, 用户可以在账户页面关闭某类通知.
+//! 页头的未读数由 [`unread`] 中间件在每个请求中查询, 模板中通过 `{{ unread_notifications() }}` 读取
+//!
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    options::{FindOneOptions, FindOptions},
+    Database, IndexModel,
+};
+use poem::{session::Session, Endpoint, FromRequest, IntoResponse, Request, Response};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::db;
+use crate::error::Result;
+use crate::mail;
+use crate::model::{Comment, User};
+
+/// 通知中评论内容的最大长度
+const EXCERPT_CHARS: usize = 100;
+
+/// 通知类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// 自己的文章收到评论
+    Comment,
+    /// 自己被回复
+    Reply,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 2] = [Kind::Comment, Kind::Reply];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Comment => "comment",
+            Kind::Reply => "reply",
+        }
+    }
+
+    /// 账户页面上显示的名称
+    pub fn label(&self) -> &'static str {
+        match self {
+            Kind::Comment => "文章收到评论",
+            Kind::Reply => "评论被回复",
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Kind::ALL
+            .into_iter()
+            .find(|k| k.as_str() == s)
+            .ok_or_else(|| format!("unknown notification kind: {}", s))
+    }
+}
+
+///
+/// Model: Notification
+/// Db table: notification
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// 接收人
+    pub user_id: ObjectId,
+    pub kind: String,
+    /// 触发通知的用户
+    pub actor_id: ObjectId,
+    pub actor_name: String,
+    pub article_id: ObjectId,
+    pub article_title: String,
+    pub comment_id: Option<ObjectId>,
+    pub excerpt: String,
+    pub read: bool,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+}
+
+/// `notification` 集合的索引, 启动时由 [`crate::indexes`] 创建
+pub fn indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"user_id": 1, "created_time": -1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"user_id": 1, "read": 1})
+            .build(),
+    ]
+}
+
+///
+/// 一条评论需要通知的人: 被回复的人收到回复通知, 文章作者收到评论通知
+/// (作者同时是被回复的人时只收到回复通知), 不通知评论者自己
+///
+pub fn recipients(article_author: ObjectId, comment: &Comment) -> Vec<(ObjectId, Kind)> {
+    let mut result = Vec::new();
+    if let Some(reply_to) = comment.reply_to {
+        result.push((reply_to, Kind::Reply));
+    }
+    if comment.reply_to != Some(article_author) {
+        result.push((article_author, Kind::Comment));
+    }
+    result.retain(|(user_id, _)| *user_id != comment.author_id);
+    result
+}
+
+pub fn excerpt(content: &str) -> String {
+    let mut chars = content.trim().chars();
+    let mut s: String = chars.by_ref().take(EXCERPT_CHARS).collect();
+    if chars.next().is_some() {
+        s.push('…');
+    }
+    s
+}
+
+#[derive(Deserialize)]
+struct ArticleSummary {
+    title: String,
+    author_id: ObjectId,
+}
+
+///
+/// 通知评论相关的人, 在评论显示出来时调用(直接显示或审核通过).
+/// 关闭了该类通知的人不会收到(站内通知和邮件分别设置), 评论者对接收人隐藏(被封禁)时也不通知
+///
+pub async fn notify_comment(
+    article_id: ObjectId,
+    comment: &Comment,
+    author: &User,
+    mongo: &Database,
+) -> Result<()> {
+    let options = FindOneOptions::builder()
+        .projection(doc! {"title": 1, "author_id": 1})
+        .build();
+    let article = mongo
+        .collection::<ArticleSummary>("article")
+        .find_one(doc! {"_id": article_id}, options)
+        .await?;
+    let article = match article {
+        Some(article) => article,
+        None => return Ok(()),
+    };
+
+    let now = Utc::now();
+    let collection = mongo.collection::<Notification>("notification");
+    for (user_id, kind) in recipients(article.author_id, comment) {
+        if author.is_hidden_from(Some(user_id), now) {
+            continue;
+        }
+        let recipient = db::find_user_by_id(&user_id.to_string(), mongo).await?;
+        let notification = Notification {
+            id: ObjectId::new(),
+            user_id,
+            kind: kind.as_str().to_owned(),
+            actor_id: author.id,
+            actor_name: author.username.clone(),
+            article_id,
+            article_title: article.title.clone(),
+            comment_id: comment.id,
+            excerpt: excerpt(&comment.content),
+            read: false,
+            created_time: now,
+        };
+        // 邮件通知和站内通知分别设置
+        if let Err(err) = mail::notify(&recipient, &notification, mongo).await {
+            warn!("queue {} mail error: {}", kind.as_str(), err);
+        }
+        if recipient.wants_notification(kind.as_str()) {
+            collection.insert_one(notification, None).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn unread_count(user_id: ObjectId, mongo: &Database) -> Result<u64> {
+    let count = mongo
+        .collection::<Notification>("notification")
+        .count_documents(doc! {"user_id": user_id, "read": false}, None)
+        .await?;
+    Ok(count)
+}
+
+/// 某个用户的通知, 按时间倒序分页, 返回 (当前页, 总数)
+pub async fn list(
+    user_id: ObjectId,
+    page: u64,
+    page_size: i64,
+    mongo: &Database,
+) -> Result<(Vec<Notification>, u64)> {
+    let collection = mongo.collection::<Notification>("notification");
+    let query = doc! {"user_id": user_id};
+    let total = collection.count_documents(query.clone(), None).await?;
+
+    let options = FindOptions::builder()
+        .sort(doc! {"created_time": -1})
+        .skip((page.max(1) - 1) * page_size as u64)
+        .limit(page_size)
+        .build();
+    let mut cursor = collection.find(query, options).await?;
+    let mut result = Vec::new();
+    while let Some(notification) = cursor.next().await {
+        result.push(notification?);
+    }
+    Ok((result, total))
+}
+
+/// 标记为已读, 只能标记自己的通知
+pub async fn mark_read(user_id: ObjectId, id: ObjectId, mongo: &Database) -> Result<bool> {
+    let matched_count = mongo
+        .collection::<Notification>("notification")
+        .update_one(
+            doc! {"_id": id, "user_id": user_id},
+            doc! {"$set": {"read": true}},
+            None,
+        )
+        .await?
+        .matched_count;
+    Ok(matched_count > 0)
+}
+
+pub async fn mark_all_read(user_id: ObjectId, mongo: &Database) -> Result<u64> {
+    let modified_count = mongo
+        .collection::<Notification>("notification")
+        .update_many(
+            doc! {"user_id": user_id, "read": false},
+            doc! {"$set": {"read": true}},
+            None,
+        )
+        .await?
+        .modified_count;
+    Ok(modified_count)
+}
+
+/// 用户改名后同步通知中保存的名字
+pub async fn rename_actor(actor_id: ObjectId, name: &str, mongo: &Database) -> Result<u64> {
+    let modified_count = mongo
+        .collection::<Notification>("notification")
+        .update_many(
+            doc! {"actor_id": actor_id},
+            doc! {"$set": {"actor_name": name}},
+            None,
+        )
+        .await?
+        .modified_count;
+    Ok(modified_count)
+}
+
+tokio::task_local! {
+    /// 当前登录用户的未读通知数, 未登录时为 None
+    static UNREAD: Option<u64>;
+}
+
+/// 注册到 Tera 的模板函数, 未登录或不在请求中(例如错误页面)时返回 null
+pub fn tera_function(_args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let unread = UNREAD.try_with(|unread| *unread).ok().flatten();
+    Ok(unread.map_or(tera::Value::Null, tera::Value::from))
+}
+
+/// 查询当前登录用户的未读通知数, 只处理 GET 请求(页面), 静态文件除外
+pub async fn unread<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+    let uid = if req.method() == poem::http::Method::GET && !req.uri().path().starts_with("/assets")
+    {
+        <&Session>::from_request_without_body(&req)
+            .await?
+            .get::<String>("uid")
+            .and_then(|uid| ObjectId::from_str(&uid).ok())
+    } else {
+        None
+    };
+    let unread = match (uid, req.data::<Database>()) {
+        (Some(uid), Some(mongo)) => unread_count(uid, mongo)
+            .await
+            .map_err(|err| warn!("count unread notifications error: {}", err))
+            .ok(),
+        _ => None,
+    };
+
+    UNREAD
+        .scope(unread, async move {
+            next.call(req).await.map(IntoResponse::into_response)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(author: ObjectId, reply_to: Option<ObjectId>) -> Comment {
+        Comment::new(
+            "写得很好".to_owned(),
+            author,
+            "joey".to_owned(),
+            reply_to,
+            reply_to.map(|_| "cat".to_owned()),
+        )
+    }
+
+    #[test]
+    fn test_recipients() {
+        let (author, commenter, other) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+
+        assert_eq!(
+            recipients(author, &comment(commenter, None)),
+            vec![(author, Kind::Comment)]
+        );
+        assert_eq!(
+            recipients(author, &comment(commenter, Some(other))),
+            vec![(other, Kind::Reply), (author, Kind::Comment)]
+        );
+        // 回复文章作者只通知一次
+        assert_eq!(
+            recipients(author, &comment(commenter, Some(author))),
+            vec![(author, Kind::Reply)]
+        );
+        // 作者在自己的文章下回复别人
+        assert_eq!(
+            recipients(author, &comment(author, Some(other))),
+            vec![(other, Kind::Reply)]
+        );
+        assert!(recipients(author, &comment(author, None)).is_empty());
+    }
+
+    #[test]
+    fn test_excerpt() {
+        assert_eq!(excerpt(" 你好 "), "你好");
+        let long = "字".repeat(EXCERPT_CHARS + 1);
+        assert_eq!(excerpt(&long).chars().count(), EXCERPT_CHARS + 1);
+        assert!(excerpt(&long).ends_with('…'));
+    }
+}
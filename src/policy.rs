@@ -0,0 +1,173 @@
+use chrono::Utc;
+use mongodb::Database;
+use poem::session::Session;
+use tracing::{info, warn};
+
+use crate::config;
+use crate::db;
+use crate::error::{AppError, OptionalExt, Result};
+use crate::model::{Article, BanKind, Role, User};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// 发表文章
+    Publish,
+    /// 编辑任意用户的文章
+    EditAnyArticle,
+    /// 审核评论
+    ModerateComments,
+    /// 管理用户(分配角色等)
+    ManageUsers,
+    /// 发表评论
+    Comment,
+}
+
+///
+/// 权限矩阵
+///
+/// |           | Publish | EditAnyArticle | ModerateComments | ManageUsers | Comment |
+/// |-----------|---------|----------------|------------------|-------------|---------|
+/// | admin     | Y       | Y              | Y                | Y           | Y       |
+/// | editor    | Y       | Y              | Y                |             | Y       |
+/// | author    | Y       |                |                  |             | Y       |
+/// | commenter |         |                |                  |             | Y       |
+///
+pub fn can(role: Role, permission: Permission) -> bool {
+    use Permission::*;
+
+    match role {
+        Role::Admin => true,
+        Role::Editor => matches!(
+            permission,
+            Publish | EditAnyArticle | ModerateComments | Comment
+        ),
+        Role::Author => matches!(permission, Publish | Comment),
+        Role::Commenter => matches!(permission, Comment),
+    }
+}
+
+/// 作者可以编辑自己的文章, 有 EditAnyArticle 权限的可以编辑所有文章
+pub fn can_edit_article(user: &User, article: &Article) -> bool {
+    if can(user.role, Permission::EditAnyArticle) {
+        return true;
+    }
+    user.id == article.author_id && can(user.role, Permission::Publish)
+}
+
+/// 当前登录的用户, 已被封禁的用户视为未登录
+pub async fn current_user(session: &Session, mongo: &Database) -> Option<User> {
+    let uid = session.get::<String>("uid")?;
+    let user = db::find_user_by_id(uid.as_str(), mongo).await.ok()?;
+    check_ban(&user).ok()?;
+    Some(user)
+}
+
+/// 被封禁的用户不能登录; 影子封禁的用户可以正常登录, 不会察觉
+pub fn check_ban(user: &User) -> Result<()> {
+    match user.active_ban(Utc::now()) {
+        Some(ban) if ban.kind == BanKind::Ban => {
+            let until = match ban.expires_at {
+                Some(t) => t
+                    .to_chrono()
+                    .with_timezone(&config::timezone())
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+                None => "永久".to_owned(),
+            };
+            Err(AppError::Banned(format!(
+                "账户已被封禁: {} (到期时间: {})",
+                ban.reason, until
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 当前登录的用户, 并且拥有指定的权限
+pub async fn authorize(
+    session: &Session,
+    mongo: &Database,
+    permission: Permission,
+) -> Result<User> {
+    let user = current_user(session, mongo)
+        .await
+        .ok_or(AppError::Unauthenticated)?;
+    if can(user.role, permission) {
+        Ok(user)
+    } else {
+        Err(AppError::Forbidden)
+    }
+}
+
+///
+/// 启动时系统中还没有管理员, 将配置的第三方账号(provider, uid)对应的用户提升为管理员.
+/// 按 provider 和第三方的用户 id 查找, 不能用登录名: 登录名可以被其他账号占用
+///
+pub async fn bootstrap_admin(mongo: &Database, identity: Option<(&str, &str)>) -> Result<()> {
+    let Some((provider, uid)) = identity else {
+        return Ok(());
+    };
+    if db::count_users_by_role(Role::Admin, mongo).await? > 0 {
+        return Ok(());
+    }
+
+    match db::find_identity(mongo, provider, uid).await.optional()? {
+        Some(identity) => {
+            info!(
+                "bootstrap admin =====> {}:{} ({})",
+                provider, uid, identity.login
+            );
+            db::update_user_role(&identity.user_id.to_string(), Role::Admin, mongo).await?;
+        }
+        None => warn!(
+            "bootstrap admin: {}:{} has not signed in yet, restart after signing in",
+            provider, uid
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permission_matrix() {
+        use Permission::*;
+
+        for p in [
+            Publish,
+            EditAnyArticle,
+            ModerateComments,
+            ManageUsers,
+            Comment,
+        ] {
+            assert!(can(Role::Admin, p));
+            assert!(can(Role::Commenter, p) == (p == Comment));
+        }
+        assert!(can(Role::Editor, EditAnyArticle));
+        assert!(!can(Role::Editor, ManageUsers));
+        assert!(can(Role::Author, Publish));
+        assert!(!can(Role::Author, ModerateComments));
+    }
+
+    #[test]
+    fn test_can_edit_article() {
+        let mut user = User {
+            role: Role::Author,
+            ..Default::default()
+        };
+        let mut article = Article::default();
+        assert!(!can_edit_article(&user, &article));
+
+        article.author_id = user.id;
+        assert!(can_edit_article(&user, &article));
+
+        user.role = Role::Commenter;
+        assert!(!can_edit_article(&user, &article));
+
+        user.role = Role::Editor;
+        article.author_id = Default::default();
+        assert!(can_edit_article(&user, &article));
+    }
+}
@@ -0,0 +1,433 @@
+//!
+//! 限流: 令牌桶算法, 已登录用户按用户 id 计数, 未登录按客户端 IP 计数, 每个路由单独配置,
+//! 超出限制时返回 429 和 Retry-After. 计数默认保存在进程内存中, 多实例部署时使用 MongoDB
+//!
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{TimeZone, Utc};
+use mongodb::{
+    bson::doc,
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+    Database, IndexModel,
+};
+use poem::{http::Method, session::Session, Endpoint, IntoResponse, Middleware, Request, Response};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::{RateLimitConfig, RateLimitRule};
+use crate::error::{AppError, Result};
+use crate::middleware;
+
+/// 进程内存储中桶的数量超过该值时清理已经补满的桶
+const MEMORY_PRUNE_THRESHOLD: usize = 10_000;
+
+/// MongoDB 存储并发更新同一个桶时的重试次数
+const MONGO_MAX_RETRIES: usize = 5;
+
+/// 令牌桶, `updated_ms` 为上次更新的时间戳(毫秒)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_ms: i64,
+}
+
+fn refill_per_ms(rule: &RateLimitRule) -> f64 {
+    rule.refill_per_minute / 60_000.0
+}
+
+/// 取一个令牌, 返回更新后的桶; 令牌不足时同时返回需要等待的秒数
+pub fn take(bucket: Option<Bucket>, rule: &RateLimitRule, now_ms: i64) -> (Bucket, Option<u64>) {
+    let capacity = rule.capacity as f64;
+    let tokens = match bucket {
+        Some(b) => {
+            let elapsed = (now_ms - b.updated_ms).max(0) as f64;
+            (b.tokens + elapsed * refill_per_ms(rule)).min(capacity)
+        }
+        None => capacity,
+    };
+
+    if tokens >= 1.0 {
+        let bucket = Bucket {
+            tokens: tokens - 1.0,
+            updated_ms: now_ms,
+        };
+        (bucket, None)
+    } else {
+        let wait_ms = (1.0 - tokens) / refill_per_ms(rule);
+        let retry_after = ((wait_ms / 1000.0).ceil() as u64).max(1);
+        let bucket = Bucket {
+            tokens,
+            updated_ms: now_ms,
+        };
+        (bucket, Some(retry_after))
+    }
+}
+
+/// 桶补满需要的毫秒数, 超过这个时间没有访问的桶可以直接删除
+fn full_after_ms(bucket: &Bucket, rule: &RateLimitRule) -> i64 {
+    ((rule.capacity as f64 - bucket.tokens).max(0.0) / refill_per_ms(rule)).ceil() as i64
+}
+
+///
+/// 限流计数的存储
+///
+#[poem::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// 从 `key` 对应的桶中取一个令牌, 返回 `Some(秒数)` 表示需要等待
+    async fn acquire(&self, key: &str, rule: &RateLimitRule) -> Result<Option<u64>>;
+}
+
+///
+/// 进程内存储, 只适用于单实例部署
+///
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryStore {
+    fn acquire_at(&self, key: &str, rule: &RateLimitRule, now_ms: i64) -> Option<u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MEMORY_PRUNE_THRESHOLD {
+            // 不同规则的桶补满时间不同, 这里用当前规则粗略估计
+            buckets.retain(|_, b| now_ms - b.updated_ms < full_after_ms(b, rule));
+        }
+        let (bucket, retry_after) = take(buckets.get(key).copied(), rule, now_ms);
+        buckets.insert(key.to_owned(), bucket);
+        retry_after
+    }
+}
+
+#[poem::async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn acquire(&self, key: &str, rule: &RateLimitRule) -> Result<Option<u64>> {
+        Ok(self.acquire_at(key, rule, Utc::now().timestamp_millis()))
+    }
+}
+
+///
+/// Model: RateLimitRecord
+/// Db table: rate_limit
+///
+/// 补满之后的桶由 TTL 索引自动清理
+///
+#[derive(Debug, Serialize, Deserialize)]
+struct RateLimitRecord {
+    #[serde(rename = "_id")]
+    key: String,
+    tokens: f64,
+    updated_ms: i64,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    expires_at: chrono::DateTime<Utc>,
+}
+
+///
+/// 基于 MongoDB 的存储, 多个实例共享计数.
+/// 通过比较 `updated_ms` 做乐观并发控制, 冲突时重新读取后重试
+///
+pub struct MongoStore {
+    mongo: Database,
+}
+
+impl MongoStore {
+    pub fn new(mongo: Database) -> Self {
+        Self { mongo }
+    }
+}
+
+/// `rate_limit` 集合的索引(只有 MongoDB 存储使用), 启动时由 [`crate::indexes`] 创建
+pub fn indexes() -> Vec<IndexModel> {
+    vec![IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build()]
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
+
+#[poem::async_trait]
+impl RateLimitStore for MongoStore {
+    async fn acquire(&self, key: &str, rule: &RateLimitRule) -> Result<Option<u64>> {
+        let collection = self.mongo.collection::<RateLimitRecord>("rate_limit");
+
+        for _ in 0..MONGO_MAX_RETRIES {
+            let now_ms = Utc::now().timestamp_millis();
+            let record = collection.find_one(doc! {"_id": key}, None).await?;
+            let old = record.map(|r| Bucket {
+                tokens: r.tokens,
+                updated_ms: r.updated_ms,
+            });
+
+            let (bucket, retry_after) = take(old, rule, now_ms);
+            if retry_after.is_some() {
+                // 被拒绝的请求不消耗令牌, 不需要写回
+                return Ok(retry_after);
+            }
+
+            let expires_at = Utc
+                .timestamp_millis_opt(now_ms + full_after_ms(&bucket, rule))
+                .unwrap();
+            match old {
+                Some(old) => {
+                    let result = collection
+                        .update_one(
+                            doc! {"_id": key, "updated_ms": old.updated_ms},
+                            doc! {"$set": {
+                                "tokens": bucket.tokens,
+                                "updated_ms": bucket.updated_ms,
+                                "expires_at": expires_at,
+                            }},
+                            None,
+                        )
+                        .await?;
+                    if result.matched_count == 1 {
+                        return Ok(None);
+                    }
+                }
+                None => {
+                    let record = RateLimitRecord {
+                        key: key.to_owned(),
+                        tokens: bucket.tokens,
+                        updated_ms: bucket.updated_ms,
+                        expires_at,
+                    };
+                    match collection.insert_one(record, None).await {
+                        Ok(_) => return Ok(None),
+                        Err(err) if is_duplicate_key(&err) => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+            }
+        }
+
+        // 同一个桶上的并发请求太多, 按超出限制处理
+        Ok(Some(1))
+    }
+}
+
+///
+/// 限流中间件, 需要放在会话中间件内层才能按用户 id 计数
+///
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    rules: Arc<Vec<RateLimitRule>>,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>, rules: Vec<RateLimitRule>) -> Self {
+        Self {
+            store,
+            rules: Arc::new(rules),
+            trusted_proxies: Default::default(),
+        }
+    }
+
+    /// 来自这些反向代理的请求按转发的客户端 IP 计数
+    pub fn trusted_proxies(mut self, proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = Arc::new(proxies);
+        self
+    }
+
+    pub fn from_config(config: &RateLimitConfig, mongo: &Database) -> Self {
+        let rules = if config.enabled {
+            config.rules.clone()
+        } else {
+            Vec::new()
+        };
+        let store: Arc<dyn RateLimitStore> = match config.store.as_str() {
+            "mongo" => Arc::new(MongoStore::new(mongo.clone())),
+            _ => Arc::new(MemoryStore::default()),
+        };
+        Self::new(store, rules)
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimiter {
+    type Output = RateLimitEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RateLimitEndpoint {
+            inner: ep,
+            limiter: self.clone(),
+        }
+    }
+}
+
+pub struct RateLimitEndpoint<E> {
+    inner: E,
+    limiter: RateLimiter,
+}
+
+fn rule_matches(rule: &RateLimitRule, method: &Method, path: &str) -> bool {
+    rule.path == path
+        && (rule.methods.is_empty()
+            || rule
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(method.as_str())))
+}
+
+///
+/// 已登录用户按用户 id 计数, 等待两步验证的按待验证的用户 id 计数(换 IP 或会话不能绕过),
+/// 否则按客户端 IP
+///
+fn client_key(req: &Request, trusted_proxies: &[IpAddr]) -> String {
+    if let Some(session) = req.extensions().get::<Session>() {
+        if let Some(uid) = session.get::<String>("uid") {
+            return format!("uid:{}", uid);
+        }
+        if let Some(uid) = session.get::<String>("pending_uid") {
+            return format!("pending_uid:{}", uid);
+        }
+    }
+    match middleware::client_ip(req, trusted_proxies) {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".to_owned(),
+    }
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for RateLimitEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        let path = req.uri().path();
+        if let Some(rule) = self
+            .limiter
+            .rules
+            .iter()
+            .find(|rule| rule_matches(rule, req.method(), path))
+        {
+            let key = format!(
+                "{} {}",
+                rule.path,
+                client_key(&req, &self.limiter.trusted_proxies)
+            );
+            match self.limiter.store.acquire(&key, rule).await {
+                Ok(Some(retry_after)) => {
+                    return Err(AppError::TooManyRequests { retry_after }.into());
+                }
+                Ok(None) => {}
+                // 存储出错时放行, 不能因为限流影响正常访问
+                Err(err) => warn!("rate limit store error: {}", err),
+            }
+        }
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{handler, http::StatusCode, post, EndpointExt, Route};
+
+    use super::*;
+
+    fn rule() -> RateLimitRule {
+        RateLimitRule {
+            path: "/comment/new".to_owned(),
+            methods: vec!["post".to_owned()],
+            capacity: 2,
+            refill_per_minute: 6.0,
+        }
+    }
+
+    #[test]
+    fn test_take() {
+        let rule = rule();
+        let (b, wait) = take(None, &rule, 0);
+        assert_eq!(wait, None);
+        assert_eq!(b.tokens, 1.0);
+        let (b, wait) = take(Some(b), &rule, 0);
+        assert_eq!(wait, None);
+        // 每 10 秒补充一个令牌
+        let (_, wait) = take(Some(b), &rule, 4_000);
+        assert_eq!(wait, Some(6));
+        let (b, wait) = take(Some(b), &rule, 10_000);
+        assert_eq!(wait, None);
+        assert!(b.tokens.abs() < 1e-9);
+        // 不会超过容量
+        let (b, _) = take(Some(b), &rule, 3_600_000);
+        assert_eq!(b.tokens, 1.0);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::default();
+        let rule = rule();
+        assert_eq!(store.acquire_at("a", &rule, 0), None);
+        assert_eq!(store.acquire_at("a", &rule, 0), None);
+        assert_eq!(store.acquire_at("a", &rule, 0), Some(10));
+        // 不同的 key 互不影响
+        assert_eq!(store.acquire_at("b", &rule, 0), None);
+        assert_eq!(store.acquire_at("a", &rule, 10_000), None);
+    }
+
+    #[test]
+    fn test_client_key() {
+        let session = Session::default();
+        let req = Request::builder().extension(session.clone()).finish();
+        assert_eq!(client_key(&req, &[]), "ip:unknown");
+
+        // 换了 IP 也按待验证的用户计数
+        session.set("pending_uid", "u1");
+        assert_eq!(client_key(&req, &[]), "pending_uid:u1");
+        session.set("uid", "u1");
+        assert_eq!(client_key(&req, &[]), "uid:u1");
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        #[handler]
+        fn ok() -> &'static str {
+            "ok"
+        }
+
+        let limiter = RateLimiter::new(Arc::new(MemoryStore::default()), vec![rule()]);
+        let app = Route::new()
+            .at("/comment/new", poem::get(ok).post(ok))
+            .at("/other", post(ok))
+            .with(limiter);
+        let req = |method: Method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path.parse().unwrap())
+                .finish()
+        };
+
+        for _ in 0..2 {
+            assert!(app.call(req(Method::POST, "/comment/new")).await.is_ok());
+        }
+        let err = app
+            .call(req(Method::POST, "/comment/new"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(matches!(
+            err.downcast_ref::<AppError>(),
+            Some(AppError::TooManyRequests { retry_after: 10 })
+        ));
+
+        // 没有配置规则的方法和路径不限制
+        assert!(app.call(req(Method::GET, "/comment/new")).await.is_ok());
+        for _ in 0..3 {
+            assert!(app.call(req(Method::POST, "/other")).await.is_ok());
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::{IndexOptions, UpdateOptions},
+    Database, IndexModel,
+};
+use poem::{
+    http::header,
+    session::{CookieConfig, Session, SessionStorage},
+    web::cookie::SameSite,
+    Endpoint, FromRequest, IntoResponse, Request, Response, Result,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::{self, SessionConfig};
+use crate::error::Result as AppResult;
+use crate::middleware;
+
+pub const COOKIE_NAME: &str = "poem-session";
+
+/// 会话中保存的设备信息, 由 [`meta`] 中间件写入
+const ENTRY_USER_AGENT: &str = "_ua";
+const ENTRY_IP: &str = "_ip";
+const ENTRY_LAST_SEEN: &str = "_last_seen";
+
+/// 最后访问时间的更新间隔(秒), 避免每个请求都写一次数据库
+const LAST_SEEN_INTERVAL: i64 = 60;
+
+///
+/// Model: Session
+/// Db table: session
+///
+/// _id 为 session id 的哈希, 数据库中不保存原始的 session id
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub entries: Document,
+    pub uid: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_time: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub last_seen: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires_at: DateTime<Utc>,
+}
+
+pub fn hash_session_id(session_id: &str) -> String {
+    let digest = Sha256::digest(session_id.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+///
+/// 基于 MongoDB 的服务端会话存储, 过期的会话由 TTL 索引自动清理
+///
+pub struct MongoStorage {
+    mongo: Database,
+    /// 没有设置 cookie max_age 时的会话有效期
+    default_ttl: Duration,
+}
+
+impl MongoStorage {
+    pub fn new(mongo: Database, default_ttl: Duration) -> Self {
+        Self { mongo, default_ttl }
+    }
+}
+
+/// `session` 集合的索引, 启动时由 [`crate::indexes`] 创建
+pub fn indexes() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder().keys(doc! {"uid": 1}).build(),
+    ]
+}
+
+#[poem::async_trait]
+impl SessionStorage for MongoStorage {
+    async fn load_session(&self, session_id: &str) -> Result<Option<BTreeMap<String, Value>>> {
+        // TTL 索引每 60 秒才清理一次, 这里需要再判断一下过期时间
+        let query = doc! {
+            "_id": hash_session_id(session_id),
+            "expires_at": {"$gt": Utc::now()},
+        };
+        let record = self
+            .mongo
+            .collection::<SessionRecord>("session")
+            .find_one(query, None)
+            .await
+            .map_err(poem::error::InternalServerError)?;
+
+        match record {
+            Some(record) => {
+                let entries = bson::from_document(record.entries)
+                    .map_err(poem::error::InternalServerError)?;
+                Ok(Some(entries))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn update_session(
+        &self,
+        session_id: &str,
+        entries: &BTreeMap<String, Value>,
+        expires: Option<Duration>,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let ttl = chrono::Duration::from_std(expires.unwrap_or(self.default_ttl))
+            .map_err(poem::error::InternalServerError)?;
+        let entry_str = |name: &str| entries.get(name).and_then(Value::as_str).map(str::to_owned);
+
+        let update = doc! {
+            "$set": {
+                "entries": bson::to_document(entries).map_err(poem::error::InternalServerError)?,
+                "uid": entry_str("uid").map(Bson::String).unwrap_or(Bson::Null),
+                "user_agent": entry_str(ENTRY_USER_AGENT).map(Bson::String).unwrap_or(Bson::Null),
+                "ip": entry_str(ENTRY_IP).map(Bson::String).unwrap_or(Bson::Null),
+                "last_seen": now,
+                "expires_at": now + ttl,
+            },
+            "$setOnInsert": {
+                "created_time": now,
+            },
+        };
+        self.mongo
+            .collection::<SessionRecord>("session")
+            .update_one(
+                doc! {"_id": hash_session_id(session_id)},
+                update,
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map_err(poem::error::InternalServerError)?;
+        Ok(())
+    }
+
+    async fn remove_session(&self, session_id: &str) -> Result<()> {
+        self.mongo
+            .collection::<SessionRecord>("session")
+            .delete_one(doc! {"_id": hash_session_id(session_id)}, None)
+            .await
+            .map_err(poem::error::InternalServerError)?;
+        Ok(())
+    }
+}
+
+pub async fn list_sessions(uid: &str, mongo: &Database) -> AppResult<Vec<SessionRecord>> {
+    let mut cursor = mongo
+        .collection::<SessionRecord>("session")
+        .find(
+            doc! {"uid": uid, "expires_at": {"$gt": Utc::now()}},
+            mongodb::options::FindOptions::builder()
+                .sort(doc! {"last_seen": -1})
+                .build(),
+        )
+        .await?;
+
+    let mut result = Vec::new();
+    while let Some(record) = cursor.next().await {
+        result.push(record?);
+    }
+    Ok(result)
+}
+
+/// 注销某个用户的一个会话
+pub async fn revoke_session(uid: &str, id: &str, mongo: &Database) -> AppResult<bool> {
+    let deleted_count = mongo
+        .collection::<SessionRecord>("session")
+        .delete_one(doc! {"_id": id, "uid": uid}, None)
+        .await?
+        .deleted_count;
+    Ok(deleted_count > 0)
+}
+
+/// 注销某个用户的所有会话(所有设备)
+pub async fn revoke_all_sessions(uid: &str, mongo: &Database) -> AppResult<u64> {
+    let deleted_count = mongo
+        .collection::<SessionRecord>("session")
+        .delete_many(doc! {"uid": uid}, None)
+        .await?
+        .deleted_count;
+    Ok(deleted_count)
+}
+
+pub fn parse_same_site(s: &str) -> Option<SameSite> {
+    match s.to_ascii_lowercase().as_str() {
+        "strict" => Some(SameSite::Strict),
+        "lax" => Some(SameSite::Lax),
+        "none" => Some(SameSite::None),
+        _ => None,
+    }
+}
+
+/// 会话 cookie 设置
+pub fn cookie_config(config: &SessionConfig) -> CookieConfig {
+    CookieConfig::default()
+        .name(COOKIE_NAME)
+        .secure(config.cookie_secure)
+        .http_only(config.cookie_http_only)
+        .same_site(parse_same_site(&config.cookie_same_site).unwrap_or(SameSite::Lax))
+}
+
+/// 记录会话的设备信息(User-Agent, IP)和最后访问时间, 需要放在会话中间件内层
+pub async fn meta<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let session = <&Session>::from_request_without_body(&req).await?.clone();
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let ip =
+        middleware::client_ip(&req, &config::get().server.trusted_proxies).map(|ip| ip.to_string());
+
+    let resp = next.call(req).await?.into_response();
+
+    // 在处理请求之后更新, 这样登录请求本身也会被记录
+    if session.get::<String>("uid").is_some() {
+        if session.get::<String>(ENTRY_USER_AGENT) != user_agent {
+            session.set(ENTRY_USER_AGENT, user_agent);
+        }
+        if session.get::<String>(ENTRY_IP) != ip {
+            session.set(ENTRY_IP, ip);
+        }
+        let now = Utc::now().timestamp();
+        let last_seen = session.get::<i64>(ENTRY_LAST_SEEN).unwrap_or_default();
+        if now - last_seen > LAST_SEEN_INTERVAL {
+            session.set(ENTRY_LAST_SEEN, now);
+        }
+    }
+
+    Ok(resp)
+}
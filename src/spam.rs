@@ -0,0 +1,254 @@
+//!
+//! 垃圾评论分类器: 朴素贝叶斯, 用审核人的决定(通过/垃圾)训练
+//!
+use std::collections::{HashMap, HashSet};
+
+use futures::StreamExt;
+use mongodb::{bson::doc, options::UpdateOptions, Database};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// 每条评论最多使用的词数
+const MAX_TOKENS: usize = 300;
+
+/// 保存训练样本数的文档 id, 分词结果不会包含 `#`
+const DOCS_KEY: &str = "#docs";
+
+///
+/// Model: TokenRecord
+/// Db table: spam_token
+///
+/// 每个词在垃圾评论/正常评论中出现的次数, `_id` 为 `#docs` 的文档保存两类样本的总数
+///
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenRecord {
+    #[serde(rename = "_id")]
+    token: String,
+    #[serde(default)]
+    spam: i64,
+    #[serde(default)]
+    ham: i64,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c, '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{f900}'..='\u{faff}')
+}
+
+///
+/// 分词: 英文和数字按单词切分(2~30 个字符), 中文没有分隔符, 使用相邻两个字组成的词.
+/// 每个词只保留一次
+///
+pub fn tokenize(text: &str) -> Vec<String> {
+    fn flush_word(word: &mut String, seen: &mut HashSet<String>, tokens: &mut Vec<String>) {
+        let len = word.chars().count();
+        if (2..=30).contains(&len) && seen.insert(word.clone()) {
+            tokens.push(word.clone());
+        }
+        word.clear();
+    }
+
+    fn flush_cjk(run: &mut Vec<char>, seen: &mut HashSet<String>, tokens: &mut Vec<String>) {
+        let grams: Vec<String> = match run.len() {
+            0 => Vec::new(),
+            1 => vec![run[0].to_string()],
+            _ => run.windows(2).map(|w| w.iter().collect()).collect(),
+        };
+        for gram in grams {
+            if seen.insert(gram.clone()) {
+                tokens.push(gram);
+            }
+        }
+        run.clear();
+    }
+
+    let mut tokens = Vec::new();
+    let mut seen = HashSet::new();
+    let mut word = String::new();
+    let mut cjk_run: Vec<char> = Vec::new();
+
+    for c in text.to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            flush_cjk(&mut cjk_run, &mut seen, &mut tokens);
+            word.push(c);
+        } else if is_cjk(c) {
+            flush_word(&mut word, &mut seen, &mut tokens);
+            cjk_run.push(c);
+        } else {
+            flush_word(&mut word, &mut seen, &mut tokens);
+            flush_cjk(&mut cjk_run, &mut seen, &mut tokens);
+        }
+    }
+    flush_word(&mut word, &mut seen, &mut tokens);
+    flush_cjk(&mut cjk_run, &mut seen, &mut tokens);
+
+    tokens.truncate(MAX_TOKENS);
+    tokens
+}
+
+/// 分类器中与一条评论相关的统计数据
+#[derive(Debug, Default)]
+pub struct Model {
+    pub spam_docs: i64,
+    pub ham_docs: i64,
+    /// 词 => (垃圾评论中出现的次数, 正常评论中出现的次数)
+    pub tokens: HashMap<String, (i64, i64)>,
+}
+
+impl Model {
+    /// 在内存中训练, 用于重新训练整个模型
+    pub fn train(&mut self, tokens: &[String], is_spam: bool) {
+        if is_spam {
+            self.spam_docs += 1;
+        } else {
+            self.ham_docs += 1;
+        }
+        for token in tokens {
+            let counts = self.tokens.entry(token.clone()).or_default();
+            if is_spam {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    /// 是垃圾评论的概率, 训练样本不足 `min_training` 时返回 None.
+    /// 只使用训练中出现过的词, 概率使用拉普拉斯平滑
+    pub fn score(&self, tokens: &[String], min_training: i64) -> Option<f64> {
+        let spam_docs = self.spam_docs.max(0);
+        let ham_docs = self.ham_docs.max(0);
+        if spam_docs < min_training.max(1) || ham_docs < min_training.max(1) {
+            return None;
+        }
+
+        let mut log_odds = (spam_docs as f64).ln() - (ham_docs as f64).ln();
+        for token in tokens {
+            let (spam, ham) = match self.tokens.get(token) {
+                Some(&(spam, ham)) if spam.max(0) + ham.max(0) > 0 => (spam.max(0), ham.max(0)),
+                _ => continue,
+            };
+            let p_spam = (spam as f64 + 1.0) / (spam_docs as f64 + 2.0);
+            let p_ham = (ham as f64 + 1.0) / (ham_docs as f64 + 2.0);
+            log_odds += p_spam.ln() - p_ham.ln();
+        }
+        Some(1.0 / (1.0 + (-log_odds).exp()))
+    }
+}
+
+/// 从数据库加载与这些词相关的统计数据
+async fn load(tokens: &[String], mongo: &Database) -> Result<Model> {
+    let mut keys: Vec<&str> = tokens.iter().map(String::as_str).collect();
+    keys.push(DOCS_KEY);
+
+    let mut model = Model::default();
+    let mut cursor = mongo
+        .collection::<TokenRecord>("spam_token")
+        .find(doc! {"_id": {"$in": keys}}, None)
+        .await?;
+    while let Some(record) = cursor.next().await {
+        let record = record?;
+        if record.token == DOCS_KEY {
+            model.spam_docs = record.spam;
+            model.ham_docs = record.ham;
+        } else {
+            model.tokens.insert(record.token, (record.spam, record.ham));
+        }
+    }
+    Ok(model)
+}
+
+/// 评论是垃圾评论的概率, 训练样本不足时返回 None
+pub async fn score(text: &str, min_training: i64, mongo: &Database) -> Result<Option<f64>> {
+    let tokens = tokenize(text);
+    let model = load(&tokens, mongo).await?;
+    Ok(model.score(&tokens, min_training))
+}
+
+/// 用一条评论训练, `delta` 为 -1 时撤销之前的训练(审核人改变了决定)
+pub async fn train(text: &str, is_spam: bool, delta: i64, mongo: &Database) -> Result<()> {
+    let field = if is_spam { "spam" } else { "ham" };
+    let collection = mongo.collection::<TokenRecord>("spam_token");
+    let upsert = UpdateOptions::builder().upsert(true).build();
+
+    for token in tokenize(text).iter().map(String::as_str).chain([DOCS_KEY]) {
+        collection
+            .update_one(
+                doc! {"_id": token},
+                doc! {"$inc": {field: delta}},
+                upsert.clone(),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// 用重新训练得到的模型替换数据库中的统计数据
+pub async fn replace(model: Model, mongo: &Database) -> Result<()> {
+    let collection = mongo.collection::<TokenRecord>("spam_token");
+    collection.delete_many(doc! {}, None).await?;
+
+    let docs = TokenRecord {
+        token: DOCS_KEY.to_owned(),
+        spam: model.spam_docs,
+        ham: model.ham_docs,
+    };
+    let records = model
+        .tokens
+        .into_iter()
+        .map(|(token, (spam, ham))| TokenRecord { token, spam, ham })
+        .chain([docs]);
+    collection.insert_many(records, None).await?;
+    Ok(())
+}
+
+/// 训练样本数: (垃圾评论, 正常评论)
+pub async fn training_size(mongo: &Database) -> Result<(i64, i64)> {
+    let record = mongo
+        .collection::<TokenRecord>("spam_token")
+        .find_one(doc! {"_id": DOCS_KEY}, None)
+        .await?;
+    Ok(record.map_or((0, 0), |r| (r.spam, r.ham)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("Buy CHEAP pills at http://spam.example a b"),
+            vec!["buy", "cheap", "pills", "at", "http", "spam", "example"]
+        );
+        assert_eq!(tokenize("写得很好"), vec!["写得", "得很", "很好"]);
+        assert_eq!(tokenize("好 rust 好"), vec!["好", "rust"]);
+    }
+
+    #[test]
+    fn test_score() {
+        let mut model = Model::default();
+        assert_eq!(model.score(&tokenize("anything"), 1), None);
+
+        for text in [
+            "buy cheap pills now",
+            "cheap casino bonus",
+            "buy casino chips",
+        ] {
+            model.train(&tokenize(text), true);
+        }
+        for text in ["写得很好, 学习了", "rust 的生命周期讲得很清楚", "感谢分享"]
+        {
+            model.train(&tokenize(text), false);
+        }
+        assert_eq!(model.score(&tokenize("cheap"), 5), None);
+
+        let spam = model.score(&tokenize("cheap casino pills"), 3).unwrap();
+        let ham = model.score(&tokenize("讲得很清楚, 感谢"), 3).unwrap();
+        assert!(spam > 0.9, "spam score {}", spam);
+        assert!(ham < 0.1, "ham score {}", ham);
+        // 没见过的词不影响结果
+        let unknown = model.score(&tokenize("lorem ipsum"), 3).unwrap();
+        assert!((unknown - 0.5).abs() < 1e-9);
+    }
+}
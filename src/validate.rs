@@ -0,0 +1,186 @@
+//!
+//! 请求参数校验: 请求结构体上用 `#[derive(Validate)]` 声明规则,
+//! 表单校验失败时重新渲染表单并展示每个字段的错误, 其他参数校验失败直接返回 400
+//!
+use std::{borrow::Cow, collections::HashMap, str::FromStr};
+
+use chrono::NaiveDate;
+use mongodb::bson::oid::ObjectId;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::error::{AppError, Result};
+use crate::model::{BanKind, Role};
+use crate::newsletter::Subscriber;
+
+/// 字段名 => 错误消息
+pub type FieldErrors = HashMap<&'static str, String>;
+
+fn error(code: &'static str, message: &'static str) -> ValidationError {
+    let mut err = ValidationError::new(code);
+    err.message = Some(Cow::Borrowed(message));
+    err
+}
+
+pub fn object_id(id: &str) -> Result<(), ValidationError> {
+    ObjectId::from_str(id)
+        .map(|_| ())
+        .map_err(|_| error("object_id", "无效的 id"))
+}
+
+/// 不能只包含空白字符
+pub fn not_blank(s: &str) -> Result<(), ValidationError> {
+    if s.trim().is_empty() {
+        Err(error("not_blank", "不能为空"))
+    } else {
+        Ok(())
+    }
+}
+
+/// 可以为空, 不为空时必须是 http(s) 地址
+pub fn http_url(url: &str) -> Result<(), ValidationError> {
+    let url = url.trim();
+    if url.is_empty() || url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(error("http_url", "需要以 http:// 或 https:// 开头"))
+    }
+}
+
+/// 账户名: 字母、数字、`-`、`_`、`.`
+pub fn login_name(login: &str) -> Result<(), ValidationError> {
+    if login
+        .trim()
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        Ok(())
+    } else {
+        Err(error("login_name", "只能包含字母、数字、-、_ 和 ."))
+    }
+}
+
+pub fn role(role: &str) -> Result<(), ValidationError> {
+    Role::from_str(role)
+        .map(|_| ())
+        .map_err(|_| error("role", "未知的角色"))
+}
+
+pub fn ban_kind(kind: &str) -> Result<(), ValidationError> {
+    BanKind::from_str(kind)
+        .map(|_| ())
+        .map_err(|_| error("ban_kind", "未知的封禁类型"))
+}
+
+/// 订阅者状态, 空字符串表示不筛选
+pub fn subscriber_status(status: &str) -> Result<(), ValidationError> {
+    if status.is_empty() || Subscriber::STATUSES.contains(&status) {
+        Ok(())
+    } else {
+        Err(error("subscriber_status", "未知的订阅状态"))
+    }
+}
+
+/// 日期, 格式为 2006-01-02, 空字符串表示不填
+pub fn date(date: &str) -> Result<(), ValidationError> {
+    if date.is_empty() || NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok() {
+        Ok(())
+    } else {
+        Err(error("date", "日期格式应为 YYYY-MM-DD"))
+    }
+}
+
+/// 每个字段只取第一条错误消息
+pub fn field_errors(errors: &ValidationErrors) -> FieldErrors {
+    errors
+        .field_errors()
+        .into_iter()
+        .filter_map(|(field, errs)| {
+            let err = errs.first()?;
+            let msg = match &err.message {
+                Some(msg) => msg.to_string(),
+                None => format!("{} 不合法", field),
+            };
+            Some((field, msg))
+        })
+        .collect()
+}
+
+/// 校验表单, 失败时返回字段错误, 由调用方重新渲染表单
+pub fn form<T: Validate>(params: &T) -> Option<FieldErrors> {
+    params.validate().err().map(|errors| field_errors(&errors))
+}
+
+/// 校验查询参数等不需要重新渲染表单的参数, 失败时返回 400
+pub fn check<T: Validate>(params: &T) -> Result<()> {
+    params.validate().map_err(|errors| {
+        let mut messages: Vec<String> = field_errors(&errors)
+            .into_iter()
+            .map(|(field, msg)| format!("{}: {}", field, msg))
+            .collect();
+        messages.sort();
+        AppError::bad_request(messages.join("; "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Validate)]
+    struct Params {
+        #[validate(custom = "object_id")]
+        id: String,
+        #[validate(length(max = 10, message = "标题最多10个字"), custom = "not_blank")]
+        title: String,
+        #[validate(custom = "http_url")]
+        blog: String,
+        #[validate(range(min = 1, message = "页码从1开始"))]
+        page: Option<i32>,
+    }
+
+    #[test]
+    fn test_field_errors() {
+        let ok = Params {
+            id: ObjectId::new().to_string(),
+            title: "标题".to_owned(),
+            blog: "".to_owned(),
+            page: None,
+        };
+        assert!(form(&ok).is_none());
+        assert!(check(&ok).is_ok());
+
+        let bad = Params {
+            id: "xx".to_owned(),
+            title: "   ".to_owned(),
+            blog: "ftp://a".to_owned(),
+            page: Some(0),
+        };
+        let errors = form(&bad).unwrap();
+        assert_eq!(errors["id"], "无效的 id");
+        assert_eq!(errors["title"], "不能为空");
+        assert_eq!(errors["blog"], "需要以 http:// 或 https:// 开头");
+        assert_eq!(errors["page"], "页码从1开始");
+
+        let long = Params {
+            title: "a".repeat(11),
+            ..ok
+        };
+        assert_eq!(form(&long).unwrap()["title"], "标题最多10个字");
+        assert!(check(&long).is_err());
+    }
+
+    #[test]
+    fn test_login_name() {
+        assert!(login_name("joey.cat_1").is_ok());
+        assert!(login_name("joey cat").is_err());
+        assert!(login_name("<script>").is_err());
+    }
+
+    #[test]
+    fn test_date() {
+        assert!(date("").is_ok());
+        assert!(date("2024-02-29").is_ok());
+        assert!(date("2023-02-29").is_err());
+        assert!(date("2024/01/01").is_err());
+    }
+}